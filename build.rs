@@ -0,0 +1,15 @@
+fn main() {
+    // the gRPC service is generated from its protobuf definition
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/kvs.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc is not vendored");
+        std::env::set_var("PROTOC", protoc);
+        // the transport helpers of clients need the 2021 prelude
+        tonic_build::configure()
+            .build_transport(false)
+            .compile_protos(&["proto/kvs.proto"], &["proto"])
+            .expect("failed to compile proto/kvs.proto");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
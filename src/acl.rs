@@ -0,0 +1,275 @@
+#![deny(missing_docs)]
+//! Access control lists of `KvsServer`, naming which users may read or write which keys.
+
+use crate::audit::Auditor;
+use crate::error::{Error, ErrorKind};
+use crate::replication::{Changes, ReplicationCursor};
+use crate::{ChangeOp, KvsEngine, Result, ScanCursor, ScanPage, Watch};
+use failure::ResultExt;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// The users of a server, each authenticating with its own token.
+///
+/// Users may read the keys starting with one of their read prefixes, and set or remove the
+/// keys starting with one of their write prefixes. The empty prefix covers every key.
+/// Listing keys by prefix only returns the keys the user may read. Only admin users may
+/// run the commands managing the server, see `AdminCommand`.
+///
+/// An ACL can be loaded from a JSON file like:
+///
+/// ```json
+/// {
+///     "users": [
+///         { "name": "billing", "token": "s3cr3t", "read": ["billing:", "shared:"], "write": ["billing:"] },
+///         { "name": "admin", "token": "r00t", "read": [""], "write": [""], "admin": true }
+///     ]
+/// }
+/// ```
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{Acl, KvStore, KvsServer, SharedKvStore, User};
+///
+/// let acl = Acl::new()
+///     .user(User::new("billing", "s3cr3t").read("billing:").write("billing:"))
+///     .user(User::new("reports", "r3p0rts").read("billing:"));
+/// let store = SharedKvStore::new(KvStore::open(".").unwrap()).unwrap();
+/// KvsServer::new(store).acl(acl).run("127.0.0.1:4000").unwrap();
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Acl {
+    users: Vec<User>,
+}
+
+/// A user of a server, see `Acl`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct User {
+    name: String,
+    token: String,
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+    #[serde(default)]
+    admin: bool,
+}
+
+impl Acl {
+    /// Create an ACL without users.
+    pub fn new() -> Acl {
+        Acl::default()
+    }
+
+    /// Load an ACL from a JSON file, see `Acl`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to read `path`.
+    /// - Serde: The file is not a valid ACL.
+    pub fn load(path: &Path) -> Result<Acl> {
+        let reader = BufReader::new(File::open(path).context(ErrorKind::Io)?);
+        Ok(serde_json::from_reader(reader).context(ErrorKind::Serde)?)
+    }
+
+    /// Add a user. If several users have the same token, the first one is used.
+    pub fn user(mut self, user: User) -> Acl {
+        self.users.push(user);
+        self
+    }
+
+    /// Add the users of `other`.
+    pub(crate) fn extend(&mut self, other: Acl) {
+        self.users.extend(other.users);
+    }
+
+    /// The user authenticating with `token`.
+    pub(crate) fn authenticate(&self, token: &[u8]) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|user| token_eq(user.token.as_bytes(), token))
+    }
+}
+
+impl User {
+    /// Create a user authenticating with `token`, without access to any key.
+    pub fn new(name: &str, token: &str) -> User {
+        User {
+            name: name.to_owned(),
+            token: token.to_owned(),
+            read: Vec::new(),
+            write: Vec::new(),
+            admin: false,
+        }
+    }
+
+    /// Allow reading the keys starting with `prefix`.
+    pub fn read(mut self, prefix: &str) -> User {
+        self.read.push(prefix.to_owned());
+        self
+    }
+
+    /// Allow setting and removing the keys starting with `prefix`.
+    pub fn write(mut self, prefix: &str) -> User {
+        self.write.push(prefix.to_owned());
+        self
+    }
+
+    /// Allow the commands managing the server, see `AdminCommand`.
+    pub fn admin(mut self) -> User {
+        self.admin = true;
+        self
+    }
+
+    /// Whether the user may run the commands managing the server.
+    pub(crate) fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    /// The name of the user.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn can_read(&self, key: &str) -> bool {
+        self.read
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    fn can_write(&self, key: &str) -> bool {
+        self.write
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
+
+/// An engine seen through the permissions of a user, or without restrictions for a server
+/// without an ACL, recording the changes of the user to the audit log of the server if it
+/// has one.
+pub(crate) struct Restricted<'a, E> {
+    engine: &'a mut E,
+    user: Option<&'a User>,
+    audit: Option<Auditor<'a>>,
+}
+
+impl<'a, E: KvsEngine> Restricted<'a, E> {
+    pub(crate) fn new(engine: &'a mut E, user: Option<&'a User>) -> Restricted<'a, E> {
+        Restricted {
+            engine,
+            user,
+            audit: None,
+        }
+    }
+
+    /// Record the changes which succeed with `audit`.
+    pub(crate) fn audit(mut self, audit: Option<Auditor<'a>>) -> Restricted<'a, E> {
+        self.audit = audit;
+        self
+    }
+
+    /// Fail unless the user may read (or write if `write`) `key`.
+    fn check(&self, key: &str, write: bool) -> Result<()> {
+        match self.user {
+            Some(user) if write && !user.can_write(key) => {
+                Err(Error::from(ErrorKind::PermissionDenied))
+            }
+            Some(user) if !write && !user.can_read(key) => {
+                Err(Error::from(ErrorKind::PermissionDenied))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a, E: KvsEngine> KvsEngine for Restricted<'a, E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.check(&key, true)?;
+        match self.audit {
+            Some(audit) => {
+                self.engine.set(key.clone(), value)?;
+                audit.record(self.user, ChangeOp::Set, &key);
+                Ok(())
+            }
+            None => self.engine.set(key, value),
+        }
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.check(&key, false)?;
+        self.engine.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.check(&key, true)?;
+        match self.audit {
+            Some(audit) => {
+                self.engine.remove(key.clone())?;
+                audit.record(self.user, ChangeOp::Remove, &key);
+                Ok(())
+            }
+            None => self.engine.remove(key),
+        }
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.check(&key, true)?;
+        let op = match new {
+            Some(_) => ChangeOp::Set,
+            None => ChangeOp::Remove,
+        };
+        let swapped = self.engine.compare_and_swap(key.clone(), expected, new)?;
+        if let (true, Some(audit)) = (swapped, self.audit) {
+            audit.record(self.user, op, &key);
+        }
+        Ok(swapped)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = self.engine.scan_prefix(prefix)?;
+        if let Some(user) = self.user {
+            pairs.retain(|(key, _)| user.can_read(key));
+        }
+        Ok(pairs)
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        // pages keep their cursor, though they may lose pairs the user cannot read
+        let mut page = self.engine.scan_page(prefix, cursor, limit)?;
+        if let Some(user) = self.user {
+            page.entries.retain(|(key, _)| user.can_read(key));
+        }
+        Ok(page)
+    }
+
+    fn changes(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        // replicas get every key
+        self.check("", false)?;
+        self.engine.changes(cursor)
+    }
+
+    fn subscribe(&mut self, prefixes: Vec<String>) -> Result<Watch> {
+        // events of keys the user cannot read are left out by the server, see
+        // `User::can_read`
+        self.engine.subscribe(prefixes)
+    }
+}
+
+/// Whether a token sent by a client is the expected one, in a time not depending on where
+/// they differ, so that the token cannot be guessed byte by byte.
+fn token_eq(expected: &[u8], token: &[u8]) -> bool {
+    expected.len() == token.len()
+        && expected
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,124 @@
+#![deny(missing_docs)]
+//! An async client of `KvsServer` on tokio. Needs the `async` feature.
+
+use crate::error::{Error, ErrorKind};
+use crate::protocol::{self, Request, Response};
+use crate::Result;
+use failure::ResultExt;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// A connection to a `KvsServer` like `KvsClient`, for async code running on tokio.
+/// Needs the `async` feature.
+///
+/// Requests are sent one at a time, each waiting for its response without blocking the
+/// thread. Tasks sending requests concurrently need a connection each.
+///
+/// The first request fails with IncompatibleVersion if the server speaks none of the
+/// versions of the protocol of the client, as for `KvsClient`.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::AsyncKvsClient;
+///
+/// # async fn example() {
+/// let mut client = AsyncKvsClient::connect("127.0.0.1:4000").await.unwrap();
+/// client.set("key1".to_owned(), "42".to_owned()).await.unwrap();
+/// assert_eq!(client.get("key1".to_owned()).await.unwrap(), Some("42".to_owned()));
+/// # }
+/// ```
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    /// Whether the answer of the server to the handshake is yet to be read.
+    handshake_pending: bool,
+}
+
+impl AsyncKvsClient {
+    /// Connect to the server listening on `addr`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to connect.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<AsyncKvsClient> {
+        let stream = TcpStream::connect(addr).await.context(ErrorKind::Io)?;
+        let (reader, writer) = stream.into_split();
+        let mut writer = BufWriter::new(writer);
+        // buffered until the first request
+        protocol::write_handshake_async(&mut writer).await?;
+        Ok(AsyncKvsClient {
+            reader: BufReader::new(reader),
+            writer,
+            handshake_pending: true,
+        })
+    }
+
+    /// Authenticate the connection, see `KvsClient::authenticate`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - Unauthenticated: The token is not the one of a user of the server.
+    pub async fn authenticate(&mut self, token: String) -> Result<()> {
+        self.request(Request::Auth { token }).await.map(|_| ())
+    }
+
+    /// Check that the connection works, by a round trip to the server.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    pub async fn ping(&mut self) -> Result<()> {
+        self.request(Request::Ping).await.map(|_| ())
+    }
+
+    /// Get the value of a key, or `None` if it is not present.
+    ///
+    /// # Errors
+    ///
+    /// Same as `remove`.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(Request::Get { key }).await
+    }
+
+    /// Set the value of a key, overwriting any previous value.
+    ///
+    /// # Errors
+    ///
+    /// Same as `remove`.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(Request::Set { key, value }).await.map(|_| ())
+    }
+
+    /// Remove a key.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - KeyNotFound: The key is not present.
+    /// - Unauthenticated: The server requires authentication, see `authenticate`.
+    /// - Others: The command failed on the server with an error of this kind.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.request(Request::Remove { key }).await.map(|_| ())
+    }
+
+    /// Send a request and wait for its response.
+    async fn request(&mut self, request: Request) -> Result<Option<String>> {
+        protocol::write_frame_async(&mut self.writer, &request).await?;
+        self.writer.flush().await.context(ErrorKind::Io)?;
+        if self.handshake_pending {
+            protocol::read_handshake_async(&mut self.reader).await?;
+            self.handshake_pending = false;
+        }
+        let response: Response = protocol::read_frame_async(&mut self.reader)
+            .await?
+            // the server closed the connection instead of answering
+            .ok_or_else(|| Error::from(ErrorKind::Io))?;
+        response.into_result()
+    }
+}
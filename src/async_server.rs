@@ -0,0 +1,145 @@
+#![deny(missing_docs)]
+//! A server multiplexing connections on tokio. Needs the `async` feature.
+
+use crate::acl::{Acl, User};
+use crate::error::ErrorKind;
+use crate::protocol::{self, Greeting};
+use crate::server::{respond, AdminPolicy};
+use crate::{KvsEngine, Result};
+use failure::ResultExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// A server like `KvsServer` speaking the protocol of `KvsClient`, serving every connection
+/// at once as a task of a tokio runtime instead of one connection after the other.
+/// Needs the `async` feature.
+///
+/// Idle connections only cost their task, so thousands of clients can stay connected.
+/// Like with `KvsServer`, each connection runs its commands on a clone of the engine.
+/// Commands block the thread of their task while they run, so the runtime has to be
+/// multi-threaded.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{AsyncKvsServer, KvStore, SharedKvStore};
+///
+/// let store = SharedKvStore::new(KvStore::open(".").unwrap()).unwrap();
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// runtime
+///     .block_on(AsyncKvsServer::new(store).run("127.0.0.1:4000"))
+///     .unwrap();
+/// ```
+pub struct AsyncKvsServer<E> {
+    engine: E,
+    acl: Option<Arc<Acl>>,
+    admin: Arc<AdminPolicy>,
+}
+
+impl<E: KvsEngine + Clone + Send + 'static> AsyncKvsServer<E> {
+    /// Create a server of `engine`, cloned for each connection.
+    pub fn new(engine: E) -> AsyncKvsServer<E> {
+        AsyncKvsServer {
+            engine,
+            acl: None,
+            admin: Arc::default(),
+        }
+    }
+
+    /// Require clients to authenticate with `token`, see `KvsServer::auth_token`.
+    pub fn auth_token(self, token: String) -> AsyncKvsServer<E> {
+        self.acl(Acl::new().user(User::new("default", &token).read("").write("")))
+    }
+
+    /// Require clients to authenticate as users of `acl`, see `KvsServer::acl`.
+    pub fn acl(mut self, acl: Acl) -> AsyncKvsServer<E> {
+        let mut users = self
+            .acl
+            .take()
+            .map_or_else(Acl::new, |users| (*users).clone());
+        users.extend(acl);
+        self.acl = Some(Arc::new(users));
+        self
+    }
+
+    /// Run the commands managing the server for clients not authenticated as an admin
+    /// user, see `KvsServer::allow_admin`.
+    pub fn allow_admin(mut self) -> AsyncKvsServer<E> {
+        Arc::make_mut(&mut self.admin).allowed = true;
+        self
+    }
+
+    /// Write the backups asked by clients in `dir`, see `KvsServer::backup_dir`.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> AsyncKvsServer<E> {
+        Arc::make_mut(&mut self.admin).backup_dir = Some(dir.into());
+        self
+    }
+
+    /// Listen on `addr` and serve the clients connecting to it, until listening fails.
+    ///
+    /// A connection failing is reported on stderr and does not stop the server.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to listen on `addr`, or to accept a connection.
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).await.context(ErrorKind::Io)?;
+        loop {
+            let (stream, peer) = listener.accept().await.context(ErrorKind::Io)?;
+            let engine = self.engine.clone();
+            let acl = self.acl.clone();
+            let admin = Arc::clone(&self.admin);
+            tokio::spawn(async move {
+                if let Err(e) = serve(engine, acl, admin, stream).await {
+                    eprintln!("Error serving client {:?}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+/// Answer the requests of a connection until the client closes it.
+///
+/// # Errors
+///
+/// - Io: Failed to read a request or write a response.
+/// - Serde: Received a malformed request.
+/// - IncompatibleVersion: The client speaks none of the versions of the protocol.
+async fn serve<E: KvsEngine>(
+    mut engine: E,
+    acl: Option<Arc<Acl>>,
+    admin: Arc<AdminPolicy>,
+    stream: TcpStream,
+) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let acl = acl.as_deref();
+    let mut first = match protocol::accept_handshake_async(&mut reader, &mut writer).await? {
+        Some(Greeting::Handshake) => None,
+        Some(Greeting::Legacy(request)) => Some(request),
+        None => return Ok(()),
+    };
+    let mut user = None;
+    loop {
+        let request = match first.take() {
+            Some(request) => request,
+            None => match protocol::read_frame_async(&mut reader).await? {
+                Some(request) => request,
+                None => break,
+            },
+        };
+        let response = tokio::task::block_in_place(|| {
+            respond(&mut engine, acl, &admin, None, &mut user, request)
+        });
+        protocol::write_frame_async(&mut writer, &response).await?;
+        // answer pipelined requests together
+        if reader.buffer().is_empty() {
+            writer.flush().await.context(ErrorKind::Io)?;
+        }
+    }
+    writer.flush().await.context(ErrorKind::Io)?;
+    Ok(())
+}
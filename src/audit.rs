@@ -0,0 +1,125 @@
+#![deny(missing_docs)]
+//! Audit logs of the changes clients make through a `KvsServer`.
+
+use crate::acl::User;
+use crate::error::ErrorKind;
+use crate::{now_millis, ChangeOp, Result};
+use failure::ResultExt;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A change of a key made by a client, as recorded by an `AuditLog`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When the change was made, in milliseconds since UNIX epoch.
+    pub time_ms: u64,
+    /// The user the connection authenticated as, or `None` on a server without an ACL.
+    pub user: Option<String>,
+    /// The address of the client, or `None` over a Unix domain socket.
+    pub peer: Option<SocketAddr>,
+    /// Whether the key was set or removed.
+    pub op: ChangeOp,
+    /// The key changed.
+    pub key: String,
+}
+
+/// Records who changed which key through a `KvsServer`, and when, see `KvsServer::audit_log`.
+///
+/// Every set and remove which succeeds is recorded, whatever the protocol, with the user
+/// the connection authenticated as and the address of the client. Values are left out, as
+/// they may be sensitive. Changes made by the server itself, like removing expired keys,
+/// are not recorded.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{AuditLog, KvStore, KvsServer, SharedKvStore};
+///
+/// let audit_log = AuditLog::open("audit.log").unwrap();
+/// KvsServer::new(SharedKvStore::new(KvStore::open(".").unwrap()).unwrap())
+///     .audit_log(audit_log)
+///     .run("127.0.0.1:4000")
+///     .unwrap();
+///
+/// // or forward the records elsewhere
+/// let audit_log = AuditLog::callback(|record| println!("{:?}", record));
+/// ```
+#[derive(Clone)]
+pub struct AuditLog {
+    sink: Arc<dyn Fn(&AuditRecord) + Send + Sync>,
+}
+
+impl AuditLog {
+    /// Append the records to the file at `path` as lines of JSON, creating it if it does
+    /// not exist. Each record is written with a single write, so that records of
+    /// concurrent connections do not interleave. Failing to write a record is reported on
+    /// stderr.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to open the file.
+    pub fn open(path: impl AsRef<Path>) -> Result<AuditLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(ErrorKind::Io)?;
+        let file = Mutex::new(file);
+        Ok(AuditLog::callback(move |record| {
+            if let Err(e) = append(&mut file.lock().unwrap(), record) {
+                eprintln!("Failed to write an audit record: {}", e);
+            }
+        }))
+    }
+
+    /// Pass the records to `callback`, on the thread of the connection making the change.
+    pub fn callback(callback: impl Fn(&AuditRecord) + Send + Sync + 'static) -> AuditLog {
+        AuditLog {
+            sink: Arc::new(callback),
+        }
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").finish()
+    }
+}
+
+/// Write `record` at the end of `file` as a line of JSON.
+fn append(file: &mut File, record: &AuditRecord) -> Result<()> {
+    let mut line = serde_json::to_vec(record).context(ErrorKind::Serde)?;
+    line.push(b'\n');
+    file.write_all(&line).context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// The audit log of a server with the address of a connection, recording the changes made
+/// by the connection.
+#[derive(Clone, Copy)]
+pub(crate) struct Auditor<'a> {
+    log: &'a AuditLog,
+    peer: Option<SocketAddr>,
+}
+
+impl<'a> Auditor<'a> {
+    pub(crate) fn new(log: &'a AuditLog, peer: Option<SocketAddr>) -> Auditor<'a> {
+        Auditor { log, peer }
+    }
+
+    /// Record that `user` made the change `op` of `key`.
+    pub(crate) fn record(&self, user: Option<&User>, op: ChangeOp, key: &str) {
+        (self.log.sink)(&AuditRecord {
+            time_ms: now_millis(),
+            user: user.map(|user| user.name().to_owned()),
+            peer: self.peer,
+            op,
+            key: key.to_owned(),
+        });
+    }
+}
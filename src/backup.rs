@@ -0,0 +1,465 @@
+#![deny(missing_docs)]
+//! Backups of a store, see `KvStore::backup`.
+//!
+//! A backup is a directory, or a tar archive if its path ends with `.tar`, holding a copy of
+//! the log file, its bloom filter, the value log if there is one and a manifest describing
+//! the copy. The log file and the value log only grow between compactions, so their bytes
+//! up to their length at any moment are a consistent snapshot, which is copied
+//! without stopping the store. A directory backup is itself a store directory that can be
+//! opened.
+//!
+//! An incremental backup only holds the bytes appended to the log file and the value log
+//! since an earlier backup, which it continues. The checksums in its manifest cover the
+//! files from the start, so a chain of backups can be checked as a whole, as
+//! `KvStore::restore` does.
+
+use crate::error::{Error, ErrorKind};
+use crate::format::{self, FORMAT_VERSION};
+use crate::{Result, BLOOM_FILE_NAME, LOG_FILE_NAME, VALUE_LOG_FILE_NAME};
+use crc32fast::Hasher;
+use failure::ResultExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest file of a backup.
+const MANIFEST_FILE_NAME: &str = "BACKUP";
+/// Number of bytes before a cursor that are checked to tell if the log file was rewritten.
+const CURSOR_TAIL_LEN: u64 = 4096;
+
+/// How far the backups of a store have copied its log file, returned by `KvStore::backup`
+/// and `KvStore::backup_since`.
+///
+/// It can be serialized to be kept until the next backup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupCursor {
+    /// Length of the log file that was copied.
+    offset: u64,
+    /// CRC-32 of the log file up to `offset`.
+    checksum: u32,
+    /// CRC-32 of the last `CURSOR_TAIL_LEN` bytes up to `offset`, to tell if the log file
+    /// is still the one the cursor was taken from without reading all of it.
+    tail_checksum: u32,
+    /// Length of the value log that was copied.
+    #[serde(default)]
+    value_log_offset: u64,
+    /// CRC-32 of the value log up to `value_log_offset`.
+    #[serde(default)]
+    value_log_checksum: u32,
+}
+
+impl BackupCursor {
+    /// Length of the log file that was copied.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+/// A full backup of a store as of when it started, returned by `KvStore::start_backup`
+/// and copied by `run`.
+pub struct BackupJob {
+    pub(crate) dest: PathBuf,
+    /// The log file, copied up to `log_len`.
+    pub(crate) log_file: File,
+    pub(crate) log_len: u64,
+    /// The value log with its length, if the store has one.
+    pub(crate) value_log: Option<(File, u64)>,
+    /// The bloom filter, encoded for the log file up to `log_len`.
+    pub(crate) bloom: Vec<u8>,
+}
+
+impl BackupJob {
+    /// Copy the backup, returning a cursor of how far the log file was copied, see
+    /// `KvStore::backup`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If the log file failed to be read, or the backup failed to be written.
+    /// - Serde: If the manifest failed to be serialized.
+    pub fn run(self) -> Result<BackupCursor> {
+        write_backup(
+            &self.dest,
+            self.log_file,
+            self.log_len,
+            self.value_log,
+            None,
+            Some(&self.bloom),
+        )
+    }
+}
+
+/// Options for `KvStore::restore_with_options`.
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// Incremental backups applied after the full backup, oldest first. Each of them must
+    /// continue the one before it.
+    pub incremental: Vec<PathBuf>,
+    /// Replace the store in the target directory if there is one.
+    pub force: bool,
+}
+
+/// Describes the copy of the log file in a backup.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// Format version of the log file.
+    format_version: u32,
+    /// Offset of the first copied byte of the log file, 0 unless the backup is incremental.
+    start: u64,
+    /// Length of the log file that was copied.
+    end: u64,
+    /// CRC-32 of the log file up to `end`.
+    checksum: u32,
+    /// Copied range of the value log, if the store has one.
+    #[serde(default)]
+    value_log: Option<Segment>,
+}
+
+/// Describes the copy of a range of a file in a backup.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct Segment {
+    /// Offset of the first copied byte, 0 unless the backup is incremental.
+    start: u64,
+    /// Length of the file that was copied.
+    end: u64,
+    /// CRC-32 of the file up to `end`.
+    checksum: u32,
+}
+
+/// Destination of a backup, a directory or a tar archive.
+enum BackupWriter {
+    Dir(PathBuf),
+    Tar(tar::Builder<BufWriter<File>>),
+}
+
+impl BackupWriter {
+    /// Create the directory or the archive at `path`.
+    fn create(path: &Path) -> io::Result<BackupWriter> {
+        if path.extension().is_some_and(|extension| extension == "tar") {
+            let file = File::create(path)?;
+            Ok(BackupWriter::Tar(tar::Builder::new(BufWriter::new(file))))
+        } else {
+            fs::create_dir_all(path)?;
+            Ok(BackupWriter::Dir(path.to_owned()))
+        }
+    }
+
+    /// Add a file of `len` bytes read from `reader`.
+    fn add<R: Read>(&mut self, name: &str, len: u64, reader: R) -> io::Result<()> {
+        match self {
+            BackupWriter::Dir(path) => {
+                let mut file = File::create(path.join(name))?;
+                io::copy(&mut reader.take(len), &mut file)?;
+                file.sync_all()
+            }
+            BackupWriter::Tar(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(len);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, name, reader.take(len))
+            }
+        }
+    }
+
+    /// Finish the backup, making sure it is on disk.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            BackupWriter::Dir(_) => Ok(()),
+            BackupWriter::Tar(builder) => builder
+                .into_inner()?
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all(),
+        }
+    }
+}
+
+/// Visit the files of the backup at `path` with their names, in the order they were written.
+fn read_backup<F>(path: &Path, mut visit: F) -> io::Result<()>
+where
+    F: FnMut(&str, &mut dyn Read) -> io::Result<()>,
+{
+    if path.is_dir() {
+        for name in [
+            LOG_FILE_NAME,
+            BLOOM_FILE_NAME,
+            VALUE_LOG_FILE_NAME,
+            MANIFEST_FILE_NAME,
+        ] {
+            match File::open(path.join(name)) {
+                Ok(mut file) => visit(name, &mut file)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+    } else {
+        let mut archive = tar::Archive::new(File::open(path)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            visit(&name, &mut entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reader computing the CRC-32 of what it reads.
+struct ChecksumReader<R> {
+    reader: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// CRC-32 of the last `CURSOR_TAIL_LEN` bytes of `file` up to `offset`.
+pub(crate) fn tail_checksum(file: &mut File, offset: u64) -> io::Result<u32> {
+    let start = offset.saturating_sub(CURSOR_TAIL_LEN);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::with_capacity((offset - start) as usize);
+    file.take(offset - start).read_to_end(&mut tail)?;
+    Ok(crc32fast::hash(&tail))
+}
+
+/// Add the bytes of `file` between `start` and `end` to `writer` as `name`, continuing the
+/// CRC-32 `checksum` of the bytes before `start`. Returns the CRC-32 up to `end`.
+fn add_segment(
+    writer: &mut BackupWriter,
+    name: &str,
+    file: &mut File,
+    start: u64,
+    end: u64,
+    checksum: u32,
+) -> io::Result<u32> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = ChecksumReader {
+        reader: file,
+        hasher: Hasher::new_with_initial(checksum),
+    };
+    writer.add(name, end - start, &mut reader)?;
+    Ok(reader.hasher.finalize())
+}
+
+/// Write a backup of the first `len` bytes of `log_file` to `dest`, with the first bytes of
+/// the value log and a serialized bloom filter if given. Only the bytes after `since` are
+/// copied if it is given.
+///
+/// # Errors
+///
+/// - StaleBackupCursor: The log file was rewritten or cut short since `since` was taken.
+/// - Io: Failed to read the log file, or to create or write the backup.
+/// - Serde: Failed to serialize the manifest.
+pub(crate) fn write_backup(
+    dest: &Path,
+    mut log_file: File,
+    len: u64,
+    value_log: Option<(File, u64)>,
+    since: Option<&BackupCursor>,
+    bloom: Option<&[u8]>,
+) -> Result<BackupCursor> {
+    let since = match since {
+        Some(cursor) => {
+            let value_log_len = value_log.as_ref().map_or(0, |(_, len)| *len);
+            if cursor.offset > len
+                || cursor.value_log_offset > value_log_len
+                || tail_checksum(&mut log_file, cursor.offset).context(ErrorKind::Io)?
+                    != cursor.tail_checksum
+            {
+                return Err(Error::from(ErrorKind::StaleBackupCursor));
+            }
+            *cursor
+        }
+        None => BackupCursor {
+            offset: 0,
+            checksum: 0,
+            tail_checksum: 0,
+            value_log_offset: 0,
+            value_log_checksum: 0,
+        },
+    };
+
+    let mut writer = BackupWriter::create(dest).context(ErrorKind::Io)?;
+    let checksum = add_segment(
+        &mut writer,
+        LOG_FILE_NAME,
+        &mut log_file,
+        since.offset,
+        len,
+        since.checksum,
+    )
+    .context(ErrorKind::Io)?;
+    let value_log = match value_log {
+        Some((mut file, end)) => {
+            let start = since.value_log_offset;
+            let checksum = add_segment(
+                &mut writer,
+                VALUE_LOG_FILE_NAME,
+                &mut file,
+                start,
+                end,
+                since.value_log_checksum,
+            )
+            .context(ErrorKind::Io)?;
+            Some(Segment {
+                start,
+                end,
+                checksum,
+            })
+        }
+        None => None,
+    };
+    if let Some(bloom) = bloom {
+        writer
+            .add(BLOOM_FILE_NAME, bloom.len() as u64, bloom)
+            .context(ErrorKind::Io)?;
+    }
+
+    // the manifest is written last, so a backup without it is incomplete
+    let manifest = serde_json::to_vec(&Manifest {
+        format_version: FORMAT_VERSION,
+        start: since.offset,
+        end: len,
+        checksum,
+        value_log,
+    })
+    .context(ErrorKind::Serde)?;
+    writer
+        .add(
+            MANIFEST_FILE_NAME,
+            manifest.len() as u64,
+            manifest.as_slice(),
+        )
+        .context(ErrorKind::Io)?;
+    writer.finish().context(ErrorKind::Io)?;
+    let value_log = value_log.unwrap_or_default();
+    Ok(BackupCursor {
+        offset: len,
+        checksum,
+        tail_checksum: tail_checksum(&mut log_file, len).context(ErrorKind::Io)?,
+        value_log_offset: value_log.end,
+        value_log_checksum: value_log.checksum,
+    })
+}
+
+/// Copy `reader` to `writer`, continuing the CRC-32 `checksum`. Returns the number of bytes
+/// copied and the CRC-32 after them.
+fn copy_segment<W: Write>(
+    reader: &mut dyn Read,
+    writer: &mut W,
+    checksum: u32,
+) -> io::Result<(u64, u32)> {
+    let mut reader = ChecksumReader {
+        reader,
+        hasher: Hasher::new_with_initial(checksum),
+    };
+    let len = io::copy(&mut reader, writer)?;
+    Ok((len, reader.hasher.finalize()))
+}
+
+/// Whether `copied` bytes continue `previous` into `segment`.
+fn continues(previous: &Segment, segment: &Segment, copied: Option<(u64, u32)>) -> bool {
+    match copied {
+        Some((len, checksum)) => {
+            segment.start == previous.end
+                && segment.end == previous.end + len
+                && segment.checksum == checksum
+        }
+        None => false,
+    }
+}
+
+/// Write the log file and the value log of a full backup and the incremental backups
+/// continuing it to `writer` and `value_log_writer`, checking them on the way. Returns the
+/// bloom filter of the full backup.
+///
+/// # Errors
+///
+/// - InvalidBackup: A backup has no manifest, is damaged, or does not continue the one
+///   before it.
+/// - UnsupportedVersion: A backup is of an unknown format version.
+/// - Io: Failed to read a backup or to write the log file or the value log.
+pub(crate) fn restore_log<W: Write, V: Write>(
+    full: &Path,
+    incremental: &[PathBuf],
+    mut writer: W,
+    mut value_log_writer: V,
+) -> Result<Option<Vec<u8>>> {
+    let mut log = Segment::default();
+    let mut value_log = Segment::default();
+    let mut bloom = None;
+    for path in std::iter::once(full).chain(incremental.iter().map(PathBuf::as_path)) {
+        let mut copied = None;
+        let mut copied_value_log = None;
+        let mut manifest = None;
+        read_backup(path, |name, reader| {
+            match name {
+                LOG_FILE_NAME => {
+                    copied = Some(copy_segment(reader, &mut writer, log.checksum)?);
+                }
+                VALUE_LOG_FILE_NAME => {
+                    copied_value_log = Some(copy_segment(
+                        reader,
+                        &mut value_log_writer,
+                        value_log.checksum,
+                    )?);
+                }
+                BLOOM_FILE_NAME if log.end == 0 => {
+                    let mut bytes = Vec::new();
+                    reader.read_to_end(&mut bytes)?;
+                    bloom = Some(bytes);
+                }
+                MANIFEST_FILE_NAME => {
+                    let parsed: Manifest = serde_json::from_reader(reader)?;
+                    manifest = Some(parsed);
+                }
+                _ => {}
+            }
+            Ok(())
+        })
+        .context(ErrorKind::Io)?;
+
+        let manifest = manifest.ok_or_else(|| Error::from(ErrorKind::InvalidBackup))?;
+        if !format::is_readable(manifest.format_version) {
+            return Err(Error::from(ErrorKind::UnsupportedVersion));
+        }
+        let manifest_log = Segment {
+            start: manifest.start,
+            end: manifest.end,
+            checksum: manifest.checksum,
+        };
+        if !continues(&log, &manifest_log, copied) {
+            return Err(Error::from(ErrorKind::InvalidBackup));
+        }
+        log = manifest_log;
+        match manifest.value_log {
+            Some(segment) if continues(&value_log, &segment, copied_value_log) => {
+                value_log = segment;
+            }
+            None if copied_value_log.is_none() => {}
+            _ => return Err(Error::from(ErrorKind::InvalidBackup)),
+        }
+    }
+    writer.flush().context(ErrorKind::Io)?;
+    value_log_writer.flush().context(ErrorKind::Io)?;
+    Ok(bloom)
+}
+
+/// Check the header of a restored log file.
+///
+/// # Errors
+///
+/// - InvalidBackup: The log file has no header.
+/// - UnsupportedVersion: The log file is of another format version.
+/// - Io: Failed to read the log file.
+pub(crate) fn check_restored_log(path: &Path) -> Result<()> {
+    match format::read_version(File::open(path).context(ErrorKind::Io)?)? {
+        Some(version) if format::is_readable(version) => Ok(()),
+        Some(_) => Err(Error::from(ErrorKind::UnsupportedVersion)),
+        None => Err(Error::from(ErrorKind::InvalidBackup)),
+    }
+}
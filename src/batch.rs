@@ -0,0 +1,72 @@
+#![deny(missing_docs)]
+//! Batch of commands applied atomically by `KvStore::write`.
+
+use crate::KvLog;
+
+/// A batch of set and remove commands.
+///
+/// The batch is written to the log as a single record, so after a crash either all of its
+/// commands are present or none of them are. Commands are applied in the order they were added.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::{KvStore, WriteBatch};
+/// use tempfile::TempDir;
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::open(tempdir.path()).unwrap();
+/// kv.set("key1".to_owned(), "42".to_owned()).unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("key2".to_owned(), "43".to_owned())
+///     .remove("key1".to_owned());
+/// kv.write(batch).unwrap();
+///
+/// assert_eq!(kv.get("key1".to_owned()).unwrap(), None);
+/// assert_eq!(kv.get("key2".to_owned()).unwrap(), Some("43".to_owned()));
+/// ```
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    logs: Vec<KvLog>,
+}
+
+impl WriteBatch {
+    /// Create an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Add a set command to the batch.
+    pub fn set<K: Into<Vec<u8>>>(&mut self, key: K, value: String) -> &mut WriteBatch {
+        self.set_bytes(key, value.into_bytes())
+    }
+
+    /// Add a set command with a binary value to the batch.
+    pub fn set_bytes<K: Into<Vec<u8>>>(&mut self, key: K, value: Vec<u8>) -> &mut WriteBatch {
+        self.logs.push(KvLog::new_set(key.into(), value));
+        self
+    }
+
+    /// Add a remove command to the batch.
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) -> &mut WriteBatch {
+        self.logs.push(KvLog::new_rm(key.into()));
+        self
+    }
+
+    /// Number of commands in the batch.
+    pub fn len(&self) -> usize {
+        self.logs.len()
+    }
+
+    /// Returns true if the batch has no command.
+    pub fn is_empty(&self) -> bool {
+        self.logs.is_empty()
+    }
+
+    /// Turn the batch into its commands.
+    pub(crate) fn into_logs(self) -> Vec<KvLog> {
+        self.logs
+    }
+}
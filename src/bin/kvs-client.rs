@@ -0,0 +1,217 @@
+use clap::Clap;
+use kvs::{ChangeOp, ErrorKind, KvsClient, Result, RetryPolicy, ScanCursor};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clap)]
+#[clap(name = "kvs-client", author, about = "Talk to a kvs-server", version)]
+pub struct Options {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+    #[clap(long, global = true, default_value = "127.0.0.1:4000")]
+    addr: Address,
+    #[clap(long, global = true, parse(from_os_str))]
+    ca: Option<PathBuf>,
+    #[clap(long, global = true, requires = "ca")]
+    server_name: Option<String>,
+    #[clap(long, global = true)]
+    auth_token: Option<String>,
+    #[clap(long, global = true)]
+    timeout_ms: Option<u64>,
+    #[clap(long, global = true, default_value = "0")]
+    retries: u32,
+}
+
+/// Address of the server: a TCP address, or the path of a Unix domain socket after
+/// `unix://`.
+enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Address, String> {
+        match s.strip_prefix("unix://") {
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Address::Tcp)
+                .map_err(|e| format!("{}: {}", s, e)),
+        }
+    }
+}
+
+#[derive(Clap)]
+enum SubCommand {
+    #[clap(author, about = "Set the value of a string key to a string", version)]
+    Set(SetCmd),
+    #[clap(author, about = "Get the string value of a given string key", version)]
+    Get(GetCmd),
+    #[clap(author, about = "Remove a given key", version)]
+    Rm(RmCmd),
+    #[clap(
+        author,
+        about = "List the keys starting with a prefix with their values, one per line",
+        version
+    )]
+    Scan(ScanCmd),
+    #[clap(
+        author,
+        about = "Print the changes of the keys starting with the prefixes, one per line",
+        version
+    )]
+    Subscribe(SubscribeCmd),
+    #[clap(author, about = "Compact the store of the server now", version)]
+    Compact,
+    #[clap(
+        author,
+        about = "Print statistics about the store of the server",
+        version
+    )]
+    Stats,
+    #[clap(
+        author,
+        about = "Make the writes the server took so far durable",
+        version
+    )]
+    Flush,
+    #[clap(
+        author,
+        about = "Start backing the store up to a directory or .tar archive in the backup directory of the server",
+        version
+    )]
+    Bgsave(BgsaveCmd),
+}
+
+#[derive(Clap)]
+struct SetCmd {
+    key: String,
+    value: String,
+}
+
+#[derive(Clap)]
+struct GetCmd {
+    key: String,
+}
+
+#[derive(Clap)]
+struct RmCmd {
+    key: String,
+}
+
+#[derive(Clap)]
+struct ScanCmd {
+    #[clap(default_value = "")]
+    prefix: String,
+    #[clap(long, default_value = "100")]
+    page_size: usize,
+}
+
+#[derive(Clap)]
+struct SubscribeCmd {
+    prefixes: Vec<String>,
+}
+
+#[derive(Clap)]
+struct BgsaveCmd {
+    path: String,
+}
+
+fn main() -> Result<()> {
+    let opt = Options::parse();
+    let mut client = match (&opt.addr, &opt.ca, &opt.server_name) {
+        #[cfg(feature = "tls")]
+        (Address::Tcp(addr), Some(ca), server_name) => {
+            // without a name, the certificate of the server has to be valid for its address
+            let server_name = match server_name {
+                Some(name) => name.clone(),
+                None => addr.ip().to_string(),
+            };
+            KvsClient::connect_tls(addr, &server_name, kvs::tls::client_config(ca)?)?
+        }
+        #[cfg(not(feature = "tls"))]
+        (Address::Tcp(_), Some(_), _) => {
+            eprintln!("kvs-client was built without the tls feature");
+            exit(1);
+        }
+        (Address::Tcp(addr), None, _) => KvsClient::connect(addr)?,
+        (Address::Unix(_), Some(_), _) => {
+            eprintln!("TLS is not supported over Unix domain sockets");
+            exit(1);
+        }
+        #[cfg(unix)]
+        (Address::Unix(path), None, _) => KvsClient::connect_unix(path)?,
+        #[cfg(not(unix))]
+        (Address::Unix(_), None, _) => {
+            eprintln!("Unix domain sockets are not supported on this platform");
+            exit(1);
+        }
+    };
+    if let Some(timeout) = opt.timeout_ms {
+        client.set_timeout(Some(Duration::from_millis(timeout)))?;
+    }
+    client.set_retry_policy(RetryPolicy::new(opt.retries));
+    if let Some(token) = opt.auth_token {
+        client.authenticate(token)?;
+    }
+    let result = match opt.subcmd {
+        SubCommand::Set(cmd) => client.set(cmd.key, cmd.value),
+        SubCommand::Get(cmd) => client.get(cmd.key).map(|value| match value {
+            None => println!("Key not found"),
+            Some(s) => println!("{}", s),
+        }),
+        SubCommand::Rm(cmd) => client.remove(cmd.key),
+        SubCommand::Scan(cmd) => scan(&mut client, cmd),
+        SubCommand::Subscribe(cmd) => subscribe(client, cmd),
+        SubCommand::Compact => client.compact(),
+        SubCommand::Stats => client.stats().map(|stats| println!("{:#?}", stats)),
+        SubCommand::Flush => client.flush(),
+        SubCommand::Bgsave(cmd) => client.start_backup(cmd.path),
+    };
+    match result {
+        Err(e) if e.kind() == ErrorKind::KeyNotFound => {
+            println!("{}", e);
+            exit(1);
+        }
+        result => result,
+    }
+}
+
+/// Print the pairs of a scan as tab-separated lines, fetching them page by page.
+fn scan(client: &mut KvsClient, cmd: ScanCmd) -> Result<()> {
+    let mut cursor = Some(ScanCursor::default());
+    while let Some(after) = cursor {
+        let page = client.scan(cmd.prefix.clone(), &after, cmd.page_size.max(1))?;
+        for (key, value) in page.entries {
+            println!("{}\t{}", key, value);
+        }
+        cursor = page.cursor;
+    }
+    Ok(())
+}
+
+/// Print the changes of a subscription as tab-separated lines, until the server closes the
+/// connection.
+fn subscribe(client: KvsClient, cmd: SubscribeCmd) -> Result<()> {
+    let mut prefixes = cmd.prefixes;
+    if prefixes.is_empty() {
+        prefixes.push(String::new());
+    }
+    for event in client.subscribe(prefixes)? {
+        let event = event?;
+        let key = String::from_utf8_lossy(&event.key);
+        match (event.op, event.new_value) {
+            (ChangeOp::Set, Some(value)) => {
+                println!("set\t{}\t{}", key, String::from_utf8_lossy(&value))
+            }
+            (ChangeOp::Set, None) => println!("set\t{}", key),
+            (ChangeOp::Remove, _) => println!("rm\t{}", key),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,642 @@
+use clap::ValueHint;
+use clap::{ArgMatches, Clap, FromArgMatches, IntoApp};
+use kvs::{
+    Acl, AuditLog, Durability, KvStore, KvsClient, KvsEngine, KvsServer, NaiveThreadPool, Protocol,
+    Replica, Result, RuntimeOptions, SharedKvStore, SlowLog, ThreadPool, User,
+};
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::{self, Read};
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+
+#[derive(Clap)]
+#[clap(
+    name = "kvs-server",
+    author,
+    about = "Serve a key-value store over TCP",
+    version
+)]
+pub struct Options {
+    #[clap(long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[clap(short, long, parse(from_os_str), value_hint = ValueHint::DirPath, default_value = ".")]
+    path: PathBuf,
+    #[clap(long, default_value = "kvs", possible_values = &["kvs", "sled"])]
+    engine: String,
+    #[clap(long, default_value = "kvs", possible_values = &["kvs", "resp", "memcached", "http", "grpc"])]
+    protocol: String,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath, requires = "key")]
+    cert: Option<PathBuf>,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath, requires = "cert")]
+    key: Option<PathBuf>,
+    #[clap(long)]
+    auth_token: Option<String>,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    acl: Option<PathBuf>,
+    #[clap(long = "async")]
+    asynchronous: bool,
+    #[clap(long, default_value = "shared", possible_values = &["naive", "shared", "rayon"])]
+    thread_pool: String,
+    #[clap(long, default_value = "32")]
+    threads: u32,
+    #[clap(long)]
+    replica_of: Option<SocketAddr>,
+    #[clap(long, requires = "raft-nodes")]
+    raft_id: Option<u64>,
+    #[clap(long, requires = "raft-id")]
+    raft_nodes: Option<RaftNodes>,
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+    #[clap(long)]
+    slow_log_ms: Option<u64>,
+    #[clap(long, requires = "slow-log-ms")]
+    slow_log_hash_keys: bool,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    socket: Option<PathBuf>,
+    #[clap(long)]
+    max_connections: Option<usize>,
+    #[clap(long)]
+    max_in_flight: Option<usize>,
+    #[clap(long)]
+    idle_timeout_ms: Option<u64>,
+    #[clap(long)]
+    expire_keys_per_sec: Option<usize>,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    audit_log: Option<PathBuf>,
+    #[clap(long)]
+    allow_admin: bool,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    backup_dir: Option<PathBuf>,
+}
+
+/// Settings read from the TOML file given with `--config`. Options given on the command
+/// line take precedence over the file.
+///
+/// The runtime options of the store can be changed by editing the file and sending SIGHUP
+/// to the server, while the other settings are only read on start.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    addr: Option<SocketAddr>,
+    path: Option<PathBuf>,
+    engine: Option<String>,
+    protocol: Option<String>,
+    thread_pool: Option<String>,
+    threads: Option<u32>,
+    metrics_addr: Option<SocketAddr>,
+    max_connections: Option<usize>,
+    max_in_flight: Option<usize>,
+    idle_timeout_ms: Option<u64>,
+    expire_keys_per_sec: Option<usize>,
+    audit_log: Option<PathBuf>,
+    backup_dir: Option<PathBuf>,
+    compaction_garbage_ratio: Option<f64>,
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+}
+
+impl Config {
+    /// Read the config file at `path`.
+    fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path);
+        let text = failure::ResultExt::context(text, kvs::ErrorKind::Io)?;
+        Ok(failure::ResultExt::context(
+            toml::from_str(&text),
+            kvs::ErrorKind::Serde,
+        )?)
+    }
+
+    /// Fill the options not given on the command line, as told by `matches`, with the
+    /// settings of the file.
+    fn apply(self, opt: &mut Options, matches: &ArgMatches) {
+        let unset = |name: &str| matches.occurrences_of(name) == 0;
+        if let Some(addr) = self.addr.filter(|_| unset("addr")) {
+            opt.addr = addr;
+        }
+        if let Some(path) = self.path.filter(|_| unset("path")) {
+            opt.path = path;
+        }
+        if let Some(engine) = self.engine.filter(|_| unset("engine")) {
+            opt.engine = engine;
+        }
+        if let Some(protocol) = self.protocol.filter(|_| unset("protocol")) {
+            opt.protocol = protocol;
+        }
+        if let Some(thread_pool) = self.thread_pool.filter(|_| unset("thread-pool")) {
+            opt.thread_pool = thread_pool;
+        }
+        if let Some(threads) = self.threads.filter(|_| unset("threads")) {
+            opt.threads = threads;
+        }
+        if opt.metrics_addr.is_none() {
+            opt.metrics_addr = self.metrics_addr;
+        }
+        if opt.max_connections.is_none() {
+            opt.max_connections = self.max_connections;
+        }
+        if opt.max_in_flight.is_none() {
+            opt.max_in_flight = self.max_in_flight;
+        }
+        if opt.idle_timeout_ms.is_none() {
+            opt.idle_timeout_ms = self.idle_timeout_ms;
+        }
+        if opt.expire_keys_per_sec.is_none() {
+            opt.expire_keys_per_sec = self.expire_keys_per_sec;
+        }
+        if opt.audit_log.is_none() {
+            opt.audit_log = self.audit_log;
+        }
+        if opt.backup_dir.is_none() {
+            opt.backup_dir = self.backup_dir;
+        }
+    }
+
+    /// The runtime options of the store, the defaults of `KvStore` for those not set.
+    fn runtime_options(&self) -> RuntimeOptions {
+        let default = RuntimeOptions::default();
+        RuntimeOptions {
+            compaction_garbage_ratio: self
+                .compaction_garbage_ratio
+                .unwrap_or(default.compaction_garbage_ratio),
+            max_key_size: self.max_key_size.or(default.max_key_size),
+            max_value_size: self.max_value_size.or(default.max_value_size),
+        }
+    }
+}
+
+/// Nodes of a raft cluster, given as comma-separated `id=addr` pairs.
+struct RaftNodes(Vec<(u64, SocketAddr)>);
+
+impl FromStr for RaftNodes {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<RaftNodes, String> {
+        s.split(',')
+            .map(|node| {
+                let (id, addr) = node
+                    .split_once('=')
+                    .ok_or_else(|| format!("{} is not of the form id=addr", node))?;
+                let id = id.parse().map_err(|e| format!("{}: {}", id, e))?;
+                let addr = addr.parse().map_err(|e| format!("{}: {}", addr, e))?;
+                Ok((id, addr))
+            })
+            .collect::<std::result::Result<_, String>>()
+            .map(RaftNodes)
+    }
+}
+
+fn main() -> Result<()> {
+    let matches = Options::into_app().get_matches();
+    let mut opt = Options::from_arg_matches(&matches);
+    let config = match &opt.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let runtime_options = config.runtime_options();
+    config.apply(&mut opt, &matches);
+    let choices: [(&str, &str, &[&str]); 3] = [
+        ("engine", &opt.engine, &["kvs", "sled"]),
+        (
+            "protocol",
+            &opt.protocol,
+            &["kvs", "resp", "memcached", "http", "grpc"],
+        ),
+        (
+            "thread_pool",
+            &opt.thread_pool,
+            &["naive", "shared", "rayon"],
+        ),
+    ];
+    for (name, value, possible_values) in choices.iter() {
+        if !possible_values.contains(value) {
+            eprintln!(
+                "{} is not a valid {}, expected one of {:?}",
+                value, name, possible_values
+            );
+            exit(1);
+        }
+    }
+    // each engine has its own files, so a directory can only be used by one of them
+    let other_engine_file = if opt.engine == "sled" { "0.bin" } else { "db" };
+    if opt.path.join(other_engine_file).exists() {
+        eprintln!("{} holds data of another engine", opt.path.display());
+        exit(1);
+    }
+    #[cfg(not(feature = "grpc"))]
+    if opt.protocol == "grpc" {
+        eprintln!("kvs-server was built without the grpc feature");
+        exit(1);
+    }
+    #[cfg(not(feature = "tls"))]
+    if opt.cert.is_some() || opt.key.is_some() {
+        eprintln!("kvs-server was built without the tls feature");
+        exit(1);
+    }
+    if opt.cert.is_some() && opt.protocol == "grpc" {
+        eprintln!("TLS is not supported with the grpc protocol");
+        exit(1);
+    }
+    #[cfg(not(feature = "rayon"))]
+    if opt.thread_pool == "rayon" {
+        eprintln!("kvs-server was built without the rayon feature");
+        exit(1);
+    }
+    #[cfg(not(feature = "async"))]
+    if opt.asynchronous {
+        eprintln!("kvs-server was built without the async feature");
+        exit(1);
+    }
+    if opt.asynchronous && (opt.protocol != "kvs" || opt.cert.is_some()) {
+        eprintln!("The async server only supports the kvs protocol, without TLS");
+        exit(1);
+    }
+    if opt.replica_of.is_some() && opt.engine != "kvs" {
+        eprintln!("Only the kvs engine can be a replica");
+        exit(1);
+    }
+    #[cfg(not(feature = "raft"))]
+    if opt.raft_id.is_some() {
+        eprintln!("kvs-server was built without the raft feature");
+        exit(1);
+    }
+    if let (Some(id), Some(RaftNodes(nodes))) = (opt.raft_id, &opt.raft_nodes) {
+        if opt.engine != "kvs" || opt.replica_of.is_some() {
+            eprintln!("Only the kvs engine can be a raft node, and not a replica");
+            exit(1);
+        }
+        if !nodes.iter().any(|(node, _)| *node == id) {
+            eprintln!("Raft node {} is not one of the raft nodes", id);
+            exit(1);
+        }
+    }
+    if opt.metrics_addr.is_some() && (opt.asynchronous || opt.protocol == "grpc") {
+        eprintln!("Metrics are not supported by the async server or the grpc protocol");
+        exit(1);
+    }
+    #[cfg(not(unix))]
+    if opt.socket.is_some() {
+        eprintln!("Unix domain sockets are not supported on this platform");
+        exit(1);
+    }
+    if opt.socket.is_some() && (opt.asynchronous || opt.protocol == "grpc") {
+        eprintln!("Unix domain sockets are not supported by the async server or the grpc protocol");
+        exit(1);
+    }
+    if opt.slow_log_ms.is_some() && (opt.asynchronous || opt.protocol == "grpc") {
+        eprintln!("The slow log is not supported by the async server or the grpc protocol");
+        exit(1);
+    }
+    let limited = opt.max_connections.is_some() || opt.max_in_flight.is_some();
+    if limited && (opt.asynchronous || opt.protocol == "grpc") {
+        eprintln!("Connection limits are not supported by the async server or the grpc protocol");
+        exit(1);
+    }
+    if opt.idle_timeout_ms.is_some() && (opt.asynchronous || opt.protocol == "grpc") {
+        eprintln!("Idle timeouts are not supported by the async server or the grpc protocol");
+        exit(1);
+    }
+    if opt.expire_keys_per_sec.is_some() && (opt.asynchronous || opt.protocol == "grpc") {
+        eprintln!("Expiring keys is not supported by the async server or the grpc protocol");
+        exit(1);
+    }
+    if opt.audit_log.is_some() && (opt.asynchronous || opt.protocol == "grpc") {
+        eprintln!("Audit logs are not supported by the async server or the grpc protocol");
+        exit(1);
+    }
+    if opt.engine != "kvs" && runtime_options != RuntimeOptions::default() {
+        eprintln!("Runtime options are only supported by the kvs engine");
+        exit(1);
+    }
+    let authenticated = opt.auth_token.is_some() || opt.acl.is_some();
+    if authenticated && ["memcached", "grpc"].contains(&opt.protocol.as_str()) {
+        eprintln!(
+            "Authentication is not supported with the {} protocol",
+            opt.protocol
+        );
+        exit(1);
+    }
+    eprintln!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    eprintln!("Storage engine: {}", opt.engine);
+    if let Some(config) = &opt.config {
+        eprintln!("Config file: {}", config.display());
+    }
+    eprintln!("Data directory: {}", opt.path.display());
+    eprintln!("Protocol: {}", opt.protocol);
+    match opt.asynchronous {
+        true => eprintln!("Server: async"),
+        false => eprintln!(
+            "Thread pool: {} of {} threads",
+            opt.thread_pool, opt.threads
+        ),
+    }
+    if let Some(cert) = &opt.cert {
+        eprintln!("TLS certificate: {}", cert.display());
+    }
+    if authenticated {
+        eprintln!("Authentication: required");
+    }
+    if let Some(primary) = opt.replica_of {
+        eprintln!("Replica of: {}", primary);
+    }
+    if let Some(id) = opt.raft_id {
+        eprintln!("Raft node: {}", id);
+    }
+    if let Some(metrics_addr) = opt.metrics_addr {
+        eprintln!("Metrics: http://{}/metrics", metrics_addr);
+    }
+    if let Some(slow_log_ms) = opt.slow_log_ms {
+        eprintln!("Slow log: commands over {} ms", slow_log_ms);
+    }
+    if let Some(max_connections) = opt.max_connections {
+        eprintln!("Max connections: {}", max_connections);
+    }
+    if let Some(max_in_flight) = opt.max_in_flight {
+        eprintln!("Max requests in flight per connection: {}", max_in_flight);
+    }
+    if let Some(idle_timeout_ms) = opt.idle_timeout_ms {
+        eprintln!("Idle timeout: {} ms", idle_timeout_ms);
+    }
+    if let Some(expire_keys_per_sec) = opt.expire_keys_per_sec {
+        eprintln!("Expiring keys: up to {} per second", expire_keys_per_sec);
+    }
+    if let Some(audit_log) = &opt.audit_log {
+        eprintln!("Audit log: {}", audit_log.display());
+    }
+    if opt.allow_admin {
+        eprintln!("Admin commands: allowed to every client");
+    }
+    if let Some(backup_dir) = &opt.backup_dir {
+        eprintln!("Backup directory: {}", backup_dir.display());
+    }
+    match &opt.socket {
+        Some(socket) => eprintln!("Listening on {}", socket.display()),
+        None => eprintln!("Listening on {}", opt.addr),
+    }
+    match opt.engine.as_str() {
+        #[cfg(feature = "sled")]
+        "sled" => run(kvs::SledKvsEngine::open(&opt.path)?, &opt),
+        #[cfg(not(feature = "sled"))]
+        "sled" => {
+            eprintln!("kvs-server was built without the sled feature");
+            exit(1);
+        }
+        // acknowledged writes survive the server being killed
+        _ => {
+            let mut store = KvStore::builder()
+                .durability(Durability::Flush)
+                .open(&opt.path)?;
+            store.set_runtime_options(runtime_options);
+            #[cfg(feature = "raft")]
+            if let (Some(id), Some(RaftNodes(nodes))) = (opt.raft_id, &opt.raft_nodes) {
+                let node = kvs::RaftNode::start(store, id, nodes)?;
+                run(node.clone(), &opt)?;
+                node.shutdown();
+                return Ok(());
+            }
+            match opt.replica_of {
+                Some(primary) => {
+                    let replica = Replica::new(store)?;
+                    let follower = replica.clone();
+                    thread::spawn(move || follower.follow(|| KvsClient::connect(primary)));
+                    run(replica, &opt)
+                }
+                None => run(SharedKvStore::new(store)?, &opt),
+            }
+        }
+    }
+}
+
+/// Serve a storage engine as configured by the options until the server fails, or until
+/// SIGINT or SIGTERM shuts the threaded server down. The async server and the grpc protocol
+/// cannot stop once running, so the signal syncs the engine and exits instead. SIGHUP
+/// reloads the runtime options of the engine from the config file.
+fn run<E: KvsEngine + Clone + Send + Sync + 'static>(engine: E, opt: &Options) -> Result<()> {
+    if opt.asynchronous || opt.protocol == "grpc" {
+        let synced = engine.clone();
+        let reloaded = engine.clone();
+        let config = opt.config.clone();
+        on_signal(
+            move || sync_and_exit(synced),
+            move || {
+                reload(config.as_deref(), |options| {
+                    reloaded.clone().set_runtime_options(options)
+                })
+            },
+        )?;
+    }
+    let protocol = match opt.protocol.as_str() {
+        "resp" => Protocol::Resp,
+        "memcached" => Protocol::Memcached,
+        "http" => Protocol::Http,
+        #[cfg(feature = "grpc")]
+        "grpc" => return kvs::grpc::serve(engine, opt.addr),
+        _ => Protocol::Kvs,
+    };
+    let acl = acl(opt)?;
+    #[cfg(feature = "async")]
+    if opt.asynchronous {
+        let mut server = kvs::AsyncKvsServer::new(engine);
+        if let Some(acl) = acl {
+            server = server.acl(acl);
+        }
+        if opt.allow_admin {
+            server = server.allow_admin();
+        }
+        if let Some(backup_dir) = &opt.backup_dir {
+            server = server.backup_dir(backup_dir);
+        }
+        let runtime = tokio::runtime::Runtime::new();
+        let runtime = failure::ResultExt::context(runtime, kvs::ErrorKind::Io)?;
+        return runtime.block_on(server.run(opt.addr));
+    }
+    let mut server = KvsServer::new(engine).protocol(protocol);
+    let shutdown = server.shutdown_handle();
+    let engine = server.engine_handle();
+    let config = opt.config.clone();
+    let reload = move || {
+        reload(config.as_deref(), |options| {
+            engine
+                .with(|engine| engine.set_runtime_options(options))
+                .unwrap_or(Ok(()))
+        })
+    };
+    let shutdown = move || {
+        eprintln!("Shutting down, send the signal again to exit at once");
+        shutdown.shutdown();
+    };
+    on_signal(shutdown, reload)?;
+    if let Some(acl) = acl {
+        server = server.acl(acl);
+    }
+    if let Some(metrics_addr) = opt.metrics_addr {
+        server = server.metrics(metrics_addr);
+    }
+    if let Some(slow_log_ms) = opt.slow_log_ms {
+        let slow_log = SlowLog::new(Duration::from_millis(slow_log_ms));
+        server = server.slow_log(slow_log.hash_keys(opt.slow_log_hash_keys));
+    }
+    if let Some(max_connections) = opt.max_connections {
+        server = server.max_connections(max_connections);
+    }
+    if let Some(max_in_flight) = opt.max_in_flight {
+        server = server.max_in_flight(max_in_flight);
+    }
+    if let Some(idle_timeout_ms) = opt.idle_timeout_ms {
+        server = server.idle_timeout(Duration::from_millis(idle_timeout_ms));
+    }
+    if let Some(expire_keys_per_sec) = opt.expire_keys_per_sec {
+        server = server.expire_keys(expire_keys_per_sec);
+    }
+    if let Some(audit_log) = &opt.audit_log {
+        server = server.audit_log(AuditLog::open(audit_log)?);
+    }
+    if opt.allow_admin {
+        server = server.allow_admin();
+    }
+    if let Some(backup_dir) = &opt.backup_dir {
+        server = server.backup_dir(backup_dir);
+    }
+    #[cfg(feature = "tls")]
+    if let (Some(cert), Some(key)) = (&opt.cert, &opt.key) {
+        server = server.tls(kvs::tls::server_config(cert, key)?);
+    }
+    match opt.thread_pool.as_str() {
+        "naive" => listen(server.thread_pool(NaiveThreadPool::new(opt.threads)?), opt),
+        #[cfg(feature = "rayon")]
+        "rayon" => {
+            let pool = kvs::RayonThreadPool::new(opt.threads)?;
+            listen(server.thread_pool(pool), opt)
+        }
+        _ => listen(server.threads(opt.threads), opt),
+    }
+}
+
+/// Reload the runtime options from the config file at `config`, setting them with `set`.
+/// How it went is reported on stderr.
+fn reload(config: Option<&Path>, set: impl FnOnce(RuntimeOptions) -> Result<()>) {
+    let path = match config {
+        Some(path) => path,
+        None => return eprintln!("No config file to reload"),
+    };
+    let reloaded = Config::load(path).and_then(|config| set(config.runtime_options()));
+    match reloaded {
+        Ok(()) => eprintln!("Reloaded {}", path.display()),
+        Err(e) => eprintln!("Failed to reload {}: {}", path.display(), e),
+    }
+}
+
+/// Sync `engine` and exit, for the servers that cannot stop once running.
+fn sync_and_exit<E: KvsEngine>(mut engine: E) -> ! {
+    eprintln!("Shutting down");
+    match engine.sync() {
+        Ok(()) => exit(0),
+        Err(e) => {
+            eprintln!("Failed to sync the engine: {}", e);
+            exit(1)
+        }
+    }
+}
+
+/// Run `server` on the Unix domain socket of the options if there is one, or else on
+/// their address.
+fn listen<E, P>(server: KvsServer<E, P>, opt: &Options) -> Result<()>
+where
+    E: KvsEngine + Clone + Send + Sync + 'static,
+    P: ThreadPool,
+{
+    #[cfg(unix)]
+    if let Some(socket) = &opt.socket {
+        return server.run_unix(socket);
+    }
+    server.run(opt.addr)
+}
+
+/// Write end of the pipe through which `handle_signal` wakes up the thread of `on_signal`.
+#[cfg(unix)]
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Run `shutdown` on a thread of its own when the process receives SIGINT or SIGTERM, and
+/// exit when it receives another one. Run `reload` on the same thread on SIGHUP.
+#[cfg(unix)]
+fn on_signal(
+    shutdown: impl FnOnce() + Send + 'static,
+    reload: impl Fn() + Send + 'static,
+) -> Result<()> {
+    let mut fds = [0; 2];
+    // a signal handler can only make async-signal-safe calls, so it writes to a pipe
+    let piped = match unsafe { libc::pipe(fds.as_mut_ptr()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    failure::ResultExt::context(piped, kvs::ErrorKind::Io)?;
+    let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+    SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGHUP, handler);
+    }
+    thread::spawn(move || {
+        let mut shutdown = Some(shutdown);
+        let mut signal = [0];
+        while signals.read_exact(&mut signal).is_ok() {
+            if libc::c_int::from(signal[0]) == libc::SIGHUP {
+                reload();
+                continue;
+            }
+            match shutdown.take() {
+                Some(shutdown) => shutdown(),
+                None => exit(1),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Signals are only caught on unix, elsewhere they kill the server.
+#[cfg(not(unix))]
+fn on_signal(
+    _shutdown: impl FnOnce() + Send + 'static,
+    _reload: impl Fn() + Send + 'static,
+) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    let fd = SIGNAL_PIPE.load(Ordering::SeqCst);
+    // the signals caught fit in a byte
+    let signal = [signal as u8];
+    unsafe {
+        libc::write(fd, signal.as_ptr() as *const libc::c_void, 1);
+    }
+}
+
+/// The users of the server, if the options require authentication.
+fn acl(opt: &Options) -> Result<Option<Acl>> {
+    let mut acl = match &opt.acl {
+        Some(path) => Some(Acl::load(path)?),
+        None => None,
+    };
+    if let Some(token) = &opt.auth_token {
+        let user = User::new("default", token).read("").write("").admin();
+        acl = Some(acl.unwrap_or_default().user(user));
+    }
+    Ok(acl)
+}
@@ -1,6 +1,6 @@
 use clap::Clap;
 use clap::ValueHint;
-use kvs::{ErrorKind, KvStore, Result};
+use kvs::{ErrorKind, KvStore, KvsEngine, Result};
 use std::path::PathBuf;
 use std::process::exit;
 
@@ -12,6 +12,8 @@ pub struct Options {
     subcmd: SubCommand,
     #[clap(short, long, parse(from_os_str), value_hint = ValueHint::DirPath, default_value = ".")]
     path: PathBuf,
+    #[clap(long, default_value = "kvs", possible_values = &["kvs", "sled"])]
+    engine: String,
 }
 
 #[derive(Clap)]
@@ -46,8 +48,27 @@ struct RmCmd {
 
 fn main() -> Result<()> {
     let opt = Options::parse();
-    let mut store = KvStore::open(opt.path)?;
-    match opt.subcmd {
+    // each engine has its own files, so a directory can only be used by one of them
+    let other_engine_file = if opt.engine == "sled" { "0.bin" } else { "db" };
+    if opt.path.join(other_engine_file).exists() {
+        eprintln!("{} holds data of another engine", opt.path.display());
+        exit(1);
+    }
+    match opt.engine.as_str() {
+        #[cfg(feature = "sled")]
+        "sled" => run(kvs::SledKvsEngine::open(opt.path)?, opt.subcmd),
+        #[cfg(not(feature = "sled"))]
+        "sled" => {
+            eprintln!("kvs was built without the sled feature");
+            exit(1);
+        }
+        _ => run(KvStore::open(opt.path)?, opt.subcmd),
+    }
+}
+
+/// Run a subcommand against a storage engine.
+fn run<E: KvsEngine>(mut store: E, subcmd: SubCommand) -> Result<()> {
+    match subcmd {
         SubCommand::Set(cmd) => store.set(cmd.key, cmd.value)?,
         SubCommand::Get(cmd) => match store.get(cmd.key)? {
             None => println!("Key not found"),
@@ -0,0 +1,152 @@
+#![deny(missing_docs)]
+//! Bloom filter of the keys in a log segment.
+//!
+//! The filter answers "definitely absent" or "maybe present" for a key, so a lookup of a
+//! missing key can stop before touching the segment. It is persisted next to its segment,
+//! stamped with the length and a checksum of the tail of the segment it describes, so a
+//! stale filter is never trusted, even for another segment of the same length.
+//!
+//! Keys are hashed with 64-bit FNV-1a, which is stable across Rust releases, and the
+//! probe positions are derived by double hashing.
+
+use crate::error::ErrorKind;
+use crate::index::LogPointerMap;
+use crate::Result;
+use failure::ResultExt;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Bits per expected key. Together with `NUM_HASHES` this gives about 1% false positives.
+const BITS_PER_KEY: usize = 10;
+/// Number of probes per key.
+const NUM_HASHES: u64 = 7;
+/// Smallest capacity a filter is created with.
+const MIN_CAPACITY: usize = 1024;
+
+/// A bloom filter sized for a number of keys.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    /// Number of keys the filter is sized for.
+    capacity: usize,
+    /// Number of keys inserted so far.
+    len: usize,
+    /// Length of the segment this filter describes, used to detect stale filter files.
+    segment_len: u64,
+    /// CRC-32 of the tail of the segment this filter describes, to tell it apart from
+    /// another segment of the same length, like one written by a compaction.
+    segment_checksum: u32,
+}
+
+impl BloomFilter {
+    /// Create an empty filter sized for at least `capacity` keys.
+    pub(crate) fn with_capacity(capacity: usize) -> BloomFilter {
+        let capacity = capacity.max(MIN_CAPACITY);
+        let num_words = (capacity * BITS_PER_KEY).div_ceil(64);
+        BloomFilter {
+            bits: vec![0; num_words],
+            capacity,
+            len: 0,
+            segment_len: 0,
+            segment_checksum: 0,
+        }
+    }
+
+    /// Create a filter of the keys in a log pointer map, leaving room for as many new keys.
+    pub(crate) fn from_index(index: &LogPointerMap) -> BloomFilter {
+        let mut filter = BloomFilter::with_capacity(index.len() * 2);
+        for key in index.keys() {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    /// Add a key to the filter.
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        let num_bits = self.bits.len() as u64 * 64;
+        for bit in probes(key, num_bits) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// Returns false if the key was definitely never inserted.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let num_bits = self.bits.len() as u64 * 64;
+        probes(key, num_bits).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Whether more keys were inserted than the filter is sized for.
+    /// A full filter still works, but its false positive rate grows.
+    pub(crate) fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    /// Persist the filter as describing a segment of `segment_len` bytes whose tail has
+    /// the CRC-32 `segment_checksum`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to create or write the filter file.
+    /// - Serde: Failed to serialize the filter.
+    pub(crate) fn save(
+        &mut self,
+        path: &Path,
+        segment_len: u64,
+        segment_checksum: u32,
+    ) -> Result<()> {
+        self.segment_len = segment_len;
+        self.segment_checksum = segment_checksum;
+        let mut writer = BufWriter::new(File::create(path).context(ErrorKind::Io)?);
+        bincode::serialize_into(&mut writer, self).context(ErrorKind::Serde)?;
+        writer.flush().context(ErrorKind::Io)?;
+        Ok(())
+    }
+
+    /// Serialize the filter like `save`, in the format of the filter file.
+    ///
+    /// # Errors
+    ///
+    /// - Serde: Failed to serialize the filter.
+    pub(crate) fn encode(&mut self, segment_len: u64, segment_checksum: u32) -> Result<Vec<u8>> {
+        self.segment_len = segment_len;
+        self.segment_checksum = segment_checksum;
+        Ok(bincode::serialize(self).context(ErrorKind::Serde)?)
+    }
+
+    /// Load a persisted filter if it exists and describes a segment of `segment_len` bytes
+    /// whose tail has the CRC-32 `segment_checksum`.
+    pub(crate) fn load(
+        path: &Path,
+        segment_len: u64,
+        segment_checksum: u32,
+    ) -> Option<BloomFilter> {
+        let reader = BufReader::new(File::open(path).ok()?);
+        let filter: BloomFilter = bincode::deserialize_from(reader).ok()?;
+        let stamp = (filter.segment_len, filter.segment_checksum);
+        if stamp == (segment_len, segment_checksum) && !filter.bits.is_empty() {
+            Some(filter)
+        } else {
+            None
+        }
+    }
+}
+
+/// Bit positions probed for a key.
+fn probes(key: &[u8], num_bits: u64) -> impl Iterator<Item = u64> {
+    let h1 = fnv1a(key);
+    let h2 = h1.rotate_left(32) | 1;
+    (0..NUM_HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+}
+
+/// 64-bit FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
@@ -0,0 +1,160 @@
+#![deny(missing_docs)]
+//! Builder of a KvStore, an alternative to filling in `Options`.
+
+use crate::{
+    Compression, Durability, Encryption, GroupCommit, KvStore, LogCodec, MergeOperator, Options,
+    Result, SecondaryIndex, TombstoneRetention, WriteHook,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Builder of a KvStore, created by `KvStore::builder`.
+///
+/// Every setting defaults to the behavior of `KvStore::open`. See `Options` for what they do.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::{Durability, KvStore};
+/// use tempfile::TempDir;
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::builder()
+///     .write_buffer_size(64 * 1024)
+///     .compaction_garbage_ratio(0.5)
+///     .durability(Durability::Flush)
+///     .open(tempdir.path())
+///     .unwrap();
+///
+/// kv.set("key1", "42".to_owned()).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreBuilder {
+    options: Options,
+}
+
+impl KvStoreBuilder {
+    /// Enable group commit mode. See `Options::group_commit`.
+    pub fn group_commit(mut self, group_commit: GroupCommit) -> KvStoreBuilder {
+        self.options.group_commit = Some(group_commit);
+        self
+    }
+
+    /// Set the byte budget of the value cache. See `Options::value_cache_bytes`.
+    pub fn value_cache_bytes(mut self, value_cache_bytes: usize) -> KvStoreBuilder {
+        self.options.value_cache_bytes = value_cache_bytes;
+        self
+    }
+
+    /// Keep the index ordered by key. See `Options::ordered_index`.
+    pub fn ordered_index(mut self, ordered_index: bool) -> KvStoreBuilder {
+        self.options.ordered_index = ordered_index;
+        self
+    }
+
+    /// Set the merge operator. See `Options::merge_operator`.
+    pub fn merge_operator(mut self, merge_operator: MergeOperator) -> KvStoreBuilder {
+        self.options.merge_operator = Some(merge_operator);
+        self
+    }
+
+    /// Open the store read-only. See `Options::read_only`.
+    pub fn read_only(mut self, read_only: bool) -> KvStoreBuilder {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// Set the capacity of the write buffer. See `Options::write_buffer_size`.
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> KvStoreBuilder {
+        self.options.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    /// Set the capacity of the read-ahead buffer. See `Options::read_ahead_size`.
+    pub fn read_ahead_size(mut self, read_ahead_size: usize) -> KvStoreBuilder {
+        self.options.read_ahead_size = read_ahead_size;
+        self
+    }
+
+    /// Set the fraction of redundant records that triggers compaction.
+    /// See `Options::compaction_garbage_ratio`.
+    pub fn compaction_garbage_ratio(mut self, compaction_garbage_ratio: f64) -> KvStoreBuilder {
+        self.options.compaction_garbage_ratio = compaction_garbage_ratio;
+        self
+    }
+
+    /// Set when written records reach the disk. See `Options::durability`.
+    pub fn durability(mut self, durability: Durability) -> KvStoreBuilder {
+        self.options.durability = durability;
+        self
+    }
+
+    /// Set the codec compressing new records. See `Options::compression`.
+    pub fn compression(mut self, compression: Compression) -> KvStoreBuilder {
+        self.options.compression = compression;
+        self
+    }
+
+    /// Encrypt records. See `Options::encryption`.
+    pub fn encryption(mut self, encryption: Encryption) -> KvStoreBuilder {
+        self.options.encryption = Some(encryption);
+        self
+    }
+
+    /// Set the wire format of records. See `Options::codec`.
+    pub fn codec<C: LogCodec + 'static>(mut self, codec: C) -> KvStoreBuilder {
+        self.options.codec = Arc::new(codec);
+        self
+    }
+
+    /// Keep values of at least `bytes` bytes in the value log. See `Options::value_log_threshold`.
+    pub fn value_log_threshold(mut self, bytes: usize) -> KvStoreBuilder {
+        self.options.value_log_threshold = Some(bytes);
+        self
+    }
+
+    /// Set how long compaction keeps tombstones. See `Options::tombstone_retention`.
+    pub fn tombstone_retention(mut self, retention: TombstoneRetention) -> KvStoreBuilder {
+        self.options.tombstone_retention = retention;
+        self
+    }
+
+    /// Set the largest key that can be written. See `Options::max_key_size`.
+    pub fn max_key_size(mut self, bytes: usize) -> KvStoreBuilder {
+        self.options.max_key_size = Some(bytes);
+        self
+    }
+
+    /// Set the largest value that can be written. See `Options::max_value_size`.
+    pub fn max_value_size(mut self, bytes: usize) -> KvStoreBuilder {
+        self.options.max_value_size = Some(bytes);
+        self
+    }
+
+    /// Add a callback run after every write. See `Options::write_hooks`.
+    pub fn write_hook(mut self, hook: WriteHook) -> KvStoreBuilder {
+        self.options.write_hooks.push(hook);
+        self
+    }
+
+    /// Add a secondary index. See `Options::indexes`.
+    pub fn index(mut self, index: SecondaryIndex) -> KvStoreBuilder {
+        self.options.indexes.push(index);
+        self
+    }
+
+    /// Open the store of this name in the directory. See `Options::store_name`.
+    pub fn store_name(mut self, name: &str) -> KvStoreBuilder {
+        self.options.store_name = Some(name.to_owned());
+        self
+    }
+
+    /// Open the store in the given directory with the settings of this builder.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::open`.
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self.options)
+    }
+}
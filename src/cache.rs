@@ -0,0 +1,86 @@
+#![deny(missing_docs)]
+//! An LRU cache of recently read values, bounded by a byte budget.
+//!
+//! The size of an entry is the length of its key plus the length of its value.
+//! A cache with a budget of 0 never stores anything.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// LRU cache of values read by `get`.
+pub(crate) struct ValueCache {
+    /// Byte budget.
+    capacity: usize,
+    /// Bytes used by cached entries.
+    size: usize,
+    /// Increases on every access, used as recency.
+    tick: u64,
+    /// Key to (value, last access tick).
+    entries: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    /// Last access tick to key, the first entry is the least recently used.
+    recency: BTreeMap<u64, Vec<u8>>,
+}
+
+impl ValueCache {
+    /// Create an empty cache with given byte budget.
+    pub(crate) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    /// Get a copy of the cached value and mark it as most recently used.
+    pub(crate) fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, last_access) = self.entries.get_mut(key)?;
+        let key = self
+            .recency
+            .remove(last_access)
+            .expect("cache recency in sync");
+        self.recency.insert(tick, key);
+        *last_access = tick;
+        Some(value.clone())
+    }
+
+    /// Cache a value, evicting least recently used entries to stay within budget.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.invalidate(&key);
+        let entry_size = key.len() + value.len();
+        if entry_size > self.capacity {
+            return;
+        }
+        while self.size + entry_size > self.capacity {
+            self.evict_lru();
+        }
+
+        self.tick += 1;
+        self.size += entry_size;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    /// Drop the cached value of a key if present.
+    pub(crate) fn invalidate(&mut self, key: &[u8]) {
+        if let Some((value, last_access)) = self.entries.remove(key) {
+            self.recency.remove(&last_access);
+            self.size -= key.len() + value.len();
+        }
+    }
+
+    /// Drop all cached values.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.size = 0;
+    }
+
+    fn evict_lru(&mut self) {
+        if let Some(key) = self.recency.values().next().cloned() {
+            self.invalidate(&key);
+        }
+    }
+}
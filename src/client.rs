@@ -0,0 +1,855 @@
+#![deny(missing_docs)]
+//! A client of `KvsServer`.
+
+use crate::error::{Error, ErrorKind};
+use crate::near_cache::{NearCache, NearCacheHandle};
+use crate::protocol::{self, AdminCommand, Request, Response};
+use crate::replication::{Changes, ReplicationCursor};
+use crate::retry::RetryPolicy;
+use crate::stream::{self, SharedStream, Socket, Stream};
+use crate::{ChangeEvent, KvsEngine, Result, ScanCursor, ScanPage, Stats};
+use failure::{Fail, ResultExt};
+use std::convert::TryFrom;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Requests sent by `pipeline` before reading their responses. Bounded so that the
+/// responses do not fill the buffers of the connection while requests are still being
+/// sent, which would block both ends.
+const PIPELINE_WINDOW: usize = 256;
+
+/// Key-value pairs asked for in each page when listing keys as a `KvsEngine`, the most a
+/// server answers with.
+const SCAN_PAGE_LEN: usize = 1000;
+
+/// Opens a connection to the server, within a timeout if there is one, returning a handle
+/// to its socket along with it.
+type Connector = Arc<dyn Fn(Option<Duration>) -> Result<(Socket, Box<dyn Stream>)> + Send + Sync>;
+
+/// A connection to a `KvsServer`.
+///
+/// It is a `KvsEngine` too, so code generic over the engine can use a remote store. Keys can
+/// be spread over several servers with a `ShardedKvsClient`.
+///
+/// By default, requests wait for the server forever, and are not retried. See
+/// `set_timeout` and `set_retry_policy`. Values read can be cached in the client, see
+/// `set_near_cache`.
+///
+/// The handshake agreeing on a version of the protocol is sent with the first request of a
+/// connection, which fails with IncompatibleVersion if the server speaks none of the
+/// versions of the client, or ServerBusy if the server has as many connections as it takes.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::KvsClient;
+///
+/// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+/// client.set("key1".to_owned(), "42".to_owned()).unwrap();
+/// assert_eq!(client.get("key1".to_owned()).unwrap(), Some("42".to_owned()));
+/// ```
+pub struct KvsClient {
+    reader: BufReader<SharedStream<Box<dyn Stream>>>,
+    writer: BufWriter<SharedStream<Box<dyn Stream>>>,
+    /// Handle to the socket of the connection, to set its timeouts.
+    socket: Socket,
+    /// Opens a new connection to retry requests on.
+    connect: Connector,
+    timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    /// Token the connection was authenticated with, to authenticate new connections.
+    token: Option<String>,
+    /// Whether a request failed in a way that leaves the connection unusable.
+    broken: bool,
+    /// Version of the protocol agreed on with the server, absent until its answer to the
+    /// handshake is read.
+    version: Option<u32>,
+    /// The sequence ID of the next batch.
+    next_id: u64,
+    near_cache: Option<NearCacheHandle>,
+}
+
+impl KvsClient {
+    /// Connect to the server listening on `addr`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to connect.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        let addrs: Vec<_> = addr.to_socket_addrs().context(ErrorKind::Io)?.collect();
+        KvsClient::new(Arc::new(move |timeout| {
+            let stream = Socket::Tcp(connect_tcp(&addrs, timeout)?);
+            let socket = stream.try_clone().context(ErrorKind::Io)?;
+            Ok((socket, Box::new(stream)))
+        }))
+    }
+
+    /// Connect to the server listening on the Unix domain socket at `path`, see
+    /// `KvsServer::run_unix`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to connect.
+    #[cfg(unix)]
+    pub fn connect_unix<P: Into<PathBuf>>(path: P) -> Result<KvsClient> {
+        let path = path.into();
+        KvsClient::new(Arc::new(move |_timeout| {
+            // connecting to a local socket does not wait for the server
+            let stream = Socket::Unix(UnixStream::connect(&path).context(ErrorKind::Io)?);
+            let socket = stream.try_clone().context(ErrorKind::Io)?;
+            Ok((socket, Box::new(stream)))
+        }))
+    }
+
+    /// Connect over TLS to the server listening on `addr`, with `config` as made by
+    /// `kvs::tls::client_config`. The certificate of the server has to be valid for
+    /// `server_name`, a DNS name or an IP address. Needs the `tls` feature.
+    ///
+    /// The TLS handshake happens with the first request, so a server that cannot be
+    /// trusted fails that request.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to connect.
+    /// - Tls: `server_name` is not a valid DNS name or IP address.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<KvsClient> {
+        let server_name = rustls::pki_types::ServerName::try_from(server_name.to_owned())
+            .context(ErrorKind::Tls)?;
+        let addrs: Vec<_> = addr.to_socket_addrs().context(ErrorKind::Io)?.collect();
+        KvsClient::new(Arc::new(move |timeout| {
+            let session = rustls::ClientConnection::new(Arc::clone(&config), server_name.clone())
+                .context(ErrorKind::Tls)?;
+            let stream = connect_tcp(&addrs, timeout)?;
+            let socket = Socket::Tcp(stream.try_clone().context(ErrorKind::Io)?);
+            Ok((socket, Box::new(rustls::StreamOwned::new(session, stream))))
+        }))
+    }
+
+    fn new(connect: Connector) -> Result<KvsClient> {
+        let (socket, stream) = connect(None)?;
+        let (reader, mut writer) = stream::split(stream);
+        // buffered until the first request
+        protocol::write_handshake(&mut writer)?;
+        Ok(KvsClient {
+            reader,
+            writer,
+            socket,
+            connect,
+            timeout: None,
+            retry_policy: RetryPolicy::none(),
+            token: None,
+            broken: false,
+            version: None,
+            next_id: 0,
+            near_cache: None,
+        })
+    }
+
+    /// Fail requests with Timeout when the server stays silent for `timeout` while
+    /// sending them or waiting for their response, or wait for it forever if `None`, the
+    /// default. Connecting again to retry a request is bounded by it too.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to set the timeout of the connection, e.g. `timeout` is zero.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        set_timeouts(&self.socket, timeout)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Retry requests that can safely run twice, when they fail on the network, as
+    /// `retry_policy` says. Requests are not retried by default.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Cache the values read by `get` as `near_cache` says, replacing any previous cache.
+    /// The changes of the cached keys are pushed by the server to a subscription on a
+    /// connection of its own, authenticated with the token of this one if there is one,
+    /// which holds a thread of a threaded server like any connection.
+    ///
+    /// # Errors
+    ///
+    /// Same as `subscribe`.
+    pub fn set_near_cache(&mut self, near_cache: NearCache) -> Result<()> {
+        self.near_cache = None;
+        let connect = Arc::clone(&self.connect);
+        let timeout = self.timeout;
+        let token = self.token.clone();
+        let subscribe = move |prefixes| {
+            let mut client = KvsClient::new(Arc::clone(&connect))?;
+            client.set_timeout(timeout)?;
+            if let Some(token) = token.clone() {
+                client.authenticate(token)?;
+            }
+            client.subscribe(prefixes)
+        };
+        self.near_cache = Some(NearCacheHandle::start(near_cache, subscribe)?);
+        Ok(())
+    }
+
+    /// The number of reads by `get` in flight whose value may be cached by the near cache,
+    /// see `set_near_cache`.
+    pub fn near_cache_reads(&self) -> usize {
+        self.near_cache.as_ref().map_or(0, NearCacheHandle::reads)
+    }
+
+    /// Authenticate the connection with the token of a server set up with
+    /// `KvsServer::auth_token`, before sending other requests.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - Timeout: The server did not answer in time, see `set_timeout`.
+    /// - Unauthenticated: The token is not the one of the server.
+    pub fn authenticate(&mut self, token: String) -> Result<()> {
+        self.retrying(|client| {
+            client.request(Request::Auth {
+                token: token.clone(),
+            })
+        })?;
+        self.token = Some(token);
+        Ok(())
+    }
+
+    /// Check that the connection works, by a round trip to the server.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - Timeout: The server did not answer in time, see `set_timeout`.
+    pub fn ping(&mut self) -> Result<()> {
+        self.retrying(|client| client.request(Request::Ping))
+            .map(|_| ())
+    }
+
+    /// Get the value of a key, or `None` if it is not present.
+    ///
+    /// # Errors
+    ///
+    /// Same as `remove`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let read = match &self.near_cache {
+            Some(near_cache) => match near_cache.get(&key) {
+                Some(value) => return Ok(Some(value)),
+                None => near_cache.start_read(&key),
+            },
+            None => None,
+        };
+        let value = self.retrying(|client| client.request(Request::Get { key: key.clone() }));
+        if let (Some(near_cache), Some(id)) = (&self.near_cache, read) {
+            match &value {
+                Ok(Some(value)) => near_cache.finish_read(&key, id, value),
+                _ => near_cache.cancel_read(&key, id),
+            }
+        }
+        value
+    }
+
+    /// Set the value of a key, overwriting any previous value.
+    ///
+    /// # Errors
+    ///
+    /// Same as `remove`.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.invalidate(&key);
+        self.retrying(|client| {
+            client.request(Request::Set {
+                key: key.clone(),
+                value: value.clone(),
+            })
+        })
+        .map(|_| ())
+    }
+
+    /// Remove a key.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - Timeout: The server did not answer in time, see `set_timeout`.
+    /// - KeyNotFound: The key is not present.
+    /// - Unauthenticated: The server requires authentication, see `authenticate`.
+    /// - Others: The command failed on the server with an error of this kind.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.invalidate(&key);
+        self.request(Request::Remove { key }).map(|_| ())
+    }
+
+    /// Run `requests` in order as a single batch, in one round trip to the server,
+    /// returning the result of each of them: the value of a get, or the error of a
+    /// command that failed on the server. A request failing does not stop the ones after it.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the batch or receive its response.
+    /// - Serde: Received a malformed response, or the response of another batch.
+    /// - Timeout: The server did not answer in time, see `set_timeout`.
+    /// - ServerBusy: The batch holds more requests than the server takes at once, see
+    ///   `KvsServer::max_in_flight`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use kvs::{KvsClient, Request};
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// let results = client
+    ///     .batch(vec![
+    ///         Request::Set { key: "key1".to_owned(), value: "42".to_owned() },
+    ///         Request::Get { key: "key1".to_owned() },
+    ///     ])
+    ///     .unwrap();
+    /// assert_eq!(results[1].as_ref().unwrap(), &Some("42".to_owned()));
+    /// ```
+    pub fn batch(&mut self, requests: Vec<Request>) -> Result<Vec<Result<Option<String>>>> {
+        requests
+            .iter()
+            .for_each(|request| self.invalidate_request(request));
+        let len = requests.len();
+        let id = self.next_id();
+        let result = self.round_trip(&Request::Batch { id, requests });
+        let responses = self.check_batch(result, id, len)?;
+        Ok(responses.into_iter().map(Response::into_result).collect())
+    }
+
+    /// Run `requests` in order, sending them all before reading their responses, so that
+    /// they take a single round trip to the server instead of one each. Returns the result
+    /// of each of them like `batch`, but the server runs and answers the requests as they
+    /// arrive instead of waiting for the whole batch. Requests are sent in windows of 256,
+    /// each taking a round trip.
+    ///
+    /// # Errors
+    ///
+    /// Same as `batch`.
+    pub fn pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<Result<Option<String>>>> {
+        requests
+            .iter()
+            .for_each(|request| self.invalidate_request(request));
+        let mut results = Vec::with_capacity(requests.len());
+        let mut requests = requests.into_iter().peekable();
+        while requests.peek().is_some() {
+            let window: Vec<_> = requests.by_ref().take(PIPELINE_WINDOW).collect();
+            results.extend(self.pipeline_window(window)?);
+        }
+        Ok(results)
+    }
+
+    /// Get the changes of the store of the server for a replica at `cursor`, or a page of a
+    /// full copy of it for a new replica, see `Replica`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - Timeout: The server did not answer in time, see `set_timeout`.
+    /// - Unsupported: The engine of the server cannot be replicated.
+    /// - PermissionDenied: The user of the connection cannot read every key.
+    /// - Others: Same as `KvStore::changes`, on the server.
+    pub fn changes(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        self.retrying(|client| client.changes_once(cursor))
+    }
+
+    /// Get a page of at most `limit` key-value pairs whose key starts with `prefix`
+    /// following `cursor`, see `KvStore::scan_prefix_from`. The server answers with at most
+    /// 1000 pairs, and leaves out the ones the user of the connection cannot read, so a
+    /// page may be shorter than `limit` though it has a cursor.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - Timeout: The server did not answer in time, see `set_timeout`.
+    /// - Unsupported: The server predates scans, or its engine cannot list keys.
+    /// - Unauthenticated: The server requires authentication, see `authenticate`.
+    /// - Others: Same as `KvStore::scan_prefix_from`, on the server.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use kvs::{KvsClient, ScanCursor};
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// let mut cursor = Some(ScanCursor::default());
+    /// while let Some(after) = cursor {
+    ///     let page = client.scan("user:".to_owned(), &after, 100).unwrap();
+    ///     for (key, value) in page.entries {
+    ///         println!("{} {}", key, value);
+    ///     }
+    ///     cursor = page.cursor;
+    /// }
+    /// ```
+    pub fn scan(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        self.retrying(|client| client.scan_once(prefix.clone(), cursor, limit))
+    }
+
+    /// Compact the store of the server now, see `KvStore::compact`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - Timeout: The server did not answer in time, see `set_timeout`.
+    /// - Unsupported: The server predates admin commands, or its engine cannot compact.
+    /// - Unauthenticated: The server requires authentication, see `authenticate`.
+    /// - PermissionDenied: The user of the connection is not an admin, see `User::admin`,
+    ///   and the server does not allow admin commands to others, see
+    ///   `KvsServer::allow_admin`.
+    /// - Others: Same as `KvStore::compact`, on the server.
+    pub fn compact(&mut self) -> Result<()> {
+        self.retrying(|client| client.admin(AdminCommand::Compact))
+            .map(|_| ())
+    }
+
+    /// Get statistics about the store of the server, see `KvStore::stats`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `compact`.
+    pub fn stats(&mut self) -> Result<Stats> {
+        match self.retrying(|client| client.admin(AdminCommand::Stats))? {
+            Some(stats) => Ok(stats),
+            None => Err(Error::from(ErrorKind::Serde)),
+        }
+    }
+
+    /// Make every write the server took so far durable, see `KvStore::sync`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `compact`.
+    pub fn flush(&mut self) -> Result<()> {
+        self.retrying(|client| client.admin(AdminCommand::Flush))
+            .map(|_| ())
+    }
+
+    /// Start backing the store of the server up to `path`, a directory or a `.tar` archive
+    /// relative to the backup directory of the server, see `KvsServer::backup_dir` and
+    /// `KvStore::start_backup`. Returns once the backup started: the server copies the files
+    /// in the background and reports on its stderr whether it succeeded.
+    ///
+    /// # Errors
+    ///
+    /// - PermissionDenied: The server has no backup directory, or `path` leaves it.
+    /// - Others: Same as `compact`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.flush().unwrap();
+    /// client.start_backup("kvs.tar".to_owned()).unwrap();
+    /// println!("{:?}", client.stats().unwrap());
+    /// ```
+    pub fn start_backup(&mut self, path: String) -> Result<()> {
+        self.reconnect()?;
+        self.admin(AdminCommand::Backup { path }).map(|_| ())
+    }
+
+    /// Turn the connection into a subscription to the changes of the keys starting with any
+    /// of `prefixes`, see `KvStore::watch_prefixes`. The server leaves out the keys the user
+    /// of the connection cannot read.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to send the request or receive the response.
+    /// - Serde: Received a malformed response.
+    /// - Timeout: The server did not answer in time, see `set_timeout`.
+    /// - Unsupported: The server predates subscriptions, or its engine cannot report
+    ///   changes.
+    /// - Unauthenticated: The server requires authentication, see `authenticate`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use kvs::KvsClient;
+    ///
+    /// let client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// for event in client.subscribe(vec!["user:".to_owned()]).unwrap() {
+    ///     let event = event.unwrap();
+    ///     println!("{:?} {}", event.op, String::from_utf8_lossy(&event.key));
+    /// }
+    /// ```
+    pub fn subscribe(mut self, prefixes: Vec<String>) -> Result<Subscription> {
+        self.reconnect()?;
+        if self.version.is_none() {
+            // the handshake tells whether the server knows subscriptions
+            self.request(Request::Ping)?;
+        }
+        if self.version < Some(3) {
+            return Err(Error::from(ErrorKind::Unsupported));
+        }
+        self.request(Request::Subscribe { prefixes })?;
+        Ok(Subscription {
+            client: self,
+            done: false,
+        })
+    }
+
+    /// Get a page of key-value pairs, without retrying.
+    fn scan_once(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        if self.version.is_none() {
+            // the handshake tells whether the server knows scans
+            self.request(Request::Ping)?;
+        }
+        if self.version < Some(2) {
+            return Err(Error::from(ErrorKind::Unsupported));
+        }
+        let response = self.round_trip(&Request::Scan {
+            prefix,
+            cursor: cursor.clone(),
+            limit: u32::try_from(limit).unwrap_or(u32::MAX),
+        });
+        self.broken |= response.is_err();
+        match response? {
+            Response::Page(page) => Ok(page),
+            Response::Err(kind) => Err(Error::from(kind)),
+            _ => {
+                self.broken = true;
+                Err(Error::from(ErrorKind::Serde))
+            }
+        }
+    }
+
+    /// Run a command managing the server, returning the statistics it answered with if
+    /// any, without retrying.
+    fn admin(&mut self, command: AdminCommand) -> Result<Option<Stats>> {
+        if self.version.is_none() {
+            // the handshake tells whether the server knows admin commands
+            self.request(Request::Ping)?;
+        }
+        if self.version < Some(4) {
+            return Err(Error::from(ErrorKind::Unsupported));
+        }
+        let response = self.round_trip(&Request::Admin(command));
+        self.broken |= response.is_err();
+        match response? {
+            Response::Ok(None) => Ok(None),
+            Response::Stats(stats) => Ok(Some(stats)),
+            Response::Err(kind) => Err(Error::from(kind)),
+            _ => {
+                self.broken = true;
+                Err(Error::from(ErrorKind::Serde))
+            }
+        }
+    }
+
+    /// Get the changes for a replica at `cursor`, without retrying.
+    fn changes_once(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        let response = self.round_trip(&Request::Replicate { cursor });
+        self.broken |= response.is_err();
+        match response? {
+            Response::Changes(changes) => Ok(changes),
+            Response::Err(kind) => Err(Error::from(kind)),
+            _ => {
+                self.broken = true;
+                Err(Error::from(ErrorKind::Serde))
+            }
+        }
+    }
+
+    /// Drop the cached value of `key`, before changing it.
+    fn invalidate(&self, key: &str) {
+        if let Some(near_cache) = &self.near_cache {
+            near_cache.invalidate(key);
+        }
+    }
+
+    /// Drop the cached values of the keys `request` changes.
+    fn invalidate_request(&self, request: &Request) {
+        match request {
+            Request::Set { key, .. } | Request::Remove { key } => self.invalidate(key),
+            Request::Batch { requests, .. } => requests
+                .iter()
+                .for_each(|request| self.invalidate_request(request)),
+            _ => {}
+        }
+    }
+
+    /// Whether a request failed to be sent or answered, so that the connection cannot be
+    /// used anymore.
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Run `request`, and run it again on a new connection after failures that may be
+    /// transient, as the retry policy says. Only for requests that can safely run twice.
+    /// With retries, a connection broken by a previous request is replaced first.
+    fn retrying<T, F>(&mut self, request: F) -> Result<T>
+    where
+        F: Fn(&mut KvsClient) -> Result<T>,
+    {
+        let mut result = match self.retry_policy.max_retries() {
+            0 => request(self),
+            _ => self.reconnect().and_then(|()| request(self)),
+        };
+        for retry in 0..self.retry_policy.max_retries() {
+            match &result {
+                Err(e) if RetryPolicy::is_transient(e.kind()) => {}
+                _ => break,
+            }
+            thread::sleep(self.retry_policy.backoff(retry));
+            result = self.reconnect().and_then(|()| request(self));
+        }
+        result
+    }
+
+    /// Replace a broken connection with a new one, authenticated with the token of the
+    /// previous one if there is one.
+    fn reconnect(&mut self) -> Result<()> {
+        if !self.broken {
+            return Ok(());
+        }
+        let (socket, stream) = (self.connect)(self.timeout).map_err(timeout_error)?;
+        set_timeouts(&socket, self.timeout)?;
+        let (reader, mut writer) = stream::split(stream);
+        protocol::write_handshake(&mut writer)?;
+        self.reader = reader;
+        self.writer = writer;
+        self.socket = socket;
+        self.broken = false;
+        self.version = None;
+        if let Some(token) = self.token.clone() {
+            self.request(Request::Auth { token })?;
+        }
+        Ok(())
+    }
+
+    /// Send a request and wait for its response.
+    fn request(&mut self, request: Request) -> Result<Option<String>> {
+        let response = self.round_trip(&request);
+        // a response may be left unread, or a request half written
+        self.broken |= response.is_err();
+        match response? {
+            Response::Batch { .. }
+            | Response::Changes(_)
+            | Response::Page(_)
+            | Response::Event(_)
+            | Response::Stats(_) => {
+                self.broken = true;
+                Err(Error::from(ErrorKind::Serde))
+            }
+            response => response.into_result(),
+        }
+    }
+
+    /// The responses of the batch `id` of `len` requests, given its response.
+    fn check_batch(
+        &mut self,
+        response: Result<Response>,
+        id: u64,
+        len: usize,
+    ) -> Result<Vec<Response>> {
+        match response {
+            Ok(Response::Batch {
+                id: response_id,
+                responses,
+            }) if response_id == id && responses.len() == len => Ok(responses),
+            // refused as a whole, e.g. by a server taking fewer requests at once
+            Ok(Response::Err(kind)) => Err(Error::from(kind)),
+            Ok(_) => {
+                self.broken = true;
+                Err(Error::from(ErrorKind::Serde))
+            }
+            Err(e) => {
+                self.broken = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Send `requests` before reading their responses.
+    fn pipeline_window(&mut self, requests: Vec<Request>) -> Result<Vec<Result<Option<String>>>> {
+        let mut ids = Vec::with_capacity(requests.len());
+        let sent = requests
+            .into_iter()
+            .try_for_each(|request| {
+                let id = self.next_id();
+                ids.push(id);
+                let batch = Request::Batch {
+                    id,
+                    requests: vec![request],
+                };
+                protocol::write_frame(&mut self.writer, &batch)
+            })
+            .and_then(|_| Ok(self.writer.flush().context(ErrorKind::Io)?));
+        if let Err(e) = sent {
+            self.broken = true;
+            return Err(timeout_error(e));
+        }
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            let response = self.read_response();
+            let mut responses = self.check_batch(response, id, 1)?;
+            results.push(responses.remove(0).into_result());
+        }
+        Ok(results)
+    }
+
+    /// Send a request and read its response.
+    fn round_trip(&mut self, request: &Request) -> Result<Response> {
+        protocol::write_frame(&mut self.writer, request).map_err(timeout_error)?;
+        self.writer
+            .flush()
+            .context(ErrorKind::Io)
+            .map_err(|e| timeout_error(e.into()))?;
+        self.read_response()
+    }
+
+    /// Read the response to the oldest request not answered yet.
+    fn read_response(&mut self) -> Result<Response> {
+        if self.version.is_none() {
+            let version = protocol::read_handshake(&mut self.reader).map_err(timeout_error)?;
+            self.version = Some(version);
+        }
+        // the server closed the connection instead of answering
+        protocol::read_frame(&mut self.reader)
+            .map_err(timeout_error)?
+            .ok_or_else(|| Error::from(ErrorKind::Io))
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
+    }
+}
+
+/// Changes of keys pushed by a server, see `KvsClient::subscribe`.
+///
+/// As an iterator it blocks until the next change, and ends after the first error, such as
+/// the server closing the connection.
+pub struct Subscription {
+    client: KvsClient,
+    /// Whether reading failed, ending the iterator.
+    done: bool,
+}
+
+impl Subscription {
+    /// Fail with Timeout when the server sends nothing for `timeout`, or wait forever if
+    /// `None`, the default. The server sends a heartbeat every second without changes, so
+    /// a few seconds tell that the connection was cut.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to set the timeout of the connection, e.g. `timeout` is zero.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.client.set_timeout(timeout)
+    }
+
+    /// Wait for the next change.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to receive the change, or the server closed the connection.
+    /// - Serde: Received a malformed change.
+    /// - Timeout: The server sent nothing in time, see `set_timeout`.
+    pub fn next_event(&mut self) -> Result<ChangeEvent> {
+        loop {
+            match self.client.read_response()? {
+                Response::Event(event) => return Ok(event),
+                // a heartbeat
+                Response::Ok(None) => {}
+                _ => return Err(Error::from(ErrorKind::Serde)),
+            }
+        }
+    }
+}
+
+impl Subscription {
+    /// Another handle to the socket of the subscription, to end it from another thread.
+    pub(crate) fn socket(&self) -> Result<Socket> {
+        Ok(self.client.socket.try_clone().context(ErrorKind::Io)?)
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Result<ChangeEvent>> {
+        if self.done {
+            return None;
+        }
+        let event = self.next_event();
+        self.done = event.is_err();
+        Some(event)
+    }
+}
+
+/// Connect to the first of `addrs` accepting a connection, within `timeout` for each if
+/// there is one.
+fn connect_tcp(addrs: &[SocketAddr], timeout: Option<Duration>) -> Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(TcpStream::connect(addrs).context(ErrorKind::Io)?),
+    };
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    let e = last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable));
+    Err(e.context(ErrorKind::Io).into())
+}
+
+/// Set the read and write timeouts of `socket`.
+fn set_timeouts(socket: &Socket, timeout: Option<Duration>) -> Result<()> {
+    Ok(socket.set_timeouts(timeout).context(ErrorKind::Io)?)
+}
+
+/// Turn an Io error caused by a timeout of the socket into a Timeout error.
+fn timeout_error(e: Error) -> Error {
+    if stream::is_timeout(&e) {
+        Error::from(ErrorKind::Timeout)
+    } else {
+        e
+    }
+}
+
+impl KvsEngine for KvsClient {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvsClient::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvsClient::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvsClient::remove(self, key)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        let mut cursor = Some(ScanCursor::default());
+        while let Some(after) = cursor {
+            let page = self.scan(prefix.clone(), &after, SCAN_PAGE_LEN)?;
+            pairs.extend(page.entries);
+            cursor = page.cursor;
+        }
+        Ok(pairs)
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        self.scan(prefix, cursor, limit)
+    }
+}
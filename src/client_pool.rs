@@ -0,0 +1,209 @@
+#![deny(missing_docs)]
+//! A pool of persistent connections to a `KvsServer`.
+
+use crate::client::KvsClient;
+use crate::error::ErrorKind;
+use crate::{KvsEngine, Result, ScanCursor, ScanPage};
+use failure::ResultExt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Connections kept idle by default.
+const DEFAULT_MAX_IDLE: usize = 16;
+/// Idle time after which a connection is checked before being reused, by default.
+const DEFAULT_CHECK_AFTER: Duration = Duration::from_secs(10);
+
+/// A pool of connections to a server, reused across requests instead of connecting for
+/// each of them. It can be shared between threads.
+///
+/// `client` takes an idle connection, or opens a new one if there is none, and the
+/// connection goes back to the pool when dropped. A connection idle for longer than
+/// `check_after` is pinged before being handed out, and replaced if the server closed it.
+/// A connection that failed a request is closed instead of going back to the pool.
+///
+/// The pool is a `KvsEngine` too, taking a connection for each command.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvsClient, KvsClientPool};
+///
+/// let pool = KvsClientPool::new("127.0.0.1:4000").unwrap();
+/// let mut client = pool.client().unwrap();
+/// client.set("key1".to_owned(), "42".to_owned()).unwrap();
+///
+/// // connections authenticating first, or using TLS, are opened by a function
+/// let pool = KvsClientPool::with_connector(|| {
+///     let mut client = KvsClient::connect("127.0.0.1:4000")?;
+///     client.authenticate("s3cr3t".to_owned())?;
+///     Ok(client)
+/// });
+/// ```
+pub struct KvsClientPool {
+    connect: Box<dyn Fn() -> Result<KvsClient> + Send + Sync>,
+    /// Idle connections with the time they were returned, the most recent last.
+    idle: Mutex<Vec<(KvsClient, Instant)>>,
+    max_idle: usize,
+    check_after: Duration,
+}
+
+impl KvsClientPool {
+    /// Create a pool of connections to the server listening on `addr`.
+    ///
+    /// No connection is opened until one is needed.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to resolve `addr`.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<KvsClientPool> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs().context(ErrorKind::Io)?.collect();
+        Ok(KvsClientPool::with_connector(move || {
+            KvsClient::connect(addrs.as_slice())
+        }))
+    }
+
+    /// Create a pool of connections opened by `connect`.
+    pub fn with_connector<F>(connect: F) -> KvsClientPool
+    where
+        F: Fn() -> Result<KvsClient> + Send + Sync + 'static,
+    {
+        KvsClientPool {
+            connect: Box::new(connect),
+            idle: Mutex::new(Vec::new()),
+            max_idle: DEFAULT_MAX_IDLE,
+            check_after: DEFAULT_CHECK_AFTER,
+        }
+    }
+
+    /// Keep at most `max_idle` idle connections, closing the connections returned beyond
+    /// that. Default to 16.
+    pub fn max_idle(mut self, max_idle: usize) -> KvsClientPool {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Ping the connections idle for longer than `check_after` before reusing them.
+    /// Default to 10 seconds.
+    pub fn check_after(mut self, check_after: Duration) -> KvsClientPool {
+        self.check_after = check_after;
+        self
+    }
+
+    /// Take a connection from the pool, opening one if none is idle.
+    ///
+    /// # Errors
+    ///
+    /// Same as the connector, e.g. Io if failed to connect.
+    pub fn client(&self) -> Result<PooledClient<'_>> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            let client = match idle {
+                Some((mut client, since)) => {
+                    if since.elapsed() >= self.check_after && client.ping().is_err() {
+                        continue;
+                    }
+                    client
+                }
+                None => (self.connect)()?,
+            };
+            return Ok(PooledClient {
+                pool: self,
+                client: Some(client),
+            });
+        }
+    }
+
+    /// Ping the connections idle for longer than `check_after`, closing those the server
+    /// closed, so that servers closing idle connections, and middleboxes dropping idle
+    /// flows, keep the others open. Call it periodically, more often than the server's idle
+    /// timeout, see `KvsServer::idle_timeout`.
+    ///
+    /// Connections are pinged outside the lock of the pool, so it can still be used
+    /// meanwhile.
+    pub fn keep_alive(&self) {
+        let idle = std::mem::take(&mut *self.idle.lock().unwrap());
+        let mut alive = Vec::with_capacity(idle.len());
+        for (mut client, since) in idle {
+            if since.elapsed() < self.check_after {
+                alive.push((client, since));
+            } else if client.ping().is_ok() {
+                alive.push((client, Instant::now()));
+            }
+        }
+        let mut idle = self.idle.lock().unwrap();
+        // connections returned meanwhile are the most recent
+        alive.append(&mut idle);
+        alive.sort_by_key(|&(_, since)| since);
+        let excess = alive.len().saturating_sub(self.max_idle);
+        alive.drain(..excess);
+        *idle = alive;
+    }
+
+    /// The number of idle connections.
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Take back a connection, unless it is broken or enough are idle.
+    fn release(&self, client: KvsClient) {
+        if client.is_broken() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push((client, Instant::now()));
+        }
+    }
+}
+
+impl KvsEngine for KvsClientPool {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client()?.set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client()?.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.client()?.remove(key)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        KvsEngine::scan_prefix(&mut *self.client()?, prefix)
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        self.client()?.scan(prefix, cursor, limit)
+    }
+}
+
+/// A connection taken from a `KvsClientPool`, going back to it when dropped.
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.release(client);
+        }
+    }
+}
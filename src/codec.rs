@@ -0,0 +1,204 @@
+#![deny(missing_docs)]
+//! Wire formats of log records.
+//!
+//! A `LogCodec` turns a `KvLog` into bytes and back. The bytes are then compressed and
+//! encrypted as configured, see `RecordFormat`. bincode is the default, JSON and MessagePack
+//! are alternatives for logs read by people or by tools in other languages.
+
+use crate::error::{Error, ErrorKind};
+use crate::{Compression, Encryption, KvLog, Options, Result};
+use failure::ResultExt;
+use serde::Deserialize;
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// Wire format of log records, set with `Options::codec`.
+///
+/// A store must always be opened with the codec its log was written with.
+pub trait LogCodec: fmt::Debug + Send + Sync {
+    /// Encode a log into bytes.
+    ///
+    /// # Errors
+    ///
+    /// - Serde: Serialization failed.
+    fn encode(&self, kvlog: &KvLog) -> Result<Vec<u8>>;
+
+    /// Decode one log from the reader, reading nothing after its end.
+    ///
+    /// # Errors
+    ///
+    /// - Serde: Deserialization failed, including when the reader ends too early.
+    fn decode(&self, reader: &mut dyn Read) -> Result<KvLog>;
+
+    /// Decode one log from the start of `bytes`, returning it with the number of bytes it
+    /// took. Codecs can check lengths read from damaged bytes against `bytes` before
+    /// allocating for them, so this is used where records may be damaged.
+    ///
+    /// # Errors
+    ///
+    /// Same as `decode`.
+    fn decode_slice(&self, bytes: &[u8]) -> Result<(KvLog, usize)> {
+        let mut reader = bytes;
+        let kvlog = self.decode(&mut reader)?;
+        Ok((kvlog, bytes.len() - reader.len()))
+    }
+}
+
+/// bincode, the compact default codec.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+impl LogCodec for BincodeCodec {
+    fn encode(&self, kvlog: &KvLog) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(kvlog).context(ErrorKind::Serde)?)
+    }
+
+    fn decode(&self, reader: &mut dyn Read) -> Result<KvLog> {
+        Ok(bincode::deserialize_from(reader).context(ErrorKind::Serde)?)
+    }
+
+    fn decode_slice(&self, bytes: &[u8]) -> Result<(KvLog, usize)> {
+        // unlike a reader, a slice is not asked for more bytes than it has
+        let kvlog: KvLog = bincode::deserialize(bytes).context(ErrorKind::Serde)?;
+        let len = bincode::serialized_size(&kvlog).context(ErrorKind::Serde)?;
+        Ok((kvlog, len as usize))
+    }
+}
+
+/// JSON with one record per line. Keys and values are arrays of bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl LogCodec for JsonCodec {
+    fn encode(&self, kvlog: &KvLog) -> Result<Vec<u8>> {
+        let mut bytes = serde_json::to_vec(kvlog).context(ErrorKind::Serde)?;
+        bytes.push(b'\n');
+        Ok(bytes)
+    }
+
+    fn decode(&self, reader: &mut dyn Read) -> Result<KvLog> {
+        // a log is an object, so the deserializer stops right after its closing brace
+        let kvlog = KvLog::deserialize(&mut serde_json::Deserializer::from_reader(&mut *reader))
+            .context(ErrorKind::Serde)?;
+        let mut newline = [0];
+        reader.read_exact(&mut newline).context(ErrorKind::Serde)?;
+        if newline != *b"\n" {
+            return Err(Error::from(ErrorKind::Serde));
+        }
+        Ok(kvlog)
+    }
+}
+
+/// MessagePack, compact and readable by many languages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePackCodec;
+
+impl LogCodec for MessagePackCodec {
+    fn encode(&self, kvlog: &KvLog) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec(kvlog).context(ErrorKind::Serde)?)
+    }
+
+    fn decode(&self, reader: &mut dyn Read) -> Result<KvLog> {
+        Ok(rmp_serde::from_read(reader).context(ErrorKind::Serde)?)
+    }
+}
+
+/// How records are written to a log file: encoded by a codec, then compressed and encrypted.
+#[derive(Clone, Debug)]
+pub(crate) struct RecordFormat {
+    codec: Arc<dyn LogCodec>,
+    compression: Compression,
+    encryption: Option<Encryption>,
+}
+
+impl Default for RecordFormat {
+    fn default() -> RecordFormat {
+        RecordFormat::new(&Options::default())
+    }
+}
+
+impl RecordFormat {
+    /// The record format configured in `options`.
+    pub(crate) fn new(options: &Options) -> RecordFormat {
+        RecordFormat {
+            codec: Arc::clone(&options.codec),
+            compression: options.compression,
+            encryption: options.encryption.clone(),
+        }
+    }
+
+    /// Write a log to writer, returning the number of bytes written.
+    ///
+    /// The log is compressed unless that does not make it smaller, and then encrypted.
+    /// It is handed to the writer in a single `write_all`, so a `BufWriter` never splits it
+    /// between the file and its buffer.
+    ///
+    /// # Errors
+    ///
+    /// - Serde: Encoding the log failed.
+    /// - CompressionUnavailable: The codec is not enabled in this build.
+    /// - Encryption: Encrypting the log failed.
+    /// - Io: Compression or writing to the writer failed.
+    pub(crate) fn write<W: Write>(&self, kvlog: &KvLog, mut writer: W) -> Result<u64> {
+        let mut bytes = self.codec.encode(kvlog)?;
+        if self.compression != Compression::None {
+            let compressed = self.compression.compress(&bytes)?;
+            let compressed = self
+                .codec
+                .encode(&KvLog::Compressed(self.compression, compressed))?;
+            if compressed.len() < bytes.len() {
+                bytes = compressed;
+            }
+        }
+        if let Some(encryption) = &self.encryption {
+            let (nonce, ciphertext) = encryption.encrypt(&bytes)?;
+            bytes = self.codec.encode(&KvLog::Encrypted(nonce, ciphertext))?;
+        }
+        writer.write_all(&bytes).context(ErrorKind::Io)?;
+        Ok(bytes.len() as u64)
+    }
+
+    /// Read a log from reader, decrypting and decompressing it.
+    ///
+    /// # Errors
+    ///
+    /// - Serde: Decoding the log failed.
+    /// - CompressionUnavailable: The log is compressed with a codec not enabled in this build.
+    /// - Corruption: Decompression of the log failed.
+    /// - BadEncryptionKey: The log is encrypted with another key, or no key is set.
+    pub(crate) fn read<R: Read>(&self, mut reader: R) -> Result<KvLog> {
+        let kvlog = self.codec.decode(&mut reader)?;
+        self.unwrap(kvlog)
+    }
+
+    /// Read a log from the start of `bytes` like `read`, returning it with the number of
+    /// bytes it took. See `LogCodec::decode_slice`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `read`.
+    pub(crate) fn read_slice(&self, bytes: &[u8]) -> Result<(KvLog, usize)> {
+        let (kvlog, len) = self.codec.decode_slice(bytes)?;
+        Ok((self.unwrap(kvlog)?, len))
+    }
+
+    /// Decrypt and decompress a decoded log.
+    fn unwrap(&self, kvlog: KvLog) -> Result<KvLog> {
+        match kvlog {
+            KvLog::Compressed(compression, bytes) => {
+                let bytes = compression.decompress(&bytes)?;
+                Ok(self.read_slice(&bytes)?.0)
+            }
+            KvLog::Encrypted(nonce, ciphertext) => {
+                let bytes = self
+                    .encryption
+                    .as_ref()
+                    .ok_or_else(|| Error::from(ErrorKind::BadEncryptionKey))?
+                    .decrypt(&nonce, &ciphertext)?;
+                Ok(self.read_slice(&bytes)?.0)
+            }
+            kvlog => Ok(kvlog),
+        }
+    }
+}
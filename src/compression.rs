@@ -0,0 +1,78 @@
+#![deny(missing_docs)]
+//! Compression of log records.
+//!
+//! A compressed record is stored as a `KvLog::Compressed` wrapping the serialized original
+//! record, so records written without compression stay readable and a store can change its
+//! codec between opens. The codecs are behind the `lz4` and `zstd` features.
+
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+
+/// Codec compressing log records, set with `Options::compression`.
+///
+/// A record is only stored compressed if that makes it smaller, so small records with
+/// nothing to gain are left as they are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Compression {
+    /// Records are not compressed.
+    #[default]
+    None,
+    /// LZ4, fast with a moderate ratio. Needs the `lz4` feature.
+    Lz4,
+    /// Zstandard at its default level, slower with a better ratio. Needs the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    /// Whether the codec is enabled in this build.
+    pub fn is_available(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// Compress `bytes` with the codec.
+    ///
+    /// # Errors
+    ///
+    /// - CompressionUnavailable: The codec is not enabled in this build.
+    /// - Io: The codec failed.
+    pub(crate) fn compress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                use failure::ResultExt;
+                Ok(zstd::bulk::compress(bytes, 0).context(ErrorKind::Io)?)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::from(ErrorKind::CompressionUnavailable)),
+        }
+    }
+
+    /// Decompress `bytes` compressed with the codec.
+    ///
+    /// # Errors
+    ///
+    /// - CompressionUnavailable: The codec is not enabled in this build.
+    /// - Corruption: `bytes` are not valid for the codec.
+    pub(crate) fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(bytes)
+                .map_err(|_| Error::from(ErrorKind::Corruption)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::stream::decode_all(bytes).map_err(|_| Error::from(ErrorKind::Corruption))
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::from(ErrorKind::CompressionUnavailable)),
+        }
+    }
+}
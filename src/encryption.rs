@@ -0,0 +1,91 @@
+#![deny(missing_docs)]
+//! Encryption at rest of log records.
+//!
+//! An encrypted record is stored as a `KvLog::Encrypted` holding a random nonce and the
+//! serialized, possibly compressed, original record sealed with AES-256-GCM. Records written
+//! without a key stay readable, and compaction rewrites everything with the current key.
+
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use std::fmt;
+
+/// Size of the nonce stored with each encrypted record.
+pub(crate) const NONCE_SIZE: usize = 12;
+
+/// Supplies the 256-bit key a store is encrypted with, e.g. from a key file or a KMS.
+pub trait KeyProvider {
+    /// Returns the key.
+    fn key(&self) -> [u8; 32];
+}
+
+impl KeyProvider for [u8; 32] {
+    fn key(&self) -> [u8; 32] {
+        *self
+    }
+}
+
+/// Encryption of log records, set with `Options::encryption`.
+///
+/// A store with encrypted records must always be opened with the same key, otherwise opening
+/// it fails with `ErrorKind::BadEncryptionKey`.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::{Encryption, KvStore};
+/// use tempfile::TempDir;
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::builder()
+///     .encryption(Encryption::new(&[7; 32]))
+///     .open(tempdir.path())
+///     .unwrap();
+/// kv.set("key1", "secret".to_owned()).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Encryption {
+    cipher: Aes256Gcm,
+}
+
+impl Encryption {
+    /// Create an encryption with the key of `provider`.
+    pub fn new<P: KeyProvider + ?Sized>(provider: &P) -> Encryption {
+        let key = provider.key();
+        Encryption {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        }
+    }
+
+    /// Encrypt `bytes` under a new random nonce, returning the nonce and the ciphertext.
+    ///
+    /// # Errors
+    ///
+    /// - Encryption: The cipher failed.
+    pub(crate) fn encrypt(&self, bytes: &[u8]) -> Result<([u8; NONCE_SIZE], Vec<u8>)> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, bytes)
+            .map_err(|_| Error::from(ErrorKind::Encryption))?;
+        Ok((nonce.into(), ciphertext))
+    }
+
+    /// Decrypt a ciphertext made by `encrypt`.
+    ///
+    /// # Errors
+    ///
+    /// - BadEncryptionKey: The ciphertext was encrypted with another key, or altered.
+    pub(crate) fn decrypt(&self, nonce: &[u8; NONCE_SIZE], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::from(ErrorKind::BadEncryptionKey))
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Encryption")
+    }
+}
@@ -0,0 +1,261 @@
+#![deny(missing_docs)]
+//! The interface of a key-value storage engine, implemented by `KvStore`.
+
+use crate::error::{Error, ErrorKind};
+use crate::replication::{Changes, ReplicationCursor};
+use crate::{BackupJob, KvStore, Result, RuntimeOptions, ScanCursor, ScanPage, Stats, Watch};
+use std::path::PathBuf;
+
+/// A key-value storage engine with string keys and values.
+///
+/// Code generic over the engine, like the `kvs` command line tool, works with any backend.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, Result};
+/// use tempfile::TempDir;
+///
+/// fn set_default<E: KvsEngine>(engine: &mut E, key: String) -> Result<String> {
+///     if let Some(value) = engine.get(key.clone())? {
+///         return Ok(value);
+///     }
+///     engine.set(key, "default".to_owned())?;
+///     Ok("default".to_owned())
+/// }
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::open(tempdir.path()).unwrap();
+/// assert_eq!(set_default(&mut kv, "key1".to_owned()).unwrap(), "default");
+/// ```
+pub trait KvsEngine {
+    /// Set the value of a key, overwriting any previous value.
+    ///
+    /// # Errors
+    ///
+    /// Depends on the engine, e.g. Io if the value failed to be written.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Get the value of a key, or `None` if it is not present.
+    ///
+    /// # Errors
+    ///
+    /// Depends on the engine, e.g. Io if the value failed to be read.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Remove a key.
+    ///
+    /// # Errors
+    ///
+    /// - KeyNotFound: The key is not present.
+    /// - Others: Depends on the engine.
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Set the value of a key to `new` only if it is `expected`, with no other write of the
+    /// engine coming between, see `KvStore::compare_and_swap`. Returns whether the swap
+    /// happened.
+    ///
+    /// # Errors
+    ///
+    /// - Unsupported: The engine cannot swap atomically, which is the default.
+    /// - Others: Depends on the engine.
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let _ = (key, expected, new);
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Get the key-value pairs whose key starts with `prefix`, in lexicographic order of
+    /// keys.
+    ///
+    /// # Errors
+    ///
+    /// - Unsupported: The engine cannot list keys, which is the default.
+    /// - Others: Depends on the engine.
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let _ = prefix;
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Get a page of at most `limit` key-value pairs whose key starts with `prefix`
+    /// following `cursor`, see `KvStore::scan_prefix_from`. By default, the page is taken
+    /// from the pairs of `scan_prefix`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `scan_prefix`.
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        let after = cursor.last_key();
+        let mut entries: Vec<_> = self
+            .scan_prefix(prefix)?
+            .into_iter()
+            .filter(|(key, _)| after.is_none_or(|after| key.as_bytes() > after))
+            // one more pair tells if there is a next page
+            .take(limit.saturating_add(1))
+            .collect();
+        let has_next = entries.len() > limit;
+        entries.truncate(limit);
+        let cursor = match entries.last() {
+            Some((key, _)) if has_next => Some(ScanCursor::after(key.clone().into_bytes())),
+            _ if has_next => Some(cursor.clone()),
+            _ => None,
+        };
+        Ok(ScanPage { entries, cursor })
+    }
+
+    /// Get the changes for a replica at `cursor` to catch up with the engine, see
+    /// `KvStore::changes`.
+    ///
+    /// # Errors
+    ///
+    /// - Unsupported: The engine cannot be replicated, which is the default.
+    /// - Others: Depends on the engine.
+    fn changes(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        let _ = cursor;
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Watch the keys starting with any of `prefixes`, see `KvStore::watch_prefixes`.
+    ///
+    /// # Errors
+    ///
+    /// - Unsupported: The engine cannot report changes, which is the default.
+    /// - Others: Depends on the engine.
+    fn subscribe(&mut self, prefixes: Vec<String>) -> Result<Watch> {
+        let _ = prefixes;
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Remove at most `limit` expired keys, returning how many were removed, see
+    /// `KvStore::expire_keys`. Engines without expired keys to remove have nothing to do,
+    /// which is the default.
+    ///
+    /// # Errors
+    ///
+    /// Depends on the engine.
+    fn expire_keys(&mut self, limit: usize) -> Result<usize> {
+        let _ = limit;
+        Ok(0)
+    }
+
+    /// Get statistics about the size of the store, see `KvStore::stats`.
+    ///
+    /// # Errors
+    ///
+    /// - Unsupported: The engine has no such statistics, which is the default.
+    /// - Others: Depends on the engine.
+    fn stats(&mut self) -> Result<Stats> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Compact the storage of the engine now, see `KvStore::compact`.
+    ///
+    /// # Errors
+    ///
+    /// - Unsupported: The engine cannot be compacted, which is the default.
+    /// - Others: Depends on the engine.
+    fn compact(&mut self) -> Result<()> {
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Start a backup of the engine to `dest`, see `KvStore::start_backup`.
+    ///
+    /// # Errors
+    ///
+    /// - Unsupported: The engine cannot be backed up, which is the default.
+    /// - Others: Depends on the engine.
+    fn start_backup(&mut self, dest: PathBuf) -> Result<BackupJob> {
+        let _ = dest;
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+
+    /// Make every write so far durable, see `KvStore::sync`. Engines making each write
+    /// durable by itself have nothing to do, which is the default.
+    ///
+    /// # Errors
+    ///
+    /// Depends on the engine.
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Change the options of the engine which can change while it is open, see
+    /// `KvStore::set_runtime_options`.
+    ///
+    /// # Errors
+    ///
+    /// - Unsupported: The engine has no such options, which is the default.
+    /// - Others: Depends on the engine.
+    fn set_runtime_options(&mut self, options: RuntimeOptions) -> Result<()> {
+        let _ = options;
+        Err(Error::from(ErrorKind::Unsupported))
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        KvStore::compare_and_swap(self, key, expected, new)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        KvStore::scan_prefix(self, prefix).collect()
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        KvStore::scan_prefix_from(self, prefix, cursor, limit)
+    }
+
+    fn changes(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        KvStore::changes(self, cursor)
+    }
+
+    fn subscribe(&mut self, prefixes: Vec<String>) -> Result<Watch> {
+        Ok(KvStore::watch_prefixes(self, prefixes))
+    }
+
+    fn expire_keys(&mut self, limit: usize) -> Result<usize> {
+        KvStore::expire_keys(self, limit)
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        KvStore::stats(self)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    fn start_backup(&mut self, dest: PathBuf) -> Result<BackupJob> {
+        KvStore::start_backup(self, dest)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        KvStore::sync(self)
+    }
+
+    fn set_runtime_options(&mut self, options: RuntimeOptions) -> Result<()> {
+        KvStore::set_runtime_options(self, options);
+        Ok(())
+    }
+}
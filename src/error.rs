@@ -1,10 +1,12 @@
 #![deny(missing_docs)]
+#![allow(non_local_definitions)] // emitted by the Fail derive
 //! Defines error for KvStore
 //!
 //! Using the "An Error and ErrorKind pair" pattern
 
 use failure::Backtrace;
 use failure::{Context, Fail};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::fmt::Display;
 
@@ -50,7 +52,7 @@ impl From<Context<ErrorKind>> for Error {
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Fail)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Fail, Serialize, Deserialize)]
 /// Error kind of KvStore
 pub enum ErrorKind {
     #[fail(display = "An IO Error occurred")]
@@ -65,4 +67,97 @@ pub enum ErrorKind {
     #[fail(display = "Corruption in log pointer map or log file detected")]
     /// Error caused by a discrepancy between log pointer map and log file
     Corruption,
+    #[fail(display = "Key or value is not valid UTF-8")]
+    /// Error caused by reading a key or value set as bytes through the string API
+    InvalidUtf8,
+    #[fail(display = "Value is not an integer")]
+    /// Error caused by incrementing or decrementing a value that is not an integer
+    NotAnInteger,
+    #[fail(display = "Increment or decrement would overflow")]
+    /// Error caused by incrementing or decrementing out of the range of `i64`
+    Overflow,
+    #[fail(display = "No merge operator is set")]
+    /// Error caused by merging without a merge operator in `Options`
+    NoMergeOperator,
+    #[fail(display = "Store is opened read-only")]
+    /// Error caused by modifying a store opened read-only
+    ReadOnly,
+    #[fail(display = "Store is locked by another KvStore")]
+    /// Error caused by opening a store that is already open for writing
+    StoreLocked,
+    #[fail(display = "Compression codec is not enabled in this build")]
+    /// Error caused by compressing or decompressing with a codec whose feature is disabled
+    CompressionUnavailable,
+    #[fail(display = "Log is encrypted with another key")]
+    /// Error caused by reading encrypted records without their key
+    BadEncryptionKey,
+    #[fail(display = "Log file format version is not supported")]
+    /// Error caused by opening a log file of an unknown format version, or of version 1,
+    /// which has to be upgraded by `KvStore::migrate` first
+    UnsupportedVersion,
+    #[fail(display = "Log file was rewritten since the backup cursor was taken")]
+    /// Error caused by an incremental backup from a cursor of a log file that has since been
+    /// compacted or cleared, which needs a full backup instead
+    StaleBackupCursor,
+    #[fail(display = "Backup is incomplete or damaged")]
+    /// Error caused by restoring a backup without a manifest, whose checksum does not match,
+    /// or an incremental backup that does not continue the backup before it
+    InvalidBackup,
+    #[fail(display = "Store already exists")]
+    /// Error caused by restoring a backup over an existing store without forcing it
+    StoreExists,
+    #[fail(display = "Key is larger than the maximum key size")]
+    /// Error caused by setting a key larger than `Options::max_key_size`
+    KeyTooLarge,
+    #[fail(display = "Value is larger than the maximum value size")]
+    /// Error caused by setting a value larger than `Options::max_value_size`
+    ValueTooLarge,
+    #[fail(display = "Version was dropped by compaction")]
+    /// Error caused by reading a key at a sequence number older than the last compaction
+    VersionCompacted,
+    #[fail(display = "Index not found")]
+    /// Error caused by looking up a secondary index the store was not opened with
+    IndexNotFound,
+    #[fail(display = "Store is not empty")]
+    /// Error caused by bulk loading a store that has keys, or starting a raft node on one
+    /// that was not written through a node
+    StoreNotEmpty,
+    #[fail(display = "Invalid store name")]
+    /// Error caused by opening a named store with a name that cannot prefix its files
+    InvalidStoreName,
+    #[fail(display = "Operation is not supported by this engine")]
+    /// Error caused by an operation a `KvsEngine` does not implement
+    Unsupported,
+    #[fail(display = "A sled Error occurred")]
+    /// Error caused by sled in `SledKvsEngine`
+    Sled,
+    #[fail(display = "A TLS Error occurred")]
+    /// Error caused by rustls, or by loading a certificate or private key for it
+    Tls,
+    #[fail(display = "Authentication required")]
+    /// Error caused by sending commands to a server requiring authentication without a
+    /// valid token
+    Unauthenticated,
+    #[fail(display = "Permission denied")]
+    /// Error caused by reading or writing a key the authenticated user has no access to
+    PermissionDenied,
+    #[fail(display = "No leader to take the write")]
+    /// Error caused by writing to a raft cluster whose leader is unknown or cannot reach a
+    /// majority of the nodes. The write may still be committed later if it reached the leader
+    NotLeader,
+    #[fail(display = "Request timed out")]
+    /// Error caused by a server not answering a request, or not accepting a connection,
+    /// within the timeout of the client
+    Timeout,
+    #[fail(display = "Incompatible protocol version")]
+    /// Error caused by a client and a server whose versions of the protocol have nothing in
+    /// common, or by connecting to something else than a `KvsServer`
+    IncompatibleVersion,
+    #[fail(display = "Server busy")]
+    /// Error caused by a server refusing a connection or a request because it is already
+    /// serving as many as it takes
+    ServerBusy,
+    #[fail(display = "Encryption failed")]
+    /// Error caused by the cipher failing to encrypt a record
+    Encryption,
 }
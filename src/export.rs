@@ -0,0 +1,116 @@
+#![deny(missing_docs)]
+//! Export and import of key-value pairs in portable formats, see `KvStore::export` and
+//! `KvStore::import`.
+
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+use failure::{Fail, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// Portable format of exported key-value pairs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// JSON Lines, one object with a `key` and a `value` string per line.
+    Json,
+    /// CSV with a `key,value` header row, fields quoted when needed.
+    Csv,
+}
+
+/// A key-value pair as a JSON object or a CSV row.
+#[derive(Serialize, Deserialize)]
+struct Pair<S> {
+    key: S,
+    value: S,
+}
+
+/// Write every pair to `writer` in `format`, returning the number of pairs written.
+///
+/// # Errors
+///
+/// - Io: Writing to the writer failed.
+/// - Serde: Serialization of a pair failed.
+/// - Others: Same as the pairs.
+pub(crate) fn write_pairs<I, W>(pairs: I, writer: W, format: Format) -> Result<usize>
+where
+    I: Iterator<Item = Result<(String, String)>>,
+    W: Write,
+{
+    let mut count = 0;
+    match format {
+        Format::Json => {
+            let mut writer = BufWriter::new(writer);
+            for pair in pairs {
+                let (key, value) = pair?;
+                let mut line = serde_json::to_vec(&Pair {
+                    key: &key,
+                    value: &value,
+                })
+                .context(ErrorKind::Serde)?;
+                line.push(b'\n');
+                writer.write_all(&line).context(ErrorKind::Io)?;
+                count += 1;
+            }
+            writer.flush().context(ErrorKind::Io)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer
+                .write_record(["key", "value"])
+                .context(ErrorKind::Io)?;
+            for pair in pairs {
+                let (key, value) = pair?;
+                writer.write_record([key, value]).context(ErrorKind::Io)?;
+                count += 1;
+            }
+            writer.flush().context(ErrorKind::Io)?;
+        }
+    }
+    Ok(count)
+}
+
+/// Read pairs in `format` from `reader`, one at a time.
+///
+/// # Errors
+///
+/// Each item has these errors:
+///
+/// - Io: Reading from the reader failed.
+/// - Serde: The input is not well-formed, or a pair is missing its key or value.
+pub(crate) fn read_pairs<'a, R>(
+    reader: R,
+    format: Format,
+) -> Box<dyn Iterator<Item = Result<(String, String)>> + 'a>
+where
+    R: Read + 'a,
+{
+    let into_tuple = |pair: Pair<String>| (pair.key, pair.value);
+    match format {
+        Format::Json => Box::new(
+            serde_json::Deserializer::from_reader(BufReader::new(reader))
+                .into_iter()
+                .map(move |pair| {
+                    pair.map(into_tuple).map_err(|e| {
+                        let kind = if e.is_io() {
+                            ErrorKind::Io
+                        } else {
+                            ErrorKind::Serde
+                        };
+                        Error::from(e.context(kind))
+                    })
+                }),
+        ),
+        Format::Csv => Box::new(csv::Reader::from_reader(reader).into_deserialize().map(
+            move |pair| {
+                pair.map(into_tuple).map_err(|e| {
+                    let kind = if e.is_io_error() {
+                        ErrorKind::Io
+                    } else {
+                        ErrorKind::Serde
+                    };
+                    Error::from(e.context(kind))
+                })
+            },
+        )),
+    }
+}
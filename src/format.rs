@@ -0,0 +1,92 @@
+#![deny(missing_docs)]
+//! On-disk format of log files.
+//!
+//! A log file starts with a header of magic bytes and a little-endian `u32` format version,
+//! followed by the records. Files of version 1 predate the header and start with a record
+//! right away. They are upgraded by `KvStore::migrate`.
+//!
+//! The version is bumped whenever a record variant is added, so that older builds refuse
+//! the file instead of taking the new records for damage at its end. Version 3 added
+//! namespaces, the value log and sequence numbers. Files of version 2 hold a subset of
+//! the records of version 3, so they are upgraded by rewriting the version in their header.
+
+use crate::error::ErrorKind;
+use crate::Result;
+use failure::{Fail, ResultExt};
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Magic bytes a log file starts with. A version 1 file starts with the variant index of
+/// its first record instead, which is far smaller than these bytes read as a number.
+const MAGIC: [u8; 4] = *b"KVS\0";
+/// Format version written by this build.
+pub(crate) const FORMAT_VERSION: u32 = 3;
+/// Version of log files without a header.
+pub(crate) const HEADERLESS_VERSION: u32 = 1;
+/// Oldest version with a header, whose files are read as they are and upgraded in place.
+pub(crate) const MIN_HEADER_VERSION: u32 = 2;
+/// Length of the header, which is the offset of the first record.
+pub(crate) const HEADER_LEN: u64 = 8;
+
+/// Write the header of a new log file.
+///
+/// # Errors
+///
+/// - Io: Writing to the writer failed.
+pub(crate) fn write_header<W: Write>(mut writer: W) -> Result<()> {
+    let mut header = [0; HEADER_LEN as usize];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    writer.write_all(&header).context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// Read the format version of a log file from its start.
+///
+/// Returns `None` if the file is too short to have a header, which means it is empty or a
+/// crash interrupted writing its header. A version 1 file always holds at least one whole
+/// record, which is longer than a header.
+///
+/// # Errors
+///
+/// - Io: Reading from the reader failed.
+pub(crate) fn read_version<R: Read>(mut reader: R) -> Result<Option<u32>> {
+    let mut header = [0; HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.context(ErrorKind::Io).into()),
+    }
+    if header[..4] != MAGIC {
+        return Ok(Some(HEADERLESS_VERSION));
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[4..]);
+    Ok(Some(u32::from_le_bytes(version)))
+}
+
+/// Whether files of `version` are read by this build, possibly after `upgrade_header`.
+pub(crate) fn is_readable(version: u32) -> bool {
+    (MIN_HEADER_VERSION..=FORMAT_VERSION).contains(&version)
+}
+
+/// Rewrite the version in the header of the log file at `path`, of a version for which
+/// `is_readable` holds, to the current one. The file is synced, so that the new records
+/// appended after are never seen under the old version.
+///
+/// # Errors
+///
+/// - Io: Failed to open, write or sync the file.
+pub(crate) fn upgrade_header(path: &Path) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .context(ErrorKind::Io)?;
+    file.seek(SeekFrom::Start(MAGIC.len() as u64))
+        .context(ErrorKind::Io)?;
+    file.write_all(&FORMAT_VERSION.to_le_bytes())
+        .context(ErrorKind::Io)?;
+    file.sync_data().context(ErrorKind::Io)?;
+    Ok(())
+}
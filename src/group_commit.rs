@@ -0,0 +1,158 @@
+#![deny(missing_docs)]
+//! Group commit: coalesce many appends into one fsync.
+//!
+//! In group commit mode every command is written through to the OS right away, but
+//! durability is handled by a background flusher thread. The flusher calls `sync_data`
+//! once enough bytes are pending or the interval elapsed, so a burst of `set` calls
+//! shares a single fsync instead of paying for one each.
+
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Configuration of group commit mode.
+#[derive(Clone, Debug)]
+pub struct GroupCommit {
+    /// The longest time a written record waits before it is synced to disk.
+    pub interval: Duration,
+    /// Sync right away once this many bytes are waiting for a sync.
+    pub max_batch_bytes: u64,
+    /// Whether `set` and `remove` block until their record is durable.
+    pub wait_for_sync: bool,
+}
+
+impl Default for GroupCommit {
+    fn default() -> GroupCommit {
+        GroupCommit {
+            interval: Duration::from_millis(5),
+            max_batch_bytes: 64 * 1024,
+            wait_for_sync: false,
+        }
+    }
+}
+
+/// Offsets shared between the store and the flusher thread.
+#[derive(Default)]
+struct SyncState {
+    /// End offset of everything handed to the OS.
+    written: u64,
+    /// End offset of everything known to be on disk.
+    synced: u64,
+    /// Set when the last sync failed. Waiters get an Io error.
+    failed: bool,
+    /// Set by the store to stop the flusher.
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<SyncState>,
+    /// Wakes the flusher up early.
+    pending: Condvar,
+    /// Wakes up everyone waiting for their record to be synced.
+    synced: Condvar,
+}
+
+/// Handle to the background flusher thread of one log file.
+///
+/// Dropping it performs a final sync and joins the thread.
+pub(crate) struct Flusher {
+    shared: Arc<Shared>,
+    max_batch_bytes: u64,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    /// Spawn a flusher syncing `file`, whose first `len` bytes are already durable.
+    pub(crate) fn spawn(file: File, len: u64, config: &GroupCommit) -> Flusher {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(SyncState {
+                written: len,
+                synced: len,
+                ..SyncState::default()
+            }),
+            pending: Condvar::new(),
+            synced: Condvar::new(),
+        });
+        let thread_shared = Arc::clone(&shared);
+        let interval = config.interval;
+        let max_batch_bytes = config.max_batch_bytes;
+        let handle =
+            thread::spawn(move || flush_loop(file, &thread_shared, interval, max_batch_bytes));
+
+        Flusher {
+            shared,
+            max_batch_bytes,
+            handle: Some(handle),
+        }
+    }
+
+    /// Tell the flusher that the log file now ends at `offset`.
+    pub(crate) fn written(&self, offset: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.written = offset;
+        if state.written - state.synced >= self.max_batch_bytes {
+            self.shared.pending.notify_one();
+        }
+    }
+
+    /// Block until everything before `offset` is synced to disk.
+    ///
+    /// # Errors
+    ///
+    /// - Io: The flusher failed to sync the log file.
+    pub(crate) fn wait_synced(&self, offset: u64) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.synced < offset && !state.failed {
+            state = self.shared.synced.wait(state).unwrap();
+        }
+        if state.failed {
+            return Err(Error::from(ErrorKind::Io));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.pending.notify_one();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("Flusher thread panicked");
+            }
+        }
+    }
+}
+
+/// Body of the flusher thread.
+fn flush_loop(file: File, shared: &Shared, interval: Duration, max_batch_bytes: u64) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if !state.shutdown && state.written - state.synced < max_batch_bytes {
+            state = shared.pending.wait_timeout(state, interval).unwrap().0;
+        }
+        let shutdown = state.shutdown;
+        if state.written > state.synced || shutdown {
+            // sync without holding the lock so the store can keep appending
+            let target = state.written;
+            drop(state);
+            let result = file.sync_data();
+            state = shared.state.lock().unwrap();
+            match result {
+                Ok(_) => state.synced = state.synced.max(target),
+                Err(e) => {
+                    eprintln!("Failed to sync log file: {}", e);
+                    state.failed = true;
+                }
+            }
+            shared.synced.notify_all();
+        }
+        if shutdown {
+            return;
+        }
+    }
+}
@@ -0,0 +1,156 @@
+#![deny(missing_docs)]
+//! gRPC service of a storage engine, defined by `proto/kvs.proto`. Needs the `grpc` feature.
+//!
+//! Clients in any language can be generated from `proto/kvs.proto`. Rust clients can use
+//! `proto::kvs_client::KvsClient` over a tonic channel.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use kvs::KvStore;
+//!
+//! let store = KvStore::open(".").unwrap();
+//! kvs::grpc::serve(store, "127.0.0.1:4000").unwrap();
+//! ```
+
+use crate::error::{Error, ErrorKind};
+use crate::{KvsEngine, Result};
+use failure::ResultExt;
+use proto::kvs_server::{Kvs, KvsServer};
+use proto::{
+    GetRequest, GetResponse, KeyValue, RemoveRequest, RemoveResponse, ScanRequest, SetRequest,
+    SetResponse,
+};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use tonic::{Request, Response, Status};
+
+/// Messages, client and server generated from `proto/kvs.proto`.
+#[allow(missing_docs)]
+pub mod proto {
+    tonic::include_proto!("kvs");
+}
+
+/// The gRPC service of a storage engine, to be served with `into_server`.
+///
+/// Commands run one at a time, on the blocking threads of the runtime.
+pub struct KvsGrpcService<E> {
+    engine: Arc<Mutex<E>>,
+}
+
+impl<E: KvsEngine + Send + 'static> KvsGrpcService<E> {
+    /// Create the service of `engine`.
+    pub fn new(engine: E) -> KvsGrpcService<E> {
+        KvsGrpcService {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// The service as a tonic server, to add to a `tonic::transport::Server`.
+    pub fn into_server(self) -> KvsServer<KvsGrpcService<E>> {
+        KvsServer::new(self)
+    }
+
+    /// Run `command` against the engine on a blocking thread.
+    async fn run<T, F>(&self, command: F) -> std::result::Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut E) -> Result<T> + Send + 'static,
+    {
+        let engine = Arc::clone(&self.engine);
+        tokio::task::spawn_blocking(move || command(&mut engine.lock().unwrap()))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(status)
+    }
+}
+
+// the size of `Status` is set by tonic
+#[allow(clippy::result_large_err)]
+#[tonic::async_trait]
+impl<E: KvsEngine + Send + 'static> Kvs for KvsGrpcService<E> {
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> std::result::Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        let value = self.run(move |engine| engine.get(key)).await?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<SetRequest>,
+    ) -> std::result::Result<Response<SetResponse>, Status> {
+        let SetRequest { key, value } = request.into_inner();
+        self.run(move |engine| engine.set(key, value)).await?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> std::result::Result<Response<RemoveResponse>, Status> {
+        let key = request.into_inner().key;
+        self.run(move |engine| engine.remove(key)).await?;
+        Ok(Response::new(RemoveResponse {}))
+    }
+
+    type ScanStream = tokio_stream::Iter<std::vec::IntoIter<std::result::Result<KeyValue, Status>>>;
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> std::result::Result<Response<Self::ScanStream>, Status> {
+        let prefix = request.into_inner().prefix;
+        let pairs = self.run(move |engine| engine.scan_prefix(prefix)).await?;
+        let pairs: Vec<_> = pairs
+            .into_iter()
+            .map(|(key, value)| Ok(KeyValue { key, value }))
+            .collect();
+        Ok(Response::new(tokio_stream::iter(pairs)))
+    }
+}
+
+/// The gRPC status of an error.
+fn status(e: Error) -> Status {
+    let message = e.to_string();
+    match e.kind() {
+        ErrorKind::KeyNotFound => Status::not_found(message),
+        ErrorKind::InvalidUtf8 | ErrorKind::KeyTooLarge | ErrorKind::ValueTooLarge => {
+            Status::invalid_argument(message)
+        }
+        ErrorKind::Unsupported => Status::unimplemented(message),
+        ErrorKind::ReadOnly => Status::failed_precondition(message),
+        ErrorKind::Unauthenticated => Status::unauthenticated(message),
+        ErrorKind::PermissionDenied => Status::permission_denied(message),
+        ErrorKind::NotLeader => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// Serve the gRPC service of `engine` on `addr`, blocking until the server fails.
+///
+/// # Errors
+///
+/// - Io: Failed to resolve `addr`, start the runtime, or listen on `addr`.
+pub fn serve<E, A>(engine: E, addr: A) -> Result<()>
+where
+    E: KvsEngine + Send + 'static,
+    A: ToSocketAddrs,
+{
+    let addr: SocketAddr = addr
+        .to_socket_addrs()
+        .context(ErrorKind::Io)?
+        .next()
+        .ok_or_else(|| Error::from(ErrorKind::Io))?;
+    let runtime = tokio::runtime::Runtime::new().context(ErrorKind::Io)?;
+    runtime
+        .block_on(
+            tonic::transport::Server::builder()
+                .add_service(KvsGrpcService::new(engine).into_server())
+                .serve(addr),
+        )
+        .context(ErrorKind::Io)?;
+    Ok(())
+}
@@ -0,0 +1,14 @@
+#![deny(missing_docs)]
+//! Versions of a key, returned by `KvStore::history`.
+
+/// A version of a key: a value it was set to, or its removal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyVersion {
+    /// Sequence number of the write of this version, see `KvStore::sequence`.
+    pub sequence: u64,
+    /// Value the key was set to, `None` if it was removed.
+    pub value: Option<String>,
+    /// Sequence number of the write that replaced this version by overwriting or removing
+    /// the key, `None` for the current version.
+    pub replaced_at: Option<u64>,
+}
@@ -0,0 +1,108 @@
+#![deny(missing_docs)]
+//! Callbacks run after writes, see `Options::write_hooks`.
+
+use crate::KvLog;
+use std::fmt;
+use std::sync::Arc;
+
+/// Function called with the log of a write.
+type HookFn = dyn Fn(&KvLog) + Send + Sync;
+
+/// A callback run after every write, set with `Options::write_hooks`.
+///
+/// It is called once the records of a write are appended and committed as
+/// `Options::durability` asks, with the log stamped with its sequence number. A batch is
+/// one log. Values are inline even if they went to the value log: they are read before the
+/// records are appended, and failing to read one fails the write, so a committed write
+/// always reaches the hooks. Hooks run on the thread of the write in the order they were
+/// set, so they should be quick. Compaction, clearing and restoring write no logs to hooks.
+///
+/// # Examples
+///
+/// Counting the removed keys:
+///
+/// ```rust
+/// use kvs::{KvStore, WriteHook};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+/// use tempfile::TempDir;
+///
+/// let removed = Arc::new(AtomicUsize::new(0));
+/// let counter = Arc::clone(&removed);
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::builder()
+///     .write_hook(WriteHook::on_remove(move |_key| {
+///         counter.fetch_add(1, Ordering::Relaxed);
+///     }))
+///     .open(tempdir.path())
+///     .unwrap();
+///
+/// kv.set("key1".to_owned(), "42".to_owned()).unwrap();
+/// kv.remove("key1".to_owned()).unwrap();
+/// assert_eq!(removed.load(Ordering::Relaxed), 1);
+/// ```
+#[derive(Clone)]
+pub struct WriteHook {
+    hook_fn: Arc<HookFn>,
+}
+
+impl WriteHook {
+    /// Create a hook called with the log of every write.
+    pub fn new<F>(hook_fn: F) -> WriteHook
+    where
+        F: Fn(&KvLog) + Send + Sync + 'static,
+    {
+        WriteHook {
+            hook_fn: Arc::new(hook_fn),
+        }
+    }
+
+    /// Create a hook called with the key and value of every set command, including the set
+    /// commands of batches and those with a TTL. Appends, merges and namespaces are skipped.
+    pub fn on_set<F>(on_set: F) -> WriteHook
+    where
+        F: Fn(&[u8], &[u8]) + Send + Sync + 'static,
+    {
+        WriteHook::new(move |kvlog| {
+            for command in commands(kvlog) {
+                if let KvLog::Set(key, value) | KvLog::SetEx(key, value, _) = command {
+                    on_set(key, value);
+                }
+            }
+        })
+    }
+
+    /// Create a hook called with the key of every remove command, including those of
+    /// batches. Namespaces are skipped.
+    pub fn on_remove<F>(on_remove: F) -> WriteHook
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        WriteHook::new(move |kvlog| {
+            for command in commands(kvlog) {
+                if let KvLog::Rm(key) = command {
+                    on_remove(key);
+                }
+            }
+        })
+    }
+
+    /// Run the hook on the log of a write.
+    pub(crate) fn call(&self, kvlog: &KvLog) {
+        (self.hook_fn)(kvlog)
+    }
+}
+
+impl fmt::Debug for WriteHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("WriteHook")
+    }
+}
+
+/// Commands of a log stamped with its sequence number.
+fn commands(kvlog: &KvLog) -> &[KvLog] {
+    match kvlog {
+        KvLog::Sequenced(_, kvlog) => kvlog.commands(),
+        kvlog => kvlog.commands(),
+    }
+}
@@ -0,0 +1,389 @@
+#![deny(missing_docs)]
+//! A REST API over HTTP/1.1, served by `KvsServer` with `Protocol::Http`.
+//!
+//! - `GET /keys/{key}` answers `{"key": ..., "value": ...}`, or 404 if the key is missing.
+//! - `PUT /keys/{key}` sets the key to the request body, and answers 204.
+//! - `DELETE /keys/{key}` removes the key and answers 204, or 404 if it is missing.
+//! - `GET /keys?prefix={prefix}` answers the pairs whose key starts with the prefix, as a
+//!   JSON array of `{"key": ..., "value": ...}` in order of keys. Without a prefix, every
+//!   pair is listed.
+//!
+//! Keys are percent-encoded in paths and queries. Errors are answered with a status code
+//! and a body like `{"error": "Key not found"}`. Connections are kept alive unless the
+//! client asks otherwise.
+//!
+//! A server with an ACL answers 401 to requests without an `Authorization: Bearer {token}`
+//! header carrying the token of a user, and 403 to requests on keys the user has no access
+//! to. Listing keys only returns the keys the user may read.
+
+use crate::acl::{Acl, Restricted};
+use crate::audit::Auditor;
+use crate::error::{Error, ErrorKind};
+use crate::{KvsEngine, Result};
+use failure::ResultExt;
+use serde_json::json;
+use std::io::{BufRead, Read, Write};
+
+/// Largest request body accepted.
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+/// Longest request line or header accepted.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// A parsed HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+    keep_alive: bool,
+    authorization: Option<String>,
+}
+
+/// An HTTP response.
+struct Response {
+    status: u16,
+    body: Option<Body>,
+}
+
+/// The body of a response.
+enum Body {
+    Json(serde_json::Value),
+    /// Metrics in the text format of Prometheus.
+    Metrics(String),
+}
+
+impl Response {
+    fn new(status: u16, body: serde_json::Value) -> Response {
+        Response {
+            status,
+            body: Some(Body::Json(body)),
+        }
+    }
+
+    fn no_content() -> Response {
+        Response {
+            status: 204,
+            body: None,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::new(status, json!({ "error": message }))
+    }
+}
+
+/// Answer the requests read from `reader` on `writer`, until the client closes the
+/// connection or asks to close it. Requests not carrying the token of a user are refused if
+/// there is an `acl`. Changes are recorded with `audit`.
+///
+/// # Errors
+///
+/// - Io: Failed to read a request or write a response.
+pub(crate) fn serve<E, R, W>(
+    engine: &mut E,
+    acl: Option<&Acl>,
+    audit: Option<Auditor<'_>>,
+    mut reader: R,
+    mut writer: W,
+) -> Result<()>
+where
+    E: KvsEngine,
+    R: BufRead,
+    W: Write,
+{
+    loop {
+        let (response, keep_alive) = match read_request(&mut reader) {
+            Ok(Some(request)) => {
+                let response = match acl {
+                    None => route(&mut Restricted::new(engine, None).audit(audit), &request),
+                    Some(acl) => match bearer_token(&request).and_then(|t| acl.authenticate(t)) {
+                        Some(user) => {
+                            let engine = &mut Restricted::new(engine, Some(user)).audit(audit);
+                            route(engine, &request)
+                        }
+                        None => Response::error(401, &ErrorKind::Unauthenticated.to_string()),
+                    },
+                };
+                (response, request.keep_alive)
+            }
+            Ok(None) => return Ok(()),
+            // the rest of a malformed request cannot be told apart from the next one
+            Err(e) if e.kind() == ErrorKind::Serde => (Response::error(400, "Bad request"), false),
+            Err(e) if e.kind() == ErrorKind::ValueTooLarge => {
+                (Response::error(413, "Payload too large"), false)
+            }
+            Err(e) => return Err(e),
+        };
+        write_response(&mut writer, &response, keep_alive)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Answer `GET /metrics` with the metrics `render` returns, and 404 to other requests,
+/// until the client closes the connection or asks to close it.
+///
+/// # Errors
+///
+/// - Io: Failed to read a request or write a response.
+pub(crate) fn serve_metrics<R, W, F>(mut reader: R, mut writer: W, render: F) -> Result<()>
+where
+    R: BufRead,
+    W: Write,
+    F: Fn() -> String,
+{
+    loop {
+        let (response, keep_alive) = match read_request(&mut reader) {
+            Ok(Some(request)) => {
+                let response = match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/metrics") => Response {
+                        status: 200,
+                        body: Some(Body::Metrics(render())),
+                    },
+                    _ => Response::error(404, "Not found"),
+                };
+                (response, request.keep_alive)
+            }
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::Serde || e.kind() == ErrorKind::ValueTooLarge => {
+                (Response::error(400, "Bad request"), false)
+            }
+            Err(e) => return Err(e),
+        };
+        write_response(&mut writer, &response, keep_alive)?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Tell a client connecting to a server which has as many connections as it takes that it
+/// is refused, with a 503 response closing the connection.
+///
+/// # Errors
+///
+/// - Io: Failed to write the response.
+pub(crate) fn write_busy<W: Write>(mut writer: W) -> Result<()> {
+    let response = Response::error(503, &ErrorKind::ServerBusy.to_string());
+    write_response(&mut writer, &response, false)?;
+    writer.flush().context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// The bearer token of the `Authorization` header of a request.
+fn bearer_token(request: &Request) -> Option<&[u8]> {
+    let (scheme, token) = request.authorization.as_deref()?.split_once(' ')?;
+    Some(token.trim().as_bytes()).filter(|_| scheme.eq_ignore_ascii_case("Bearer"))
+}
+
+/// Run a request against the engine.
+fn route<E: KvsEngine>(engine: &mut E, request: &Request) -> Response {
+    let key = match request.path.strip_prefix("/keys/") {
+        Some(key) => match percent_decode(key) {
+            Some(key) => Some(key),
+            None => return Response::error(400, "Key is not valid percent-encoded UTF-8"),
+        },
+        None if request.path == "/keys" => None,
+        None => return Response::error(404, "Not found"),
+    };
+    let result = match (request.method.as_str(), key) {
+        ("GET", Some(key)) => engine.get(key.clone()).map(|value| match value {
+            Some(value) => Response::new(200, json!({ "key": key, "value": value })),
+            None => Response::error(404, &ErrorKind::KeyNotFound.to_string()),
+        }),
+        ("PUT", Some(key)) => match String::from_utf8(request.body.clone()) {
+            Ok(value) => engine.set(key, value).map(|_| Response::no_content()),
+            Err(_) => Err(Error::from(ErrorKind::InvalidUtf8)),
+        },
+        ("DELETE", Some(key)) => engine.remove(key).map(|_| Response::no_content()),
+        ("GET", None) => match prefix_param(request.query.as_deref()) {
+            Some(prefix) => engine.scan_prefix(prefix).map(|pairs| {
+                let pairs: Vec<_> = pairs
+                    .into_iter()
+                    .map(|(key, value)| json!({ "key": key, "value": value }))
+                    .collect();
+                Response::new(200, json!(pairs))
+            }),
+            None => return Response::error(400, "Prefix is not valid percent-encoded UTF-8"),
+        },
+        _ => return Response::error(405, "Method not allowed"),
+    };
+    result.unwrap_or_else(|e| {
+        let status = match e.kind() {
+            ErrorKind::KeyNotFound => 404,
+            ErrorKind::InvalidUtf8 => 400,
+            ErrorKind::PermissionDenied => 403,
+            ErrorKind::KeyTooLarge | ErrorKind::ValueTooLarge => 413,
+            ErrorKind::Unsupported => 501,
+            ErrorKind::NotLeader => 503,
+            _ => 500,
+        };
+        Response::error(status, &e.to_string())
+    })
+}
+
+/// The decoded `prefix` parameter of a query, empty if there is none, or None if it is
+/// malformed.
+fn prefix_param(query: Option<&str>) -> Option<String> {
+    for param in query.unwrap_or_default().split('&') {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        if name == "prefix" {
+            return percent_decode(&value.replace('+', " "));
+        }
+    }
+    Some(String::new())
+}
+
+/// Decode the percent-encoded bytes of `s`, or None if they are malformed or not UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Read the next request, or None if the connection was closed before it.
+///
+/// # Errors
+///
+/// - Io: Failed to read the request, or the connection was closed in the middle of it.
+/// - Serde: The request is malformed.
+/// - ValueTooLarge: The body is larger than `MAX_BODY_LEN`.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>> {
+    let line = match read_line(reader)? {
+        Some(line) if line.is_empty() => return Err(Error::from(ErrorKind::Serde)),
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+            (method, target, version)
+        }
+        _ => return Err(Error::from(ErrorKind::Serde)),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_len = 0;
+    let mut authorization = None;
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| Error::from(ErrorKind::Io))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Error::from(ErrorKind::Serde))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_len = value.parse().map_err(|_| Error::from(ErrorKind::Serde))?;
+        } else if name.eq_ignore_ascii_case("connection") {
+            keep_alive = match value.to_ascii_lowercase().as_str() {
+                "close" => false,
+                "keep-alive" => true,
+                _ => keep_alive,
+            };
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            // chunked bodies are not supported
+            return Err(Error::from(ErrorKind::Serde));
+        }
+    }
+    if content_len > MAX_BODY_LEN {
+        return Err(Error::from(ErrorKind::ValueTooLarge));
+    }
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body).context(ErrorKind::Io)?;
+    Ok(Some(Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        body,
+        keep_alive,
+        authorization,
+    }))
+}
+
+/// Read a line without its line ending, or None at the end of the stream.
+///
+/// # Errors
+///
+/// - Io: Failed to read the line.
+/// - Serde: The line is too long or not UTF-8.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    let len = reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)
+        .context(ErrorKind::Io)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(Error::from(ErrorKind::Serde));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| Error::from(ErrorKind::Serde))
+}
+
+/// Write a response.
+fn write_response<W: Write>(writer: &mut W, response: &Response, keep_alive: bool) -> Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let (body, content_type) = match &response.body {
+        Some(Body::Json(body)) => (
+            serde_json::to_vec(body).context(ErrorKind::Serde)?,
+            Some("application/json"),
+        ),
+        Some(Body::Metrics(text)) => (text.as_bytes().to_vec(), Some("text/plain; version=0.0.4")),
+        None => (Vec::new(), None),
+    };
+    write!(writer, "HTTP/1.1 {} {}\r\n", response.status, reason).context(ErrorKind::Io)?;
+    if let Some(content_type) = content_type {
+        write!(writer, "Content-Type: {}\r\n", content_type).context(ErrorKind::Io)?;
+    }
+    if response.status == 401 {
+        write!(writer, "WWW-Authenticate: Bearer\r\n").context(ErrorKind::Io)?;
+    }
+    if response.status != 204 {
+        write!(writer, "Content-Length: {}\r\n", body.len()).context(ErrorKind::Io)?;
+    }
+    if !keep_alive {
+        write!(writer, "Connection: close\r\n").context(ErrorKind::Io)?;
+    }
+    write!(writer, "\r\n").context(ErrorKind::Io)?;
+    writer.write_all(&body).context(ErrorKind::Io)?;
+    writer.flush().context(ErrorKind::Io)?;
+    Ok(())
+}
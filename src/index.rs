@@ -0,0 +1,525 @@
+#![deny(missing_docs)]
+//! The in-memory log pointer map, from each key to where its live set command is.
+//!
+//! By default it is a `HashMap`. Stores opened with `Options::ordered_index` keep it in a
+//! `BTreeMap` instead, which makes range and prefix queries proportional to the size of
+//! their result instead of the whole map.
+//!
+//! Keys set with a TTL stay in the map after they expire, until compaction drops them.
+//! Queries skip expired keys, given the current time.
+//!
+//! Removed keys are kept apart as tombstones, with the time they were removed, until
+//! compaction drops them according to `Options::tombstone_retention`.
+//!
+//! Every log has a sequence number. The map keeps the earlier versions of each key, with
+//! the sequence numbers they were written at, until compaction drops them too. Snapshots
+//! read the keys as of their sequence number from the versions.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
+
+/// Where the live set command of a key is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct LogPointer {
+    /// Offset of the log in the log file.
+    pub(crate) offset: u64,
+    /// Bytes of the log, or its share of a batch, including earlier logs of an append or
+    /// merge chain and a value kept in the value log.
+    pub(crate) len: u64,
+    /// Expiration time in milliseconds since UNIX epoch, if the key has a TTL.
+    pub(crate) expires_at: Option<u64>,
+    /// Sequence number of the log.
+    pub(crate) sequence: u64,
+}
+
+impl LogPointer {
+    /// Pointer to a set command without TTL.
+    pub(crate) fn new(offset: u64, len: u64, sequence: u64) -> LogPointer {
+        LogPointer {
+            offset,
+            len,
+            expires_at: None,
+            sequence,
+        }
+    }
+
+    /// Whether the key is expired at `now`, in milliseconds since UNIX epoch.
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A removed key.
+#[derive(Clone, Copy)]
+pub(crate) struct Tombstone {
+    /// When the key was removed, in milliseconds since UNIX epoch.
+    removed_at: u64,
+    /// Sequence number of the remove command.
+    pub(crate) sequence: u64,
+}
+
+/// An earlier version of a key: its log pointer, or `None` if it was removed, from the
+/// sequence number it was written at.
+pub(crate) type Version = (u64, Option<LogPointer>);
+
+#[derive(Clone)]
+enum Map {
+    /// Unordered map, fastest for point queries.
+    Hash(HashMap<Vec<u8>, LogPointer>),
+    /// Map ordered by key.
+    Ordered(BTreeMap<Vec<u8>, LogPointer>),
+}
+
+/// Log pointer map.
+#[derive(Clone)]
+pub(crate) struct LogPointerMap {
+    map: Map,
+    /// Number of pointers with an expiration time.
+    expiring: usize,
+    /// Log pointer maps of namespaces, by name. They are not counted by the other methods.
+    namespaces: HashMap<String, LogPointerMap>,
+    /// Removed keys.
+    tombstones: HashMap<Vec<u8>, Tombstone>,
+    /// Earlier versions of keys, oldest first.
+    versions: HashMap<Vec<u8>, Vec<Version>>,
+    /// Greatest sequence number seen.
+    sequence: u64,
+    /// Sequence number of the last compaction. Versions before it are dropped.
+    horizon: u64,
+}
+
+impl LogPointerMap {
+    /// Create an empty map, ordered by key if `ordered` is set.
+    pub(crate) fn new(ordered: bool) -> LogPointerMap {
+        let map = if ordered {
+            Map::Ordered(BTreeMap::new())
+        } else {
+            Map::Hash(HashMap::new())
+        };
+        LogPointerMap {
+            map,
+            expiring: 0,
+            namespaces: HashMap::new(),
+            tombstones: HashMap::new(),
+            versions: HashMap::new(),
+            sequence: 0,
+            horizon: 0,
+        }
+    }
+
+    /// Create an empty map continuing the sequence numbers of `self`, as after removing
+    /// every key and compacting.
+    pub(crate) fn cleared(&self, ordered: bool) -> LogPointerMap {
+        LogPointerMap {
+            sequence: self.sequence,
+            horizon: self.sequence,
+            ..LogPointerMap::new(ordered)
+        }
+    }
+
+    /// Greatest sequence number seen.
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Record that a log of `sequence` was seen.
+    pub(crate) fn observe(&mut self, sequence: u64) {
+        self.sequence = self.sequence.max(sequence);
+    }
+
+    /// Sequence number of the last compaction, before which versions are unknown.
+    pub(crate) fn horizon(&self) -> u64 {
+        self.horizon
+    }
+
+    /// Record that versions before `horizon` were dropped.
+    pub(crate) fn set_horizon(&mut self, horizon: u64) {
+        self.horizon = horizon;
+    }
+
+    /// Drop the earlier versions of every key, as compaction does. Snapshots keep the map
+    /// they were taken of.
+    pub(crate) fn drop_versions(&mut self) {
+        self.versions.clear();
+        self.horizon = self.sequence;
+    }
+
+    /// Get the log pointer of the version of a key at `sequence`, even if it is expired.
+    /// `None` if the key was absent then, or its version was dropped.
+    pub(crate) fn get_at(&self, key: &[u8], sequence: u64) -> Option<LogPointer> {
+        let current = match (self.get(key), self.tombstones.get(key)) {
+            (Some(pointer), _) => Some((pointer.sequence, Some(*pointer))),
+            (None, Some(tombstone)) => Some((tombstone.sequence, None)),
+            (None, None) => None,
+        };
+        match current {
+            Some((since, version)) if since <= sequence => version,
+            _ => self
+                .versions
+                .get(key)?
+                .iter()
+                .rev()
+                .find(|(since, _)| *since <= sequence)
+                .and_then(|(_, version)| *version),
+        }
+    }
+
+    /// Keys and their log pointers at `sequence`, skipping keys expired at `now`, in no
+    /// particular order.
+    pub(crate) fn iter_at(&self, sequence: u64, now: u64) -> Vec<(Vec<u8>, LogPointer)> {
+        let replaced = self.versions.keys().filter(|key| self.get(key).is_none());
+        self.keys()
+            .chain(replaced)
+            .filter_map(|key| Some((key.clone(), self.get_at(key, sequence)?)))
+            .filter(|(_, pointer)| !pointer.is_expired(now))
+            .collect()
+    }
+
+    /// Every known version of a key, oldest first, ending with the current one.
+    pub(crate) fn versions(&self, key: &[u8]) -> Vec<Version> {
+        let mut versions = self.versions.get(key).cloned().unwrap_or_default();
+        match (self.get(key), self.tombstones.get(key)) {
+            (Some(pointer), _) => versions.push((pointer.sequence, Some(*pointer))),
+            (None, Some(tombstone)) => versions.push((tombstone.sequence, None)),
+            (None, None) => {}
+        }
+        versions
+    }
+
+    /// Keep the current version of a key, which is being replaced, as an earlier version.
+    fn keep_version(&mut self, key: &[u8], replaced: Option<LogPointer>) {
+        let version = match (replaced, self.tombstones.remove(key)) {
+            (Some(pointer), _) => (pointer.sequence, Some(pointer)),
+            (None, Some(tombstone)) => (tombstone.sequence, None),
+            (None, None) => return,
+        };
+        match self.versions.get_mut(key) {
+            Some(versions) => versions.push(version),
+            None => {
+                self.versions.insert(key.to_vec(), vec![version]);
+            }
+        }
+    }
+
+    /// Get the log pointer map of a namespace, if any of its keys was ever set.
+    pub(crate) fn namespace(&self, name: &str) -> Option<&LogPointerMap> {
+        self.namespaces.get(name)
+    }
+
+    /// Iterate over namespaces and their log pointer maps.
+    pub(crate) fn namespaces(&self) -> impl Iterator<Item = (&String, &LogPointerMap)> {
+        self.namespaces.iter()
+    }
+
+    /// Get the log pointer map of a namespace for modification, creating it if needed.
+    pub(crate) fn namespace_mut(&mut self, name: &str) -> &mut LogPointerMap {
+        let ordered = matches!(self.map, Map::Ordered(_));
+        self.namespaces
+            .entry(name.to_owned())
+            .or_insert_with(|| LogPointerMap::new(ordered))
+    }
+
+    /// Remove the log pointer map of a namespace, returning it if it was present.
+    pub(crate) fn drop_namespace(&mut self, name: &str) -> Option<LogPointerMap> {
+        self.namespaces.remove(name)
+    }
+
+    /// Iterate over namespaces and their log pointer maps, allowing them to be updated.
+    pub(crate) fn namespaces_mut(&mut self) -> impl Iterator<Item = (&String, &mut LogPointerMap)> {
+        self.namespaces.iter_mut()
+    }
+
+    /// Get the log pointer of a key, even if it is expired.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&LogPointer> {
+        match &self.map {
+            Map::Hash(map) => map.get(key),
+            Map::Ordered(map) => map.get(key),
+        }
+    }
+
+    /// Get the log pointer of a key if it is not expired at `now`.
+    pub(crate) fn get_live(&self, key: &[u8], now: u64) -> Option<LogPointer> {
+        self.get(key)
+            .filter(|pointer| !pointer.is_expired(now))
+            .copied()
+    }
+
+    /// Insert a log pointer, returning the previous one of the key.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, pointer: LogPointer) -> Option<LogPointer> {
+        if pointer.expires_at.is_some() {
+            self.expiring += 1;
+        }
+        self.keep_version(&key, self.get(&key).copied());
+        let replaced = match &mut self.map {
+            Map::Hash(map) => map.insert(key, pointer),
+            Map::Ordered(map) => map.insert(key, pointer),
+        };
+        self.forget(replaced)
+    }
+
+    /// Remove a log pointer, returning it if the key was present.
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<LogPointer> {
+        let removed = match &mut self.map {
+            Map::Hash(map) => map.remove(key),
+            Map::Ordered(map) => map.remove(key),
+        };
+        self.forget(removed)
+    }
+
+    /// Remove a log pointer and keep a tombstone of the key, removed at `now` by the remove
+    /// command of `sequence`. Returns the log pointer if the key was present.
+    pub(crate) fn remove_with_tombstone(
+        &mut self,
+        key: Vec<u8>,
+        now: u64,
+        sequence: u64,
+    ) -> Option<LogPointer> {
+        let removed = self.remove(&key);
+        self.keep_version(&key, removed);
+        let tombstone = Tombstone {
+            removed_at: now,
+            sequence,
+        };
+        self.tombstones.insert(key, tombstone);
+        removed
+    }
+
+    /// Iterate over tombstones.
+    pub(crate) fn tombstones(&self) -> impl Iterator<Item = (&Vec<u8>, &Tombstone)> + '_ {
+        self.tombstones.iter()
+    }
+
+    /// Drop the tombstones of keys removed at or before `time`.
+    pub(crate) fn drop_tombstones(&mut self, time: u64) {
+        self.tombstones
+            .retain(|_, tombstone| tombstone.removed_at > time);
+    }
+
+    /// Update the expiring count for a pointer leaving the map.
+    fn forget(&mut self, pointer: Option<LogPointer>) -> Option<LogPointer> {
+        if let Some(LogPointer {
+            expires_at: Some(_),
+            ..
+        }) = pointer
+        {
+            self.expiring -= 1;
+        }
+        pointer
+    }
+
+    /// Number of keys, including expired ones.
+    pub(crate) fn len(&self) -> usize {
+        match &self.map {
+            Map::Hash(map) => map.len(),
+            Map::Ordered(map) => map.len(),
+        }
+    }
+
+    /// Number of keys not expired at `now`.
+    /// Only has to look at every key if some of them have a TTL.
+    pub(crate) fn live_len(&self, now: u64) -> usize {
+        if self.expiring == 0 {
+            self.len()
+        } else {
+            self.iter_live(now).count()
+        }
+    }
+
+    /// Iterate over keys and their log pointers, sorted by key if the map is ordered.
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = (&Vec<u8>, &LogPointer)> + '_> {
+        match &self.map {
+            Map::Hash(map) => Box::new(map.iter()),
+            Map::Ordered(map) => Box::new(map.iter()),
+        }
+    }
+
+    /// Like `iter`, but skipping keys expired at `now`.
+    pub(crate) fn iter_live(&self, now: u64) -> impl Iterator<Item = (&Vec<u8>, &LogPointer)> + '_ {
+        self.iter()
+            .filter(move |(_, pointer)| !pointer.is_expired(now))
+    }
+
+    /// Iterate over keys, including expired ones.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &Vec<u8>> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Estimated memory used by keys and log pointers, not counting the map itself.
+    /// Namespaces, tombstones and earlier versions are included.
+    pub(crate) fn memory_usage(&self) -> usize {
+        let entry_size = std::mem::size_of::<(Vec<u8>, LogPointer)>();
+        let tombstone_size = std::mem::size_of::<(Vec<u8>, Tombstone)>();
+        let tombstones: usize = self
+            .tombstones
+            .keys()
+            .map(|key| key.capacity() + tombstone_size)
+            .sum();
+        let version_size = std::mem::size_of::<Version>();
+        let versions: usize = self
+            .versions
+            .iter()
+            .map(|(key, versions)| key.capacity() + versions.capacity() * version_size)
+            .sum();
+        let namespaces: usize = self
+            .namespaces
+            .iter()
+            .map(|(name, map)| name.capacity() + map.memory_usage())
+            .sum();
+        namespaces
+            + tombstones
+            + versions
+            + self
+                .keys()
+                .map(|key| key.capacity() + entry_size)
+                .sum::<usize>()
+    }
+
+    /// Iterate over log pointers, allowing their offsets to be updated.
+    pub(crate) fn iter_mut(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = (&Vec<u8>, &mut LogPointer)> + '_> {
+        match &mut self.map {
+            Map::Hash(map) => Box::new(map.iter_mut()),
+            Map::Ordered(map) => Box::new(map.iter_mut()),
+        }
+    }
+
+    /// Remove all keys expired at `now`.
+    pub(crate) fn remove_expired(&mut self, now: u64) {
+        if self.expiring == 0 {
+            return;
+        }
+        let expired = |pointer: &LogPointer| pointer.is_expired(now);
+        match &mut self.map {
+            Map::Hash(map) => map.retain(|_, pointer| !expired(pointer)),
+            Map::Ordered(map) => map.retain(|_, pointer| !expired(pointer)),
+        }
+        self.expiring = self
+            .iter()
+            .filter(|(_, pointer)| pointer.expires_at.is_some())
+            .count();
+    }
+
+    /// Keys expired at `now`, at most `limit` of them.
+    pub(crate) fn expired(&self, now: u64, limit: usize) -> Vec<Vec<u8>> {
+        if self.expiring == 0 {
+            return Vec::new();
+        }
+        self.iter()
+            .filter(|(_, pointer)| pointer.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .take(limit)
+            .collect()
+    }
+
+    /// Offsets of keys within `range` not expired at `now`, sorted by key.
+    pub(crate) fn range(
+        &self,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+        now: u64,
+    ) -> Vec<(Vec<u8>, u64)> {
+        match &self.map {
+            Map::Hash(map) => {
+                let mut pointers: Vec<_> = map
+                    .iter()
+                    .filter(|(key, pointer)| {
+                        RangeBounds::<[u8]>::contains(&range, key.as_slice())
+                            && !pointer.is_expired(now)
+                    })
+                    .map(|(key, pointer)| (key.clone(), pointer.offset))
+                    .collect();
+                pointers.sort_unstable();
+                pointers
+            }
+            Map::Ordered(map) => map
+                .range::<[u8], _>(range)
+                .filter(|(_, pointer)| !pointer.is_expired(now))
+                .map(|(key, pointer)| (key.clone(), pointer.offset))
+                .collect(),
+        }
+    }
+
+    /// Total length of the logs of keys within `range` not expired at `now`.
+    pub(crate) fn range_len(&self, range: (Bound<&[u8]>, Bound<&[u8]>), now: u64) -> u64 {
+        match &self.map {
+            Map::Hash(map) => map
+                .iter()
+                .filter(|(key, pointer)| {
+                    RangeBounds::<[u8]>::contains(&range, key.as_slice())
+                        && !pointer.is_expired(now)
+                })
+                .map(|(_, pointer)| pointer.len)
+                .sum(),
+            Map::Ordered(map) => map
+                .range::<[u8], _>(range)
+                .filter(|(_, pointer)| !pointer.is_expired(now))
+                .map(|(_, pointer)| pointer.len)
+                .sum(),
+        }
+    }
+
+    /// Offsets of keys starting with `prefix` not expired at `now`, sorted by key.
+    pub(crate) fn prefix(&self, prefix: &[u8], now: u64) -> Vec<(Vec<u8>, u64)> {
+        match &self.map {
+            Map::Hash(map) => {
+                let mut pointers: Vec<_> = map
+                    .iter()
+                    .filter(|(key, pointer)| key.starts_with(prefix) && !pointer.is_expired(now))
+                    .map(|(key, pointer)| (key.clone(), pointer.offset))
+                    .collect();
+                pointers.sort_unstable();
+                pointers
+            }
+            Map::Ordered(map) => map
+                .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .filter(|(_, pointer)| !pointer.is_expired(now))
+                .map(|(key, pointer)| (key.clone(), pointer.offset))
+                .collect(),
+        }
+    }
+
+    /// Offsets of the first `limit` keys starting with `prefix` after `after` not expired at
+    /// `now`, sorted by key. Without `after` the first such keys are returned.
+    pub(crate) fn page(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+        now: u64,
+    ) -> Vec<(Vec<u8>, u64)> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        match &self.map {
+            Map::Hash(map) => {
+                let mut pointers: Vec<_> = map
+                    .iter()
+                    .filter(|(key, pointer)| {
+                        RangeBounds::<[u8]>::contains(&(start, Bound::Unbounded), key.as_slice())
+                            && key.starts_with(prefix)
+                            && !pointer.is_expired(now)
+                    })
+                    .collect();
+                // only the keys of the page need sorting
+                if pointers.len() > limit {
+                    pointers.select_nth_unstable_by_key(limit, |(key, _)| *key);
+                    pointers.truncate(limit);
+                }
+                pointers.sort_unstable_by_key(|(key, _)| *key);
+                pointers
+                    .into_iter()
+                    .map(|(key, pointer)| (key.clone(), pointer.offset))
+                    .collect()
+            }
+            Map::Ordered(map) => map
+                .range::<[u8], _>((start, Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .filter(|(_, pointer)| !pointer.is_expired(now))
+                .take(limit)
+                .map(|(key, pointer)| (key.clone(), pointer.offset))
+                .collect(),
+        }
+    }
+}
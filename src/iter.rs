@@ -0,0 +1,56 @@
+#![deny(missing_docs)]
+//! Iterators over the content of a KvStore.
+
+use crate::kvlog::into_string;
+use crate::{KvStore, Result};
+use std::vec;
+
+/// Something values can be read from by log pointer.
+pub(crate) trait ReadValue {
+    /// Read the value of `key` from the log at `offset`.
+    fn read_value(&mut self, key: &[u8], offset: u64) -> Result<Vec<u8>>;
+}
+
+impl ReadValue for KvStore {
+    fn read_value(&mut self, key: &[u8], offset: u64) -> Result<Vec<u8>> {
+        KvStore::read_value(self, key, offset)
+    }
+}
+
+/// Iterator over key-value pairs of a `KvStore`, created by `KvStore::iter`,
+/// `KvStore::scan_prefix`, `KvStore::range` or `Snapshot::iter`.
+///
+/// It walks a list of log pointers taken from the log pointer map and reads each value
+/// only when it is reached. A key or value that is not valid UTF-8 is yielded as an
+/// InvalidUtf8 error.
+pub struct Iter<'a> {
+    store: &'a mut dyn ReadValue,
+    pointers: vec::IntoIter<(Vec<u8>, u64)>,
+}
+
+impl<'a> Iter<'a> {
+    /// Create an iterator reading the given log pointers from `store`.
+    pub(crate) fn new(store: &'a mut dyn ReadValue, pointers: Vec<(Vec<u8>, u64)>) -> Iter<'a> {
+        Iter {
+            store,
+            pointers: pointers.into_iter(),
+        }
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, offset) = self.pointers.next()?;
+        Some(
+            self.store
+                .read_value(&key, offset)
+                .and_then(|value| Ok((into_string(key)?, into_string(value)?))),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.pointers.size_hint()
+    }
+}
@@ -4,66 +4,312 @@
 //! I used bincode ser/de format. It is simple, minimizes the space used by
 //! each log by only storing what is necessary (no field names), and content
 //! of key/value is human-readable to certain extent.
+//!
+//! Keys and values are stored as raw bytes. bincode encodes a `Vec<u8>` exactly like a
+//! `String`, so logs written when keys and values were strings are still readable.
 
-use crate::error::ErrorKind;
-use crate::Result;
+use crate::codec::RecordFormat;
+use crate::compression::Compression;
+use crate::encryption::NONCE_SIZE;
+use crate::error::{Error, ErrorKind};
+use crate::merge::MergeOperator;
+use crate::{Result, CORRUPTION_CHECK};
 use failure::ResultExt;
 use serde::{Deserialize, Serialize};
 use std::io;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 /// Definition of KvLog.
 pub enum KvLog {
     /// set command, stores key and value
-    Set(String, String),
+    Set(Vec<u8>, Vec<u8>),
     /// remove command, stores key
-    Rm(String),
+    Rm(Vec<u8>),
+    /// batch of set and remove commands, applied all together or not at all
+    Batch(Vec<KvLog>),
+    /// set command with TTL, stores key, value and expiration time in milliseconds since UNIX epoch
+    SetEx(Vec<u8>, Vec<u8>, u64),
+    /// append command, stores key, suffix and offset of the previous log of the key,
+    /// which is a set command or another append command
+    Append(Vec<u8>, Vec<u8>, u64),
+    /// merge command, stores key, operand and offset of the previous log of the key
+    /// if it was present
+    Merge(Vec<u8>, Vec<u8>, Option<u64>),
+    /// another log compressed with a codec, only seen on disk as it is decompressed when read
+    Compressed(Compression, Vec<u8>),
+    /// another log encrypted under a nonce, only seen on disk as it is decrypted when read
+    Encrypted([u8; NONCE_SIZE], Vec<u8>),
+    /// set or remove command in a namespace, stores the namespace and the command
+    Namespaced(String, Box<KvLog>),
+    /// drop namespace command, stores the namespace whose keys are all removed
+    DropNamespace(String),
+    /// set command with the value in the value log, stores key, offset and length of the
+    /// value there and expiration time if the key has a TTL
+    SetSeparated(Vec<u8>, u64, u64, Option<u64>),
+    /// another log stamped with its sequence number
+    Sequenced(u64, Box<KvLog>),
 }
 
 impl KvLog {
     /// Creating a new KvLog::Set
-    pub fn new_set(key: String, value: String) -> KvLog {
+    pub fn new_set(key: Vec<u8>, value: Vec<u8>) -> KvLog {
         KvLog::Set(key, value)
     }
 
+    /// Creating a new KvLog::SetEx
+    pub fn new_set_ex(key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> KvLog {
+        KvLog::SetEx(key, value, expires_at)
+    }
+
+    /// Creating a new KvLog::Append
+    pub fn new_append(key: Vec<u8>, suffix: Vec<u8>, prev: u64) -> KvLog {
+        KvLog::Append(key, suffix, prev)
+    }
+
+    /// Creating a new KvLog::Merge
+    pub fn new_merge(key: Vec<u8>, operand: Vec<u8>, prev: Option<u64>) -> KvLog {
+        KvLog::Merge(key, operand, prev)
+    }
+
+    /// Creating a new KvLog::Namespaced
+    pub fn new_namespaced(namespace: String, kvlog: KvLog) -> KvLog {
+        KvLog::Namespaced(namespace, Box::new(kvlog))
+    }
+
+    /// Creating a new KvLog::Sequenced
+    pub fn new_sequenced(sequence: u64, kvlog: KvLog) -> KvLog {
+        KvLog::Sequenced(sequence, Box::new(kvlog))
+    }
+
     /// Creating a new KvLog::Rm
-    pub fn new_rm(key: String) -> KvLog {
+    pub fn new_rm(key: Vec<u8>) -> KvLog {
         KvLog::Rm(key)
     }
 
-    /// Serialize to writer using bincode format
+    /// Serialize to writer using bincode format, returning the number of bytes written.
+    ///
+    /// The log is handed to the writer in a single `write_all`, so a `BufWriter` never
+    /// splits it between the file and its buffer.
     ///
     /// # Errors
     ///
     /// Serde - Serialization of a `KvLog` failed.
+    /// Io - Writing to the writer failed.
     ///
-    pub fn serialize_to_writer<W>(&self, writer: W) -> Result<()>
+    pub fn serialize_to_writer<W>(&self, mut writer: W) -> Result<u64>
     where
         W: io::Write,
     {
-        bincode::serialize_into(writer, self).context(ErrorKind::Serde)?;
-        Ok(())
+        let bytes = bincode::serialize(self).context(ErrorKind::Serde)?;
+        writer.write_all(&bytes).context(ErrorKind::Io)?;
+        Ok(bytes.len() as u64)
     }
 
-    /// Deserialize from reader using bincode format
+    /// Deserialize from reader using bincode format. A compressed log is decompressed.
     ///
     /// # Errors
     ///
     /// Serde - Deserialization of a `KvLog` failed.
+    /// CompressionUnavailable - The log is compressed with a codec not enabled in this build.
+    /// Corruption - Decompression of the log failed.
+    /// BadEncryptionKey - The log is encrypted.
     ///
     pub fn deserialize_from_reader<R>(reader: R) -> Result<KvLog>
     where
         R: io::Read,
     {
-        let kvstore = bincode::deserialize_from(reader).context(ErrorKind::Serde)?;
-        Ok(kvstore)
+        RecordFormat::default().read(reader)
     }
 
     /// Turn KvLog into its key.
-    pub fn into_key(self) -> String {
+    ///
+    /// # Panics
+    ///
+    /// If the KvLog is a batch, compressed, encrypted, namespaced, drops a namespace or is
+    /// sequenced, which has no single key.
+    pub fn into_key(self) -> Vec<u8> {
         match self {
             KvLog::Set(k, _) => k,
+            KvLog::SetEx(k, _, _) => k,
+            KvLog::SetSeparated(k, _, _, _) => k,
+            KvLog::Append(k, _, _) => k,
+            KvLog::Merge(k, _, _) => k,
             KvLog::Rm(k) => k,
+            KvLog::Batch(_) => panic!("a batch has no single key"),
+            KvLog::Compressed(..) => panic!("a compressed log has no single key"),
+            KvLog::Encrypted(..) => panic!("an encrypted log has no single key"),
+            KvLog::Namespaced(..) | KvLog::DropNamespace(_) => {
+                panic!("a log of a namespace has no single key of the store")
+            }
+            KvLog::Sequenced(..) => panic!("a sequenced log has to be unwrapped first"),
+        }
+    }
+
+    /// Take the live log of `key` out of a log that a log pointer refers to.
+    ///
+    /// The log is either the set, append or merge command itself or a batch containing it,
+    /// in which case the last set of the key in the batch is the live one.
+    ///
+    /// # Errors
+    ///
+    /// Corruption - The log has no set, append or merge command of `key`.
+    ///
+    pub(crate) fn into_live_set(self, key: &[u8]) -> Result<KvLog> {
+        let kvlog = match self.into_unsequenced() {
+            KvLog::Batch(logs) => logs
+                .into_iter()
+                .rev()
+                .find(|log| log.is_set() && log.key() == Some(key))
+                .ok_or_else(|| Error::from(ErrorKind::Corruption))?,
+            kvlog => kvlog,
+        };
+        let has_value = kvlog.is_set() || matches!(kvlog, KvLog::Append(..) | KvLog::Merge(..));
+        if !has_value || (CORRUPTION_CHECK && kvlog.key() != Some(key)) {
+            return Err(Error::from(ErrorKind::Corruption));
+        }
+        Ok(kvlog)
+    }
+
+    /// Take the command out of a log of `namespace`, or the commands of `namespace` out of
+    /// a batch.
+    ///
+    /// # Errors
+    ///
+    /// Corruption - The log is not a command of `namespace`.
+    ///
+    pub(crate) fn into_namespaced(self, namespace: &str) -> Result<KvLog> {
+        match self.into_unsequenced() {
+            KvLog::Namespaced(name, kvlog) if name == namespace => Ok(*kvlog),
+            KvLog::Batch(logs) => Ok(KvLog::Batch(
+                logs.into_iter()
+                    .filter_map(|kvlog| match kvlog {
+                        KvLog::Namespaced(name, kvlog) if name == namespace => Some(*kvlog),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            _ => Err(Error::from(ErrorKind::Corruption)),
+        }
+    }
+
+    /// Take the log out of its sequence number stamp, if it has one.
+    pub fn into_unsequenced(self) -> KvLog {
+        match self {
+            KvLog::Sequenced(_, kvlog) => *kvlog,
+            kvlog => kvlog,
+        }
+    }
+
+    /// The set and remove commands in the log: the commands of a batch, or the log itself.
+    pub(crate) fn commands(&self) -> &[KvLog] {
+        match self {
+            KvLog::Batch(logs) => logs,
+            kvlog => std::slice::from_ref(kvlog),
         }
     }
+
+    /// Whether the log is a set command, with or without TTL or the value in the value log.
+    pub(crate) fn is_set(&self) -> bool {
+        matches!(
+            self,
+            KvLog::Set(..) | KvLog::SetEx(..) | KvLog::SetSeparated(..)
+        )
+    }
+
+    /// Key of the log, if it has a single one.
+    pub(crate) fn key(&self) -> Option<&[u8]> {
+        match self {
+            KvLog::Set(k, _)
+            | KvLog::SetEx(k, _, _)
+            | KvLog::SetSeparated(k, _, _, _)
+            | KvLog::Append(k, _, _)
+            | KvLog::Merge(k, _, _)
+            | KvLog::Rm(k) => Some(k),
+            KvLog::Batch(_)
+            | KvLog::Compressed(..)
+            | KvLog::Encrypted(..)
+            | KvLog::Namespaced(..)
+            | KvLog::DropNamespace(_)
+            | KvLog::Sequenced(..) => None,
+        }
+    }
+
+    /// Turn a set command into its value. A value in the value log has to be resolved by
+    /// `ValueLog::resolve` first.
+    pub(crate) fn into_value(self) -> Option<Vec<u8>> {
+        match self {
+            KvLog::Set(_, v) | KvLog::SetEx(_, v, _) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Read the value of `key` from the log at `offset`, following its chain of append and merge
+/// commands back to where it starts, then applying them in order.
+///
+/// # Errors
+///
+/// Corruption - The chain is broken.
+/// NoMergeOperator - The chain has a merge command but no merge operator is given.
+/// Others - Same as `read_at`.
+///
+pub(crate) fn read_value_chain<F>(
+    key: &[u8],
+    mut offset: u64,
+    merge_operator: Option<&MergeOperator>,
+    mut read_at: F,
+) -> Result<Vec<u8>>
+where
+    F: FnMut(u64) -> Result<KvLog>,
+{
+    // walk back to the start of the chain
+    let mut chain = Vec::new();
+    let mut value = loop {
+        let kvlog = read_at(offset)?.into_live_set(key)?;
+        let prev = match kvlog {
+            KvLog::Append(_, _, prev) | KvLog::Merge(_, _, Some(prev)) => prev,
+            KvLog::Merge(_, _, None) => {
+                chain.push(kvlog);
+                break None;
+            }
+            kvlog => {
+                break Some(
+                    kvlog
+                        .into_value()
+                        .ok_or_else(|| Error::from(ErrorKind::Corruption))?,
+                )
+            }
+        };
+        // a log can only refer to an earlier one, which also rules out cycles
+        if prev >= offset {
+            return Err(Error::from(ErrorKind::Corruption));
+        }
+        chain.push(kvlog);
+        offset = prev;
+    };
+
+    for kvlog in chain.into_iter().rev() {
+        value = Some(match kvlog {
+            KvLog::Append(_, suffix, _) => {
+                let mut value = value.unwrap_or_default();
+                value.extend(suffix);
+                value
+            }
+            KvLog::Merge(_, operand, _) => merge_operator
+                .ok_or_else(|| Error::from(ErrorKind::NoMergeOperator))?
+                .merge(key, value.as_deref(), &operand),
+            _ => return Err(Error::from(ErrorKind::Corruption)),
+        });
+    }
+    value.ok_or_else(|| Error::from(ErrorKind::Corruption))
+}
+
+/// Turn a key or value into a string for the string API.
+///
+/// # Errors
+///
+/// InvalidUtf8 - The key or value was set as bytes that are not valid UTF-8.
+///
+pub(crate) fn into_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| Error::from(ErrorKind::InvalidUtf8))
 }
@@ -22,25 +22,193 @@
 //! assert_eq!(kv.get("key1".to_owned()).unwrap(), None);
 //! ```
 
+mod acl;
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_server;
+mod audit;
+mod backup;
+mod batch;
+mod bloom;
+mod builder;
+mod cache;
+mod client;
+mod client_pool;
+mod codec;
+mod compression;
+mod encryption;
+mod engine;
 mod error;
+mod export;
+mod format;
+mod group_commit;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod history;
+mod hook;
+mod http;
+mod index;
+mod iter;
 mod kvlog;
+mod log_reader;
+mod manifest;
+mod mem_engine;
+mod memcached;
+mod merge;
+mod metrics;
+mod namespace;
+mod near_cache;
+mod options;
+mod protocol;
+#[cfg(feature = "raft")]
+mod raft;
+mod repair;
+mod replicated_client;
+mod replication;
+mod resp;
+mod retry;
+mod scan;
+mod secondary;
+mod server;
+mod sharded;
+mod sharded_client;
+mod shared;
+#[cfg(feature = "sled")]
+mod sled_engine;
+mod slow_log;
+mod snapshot;
+mod stats;
+mod stream;
+mod tail;
+mod thread_pool;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod transaction;
+mod value_log;
+mod verify;
+mod watch;
 
+pub use crate::acl::{Acl, User};
+#[cfg(feature = "async")]
+pub use crate::async_client::AsyncKvsClient;
+#[cfg(feature = "async")]
+pub use crate::async_server::AsyncKvsServer;
+pub use crate::audit::{AuditLog, AuditRecord};
+pub use crate::backup::{BackupCursor, BackupJob, RestoreOptions};
+pub use crate::batch::WriteBatch;
+use crate::bloom::BloomFilter;
+pub use crate::builder::KvStoreBuilder;
+use crate::cache::ValueCache;
+pub use crate::client::{KvsClient, Subscription};
+pub use crate::client_pool::{KvsClientPool, PooledClient};
+use crate::codec::RecordFormat;
+pub use crate::codec::{BincodeCodec, JsonCodec, LogCodec, MessagePackCodec};
+pub use crate::compression::Compression;
+pub use crate::encryption::{Encryption, KeyProvider};
+pub use crate::engine::KvsEngine;
 use crate::error::Error;
 pub use crate::error::ErrorKind;
+pub use crate::export::Format;
+use crate::format::{FORMAT_VERSION, HEADERLESS_VERSION, HEADER_LEN};
+use crate::group_commit::Flusher;
+pub use crate::group_commit::GroupCommit;
+pub use crate::history::KeyVersion;
+pub use crate::hook::WriteHook;
+use crate::index::{LogPointer, LogPointerMap};
+pub use crate::iter::Iter;
 pub use crate::kvlog::KvLog;
-use failure::ResultExt;
-use std::collections::HashMap;
+use crate::kvlog::{into_string, read_value_chain};
+use crate::log_reader::LogReader;
+use crate::manifest::{CompactionState, StoreManifest};
+pub use crate::mem_engine::MemKvsEngine;
+pub use crate::merge::MergeOperator;
+pub use crate::namespace::Namespace;
+pub use crate::near_cache::NearCache;
+pub use crate::options::{Durability, Options, RuntimeOptions, TombstoneRetention};
+pub use crate::protocol::{AdminCommand, Request, Response, PROTOCOL_VERSION};
+#[cfg(feature = "raft")]
+pub use crate::raft::RaftNode;
+pub use crate::repair::RepairReport;
+pub use crate::replicated_client::{ReadBalance, ReplicatedKvsClient};
+pub use crate::replication::{Changes, Replica, ReplicationCursor};
+use crate::replication::{CopyCursor, FullCopy, NextChanges};
+pub use crate::retry::RetryPolicy;
+pub use crate::scan::{ScanCursor, ScanPage};
+use crate::secondary::Indexes;
+pub use crate::secondary::SecondaryIndex;
+pub use crate::server::{EngineHandle, KvsServer, Protocol, ShutdownHandle};
+pub use crate::sharded::ShardedKvsEngine;
+pub use crate::sharded_client::{MovedKeys, ShardedKvsClient};
+pub use crate::shared::SharedKvStore;
+#[cfg(feature = "sled")]
+pub use crate::sled_engine::SledKvsEngine;
+pub use crate::slow_log::SlowLog;
+pub use crate::snapshot::Snapshot;
+pub use crate::stats::{DiskUsage, Stats};
+pub use crate::tail::Tail;
+#[cfg(feature = "rayon")]
+pub use crate::thread_pool::RayonThreadPool;
+pub use crate::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use crate::transaction::Transaction;
+use crate::value_log::ValueLog;
+pub use crate::verify::{VerifyIssue, VerifyReport};
+use crate::watch::Watchers;
+pub use crate::watch::{ChangeEvent, ChangeOp, Watch};
+use failure::{Fail, ResultExt};
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::fs::*;
-use std::io::{BufRead, BufReader, BufWriter, Cursor, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Since there is only 1 log file right now, its name is hardcoded.
 const LOG_FILE_NAME: &str = "0.bin";
+/// Bloom filter of the keys in the log file.
+const BLOOM_FILE_NAME: &str = "0.bloom";
+/// Large values kept apart from the log file, see `Options::value_log_threshold`.
+const VALUE_LOG_FILE_NAME: &str = "0.vlog";
 /// Used by compaction
 const TEMP_LOG_FILE_NAME: &str = "compact.tmp";
-/// Write buffer size is 16 KiB. This allows for lower writing frequency.
-/// (If I set it higher the compaction test will falsely pass)
-const WRITE_BUFFER_SIZE: usize = 16 * 1024;
+/// Value log written by compaction, replacing the value log once the log file is replaced.
+const TEMP_COMPACT_VALUE_LOG_FILE_NAME: &str = "compact.vlog.tmp";
+/// Value log being restored, see `KvStore::restore`.
+const TEMP_VALUE_LOG_FILE_NAME: &str = "restore.vlog.tmp";
+/// Locked while the store is open for writing.
+const LOCK_FILE_NAME: &str = "LOCK";
+/// Layout of the store, see `StoreManifest`.
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+/// Used when replacing the manifest.
+const TEMP_MANIFEST_FILE_NAME: &str = "MANIFEST.tmp";
+/// All files a store may create in its directory.
+const STORE_FILE_NAMES: [&str; 9] = [
+    LOG_FILE_NAME,
+    BLOOM_FILE_NAME,
+    VALUE_LOG_FILE_NAME,
+    TEMP_LOG_FILE_NAME,
+    TEMP_COMPACT_VALUE_LOG_FILE_NAME,
+    TEMP_VALUE_LOG_FILE_NAME,
+    MANIFEST_FILE_NAME,
+    TEMP_MANIFEST_FILE_NAME,
+    LOCK_FILE_NAME,
+];
+/// Default capacity of the write buffer and of the read-ahead buffer, the same as the
+/// buffers of `BufWriter` and `BufReader`. Records are small, so this holds many of them,
+/// while the log file on disk still grows every few hundred records.
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+/// Capacity of the write buffer while importing, see `KvStore::import`.
+const IMPORT_BUFFER_SIZE: usize = 1024 * 1024;
+/// Number of pairs imported in one go, see `KvStore::import`.
+const IMPORT_BATCH_LEN: usize = 4096;
+/// Records, or keys of a full copy, returned at most by one call to `KvStore::changes`.
+const REPLICATION_BATCH_LEN: usize = 1024;
+/// Full copies for replicas kept in progress at most, see `KvStore::changes`.
+const MAX_FULL_COPIES: usize = 4;
 /// Compact file when there are enough redundant records.
 const COMPACT_REDUNDANT_THRESHOLD: usize = 1024;
 /// Whether to enable corruption check
@@ -48,7 +216,6 @@ const CORRUPTION_CHECK: bool = false;
 
 /// Result type of KvStore
 pub type Result<T> = std::result::Result<T, Error>;
-type LogPointerMap = HashMap<String, u64>;
 
 /// A KvStore stores key-value pairs in log structure on disk.
 ///
@@ -73,52 +240,3267 @@ type LogPointerMap = HashMap<String, u64>;
 pub struct KvStore {
     /// Path to the log file.
     log_file_path: PathBuf,
+    /// Path to the persisted bloom filter of the log file.
+    bloom_file_path: PathBuf,
     /// Reader that can be reused by get.
-    reader: BufReader<File>,
-    /// Writer in append mode for adding new log to disk.
+    reader: LogReader,
+    /// Writer in append mode for adding new log to disk, absent if the store is read-only.
     /// The cursor should always be at the end of the log file
-    append_writer: BufWriter<File>,
-    /// Log pointer map
-    log_pointer: LogPointerMap,
+    append_writer: Option<BufWriter<File>>,
+    /// Log pointer map, shared with the snapshots taken since the log file was last rewritten.
+    log_pointer: Arc<RwLock<LogPointerMap>>,
+    /// Cache of recently read values.
+    cache: ValueCache,
+    /// Bloom filter of keys ever set in the log file, persisted on drop.
+    bloom: BloomFilter,
     /// Redundant record number, used for compaction.
     redundant_count: usize,
+    /// Number of compactions since the store was opened.
+    compactions: u64,
+    /// Number of times the log file was replaced since the store was opened, by compaction
+    /// or otherwise.
+    log_generation: u64,
+    /// Whether replicas got changes since the store was opened, see `changes`.
+    replicated: bool,
+    /// Log file replaced by the last compaction with its value log, kept open for replicas
+    /// to read its end.
+    previous_log: Option<(File, ValueLog)>,
+    /// Full copies sent to replicas a page at a time by id, the oldest first.
+    full_copies: Vec<(u64, Arc<Mutex<FullCopy>>)>,
+    /// Id of the next full copy.
+    next_copy_id: u64,
+    /// Whether an import is in progress, which defers making records durable and compaction
+    /// until it ends.
+    importing: bool,
+    /// Logs of an import for write hooks, waiting for the records to be committed.
+    pending_hooks: Vec<KvLog>,
+    /// Background flusher, present in group commit mode.
+    flusher: Option<Flusher>,
+    /// Options the store was opened with.
+    options: Options,
+    /// Format of the records in the log file.
+    format: RecordFormat,
+    /// Value log holding values too large for the log file.
+    value_log: ValueLog,
+    /// Watches of changes of keys.
+    watchers: Watchers,
+    /// Secondary indexes of the keys.
+    indexes: Indexes,
+    /// Lock file of the store, which is unlocked when it is closed after everything else.
+    /// Absent if the store is read-only.
+    _lock_file: Option<File>,
 }
 
 impl Drop for KvStore {
-    /// To make sure buffer is flushed on drop.
+    /// To make sure buffer is flushed and bloom filter is persisted on drop.
     fn drop(&mut self) {
-        match self.append_writer.flush() {
+        let append_writer = match &mut self.append_writer {
+            Some(append_writer) => append_writer,
+            None => return,
+        };
+        match append_writer.flush() {
             Ok(_) => {}
-            Err(e) => eprintln!("An error occurred when flushing buffer: {}", e.to_string()),
+            Err(e) => eprintln!("An error occurred when flushing buffer: {}", e),
+        }
+        let saved = log_stamp(&self.log_file_path).and_then(|(log_len, checksum)| {
+            self.bloom.save(&self.bloom_file_path, log_len, checksum)
+        });
+        if let Err(e) = saved {
+            eprintln!("An error occurred when saving bloom filter: {}", e);
         }
     }
 }
 
 /// Detect if we have not reached eof
 fn has_more<R: BufRead>(mut reader: R) -> Result<bool> {
-    Ok(reader.fill_buf().context(ErrorKind::Io)?.len() > 0)
+    Ok(!reader.fill_buf().context(ErrorKind::Io)?.is_empty())
 }
 
 /// Get current reader position
 fn position<R: Seek>(mut reader: R) -> Result<u64> {
-    Ok(reader.seek(SeekFrom::Current(0)).context(ErrorKind::Io)?)
+    Ok(reader.stream_position().context(ErrorKind::Io)?)
+}
+
+/// Get file length in bytes
+fn file_len(path: &PathBuf) -> Result<u64> {
+    Ok(metadata(path).context(ErrorKind::Io)?.len())
+}
+
+/// The length of a log file with the CRC-32 of its tail, which stamp its bloom filter.
+fn log_stamp(path: &PathBuf) -> Result<(u64, u32)> {
+    let mut file = File::open(path).context(ErrorKind::Io)?;
+    let len = file.metadata().context(ErrorKind::Io)?.len();
+    let checksum = backup::tail_checksum(&mut file, len).context(ErrorKind::Io)?;
+    Ok((len, checksum))
+}
+
+/// Apply the log at `offset`, `len` bytes long, to the log pointer map.
+/// A log without a sequence number gets the one after the greatest seen so far.
+/// Returns the number of records it made redundant.
+fn index_log(log_pointer: &mut LogPointerMap, kvlog: KvLog, offset: u64, len: u64) -> usize {
+    let (sequence, kvlog) = match kvlog {
+        // an empty batch marks the start of a compacted log file
+        KvLog::Sequenced(sequence, kvlog) if matches!(&*kvlog, KvLog::Batch(logs) if logs.is_empty()) =>
+        {
+            log_pointer.observe(sequence);
+            log_pointer.set_horizon(sequence);
+            return 0;
+        }
+        KvLog::Sequenced(sequence, kvlog) => (sequence, *kvlog),
+        kvlog => (log_pointer.sequence() + 1, kvlog),
+    };
+    log_pointer.observe(sequence);
+    index_command(log_pointer, kvlog, offset, len, sequence)
+}
+
+/// Apply the commands of the log at `offset`, `len` bytes long, with `sequence` to the log
+/// pointer map. Returns the number of records they made redundant.
+fn index_command(
+    log_pointer: &mut LogPointerMap,
+    kvlog: KvLog,
+    offset: u64,
+    len: u64,
+    sequence: u64,
+) -> usize {
+    let replaced = match kvlog {
+        KvLog::Set(key, _) => log_pointer.insert(key, LogPointer::new(offset, len, sequence)),
+        KvLog::SetEx(key, _, expires_at) => log_pointer.insert(
+            key,
+            LogPointer {
+                offset,
+                len,
+                expires_at: Some(expires_at),
+                sequence,
+            },
+        ),
+        KvLog::Append(key, _, _) | KvLog::Merge(key, _, Some(_)) => {
+            // the key keeps its TTL, and its value spans the whole chain
+            let previous = log_pointer.get(&key).copied();
+            let pointer = LogPointer {
+                offset,
+                len: previous.map_or(0, |pointer| pointer.len) + len,
+                expires_at: previous.and_then(|pointer| pointer.expires_at),
+                sequence,
+            };
+            log_pointer.insert(key, pointer)
+        }
+        KvLog::SetSeparated(key, _, value_len, expires_at) => {
+            let pointer = LogPointer {
+                offset,
+                len: len + value_len,
+                expires_at,
+                sequence,
+            };
+            log_pointer.insert(key, pointer)
+        }
+        KvLog::Merge(key, _, None) => {
+            log_pointer.insert(key, LogPointer::new(offset, len, sequence))
+        }
+        KvLog::Rm(key) => log_pointer.remove_with_tombstone(key, now_millis(), sequence),
+        // never indexed, as logs are decoded when read
+        KvLog::Compressed(..) | KvLog::Encrypted(..) => None,
+        KvLog::Namespaced(namespace, kvlog) => {
+            let log_pointer = log_pointer.namespace_mut(&namespace);
+            return index_command(log_pointer, *kvlog, offset, len, sequence);
+        }
+        KvLog::DropNamespace(namespace) => {
+            return log_pointer
+                .drop_namespace(&namespace)
+                .map_or(0, |dropped| dropped.len());
+        }
+        KvLog::Batch(logs) => {
+            // each command gets an equal share of the batch
+            let len = len / logs.len().max(1) as u64;
+            return logs
+                .into_iter()
+                .map(|log| index_command(log_pointer, log, offset, len, sequence))
+                .sum();
+        }
+        KvLog::Sequenced(_, kvlog) => {
+            return index_command(log_pointer, *kvlog, offset, len, sequence)
+        }
+    };
+    replaced.map_or(0, |_| 1)
+}
+
+/// Check the keys and values written by a log against the size limits of `options`.
+///
+/// # Errors
+///
+/// - KeyTooLarge: A key is larger than `Options::max_key_size`.
+/// - ValueTooLarge: A value is larger than `Options::max_value_size`.
+fn check_size(kvlog: &KvLog, options: &Options) -> Result<()> {
+    let (key, value) = match kvlog {
+        KvLog::Set(key, value)
+        | KvLog::SetEx(key, value, _)
+        | KvLog::Append(key, value, _)
+        | KvLog::Merge(key, value, _) => (key, value.as_slice()),
+        // the value was checked when it was written, this is a copy of it
+        KvLog::SetSeparated(key, ..) => (key, &[][..]),
+        KvLog::Namespaced(_, kvlog) => return check_size(kvlog, options),
+        KvLog::Batch(logs) => {
+            return logs.iter().try_for_each(|kvlog| check_size(kvlog, options));
+        }
+        _ => return Ok(()),
+    };
+    if options.max_key_size.is_some_and(|max| key.len() > max) {
+        return Err(Error::from(ErrorKind::KeyTooLarge));
+    }
+    if options.max_value_size.is_some_and(|max| value.len() > max) {
+        return Err(Error::from(ErrorKind::ValueTooLarge));
+    }
+    Ok(())
+}
+
+/// Whether `cursor` is at a record of `log_file`, `log_len` bytes long.
+///
+/// # Errors
+///
+/// - Io: Failed to read the log file.
+fn cursor_matches(log_file: &mut File, log_len: u64, cursor: &ReplicationCursor) -> Result<bool> {
+    Ok(cursor.offset <= log_len
+        && backup::tail_checksum(log_file, cursor.offset).context(ErrorKind::Io)?
+            == cursor.tail_checksum)
+}
+
+/// The cursor of a replica that got the records of `log_file` up to `offset`, the last of
+/// them with `sequence`.
+///
+/// # Errors
+///
+/// - Io: Failed to read the log file.
+fn replication_cursor(
+    log_file: &mut File,
+    offset: u64,
+    sequence: u64,
+) -> Result<ReplicationCursor> {
+    Ok(ReplicationCursor {
+        offset,
+        tail_checksum: backup::tail_checksum(log_file, offset).context(ErrorKind::Io)?,
+        sequence,
+        copy: None,
+    })
+}
+
+/// Current time in milliseconds since UNIX epoch, the unit of expiration times.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Name of a file of the store `name` in its directory, see `KvStore::open_named`.
+/// The files of the default store keep their names, those of a named store start with its
+/// name instead.
+fn store_file_name(name: Option<&str>, file_name: &str) -> String {
+    match name {
+        None => file_name.to_owned(),
+        Some(name) => format!("{}.{}", name, file_name.trim_start_matches("0.")),
+    }
+}
+
+/// Check that `name` can prefix the files of a store.
+///
+/// # Errors
+///
+/// - InvalidStoreName: The name is empty, is "0", or has other characters than ASCII
+///   letters, digits, `-` and `_`.
+fn check_store_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name != "0"
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::from(ErrorKind::InvalidStoreName))
+    }
+}
+
+/// Replace the manifest of the store `name` in `dir_path`, recording that its log file is
+/// being rewritten to the file `rewrite`, or that it is not.
+///
+/// # Errors
+///
+/// Same as `StoreManifest::store`.
+fn write_manifest(dir_path: &Path, name: Option<&str>, rewrite: Option<&str>) -> Result<()> {
+    let mut manifest = StoreManifest::new(store_file_name(name, LOG_FILE_NAME));
+    manifest.compaction = rewrite.map(|temp_file| CompactionState {
+        temp_file: temp_file.to_owned(),
+    });
+    manifest.store(
+        &dir_path.join(store_file_name(name, MANIFEST_FILE_NAME)),
+        &dir_path.join(store_file_name(name, TEMP_MANIFEST_FILE_NAME)),
+    )
+}
+
+/// Read the manifest of the store `name` in `dir_path` and finish what a crash left
+/// undone, writing the manifest if there is none yet. Read-only stores are left as they are.
+///
+/// # Errors
+///
+/// - UnsupportedVersion: The manifest names another format version or other segments.
+/// - Corruption: The manifest file is malformed.
+/// - Io: Failed to read the manifest, or to remove or rename the files of an interrupted
+///   rewrite.
+/// - Others: Same as `write_manifest`.
+fn recover_manifest(dir_path: &Path, name: Option<&str>, read_only: bool) -> Result<()> {
+    let manifest = StoreManifest::load(&dir_path.join(store_file_name(name, MANIFEST_FILE_NAME)))?;
+    let log_file_name = store_file_name(name, LOG_FILE_NAME);
+    if let Some(manifest) = &manifest {
+        if !format::is_readable(manifest.format_version) || manifest.segments != [log_file_name] {
+            return Err(Error::from(ErrorKind::UnsupportedVersion));
+        }
+    }
+    if read_only {
+        return Ok(());
+    }
+    match manifest {
+        Some(StoreManifest {
+            compaction: None,
+            format_version,
+            ..
+        }) if format_version == FORMAT_VERSION => Ok(()),
+        Some(StoreManifest {
+            compaction: Some(compaction),
+            ..
+        }) => {
+            // the log file was only replaced if the rewrite finished, in which case the value
+            // log written with it may still have to replace the old one
+            let temp_value_log_path =
+                dir_path.join(store_file_name(name, TEMP_COMPACT_VALUE_LOG_FILE_NAME));
+            let replaced = match remove_file(dir_path.join(&compaction.temp_file)) {
+                Ok(()) => false,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+                Err(e) => return Err(e.context(ErrorKind::Io).into()),
+            };
+            if replaced && temp_value_log_path.exists() {
+                let value_log_path = dir_path.join(store_file_name(name, VALUE_LOG_FILE_NAME));
+                rename(&temp_value_log_path, value_log_path).context(ErrorKind::Io)?;
+            } else if temp_value_log_path.exists() {
+                remove_file(&temp_value_log_path).context(ErrorKind::Io)?;
+            }
+            write_manifest(dir_path, name, None)
+        }
+        // the log file is upgraded right after
+        Some(_) | None => write_manifest(dir_path, name, None),
+    }
+}
+
+/// Take the exclusive lock of the store `name` in `dir_path`, held until the returned file is
+/// closed.
+///
+/// # Errors
+///
+/// - StoreLocked: Another KvStore, in this or another process, holds the lock.
+/// - Io: Failed to create or lock the lock file.
+fn lock_store(dir_path: &Path, name: Option<&str>) -> Result<File> {
+    let lock_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir_path.join(store_file_name(name, LOCK_FILE_NAME)))
+        .context(ErrorKind::Io)?;
+    match lock_file.try_lock_exclusive() {
+        Ok(()) => Ok(lock_file),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(Error::from(ErrorKind::StoreLocked))
+        }
+        Err(e) => Err(e.context(ErrorKind::Io).into()),
+    }
+}
+
+/// Spawn a flusher for the log file at `path`, treating its current content as durable.
+fn spawn_flusher(path: &PathBuf, config: &GroupCommit) -> Result<Flusher> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .context(ErrorKind::Io)?;
+    Ok(Flusher::spawn(file, file_len(path)?, config))
 }
 
-/// Get file length in bytes
-fn file_len(path: &PathBuf) -> Result<u64> {
-    Ok(metadata(path).context(ErrorKind::Io)?.len())
-}
+impl KvStore {
+    /// Set a key-value pair.
+    ///
+    /// If the KvStore did have this key present, the value is updated via a new set command appended.
+    /// The new command is not necessarily writen to log file immediately due to buffer.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to open log file or failed to read metadata of log file
+    /// - Serde: Failed to serialize the set command
+    /// - KeyTooLarge: The key is larger than `Options::max_key_size`
+    /// - ValueTooLarge: The value is larger than `Options::max_value_size`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let path = tempdir.path();
+    /// let mut kv = KvStore::open(path).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("12".to_owned()));
+    ///
+    /// kv.set("key1".to_owned(), "11".to_owned()).unwrap();
+    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("11".to_owned()));
+    /// ```
+    pub fn set<K: Into<Vec<u8>>>(&mut self, key: K, value: String) -> Result<()> {
+        self.set_bytes(key, value.into_bytes())
+    }
+
+    /// Set a key to a binary value.
+    ///
+    /// Binary values are read back with `get_bytes`. Reading a value that is not valid
+    /// UTF-8 through the string API fails with InvalidUtf8.
+    ///
+    /// Like all methods taking a key, it accepts both string and byte keys.
+    ///
+    /// # Errors
+    ///
+    /// Same as `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set_bytes("image", vec![0x89, 0x50, 0x4e, 0x47]).unwrap();
+    /// assert_eq!(kv.get_bytes("image").unwrap(), Some(vec![0x89, 0x50, 0x4e, 0x47]));
+    ///
+    /// let hash = [0xde, 0xad, 0xbe, 0xef];
+    /// kv.set_bytes(&hash[..], b"blob".to_vec()).unwrap();
+    /// assert_eq!(kv.get(&hash[..]).unwrap(), Some("blob".to_owned()));
+    /// ```
+    pub fn set_bytes<K: Into<Vec<u8>>>(&mut self, key: K, value: Vec<u8>) -> Result<()> {
+        self.apply_log(KvLog::new_set(key.into(), value))
+    }
+
+    /// Set a key-value pair that expires after `ttl`.
+    ///
+    /// Once expired, the key is treated as absent and compaction drops it.
+    /// A later `set` of the key removes the TTL.
+    ///
+    /// # Errors
+    ///
+    /// Same as `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use std::time::Duration;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set_with_ttl("session".to_owned(), "abc".to_owned(), Duration::from_secs(60)).unwrap();
+    /// assert_eq!(kv.get("session".to_owned()).unwrap(), Some("abc".to_owned()));
+    /// assert!(kv.ttl("session").unwrap().unwrap() <= Duration::from_secs(60));
+    /// ```
+    pub fn set_with_ttl<K: Into<Vec<u8>>>(
+        &mut self,
+        key: K,
+        value: String,
+        ttl: Duration,
+    ) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.apply_log(KvLog::new_set_ex(
+            key.into(),
+            value.into_bytes(),
+            expires_at,
+        ))
+    }
+
+    /// Returns the remaining time to live of a key, or `None` if it does not expire.
+    ///
+    /// # Errors
+    ///
+    /// - KeyNotFound: If the key does not exist.
+    pub fn ttl<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Duration>> {
+        let now = now_millis();
+        match self.log_pointer().get_live(key.as_ref(), now) {
+            None => Err(Error::from(ErrorKind::KeyNotFound)),
+            Some(pointer) => Ok(pointer
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at - now))),
+        }
+    }
+
+    /// Removes the TTL of a key, so it never expires.
+    /// Returns false if the key did not have a TTL.
+    ///
+    /// The value is appended again as a set command without TTL.
+    ///
+    /// # Errors
+    ///
+    /// - KeyNotFound: If the key does not exist.
+    /// - Others: Same as `get` and `set`.
+    pub fn persist<K: AsRef<[u8]>>(&mut self, key: K) -> Result<bool> {
+        let key = key.as_ref();
+        let pointer = self.log_pointer().get_live(key, now_millis());
+        match pointer {
+            None => Err(Error::from(ErrorKind::KeyNotFound)),
+            Some(LogPointer {
+                expires_at: None, ..
+            }) => Ok(false),
+            Some(pointer) => {
+                let value = self.read_value(key, pointer.offset)?;
+                self.apply_log(KvLog::new_set(key.to_vec(), value))?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Removes at most `limit` expired keys, returning how many were removed.
+    ///
+    /// Expired keys are treated as absent, but their records only count as redundant once
+    /// they are removed, so a store whose keys expire without being rewritten may never be
+    /// compacted. A remove command is appended for each key, making its records redundant
+    /// and reported to watches as removals without an old value. Keys of namespaces are
+    /// left to compaction. See `KvsServer::expire_keys` to remove them in the background.
+    ///
+    /// # Errors
+    ///
+    /// Same as `remove`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use std::thread;
+    /// use std::time::Duration;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set_with_ttl("session".to_owned(), "abc".to_owned(), Duration::from_millis(1)).unwrap();
+    /// thread::sleep(Duration::from_millis(10));
+    /// assert_eq!(kv.expire_keys(100).unwrap(), 1);
+    /// assert_eq!(kv.expire_keys(100).unwrap(), 0);
+    /// ```
+    pub fn expire_keys(&mut self, limit: usize) -> Result<usize> {
+        Ok(self.expire(limit)?.len())
+    }
+
+    /// Removes at most `limit` expired keys, returning them. See `expire_keys`.
+    pub(crate) fn expire(&mut self, limit: usize) -> Result<Vec<Vec<u8>>> {
+        let expired = self.log_pointer().expired(now_millis(), limit);
+        if !expired.is_empty() {
+            self.apply_logs(expired.iter().cloned().map(KvLog::new_rm).collect())?;
+        }
+        Ok(expired)
+    }
+
+    /// Append a log, then apply it to the log pointer map, value cache and bloom filter.
+    fn apply_log(&mut self, kvlog: KvLog) -> Result<()> {
+        self.apply_logs(vec![kvlog])
+    }
+
+    /// Append logs, then apply them to the log pointer map, value cache and bloom filter.
+    fn apply_logs(&mut self, logs: Vec<KvLog>) -> Result<()> {
+        self.check_writable()?;
+        for kvlog in &logs {
+            check_size(kvlog, &self.options)?;
+        }
+        let changes = self.write_changes(&logs)?;
+        for command in logs.iter().flat_map(KvLog::commands) {
+            if let Some(key) = command.key() {
+                self.cache.invalidate(key);
+                // only set commands and merges into missing keys create keys
+                if command.is_set() || matches!(command, KvLog::Merge(_, _, None)) {
+                    self.bloom.insert(key);
+                }
+            }
+        }
+
+        // hooks get the logs with their values inline, copies share values in the value log
+        let hooked = if self.options.write_hooks.is_empty() {
+            Vec::new()
+        } else {
+            logs.iter()
+                .map(|kvlog| self.value_log.resolve(kvlog.clone()))
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        // move large values to the value log, then append logs and update log pointer map
+        let logs = match self.options.value_log_threshold {
+            Some(threshold) => logs
+                .into_iter()
+                .map(|kvlog| self.separate_value(kvlog, threshold))
+                .collect::<Result<Vec<_>>>()?,
+            None => logs,
+        };
+        // stamp each log with the next sequence number
+        let sequence = self.log_pointer().sequence();
+        let logs = (sequence + 1..)
+            .zip(logs)
+            .map(|(sequence, kvlog)| KvLog::new_sequenced(sequence, kvlog))
+            .collect::<Vec<_>>();
+        let records = self.append_logs(&logs)?;
+        let mut log_pointer = self.log_pointer_mut();
+        let redundant = logs
+            .into_iter()
+            .zip(records)
+            .map(|(kvlog, (offset, len))| index_log(&mut log_pointer, kvlog, offset, len))
+            .sum();
+        drop(log_pointer);
+        if self.bloom.is_full() {
+            self.bloom = BloomFilter::from_index(&self.log_pointer.read().unwrap());
+        }
+        self.add_redundant(redundant);
+        for event in changes {
+            if !self.indexes.is_empty() {
+                self.indexes.update(&event.key, event.new_value.as_deref());
+            }
+            self.watchers.send(event);
+        }
+        let hooked = (sequence + 1..)
+            .zip(hooked)
+            .map(|(sequence, kvlog)| KvLog::new_sequenced(sequence, kvlog));
+        if self.importing {
+            // the records of an import are committed at the end of each of its batches
+            self.pending_hooks.extend(hooked);
+        } else {
+            self.call_hooks(hooked);
+        }
+        Ok(())
+    }
+
+    /// Pass committed logs to the write hooks.
+    fn call_hooks(&self, logs: impl IntoIterator<Item = KvLog>) {
+        for kvlog in logs {
+            for hook in &self.options.write_hooks {
+                hook.call(&kvlog);
+            }
+        }
+    }
+
+    /// Read the values of all live keys into the secondary indexes, in log order.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`.
+    fn build_indexes(&mut self) -> Result<()> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+        let mut pointers: Vec<_> = self
+            .log_pointer()
+            .iter_live(now_millis())
+            .map(|(key, pointer)| (key.clone(), pointer.offset))
+            .collect();
+        pointers.sort_unstable_by_key(|x| x.1);
+        for (key, offset) in pointers {
+            let value = self.read_value(&key, offset)?;
+            self.indexes.update(&key, Some(&value));
+        }
+        Ok(())
+    }
+
+    /// Returns the changes `logs` make to watched keys, or to any key if there are secondary
+    /// indexes, one per write in order, with the values of the keys before and after it.
+    /// They are computed before the logs are appended, so that a write failing to read a
+    /// value fails before it is committed.
+    ///
+    /// # Errors
+    ///
+    /// - NoMergeOperator: A merge is written to a watched key without a merge operator.
+    /// - Others: Same as `get`.
+    fn write_changes(&mut self, logs: &[KvLog]) -> Result<Vec<ChangeEvent>> {
+        if self.watchers.is_empty() && self.indexes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sequence = self.log_pointer().sequence();
+        let mut values: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new();
+        let mut changes = Vec::new();
+        for (sequence, kvlog) in (sequence + 1..).zip(logs) {
+            for command in kvlog.commands() {
+                let key = match command.key() {
+                    Some(key) if !self.indexes.is_empty() || self.watchers.is_watched(key) => key,
+                    _ => continue,
+                };
+                let old_value = match values.get(key) {
+                    Some(value) => value.clone(),
+                    None => self.get_bytes(key.to_vec())?,
+                };
+                let new_value = match command {
+                    KvLog::Rm(_) => None,
+                    KvLog::Append(_, suffix, _) => {
+                        let mut value = old_value.clone().unwrap_or_default();
+                        value.extend_from_slice(suffix);
+                        Some(value)
+                    }
+                    KvLog::Merge(_, operand, _) => Some(
+                        self.options
+                            .merge_operator
+                            .as_ref()
+                            .ok_or_else(|| Error::from(ErrorKind::NoMergeOperator))?
+                            .merge(key, old_value.as_deref(), operand),
+                    ),
+                    command => self.value_log.resolve(command.clone())?.into_value(),
+                };
+                let op = if new_value.is_some() {
+                    ChangeOp::Set
+                } else {
+                    ChangeOp::Remove
+                };
+                values.insert(key.to_vec(), new_value.clone());
+                changes.push(ChangeEvent {
+                    key: key.to_vec(),
+                    old_value,
+                    new_value,
+                    op,
+                    sequence,
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Move the value of a set command to the value log if it has at least `threshold` bytes.
+    /// Other logs, including the set commands of a batch, are returned as they are.
+    ///
+    /// The value is synced if records are, so that a durable record never points to a lost
+    /// value.
+    fn separate_value(&mut self, kvlog: KvLog, threshold: usize) -> Result<KvLog> {
+        let sync = self.syncs();
+        self.value_log.separate(kvlog, threshold, sync)
+    }
+
+    /// Whether records are synced to disk, in group commit mode or with `Durability::Sync`.
+    fn syncs(&self) -> bool {
+        self.options.group_commit.is_some() || self.options.durability == Durability::Sync
+    }
+
+    /// Append logs to the end of log file and return their offsets and lengths.
+    ///
+    /// The logs are made durable by `commit`, unless an import is in progress.
+    fn append_logs(&mut self, logs: &[KvLog]) -> Result<Vec<(u64, u64)>> {
+        let append_writer = self
+            .append_writer
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::ReadOnly))?;
+        let mut offset = file_len(&self.log_file_path)? + append_writer.buffer().len() as u64;
+        let mut records = Vec::with_capacity(logs.len());
+        for kvlog in logs {
+            let len = self.format.write(kvlog, &mut *append_writer)?;
+            records.push((offset, len));
+            offset += len;
+        }
+        if !self.importing {
+            self.commit()?;
+        }
+
+        Ok(records)
+    }
+
+    /// Make the appended logs as durable as the options ask.
+    ///
+    /// In group commit mode the logs are written through to the OS and handed to the flusher.
+    /// If `wait_for_sync` is set, this blocks until the logs are durable.
+    /// Otherwise they are flushed or synced as `Options::durability` asks.
+    fn commit(&mut self) -> Result<()> {
+        let append_writer = self
+            .append_writer
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::ReadOnly))?;
+        match &self.flusher {
+            Some(flusher) => {
+                append_writer.flush().context(ErrorKind::Io)?;
+                let end = file_len(&self.log_file_path)?;
+                flusher.written(end);
+                if let Some(GroupCommit {
+                    wait_for_sync: true,
+                    ..
+                }) = self.options.group_commit
+                {
+                    flusher.wait_synced(end)?;
+                }
+            }
+            None => match self.options.durability {
+                Durability::Buffered => {}
+                Durability::Flush => append_writer.flush().context(ErrorKind::Io)?,
+                Durability::Sync => {
+                    append_writer.flush().context(ErrorKind::Io)?;
+                    append_writer.get_ref().sync_data().context(ErrorKind::Io)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Replace the write buffer with one of another capacity, flushing it first.
+    ///
+    /// # Errors
+    ///
+    /// - ReadOnly: The store was opened read-only.
+    /// - Io: Failed to flush the buffer or to duplicate the handle of the log file.
+    fn resize_write_buffer(&mut self, capacity: usize) -> Result<()> {
+        let append_writer = self
+            .append_writer
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::ReadOnly))?;
+        append_writer.flush().context(ErrorKind::Io)?;
+        let file = append_writer.get_ref().try_clone().context(ErrorKind::Io)?;
+        self.append_writer = Some(BufWriter::with_capacity(capacity, file));
+        Ok(())
+    }
+
+    /// Returns the value corresponding to the key.
+    ///
+    /// The returned value is a copy of the value stored in `KvStore` if present.
+    /// Recently read values are served from the value cache if it is enabled in `Options`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If log file or its metadata failed to be read
+    /// - Serde: If log deserialization failed when reading log file.
+    /// - Corruption: If log file is different from log pointer map in memory.
+    /// - InvalidUtf8: If the value was set as bytes that are not valid UTF-8.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let path = tempdir.path();
+    /// let mut kv = KvStore::open(path).unwrap();
+    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), None);
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// let returned_opt = kv.get("key1".to_owned()).unwrap();
+    /// assert_eq!(returned_opt, Some("12".to_owned()));
+    ///
+    /// kv.set("key1".to_owned(), "11".to_owned()).unwrap();
+    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("11".to_owned()));
+    /// assert_eq!(returned_opt, Some("12".to_owned()));
+    /// ```
+    pub fn get<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(into_string).transpose()
+    }
+
+    /// Returns the value corresponding to the key as bytes.
+    ///
+    /// Works for values set through either the string or the binary API.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`, except InvalidUtf8.
+    pub fn get_bytes<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if !self.bloom.may_contain(&key) {
+            return Ok(None);
+        }
+        let pointer = self.log_pointer().get_live(&key, now_millis());
+        match pointer {
+            None => Ok(None),
+            Some(pointer) => {
+                if let Some(value) = self.cache.get(&key) {
+                    return Ok(Some(value));
+                }
+                let value = self.read_value(&key, pointer.offset)?;
+                self.cache.insert(key, value.clone());
+                Ok(Some(value))
+            }
+        }
+    }
+
+    /// Returns the sequence number of the last write.
+    ///
+    /// Every write gets the next sequence number, and all commands of a batch share one.
+    /// Sequence numbers keep growing across compactions and reopening.
+    pub fn sequence(&self) -> u64 {
+        self.log_pointer().sequence()
+    }
+
+    /// Returns up to `limit` versions of a key, newest first: the values it was set to and
+    /// its removals, with the sequence numbers of the writes that made and replaced them.
+    ///
+    /// Versions are kept until the next compaction, so only the current version of a key
+    /// is returned for the writes before it. Removals of keys compacted away are not known.
+    /// Versions are returned even if the key has expired since.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.remove("key1".to_owned()).unwrap();
+    ///
+    /// let history = kv.history("key1", 10).unwrap();
+    /// assert_eq!(history[0].value, None);
+    /// assert_eq!(history[1].value, Some("12".to_owned()));
+    /// assert_eq!(history[1].replaced_at, Some(history[0].sequence));
+    /// ```
+    pub fn history<K: AsRef<[u8]>>(&mut self, key: K, limit: usize) -> Result<Vec<KeyVersion>> {
+        let key = key.as_ref();
+        let mut replaced_at = None;
+        let mut history = Vec::new();
+        let versions = self.log_pointer().versions(key);
+        for (sequence, pointer) in versions.into_iter().rev().take(limit) {
+            let value = match pointer {
+                Some(pointer) => Some(into_string(self.read_value(key, pointer.offset)?)?),
+                None => None,
+            };
+            history.push(KeyVersion {
+                sequence,
+                value,
+                replaced_at,
+            });
+            replaced_at = Some(sequence);
+        }
+        Ok(history)
+    }
+
+    /// Returns the value a key had right after the write of `sequence`.
+    ///
+    /// Earlier versions of keys are kept until the next compaction, so `sequence` must not
+    /// be older than it. Keys with a TTL are expired as of now.
+    ///
+    /// # Errors
+    ///
+    /// - VersionCompacted: The versions at `sequence` were dropped by compaction.
+    /// - Others: Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// let sequence = kv.sequence();
+    /// kv.set("key1".to_owned(), "13".to_owned()).unwrap();
+    ///
+    /// assert_eq!(kv.get_at("key1", sequence).unwrap(), Some("12".to_owned()));
+    /// assert_eq!(kv.get_at("key1", sequence - 1).unwrap(), None);
+    /// ```
+    pub fn get_at<K: AsRef<[u8]>>(&mut self, key: K, sequence: u64) -> Result<Option<String>> {
+        let key = key.as_ref();
+        if sequence < self.log_pointer().horizon() {
+            return Err(Error::from(ErrorKind::VersionCompacted));
+        }
+        let pointer = self.log_pointer().get_at(key, sequence);
+        match pointer {
+            Some(pointer) if !pointer.is_expired(now_millis()) => self
+                .read_value(key, pointer.offset)
+                .and_then(into_string)
+                .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Set a key to any serializable value, encoded with bincode.
+    ///
+    /// The value is read back with `get_typed` using the same type.
+    ///
+    /// # Errors
+    ///
+    /// - Serde: Failed to serialize the value.
+    /// - Others: Same as `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use serde::{Deserialize, Serialize};
+    /// use tempfile::TempDir;
+    ///
+    /// #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    /// struct User {
+    ///     name: String,
+    ///     age: u32,
+    /// }
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// let user = User { name: "alice".to_owned(), age: 30 };
+    /// kv.set_typed("user:1".to_owned(), &user).unwrap();
+    /// assert_eq!(kv.get_typed::<User>("user:1".to_owned()).unwrap(), Some(user));
+    /// ```
+    pub fn set_typed<K, T>(&mut self, key: K, value: &T) -> Result<()>
+    where
+        K: Into<Vec<u8>>,
+        T: Serialize + ?Sized,
+    {
+        let value = bincode::serialize(value).context(ErrorKind::Serde)?;
+        self.set_bytes(key, value)
+    }
+
+    /// Returns the value corresponding to the key, decoded with bincode.
+    ///
+    /// # Errors
+    ///
+    /// - Serde: The value is not a bincode encoding of `T`.
+    /// - Others: Same as `get_bytes`.
+    pub fn get_typed<T: DeserializeOwned>(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<T>> {
+        match self.get_bytes(key)? {
+            None => Ok(None),
+            Some(value) => Ok(Some(
+                bincode::deserialize(&value).context(ErrorKind::Serde)?,
+            )),
+        }
+    }
+
+    /// Read the value of `key` from the set command at `offset`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`.
+    pub(crate) fn read_value(&mut self, key: &[u8], offset: u64) -> Result<Vec<u8>> {
+        let merge_operator = self.options.merge_operator.clone();
+        read_value_chain(key, offset, merge_operator.as_ref(), |offset| {
+            let kvlog = self.get_kvlog_from_offset(offset)?;
+            self.value_log.resolve(kvlog)
+        })
+    }
+
+    /// Read the live log of `key` at `offset`, taking it out of its batch if needed.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`.
+    fn read_live_log(&mut self, key: &[u8], offset: u64) -> Result<KvLog> {
+        self.get_kvlog_from_offset(offset)?.into_live_set(key)
+    }
+
+    /// Underlying implementation for get
+    /// Please refer to `get`
+    fn get_kvlog_from_offset(&mut self, offset: u64) -> Result<KvLog> {
+        let log_len = file_len(&self.log_file_path)?;
+
+        let kvlog = if offset >= log_len {
+            // log is still in buffer
+            let buffer = self
+                .append_writer
+                .as_ref()
+                .map_or(&[][..], |append_writer| append_writer.buffer());
+            let mut reader = Cursor::new(buffer);
+            reader
+                .seek(SeekFrom::Start(offset - log_len))
+                .context(ErrorKind::Io)?;
+            self.format.read(reader)?
+        } else {
+            // log is in file
+            self.reader.read_at(offset)?
+        };
+
+        Ok(kvlog)
+    }
+
+    /// Removes a key from the map if the key is present.
+    ///
+    /// If the KvStore did have this key present, the value is "removed" via a new remove command appended.
+    /// The new command is not necessarily writen to log file immediately due to buffer.
+    ///
+    /// # Errors
+    ///
+    /// - KeyNotFound: If the key does not exist.
+    /// - Serde: If log serialization failed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let path = tempdir.path();
+    /// let mut kv = KvStore::open(path).unwrap();
+    ///
+    /// kv.remove("key1".to_owned()).unwrap_err(); // missing key
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("12".to_owned()));
+    ///
+    /// kv.remove("key1".to_owned()).unwrap();
+    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), None);
+    ///
+    /// ```
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<()> {
+        let key = key.into();
+        if self.log_pointer().get_live(&key, now_millis()).is_some() {
+            self.apply_log(KvLog::new_rm(key))
+        } else {
+            Err(Error::from(ErrorKind::KeyNotFound))
+        }
+    }
+
+    /// Renames a key, keeping its value and TTL. A key already named `new_key` is replaced.
+    ///
+    /// The new key is set and the old one removed in a single batch, so after a crash
+    /// exactly one of them holds the value.
+    ///
+    /// # Errors
+    ///
+    /// - KeyNotFound: If `old_key` does not exist.
+    /// - Others: Same as `get` and `write`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.rename("key1", "key2").unwrap();
+    /// assert_eq!(kv.get("key1").unwrap(), None);
+    /// assert_eq!(kv.get("key2").unwrap(), Some("12".to_owned()));
+    /// ```
+    pub fn rename<K, N>(&mut self, old_key: K, new_key: N) -> Result<()>
+    where
+        K: Into<Vec<u8>>,
+        N: Into<Vec<u8>>,
+    {
+        let (old_key, new_key) = (old_key.into(), new_key.into());
+        let pointer = self
+            .log_pointer()
+            .get_live(&old_key, now_millis())
+            .ok_or_else(|| Error::from(ErrorKind::KeyNotFound))?;
+        if old_key == new_key {
+            return Ok(());
+        }
+        let set = self.copy_command(&old_key, &pointer, new_key)?;
+        self.apply_log(KvLog::Batch(vec![set, KvLog::new_rm(old_key)]))
+    }
+
+    /// Copies the value and TTL of a key to another key, returning whether it was copied.
+    ///
+    /// If `dst` exists, it is replaced if `overwrite` is set, and left alone otherwise.
+    /// A value in the value log is shared by both keys instead of being written again, see
+    /// `Options::value_log_threshold`.
+    ///
+    /// # Errors
+    ///
+    /// - KeyNotFound: If `src` does not exist.
+    /// - Others: Same as `get` and `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.set("key2".to_owned(), "34".to_owned()).unwrap();
+    /// assert!(!kv.copy("key1", "key2", false).unwrap());
+    /// assert!(kv.copy("key1", "key2", true).unwrap());
+    /// assert_eq!(kv.get("key1").unwrap(), Some("12".to_owned()));
+    /// assert_eq!(kv.get("key2").unwrap(), Some("12".to_owned()));
+    /// ```
+    pub fn copy<K, D>(&mut self, src: K, dst: D, overwrite: bool) -> Result<bool>
+    where
+        K: Into<Vec<u8>>,
+        D: Into<Vec<u8>>,
+    {
+        let (src, dst) = (src.into(), dst.into());
+        let now = now_millis();
+        let pointer = self
+            .log_pointer()
+            .get_live(&src, now)
+            .ok_or_else(|| Error::from(ErrorKind::KeyNotFound))?;
+        if !overwrite && self.log_pointer().get_live(&dst, now).is_some() {
+            return Ok(false);
+        }
+        if src != dst {
+            let set = self.copy_command(&src, &pointer, dst)?;
+            self.apply_log(set)?;
+        }
+        Ok(true)
+    }
+
+    /// Set command giving `new_key` the live value and TTL of `key` at `pointer`. A value in
+    /// the value log is shared rather than read and written again.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`.
+    fn copy_command(
+        &mut self,
+        key: &[u8],
+        pointer: &LogPointer,
+        new_key: Vec<u8>,
+    ) -> Result<KvLog> {
+        let value = match self.read_live_log(key, pointer.offset)? {
+            KvLog::SetSeparated(_, offset, len, expires_at) => {
+                return Ok(KvLog::SetSeparated(new_key, offset, len, expires_at))
+            }
+            KvLog::Set(_, value) | KvLog::SetEx(_, value, _) => value,
+            // chains of appends and merges are folded into the copy
+            _ => self.read_value(key, pointer.offset)?,
+        };
+        Ok(match pointer.expires_at {
+            Some(expires_at) => KvLog::new_set_ex(new_key, value, expires_at),
+            None => KvLog::new_set(new_key, value),
+        })
+    }
+
+    /// Sets the key to `new` only if its current value is `expected`, returning whether
+    /// the swap happened.
+    ///
+    /// `None` as `expected` means the key must be absent, and `None` as `new` removes the key.
+    /// Since the store is borrowed mutably, no other write can come between the comparison
+    /// and the swap.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`, `set` and `remove`. A mismatch is not an error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// assert!(kv.compare_and_swap("lock", None, Some("owner1".to_owned())).unwrap());
+    /// assert!(!kv.compare_and_swap("lock", None, Some("owner2".to_owned())).unwrap());
+    /// assert!(kv.compare_and_swap("lock", Some("owner1"), None).unwrap());
+    /// assert_eq!(kv.get("lock").unwrap(), None);
+    /// ```
+    pub fn compare_and_swap<K: Into<Vec<u8>>>(
+        &mut self,
+        key: K,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let key = key.into();
+        let current = self.get(key.clone())?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if current.is_some() => self.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the new value.
+    ///
+    /// A missing key counts as 0. The new value is appended as a set command,
+    /// so like `set` it removes the TTL of the key.
+    ///
+    /// # Errors
+    ///
+    /// - NotAnInteger: The value cannot be parsed as an `i64`.
+    /// - Overflow: The new value would be out of the range of `i64`.
+    /// - Others: Same as `get` and `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// assert_eq!(kv.incr("visits", 1).unwrap(), 1);
+    /// assert_eq!(kv.incr("visits", 10).unwrap(), 11);
+    /// assert_eq!(kv.decr("visits", 2).unwrap(), 9);
+    /// assert_eq!(kv.get("visits").unwrap(), Some("9".to_owned()));
+    /// ```
+    pub fn incr<K: Into<Vec<u8>>>(&mut self, key: K, delta: i64) -> Result<i64> {
+        let key = key.into();
+        let current = match self.get(key.clone())? {
+            None => 0,
+            Some(value) => value
+                .parse::<i64>()
+                .map_err(|_| Error::from(ErrorKind::NotAnInteger))?,
+        };
+        let new = current
+            .checked_add(delta)
+            .ok_or_else(|| Error::from(ErrorKind::Overflow))?;
+        self.set(key, new.to_string())?;
+        Ok(new)
+    }
+
+    /// Subtracts `delta` from the integer value of a key and returns the new value.
+    ///
+    /// # Errors
+    ///
+    /// Same as `incr`.
+    pub fn decr<K: Into<Vec<u8>>>(&mut self, key: K, delta: i64) -> Result<i64> {
+        let delta = delta
+            .checked_neg()
+            .ok_or_else(|| Error::from(ErrorKind::Overflow))?;
+        self.incr(key, delta)
+    }
+
+    /// Appends `suffix` to the value of a key, creating the key if it is missing.
+    ///
+    /// Only the suffix is written, as an append command referring to the previous log of
+    /// the key, so a long value is not rewritten. `get` follows the chain of appends and
+    /// compaction folds it into one set command. The key keeps its TTL.
+    ///
+    /// # Errors
+    ///
+    /// - ValueTooLarge: The value with `suffix` appended is larger than
+    ///   `Options::max_value_size`.
+    /// - Others: Same as `get` and `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.append("log", "line 1\n").unwrap();
+    /// kv.append("log", "line 2\n").unwrap();
+    /// assert_eq!(kv.get("log").unwrap(), Some("line 1\nline 2\n".to_owned()));
+    /// ```
+    pub fn append<K, V>(&mut self, key: K, suffix: V) -> Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let key = key.into();
+        let suffix = suffix.into();
+        let previous = self
+            .log_pointer()
+            .get_live(&key, now_millis())
+            .map(|pointer| pointer.offset);
+        let kvlog = match previous {
+            Some(offset) => {
+                // the value the suffix makes has to fit, not only the suffix
+                if let Some(max) = self.options.max_value_size {
+                    if self.read_value(&key, offset)?.len() + suffix.len() > max {
+                        return Err(Error::from(ErrorKind::ValueTooLarge));
+                    }
+                }
+                KvLog::new_append(key, suffix, offset)
+            }
+            None => KvLog::new_set(key, suffix),
+        };
+        self.apply_log(kvlog)
+    }
+
+    /// Merges `operand` into the value of a key, using the merge operator in `Options`.
+    ///
+    /// Only the operand is written, as a merge command referring to the previous log of
+    /// the key. Operands are resolved by the merge operator when the value is read, and
+    /// folded into one set command by compaction. The key keeps its TTL.
+    ///
+    /// # Errors
+    ///
+    /// - NoMergeOperator: The store was opened without a merge operator.
+    /// - Others: Same as `set`.
+    ///
+    /// # Examples
+    ///
+    /// See `MergeOperator`.
+    pub fn merge<K, V>(&mut self, key: K, operand: V) -> Result<()>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        if self.options.merge_operator.is_none() {
+            return Err(Error::from(ErrorKind::NoMergeOperator));
+        }
+        let key = key.into();
+        let prev = self
+            .log_pointer()
+            .get_live(&key, now_millis())
+            .map(|pointer| pointer.offset);
+        self.apply_log(KvLog::new_merge(key, operand.into(), prev))
+    }
+
+    /// Returns the value of a key, or computes it with `f`, stores it and returns it if
+    /// the key is missing.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get` and `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// assert_eq!(kv.get_or_insert_with("key1", || "12".to_owned()).unwrap(), "12");
+    /// assert_eq!(kv.get_or_insert_with("key1", || "13".to_owned()).unwrap(), "12");
+    /// ```
+    pub fn get_or_insert_with<K, F>(&mut self, key: K, f: F) -> Result<String>
+    where
+        K: Into<Vec<u8>>,
+        F: FnOnce() -> String,
+    {
+        let key = key.into();
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        let value = f();
+        self.set(key, value.clone())?;
+        Ok(value)
+    }
+
+    /// Removes a key and returns its value, or `None` if the key was missing.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get` and `remove`, except KeyNotFound. The key is left in place if reading
+    /// its value fails.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("job".to_owned(), "build".to_owned()).unwrap();
+    /// assert_eq!(kv.take("job").unwrap(), Some("build".to_owned()));
+    /// assert_eq!(kv.take("job").unwrap(), None);
+    /// ```
+    pub fn take<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<Option<String>> {
+        let key = key.into();
+        let value = self.get(key.clone())?;
+        if value.is_some() {
+            self.remove(key)?;
+        }
+        Ok(value)
+    }
+
+    /// Returns the values of many keys, in the order of `keys`.
+    ///
+    /// Values are read in the order they appear in the log, so the log file is read in one
+    /// sequential pass instead of a seek per key. Unlike `get`, values read from disk are not
+    /// added to the value cache.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.set("key2".to_owned(), "13".to_owned()).unwrap();
+    /// assert_eq!(
+    ///     kv.multi_get(&["key2", "key3", "key1"]).unwrap(),
+    ///     vec![Some("13".to_owned()), None, Some("12".to_owned())]
+    /// );
+    /// ```
+    pub fn multi_get<K: AsRef<[u8]>>(&mut self, keys: &[K]) -> Result<Vec<Option<String>>> {
+        let now = now_millis();
+        let mut pointers: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| self.bloom.may_contain(key.as_ref()))
+            .filter_map(|(index, key)| {
+                let pointer = self.log_pointer().get_live(key.as_ref(), now)?;
+                Some((pointer.offset, index))
+            })
+            .collect();
+        // Sort by log pointer so values are read sequentially.
+        pointers.sort_unstable();
+
+        let mut values = vec![None; keys.len()];
+        for (offset, index) in pointers {
+            let key = keys[index].as_ref();
+            let value = match self.cache.get(key) {
+                Some(value) => value,
+                None => self.read_value(key, offset)?,
+            };
+            values[index] = Some(into_string(value)?);
+        }
+        Ok(values)
+    }
+
+    /// Sets many key-value pairs.
+    ///
+    /// All set commands are appended in one go and the log pointer map is updated once,
+    /// which is much faster than calling `set` for each pair. Unlike `write`, the pairs are
+    /// separate commands and are not applied atomically.
+    ///
+    /// # Errors
+    ///
+    /// Same as `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.multi_set((0..100).map(|i| (format!("key{}", i), i.to_string()))).unwrap();
+    /// assert_eq!(kv.get("key42").unwrap(), Some("42".to_owned()));
+    /// ```
+    pub fn multi_set<I, K>(&mut self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, String)>,
+        K: Into<Vec<u8>>,
+    {
+        let logs = pairs
+            .into_iter()
+            .map(|(key, value)| KvLog::new_set(key.into(), value.into_bytes()))
+            .collect();
+        self.apply_logs(logs)
+    }
+
+    /// Removes many keys, returning how many of them were present.
+    ///
+    /// Missing keys are skipped. Like `multi_set`, all remove commands are appended in one go.
+    ///
+    /// # Errors
+    ///
+    /// Same as `remove`, except KeyNotFound.
+    pub fn multi_remove<I, K>(&mut self, keys: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<Vec<u8>>,
+    {
+        let now = now_millis();
+        let mut removed = HashSet::new();
+        let logs: Vec<_> = keys
+            .into_iter()
+            .map(Into::into)
+            .filter(|key| self.log_pointer().get_live(key, now).is_some())
+            .filter(|key| removed.insert(key.clone()))
+            .map(KvLog::new_rm)
+            .collect();
+        let count = logs.len();
+        self.apply_logs(logs)?;
+        Ok(count)
+    }
+
+    /// Removes all keys starting with `prefix`, returning how many there were.
+    ///
+    /// The remove commands are appended as a single batch, so either all of the keys are
+    /// removed or none. With an ordered index (see `Options::ordered_index`) only matching
+    /// keys are visited, otherwise the whole log pointer map is filtered.
+    ///
+    /// # Errors
+    ///
+    /// Same as `write`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    /// kv.set("user:2".to_owned(), "bob".to_owned()).unwrap();
+    /// kv.set("order:1".to_owned(), "book".to_owned()).unwrap();
+    /// assert_eq!(kv.delete_prefix("user:").unwrap(), 2);
+    /// assert_eq!(kv.len(), 1);
+    /// ```
+    pub fn delete_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Result<usize> {
+        let logs: Vec<_> = self
+            .log_pointer()
+            .prefix(prefix.as_ref(), now_millis())
+            .into_iter()
+            .map(|(key, _)| KvLog::new_rm(key))
+            .collect();
+        let count = logs.len();
+        if count > 0 {
+            self.apply_log(KvLog::Batch(logs))?;
+        }
+        Ok(count)
+    }
+
+    /// Returns an iterator over all live keys, in arbitrary order.
+    ///
+    /// Keys come from the in-memory log pointer map, so no disk I/O is involved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.set("key2".to_owned(), "13".to_owned()).unwrap();
+    /// kv.remove("key1".to_owned()).unwrap();
+    /// assert_eq!(kv.keys().collect::<Vec<_>>(), vec![b"key2"]);
+    /// ```
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.log_pointer_ref()
+            .iter_live(now_millis())
+            .map(|(key, _)| key.as_slice())
+    }
+
+    /// Applies all commands in a batch atomically.
+    ///
+    /// The batch is appended as one record and the log pointer map is only updated after
+    /// it is fully written. After a crash, either the whole batch or none of it is present.
+    ///
+    /// # Errors
+    ///
+    /// - KeyNotFound: If the batch removes a key that does not exist at that point.
+    ///   Nothing is written in this case.
+    /// - Io: Failed to read metadata of log file or write to it.
+    /// - Serde: Failed to serialize the batch.
+    ///
+    /// # Examples
+    ///
+    /// See `WriteBatch`.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        let logs = batch.into_logs();
+
+        // check every removed key is live when the batch reaches it
+        let now = now_millis();
+        let mut batch_live: HashMap<&[u8], bool> = HashMap::new();
+        for log in &logs {
+            match log {
+                KvLog::Set(key, _)
+                | KvLog::SetEx(key, _, _)
+                | KvLog::SetSeparated(key, _, _, _)
+                | KvLog::Append(key, _, _)
+                | KvLog::Merge(key, _, _) => {
+                    batch_live.insert(key, true);
+                }
+                KvLog::Rm(key) => {
+                    let live = match batch_live.get(key.as_slice()) {
+                        Some(&live) => live,
+                        None => self.log_pointer().get_live(key, now).is_some(),
+                    };
+                    if !live {
+                        return Err(Error::from(ErrorKind::KeyNotFound));
+                    }
+                    batch_live.insert(key, false);
+                }
+                KvLog::Batch(_)
+                | KvLog::Compressed(..)
+                | KvLog::Encrypted(..)
+                | KvLog::Namespaced(..)
+                | KvLog::DropNamespace(_)
+                | KvLog::Sequenced(..) => {}
+            }
+        }
+        if logs.is_empty() {
+            return Ok(());
+        }
+
+        self.apply_log(KvLog::Batch(logs))
+    }
+
+    /// Takes a point-in-time snapshot of the store. See `Snapshot`.
+    ///
+    /// The write buffer is flushed so that the snapshot can read everything from the log file.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to flush the write buffer or to open the log file or the value log.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// let mut snapshot = kv.snapshot().unwrap();
+    /// kv.set("key1".to_owned(), "13".to_owned()).unwrap();
+    /// assert_eq!(snapshot.get("key1").unwrap(), Some("12".to_owned()));
+    /// ```
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        if let Some(append_writer) = &mut self.append_writer {
+            append_writer.flush().context(ErrorKind::Io)?;
+        }
+        let reader = LogReader::new(
+            File::open(&self.log_file_path).context(ErrorKind::Io)?,
+            self.options.read_ahead_size,
+            self.format.clone(),
+        );
+        let value_log = ValueLog::open(self.value_log.path().to_owned())?;
+        let sequence = self.sequence();
+        Ok(Snapshot::new(
+            Arc::clone(&self.log_pointer),
+            sequence,
+            reader,
+            value_log,
+            now_millis(),
+            self.options.merge_operator.clone(),
+        ))
+    }
+
+    /// Returns an iterator over the records appended to the log file from `from_offset` on,
+    /// with their offsets. See `Tail`.
+    ///
+    /// Start from 0 to read the whole log file, then from `Tail::position` of the previous
+    /// iterator to read only what was appended since. The write buffer is flushed first.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to flush the write buffer or to open the log file.
+    ///
+    /// Each item has these errors:
+    ///
+    /// - Serde: `from_offset` is not the offset of a record, or the log file was rewritten
+    ///   since it was returned.
+    /// - Others: Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    ///
+    /// let mut tail = kv.tail(0).unwrap();
+    /// assert_eq!(tail.by_ref().count(), 1);
+    ///
+    /// kv.remove("key1".to_owned()).unwrap();
+    /// let changes: Vec<_> = kv.tail(tail.position()).unwrap().collect();
+    /// assert_eq!(changes.len(), 1);
+    /// ```
+    pub fn tail(&mut self, from_offset: u64) -> Result<Tail> {
+        if let Some(append_writer) = &mut self.append_writer {
+            append_writer.flush().context(ErrorKind::Io)?;
+        }
+        Tail::new(
+            File::open(&self.log_file_path).context(ErrorKind::Io)?,
+            self.options.read_ahead_size,
+            self.format.clone(),
+            from_offset.max(HEADER_LEN),
+            file_len(&self.log_file_path)?,
+        )
+    }
+
+    /// Returns the changes for a replica to catch up with the store from `cursor`, the
+    /// cursor of the changes it applied last, or `None` for a new replica. See `Replica`.
+    ///
+    /// The changes are the next records appended to the log file, up to 1024 of them, with
+    /// their values inline. Once a replica got changes, compaction keeps the log file it
+    /// replaces open until the next one, so that replicas read the end of it before going on
+    /// with the new one. A replica left further behind, or at a log file that was cleared,
+    /// gets a full copy of the store like a new replica.
+    ///
+    /// A full copy comes in pages of up to 1024 keys, as of when it started: the first page
+    /// is full, see `Changes::is_full`, and the cursor of each page gets the next one, then
+    /// the changes made since the copy started. Up to 4 copies are kept in progress, and a
+    /// replica whose copy was dropped gets a new one.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to flush the write buffer, or to read the log file.
+    /// - Others: Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut primary = KvStore::open(tempdir.path().join("primary")).unwrap();
+    /// let mut replica = KvStore::open(tempdir.path().join("replica")).unwrap();
+    ///
+    /// primary.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// let changes = primary.changes(None).unwrap();
+    /// assert!(changes.is_full());
+    /// let cursor = changes.cursor();
+    /// replica.apply_changes(changes).unwrap();
+    ///
+    /// primary.remove("key1".to_owned()).unwrap();
+    /// let changes = primary.changes(Some(cursor)).unwrap();
+    /// assert_eq!(changes.logs().len(), 1);
+    /// replica.apply_changes(changes).unwrap();
+    /// assert_eq!(replica.get("key1".to_owned()).unwrap(), None);
+    /// ```
+    pub fn changes(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        let (copy, position) = match self.next_changes(cursor)? {
+            NextChanges::Logs(changes) => return Ok(changes),
+            NextChanges::Copy(copy, position) => (copy, position),
+        };
+        let changes = copy.lock().unwrap().page(position)?;
+        if changes.cursor.copy.is_none() {
+            self.end_full_copy(&copy);
+        }
+        Ok(changes)
+    }
+
+    /// Returns the changes for a replica at `cursor` read from the log file, or the page
+    /// of a full copy it gets next, to be read without the store, see `changes`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `changes`.
+    pub(crate) fn next_changes(
+        &mut self,
+        cursor: Option<ReplicationCursor>,
+    ) -> Result<NextChanges> {
+        self.replicated = true;
+        if let Some(append_writer) = &mut self.append_writer {
+            append_writer.flush().context(ErrorKind::Io)?;
+        }
+        let mut log_file = File::open(&self.log_file_path).context(ErrorKind::Io)?;
+        let log_len = file_len(&self.log_file_path)?;
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => return self.start_full_copy(&mut log_file, log_len),
+        };
+        if let Some(CopyCursor { id, position }) = cursor.copy {
+            let copy = self.full_copies.iter().find(|(copy_id, _)| *copy_id == id);
+            return match copy {
+                Some((_, copy)) => Ok(NextChanges::Copy(Arc::clone(copy), position)),
+                None => self.start_full_copy(&mut log_file, log_len),
+            };
+        }
+        let mut changes = Changes {
+            full: false,
+            logs: Vec::new(),
+            cursor,
+        };
+        if cursor_matches(&mut log_file, log_len, &cursor)? {
+            self.read_changes(&mut changes, log_file, log_len, false)?;
+            return Ok(NextChanges::Logs(changes));
+        }
+        if let Some((previous_log, _)) = &self.previous_log {
+            let mut previous_log = previous_log.try_clone().context(ErrorKind::Io)?;
+            let previous_len = previous_log.metadata().context(ErrorKind::Io)?.len();
+            if cursor_matches(&mut previous_log, previous_len, &cursor)? {
+                self.read_changes(&mut changes, previous_log, previous_len, true)?;
+                if changes.logs.len() < REPLICATION_BATCH_LEN {
+                    // the rest is in the compacted log file, after the records kept from
+                    // the previous one
+                    changes.cursor.offset = HEADER_LEN;
+                    self.read_changes(&mut changes, log_file, log_len, false)?;
+                }
+                return Ok(NextChanges::Logs(changes));
+            }
+        }
+        self.start_full_copy(&mut log_file, log_len)
+    }
+
+    /// Forget a full copy a replica got all of.
+    pub(crate) fn end_full_copy(&mut self, copy: &Arc<Mutex<FullCopy>>) {
+        self.full_copies
+            .retain(|(_, other)| !Arc::ptr_eq(other, copy));
+    }
+
+    /// Add the records of `log_file` from `changes.cursor` up to `log_len` to `changes`,
+    /// skipping those up to the sequence number of the cursor, and move the cursor after
+    /// them. Values are read from the value log of the previous log file if `previous`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `changes`.
+    fn read_changes(
+        &mut self,
+        changes: &mut Changes,
+        mut log_file: File,
+        log_len: u64,
+        previous: bool,
+    ) -> Result<()> {
+        let from = changes.cursor.offset.max(HEADER_LEN);
+        let file = log_file.try_clone().context(ErrorKind::Io)?;
+        let mut tail = Tail::new(
+            file,
+            self.options.read_ahead_size,
+            self.format.clone(),
+            from,
+            log_len,
+        )?;
+        let value_log = match (previous, &mut self.previous_log) {
+            (true, Some((_, value_log))) => value_log,
+            _ => &mut self.value_log,
+        };
+        let after = changes.cursor.sequence;
+        let mut sequence = after;
+        while changes.logs.len() < REPLICATION_BATCH_LEN {
+            let kvlog = match tail.next() {
+                Some(record) => record?.1,
+                None => break,
+            };
+            let (record_sequence, kvlog) = match kvlog {
+                KvLog::Sequenced(record_sequence, kvlog) => (record_sequence, *kvlog),
+                kvlog => (sequence + 1, kvlog),
+            };
+            // records kept by compaction, which the replica already has
+            if record_sequence <= after {
+                continue;
+            }
+            sequence = record_sequence;
+            match kvlog {
+                // the start of a compacted log file
+                KvLog::Batch(batch) if batch.is_empty() => {}
+                kvlog => changes.logs.push(value_log.resolve(kvlog)?),
+            }
+        }
+        changes.cursor = replication_cursor(&mut log_file, tail.position(), sequence)?;
+        Ok(())
+    }
+
+    /// Start a full copy of the store for a new replica, up to `log_len` of `log_file`, and
+    /// return its first page to read. The oldest copy in progress is dropped if there are
+    /// too many.
+    ///
+    /// # Errors
+    ///
+    /// Same as `changes`.
+    fn start_full_copy(&mut self, log_file: &mut File, log_len: u64) -> Result<NextChanges> {
+        let now = now_millis();
+        let (mut entries, sequence) = {
+            let log_pointer = self.log_pointer();
+            let mut entries: Vec<_> = log_pointer
+                .iter_live(now)
+                .map(|(key, pointer)| (None, key.clone(), *pointer))
+                .collect();
+            for (namespace, log_pointer) in log_pointer.namespaces() {
+                entries.extend(
+                    log_pointer
+                        .iter_live(now)
+                        .map(|(key, pointer)| (Some(namespace.clone()), key.clone(), *pointer)),
+                );
+            }
+            (entries, log_pointer.sequence())
+        };
+        // so that values are read sequentially
+        entries.sort_unstable_by_key(|(_, _, pointer)| pointer.offset);
+        let reader = LogReader::new(
+            File::open(&self.log_file_path).context(ErrorKind::Io)?,
+            self.options.read_ahead_size,
+            self.format.clone(),
+        );
+        let id = self.next_copy_id;
+        let copy = FullCopy::new(
+            id,
+            entries,
+            reader,
+            ValueLog::open(self.value_log.path().to_owned())?,
+            self.options.merge_operator.clone(),
+            replication_cursor(log_file, log_len, sequence)?,
+        );
+        self.next_copy_id += 1;
+        if self.full_copies.len() == MAX_FULL_COPIES {
+            self.full_copies.remove(0);
+        }
+        let copy = Arc::new(Mutex::new(copy));
+        self.full_copies.push((id, Arc::clone(&copy)));
+        Ok(NextChanges::Copy(copy, 0))
+    }
+
+    /// Applies changes of a primary store returned by `changes`, making this store a replica
+    /// of it. Full changes replace the data of the store.
+    ///
+    /// Merges are applied with the merge operator of this store, which has to be the one of
+    /// the primary.
+    ///
+    /// # Errors
+    ///
+    /// - Corruption: The changes have a log that is only found on disk.
+    /// - Others: Same as `set`, or `clear` for full changes.
+    pub fn apply_changes(&mut self, changes: Changes) -> Result<()> {
+        if changes.full {
+            self.clear()?;
+        }
+        let mut logs = Vec::new();
+        for kvlog in changes.logs {
+            match kvlog {
+                KvLog::Set(..)
+                | KvLog::SetEx(..)
+                | KvLog::Rm(_)
+                | KvLog::Batch(_)
+                | KvLog::Namespaced(..)
+                | KvLog::DropNamespace(_) => logs.push(kvlog),
+                // chains point to earlier logs of the primary, so they are made again
+                KvLog::Append(key, suffix, _) => {
+                    self.apply_replicated(std::mem::take(&mut logs))?;
+                    self.append(key, suffix)?;
+                }
+                KvLog::Merge(key, operand, _) => {
+                    self.apply_replicated(std::mem::take(&mut logs))?;
+                    self.merge(key, operand)?;
+                }
+                KvLog::Compressed(..)
+                | KvLog::Encrypted(..)
+                | KvLog::SetSeparated(..)
+                | KvLog::Sequenced(..) => return Err(Error::from(ErrorKind::Corruption)),
+            }
+        }
+        self.apply_replicated(logs)
+    }
+
+    /// Apply logs of a primary store, if there are any.
+    fn apply_replicated(&mut self, logs: Vec<KvLog>) -> Result<()> {
+        if logs.is_empty() {
+            return Ok(());
+        }
+        self.apply_logs(logs)
+    }
+
+    /// Watches the keys starting with `prefix`, returning a stream of their changes.
+    ///
+    /// Every write changing a watched key sends an event with its old and new value, after
+    /// the write is applied. The watch stops receiving events once it is dropped. See
+    /// `Watch` for which changes are reported.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use kvs::{ChangeOp, KvStore};
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// let watch = kv.watch("user:");
+    ///
+    /// kv.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    /// kv.set("order:1".to_owned(), "book".to_owned()).unwrap();
+    /// kv.remove("user:1".to_owned()).unwrap();
+    ///
+    /// let set = watch.try_next().unwrap();
+    /// assert_eq!(set.key, b"user:1");
+    /// assert_eq!(set.new_value, Some(b"alice".to_vec()));
+    /// let remove = watch.try_next().unwrap();
+    /// assert_eq!(remove.op, ChangeOp::Remove);
+    /// assert_eq!(remove.old_value, Some(b"alice".to_vec()));
+    /// assert!(watch.try_next().is_none());
+    /// ```
+    pub fn watch<P: Into<Vec<u8>>>(&mut self, prefix: P) -> Watch {
+        self.watchers.add(prefix.into())
+    }
+
+    /// Watches the keys starting with any of `prefixes`, like `watch`, in a single stream.
+    /// A change of a key starting with several of the prefixes is reported once.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// let watch = kv.watch_prefixes(vec!["user:", "user:1", "order:"]);
+    /// kv.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    /// kv.set("order:1".to_owned(), "book".to_owned()).unwrap();
+    ///
+    /// assert_eq!(watch.try_next().unwrap().key, b"user:1");
+    /// assert_eq!(watch.try_next().unwrap().key, b"order:1");
+    /// assert!(watch.try_next().is_none());
+    /// ```
+    pub fn watch_prefixes<P: Into<Vec<u8>>>(&mut self, prefixes: Vec<P>) -> Watch {
+        self.watchers
+            .add_all(prefixes.into_iter().map(Into::into).collect())
+    }
+
+    /// Starts a transaction. See `Transaction`.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Opens the namespace `name`. See `Namespace`.
+    pub fn namespace(&mut self, name: &str) -> Namespace<'_> {
+        Namespace::new(self, name)
+    }
+
+    /// Removes all keys of the namespace `name` at once, returning how many there were.
+    ///
+    /// A single record is appended whatever the size of the namespace, and the records of
+    /// its keys count as redundant for compaction.
+    ///
+    /// # Errors
+    ///
+    /// - ReadOnly: If the store was opened read-only.
+    /// - Io: If the record failed to be written.
+    /// - Serde: If the record failed to be serialized.
+    pub fn drop_namespace(&mut self, name: &str) -> Result<usize> {
+        self.check_writable()?;
+        let len = self
+            .log_pointer()
+            .namespace(name)
+            .map_or(0, |log_pointer| log_pointer.live_len(now_millis()));
+        if self.log_pointer().namespace(name).is_some() {
+            self.apply_log(KvLog::DropNamespace(name.to_owned()))?;
+        }
+        Ok(len)
+    }
+
+    /// Returns true if the store has the key.
+    ///
+    /// Unlike `get`, it is answered from the in-memory log pointer map without reading
+    /// the value from disk.
+    ///
+    /// # Errors
+    ///
+    /// None at the moment, the `Result` leaves room for checks that need disk I/O.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// assert!(!kv.contains_key("key1").unwrap());
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// assert!(kv.contains_key("key1").unwrap());
+    /// ```
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        let key = key.as_ref();
+        Ok(self.bloom.may_contain(key) && self.log_pointer().get_live(key, now_millis()).is_some())
+    }
+
+    /// Returns the number of live keys.
+    ///
+    /// It is answered from the in-memory log pointer map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// assert_eq!(kv.len(), 0);
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.set("key1".to_owned(), "13".to_owned()).unwrap();
+    /// assert_eq!(kv.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.log_pointer().live_len(now_millis())
+    }
+
+    /// Returns true if the store has no live keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the store has a key, in a namespace or not.
+    pub(crate) fn has_keys(&self) -> bool {
+        let now = now_millis();
+        !self.is_empty()
+            || self
+                .log_pointer()
+                .namespaces()
+                .any(|(_, log_pointer)| log_pointer.live_len(now) > 0)
+    }
+
+    /// Checks the whole log file against the in-memory index. See `VerifyReport`.
+    ///
+    /// Every record is read, the index is rebuilt from them and compared with the one in
+    /// memory, and the value of every key in the index is read. Problems are collected in the
+    /// report instead of failing on the first one. Buffered records are flushed first.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If the log file failed to be flushed, opened or read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    ///
+    /// let report = kv.verify().unwrap();
+    /// assert!(report.is_ok());
+    /// assert_eq!(report.records, 1);
+    /// ```
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        if let Some(append_writer) = self.append_writer.as_mut() {
+            append_writer.flush().context(ErrorKind::Io)?;
+        }
+        let mut report = VerifyReport {
+            bytes: file_len(&self.log_file_path)?,
+            ..VerifyReport::default()
+        };
+
+        // rebuild the index from the log
+        let mut reader = BufReader::with_capacity(
+            self.options.read_ahead_size.max(1),
+            File::open(&self.log_file_path).context(ErrorKind::Io)?,
+        );
+        reader
+            .seek(SeekFrom::Start(HEADER_LEN))
+            .context(ErrorKind::Io)?;
+        let mut replayed = LogPointerMap::new(self.options.ordered_index);
+        while has_more(&mut reader)? {
+            let offset = position(&mut reader)?;
+            match self.format.read(&mut reader) {
+                Ok(kvlog) => {
+                    let len = position(&mut reader)? - offset;
+                    index_log(&mut replayed, kvlog, offset, len);
+                    report.records += 1;
+                }
+                Err(e) => {
+                    let kind = e.kind();
+                    report
+                        .issues
+                        .push(VerifyIssue::UnreadableRecord { offset, kind });
+                    break;
+                }
+            }
+        }
+
+        // compare it with the index in memory, and read every value
+        let mut keys: Vec<Vec<u8>> = self
+            .log_pointer()
+            .keys()
+            .chain(replayed.keys())
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        for key in keys {
+            let indexed = self.log_pointer().get(&key).map(|pointer| pointer.offset);
+            let logged = replayed.get(&key).map(|pointer| pointer.offset);
+            match indexed {
+                Some(offset) if indexed == logged => {
+                    if let Err(e) = self.read_value(&key, offset) {
+                        let kind = e.kind();
+                        report
+                            .issues
+                            .push(VerifyIssue::UnreadableValue { key, offset, kind });
+                    }
+                }
+                _ if indexed == logged => {}
+                _ => report.issues.push(VerifyIssue::IndexMismatch {
+                    key,
+                    indexed,
+                    logged,
+                }),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Returns statistics about the size of the store and its garbage. See `Stats`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If metadata of the log file failed to be read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.set("key1".to_owned(), "13".to_owned()).unwrap();
+    ///
+    /// let stats = kv.stats().unwrap();
+    /// assert_eq!(stats.live_keys, 1);
+    /// assert_eq!(stats.redundant_records, 1);
+    /// ```
+    pub fn stats(&self) -> Result<Stats> {
+        let buffered = self
+            .append_writer
+            .as_ref()
+            .map_or(0, |append_writer| append_writer.buffer().len());
+        let log_bytes = file_len(&self.log_file_path)? + buffered as u64;
+        let records = (self.redundant_count + self.log_pointer().len()) as u64;
+        let dead_bytes = (log_bytes * self.redundant_count as u64)
+            .checked_div(records)
+            .unwrap_or(0);
+        Ok(Stats {
+            live_keys: self.len(),
+            log_bytes,
+            dead_bytes,
+            redundant_records: self.redundant_count,
+            compactions: self.compactions,
+            index_bytes: self.log_pointer().memory_usage(),
+        })
+    }
+
+    /// Flush the buffered records and sync the log file to disk, so that every write so
+    /// far survives a crash of the machine, whatever the `Durability` of the store.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to write the buffered records or to sync the log file.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.sync().unwrap();
+    /// ```
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(append_writer) = &mut self.append_writer {
+            append_writer.flush().context(ErrorKind::Io)?;
+            append_writer.get_ref().sync_data().context(ErrorKind::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the options of the store which can change while it is open.
+    pub fn runtime_options(&self) -> RuntimeOptions {
+        RuntimeOptions {
+            compaction_garbage_ratio: self.options.compaction_garbage_ratio,
+            max_key_size: self.options.max_key_size,
+            max_value_size: self.options.max_value_size,
+        }
+    }
+
+    /// Change the options of the store which can change while it is open. Writes after the
+    /// change are checked against the new limits, and the next compaction waits for the new
+    /// ratio of garbage. Records already in the log are kept whatever their size.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{ErrorKind, KvStore, RuntimeOptions};
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// kv.set_runtime_options(RuntimeOptions {
+    ///     max_value_size: Some(4),
+    ///     ..kv.runtime_options()
+    /// });
+    /// let e = kv.set("key1".to_owned(), "12345".to_owned()).unwrap_err();
+    /// assert_eq!(e.kind(), ErrorKind::ValueTooLarge);
+    /// ```
+    pub fn set_runtime_options(&mut self, options: RuntimeOptions) {
+        self.options.compaction_garbage_ratio = options.compaction_garbage_ratio;
+        self.options.max_key_size = options.max_key_size;
+        self.options.max_value_size = options.max_value_size;
+    }
+
+    /// Returns the space used by the store on disk and how much of it is live. See
+    /// `DiskUsage`.
+    ///
+    /// Buffered records are flushed first, then every live record is read, in log order.
+    /// Values in the value log are not read.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If the log file failed to be flushed, or metadata of a file failed to be read.
+    /// - Others: Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// for i in 0..10 {
+    ///     kv.set("key1".to_owned(), i.to_string()).unwrap();
+    /// }
+    ///
+    /// let usage = kv.disk_usage().unwrap();
+    /// assert!(usage.live_bytes < usage.disk_bytes);
+    /// assert!(usage.space_amplification > 1.0);
+    /// ```
+    pub fn disk_usage(&mut self) -> Result<DiskUsage> {
+        if let Some(append_writer) = &mut self.append_writer {
+            append_writer.flush().context(ErrorKind::Io)?;
+        }
+        let log_bytes = file_len(&self.log_file_path)?;
+        let value_log_bytes = match metadata(self.value_log.path()) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(Error::from(e.context(ErrorKind::Io))),
+        };
+
+        // live keys of the store and of its namespaces, in log order
+        let now = now_millis();
+        let mut pointers: Vec<_> = self
+            .log_pointer()
+            .iter_live(now)
+            .map(|(key, pointer)| (None, key.clone(), pointer.offset))
+            .collect();
+        for (namespace, log_pointer) in self.log_pointer().namespaces() {
+            pointers.extend(
+                log_pointer
+                    .iter_live(now)
+                    .map(|(key, pointer)| (Some(namespace.clone()), key.clone(), pointer.offset)),
+            );
+        }
+        pointers.sort_unstable_by_key(|x| x.2);
+
+        // a batch is counted once however many of its keys are live
+        let mut records = HashMap::new();
+        // values in the value log are counted once however many keys share them
+        let mut values = HashMap::new();
+        for (namespace, key, offset) in pointers {
+            let mut next = Some(offset);
+            while let Some(offset) = next {
+                let (kvlog, len) = self.reader.read_len_at(offset)?;
+                records.insert(offset, len);
+                let kvlog = match &namespace {
+                    Some(namespace) => kvlog.into_namespaced(namespace)?,
+                    None => kvlog,
+                };
+                // follow chains of appends and merges back to where they start
+                next = match kvlog.into_live_set(&key)? {
+                    // a log can only refer to an earlier one, which also rules out cycles
+                    KvLog::Append(_, _, previous) | KvLog::Merge(_, _, Some(previous))
+                        if previous >= offset =>
+                    {
+                        return Err(Error::from(ErrorKind::Corruption));
+                    }
+                    KvLog::Append(_, _, previous) | KvLog::Merge(_, _, Some(previous)) => {
+                        Some(previous)
+                    }
+                    KvLog::SetSeparated(_, value_offset, len, _) => {
+                        values.insert(value_offset, len);
+                        None
+                    }
+                    _ => None,
+                };
+            }
+        }
+
+        let disk_bytes = log_bytes + value_log_bytes;
+        let live_bytes = HEADER_LEN.min(log_bytes)
+            + records.values().sum::<u64>()
+            + values.values().sum::<u64>();
+        let space_amplification = if live_bytes == 0 {
+            1.0
+        } else {
+            disk_bytes as f64 / live_bytes as f64
+        };
+        Ok(DiskUsage {
+            disk_bytes,
+            live_bytes,
+            space_amplification,
+        })
+    }
+
+    /// Compacts the log file now, however few of its records are redundant, instead of
+    /// waiting for enough of them to be, see `Options::compaction_garbage_ratio`.
+    ///
+    /// # Errors
+    ///
+    /// - ReadOnly: If the store was opened read-only.
+    /// - Io: If the compacted log file failed to be written, or to replace the log file.
+    /// - Serde: If a record failed to be serialized or deserialized.
+    /// - Corruption: If the log file does not match the index in memory.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.set("key1".to_owned(), "13".to_owned()).unwrap();
+    ///
+    /// kv.compact().unwrap();
+    /// assert_eq!(kv.stats().unwrap().redundant_records, 0);
+    /// assert_eq!(kv.get("key1").unwrap(), Some("13".to_owned()));
+    /// ```
+    pub fn compact(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.compact_log()
+    }
+
+    /// Backs up the store to a directory, or to a tar archive if `dest` ends with `.tar`.
+    ///
+    /// Buffered records are flushed, then the log file up to its current length is copied
+    /// together with the bloom filter, the value log and a manifest, while the store stays
+    /// open. Records written after the backup starts are not part of it. A directory backup
+    /// can be opened as a store. Existing files of a backup at `dest` are overwritten.
+    ///
+    /// Returns a cursor of how far the log file was copied.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If the log file failed to be flushed or read, or the backup failed to be written.
+    /// - Serde: If the bloom filter or the manifest failed to be serialized.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path().join("store")).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    ///
+    /// kv.backup(tempdir.path().join("backup")).unwrap();
+    /// kv.set("key1".to_owned(), "13".to_owned()).unwrap();
+    /// drop(kv);
+    ///
+    /// let mut backup = KvStore::open(tempdir.path().join("backup")).unwrap();
+    /// assert_eq!(backup.get("key1").unwrap(), Some("12".to_owned()));
+    /// ```
+    pub fn backup(&mut self, dest: impl Into<PathBuf>) -> Result<BackupCursor> {
+        self.start_backup(dest)?.run()
+    }
+
+    /// Starts a backup like `backup`, copied by `BackupJob::run`, which can run later or on
+    /// another thread without holding up the store. The backup holds the records written
+    /// before it starts.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If the log file failed to be flushed or opened.
+    /// - Serde: If the bloom filter failed to be serialized.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use std::thread;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path().join("store")).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    ///
+    /// let job = kv.start_backup(tempdir.path().join("backup")).unwrap();
+    /// let copying = thread::spawn(move || job.run());
+    /// kv.set("key1".to_owned(), "13".to_owned()).unwrap();
+    /// copying.join().unwrap().unwrap();
+    ///
+    /// let mut backup = KvStore::open(tempdir.path().join("backup")).unwrap();
+    /// assert_eq!(backup.get("key1").unwrap(), Some("12".to_owned()));
+    /// ```
+    pub fn start_backup(&mut self, dest: impl Into<PathBuf>) -> Result<BackupJob> {
+        if let Some(append_writer) = self.append_writer.as_mut() {
+            append_writer.flush().context(ErrorKind::Io)?;
+        }
+        let (log_len, checksum) = log_stamp(&self.log_file_path)?;
+        let log_file = File::open(&self.log_file_path).context(ErrorKind::Io)?;
+        let bloom = self.bloom.encode(log_len, checksum)?;
+        let value_log = self.open_value_log()?;
+        Ok(BackupJob {
+            dest: dest.into(),
+            log_file,
+            log_len,
+            value_log,
+            bloom,
+        })
+    }
+
+    /// Open the value log file with its length, if it exists.
+    fn open_value_log(&self) -> Result<Option<(File, u64)>> {
+        match File::open(self.value_log.path()) {
+            Ok(file) => {
+                let len = file.metadata().context(ErrorKind::Io)?.len();
+                Ok(Some((file, len)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.context(ErrorKind::Io).into()),
+        }
+    }
+
+    /// Backs up the records appended to the log file since the backup that returned `cursor`,
+    /// to a directory, or to a tar archive if `dest` ends with `.tar`.
+    ///
+    /// Only the bytes after the cursor are copied, so the backup is only usable together
+    /// with the backups it continues. Returns a cursor for the next backup.
+    ///
+    /// # Errors
+    ///
+    /// - StaleBackupCursor: If the log file was compacted or cleared since the cursor was
+    ///   returned, which needs a full `backup` instead.
+    /// - Io: If the log file failed to be flushed or read, or the backup failed to be written.
+    /// - Serde: If the manifest failed to be serialized.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path().join("store")).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// let cursor = kv.backup(tempdir.path().join("full")).unwrap();
+    ///
+    /// kv.set("key2".to_owned(), "13".to_owned()).unwrap();
+    /// let cursor = kv.backup_since(&cursor, tempdir.path().join("incremental")).unwrap();
+    /// ```
+    pub fn backup_since(
+        &mut self,
+        cursor: &BackupCursor,
+        dest: impl Into<PathBuf>,
+    ) -> Result<BackupCursor> {
+        if let Some(append_writer) = self.append_writer.as_mut() {
+            append_writer.flush().context(ErrorKind::Io)?;
+        }
+        let log_len = file_len(&self.log_file_path)?;
+        let log_file = File::open(&self.log_file_path).context(ErrorKind::Io)?;
+        let value_log = self.open_value_log()?;
+        backup::write_backup(
+            &dest.into(),
+            log_file,
+            log_len,
+            value_log,
+            Some(cursor),
+            None,
+        )
+    }
+
+    /// Returns an iterator over all live key-value pairs, in the order they appear in the log.
+    ///
+    /// The live keys are taken when the iterator is created and each value is read when
+    /// the iterator reaches it, including values still in the write buffer.
+    ///
+    /// # Errors
+    ///
+    /// Each item has the same errors as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.set("key2".to_owned(), "13".to_owned()).unwrap();
+    /// for pair in kv.iter() {
+    ///     let (key, value) = pair.unwrap();
+    ///     println!("{} = {}", key, value);
+    /// }
+    /// ```
+    pub fn iter(&mut self) -> Iter<'_> {
+        let mut pointers: Vec<_> = self
+            .log_pointer()
+            .iter_live(now_millis())
+            .map(|(key, pointer)| (key.clone(), pointer.offset))
+            .collect();
+        // Sort by log pointer so values are read sequentially.
+        pointers.sort_unstable_by_key(|x| x.1);
+        Iter::new(self, pointers)
+    }
+
+    /// Loads key-value pairs into an empty store, returning the number of pairs loaded.
+    ///
+    /// The pairs are written straight to a new log file, which replaces the current one
+    /// like `clear` does once all of them are written, so either every pair is loaded or
+    /// none. The log pointer map is built as they are written, without compacting. Feeding
+    /// the pairs sorted by key keeps the log file in key order, which speeds up `range` and
+    /// `scan_prefix`. A pair later in `pairs` wins over an earlier one with the same key.
+    ///
+    /// All pairs share one sequence number, and no watch or write hook hears of them.
+    ///
+    /// # Errors
+    ///
+    /// - StoreNotEmpty: The store or one of its namespaces has keys.
+    /// - ReadOnly: The store was opened read-only.
+    /// - Others: Same as `set` and `clear`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// let pairs = (0..1000).map(|i| (format!("key{:04}", i), i.to_string()));
+    /// assert_eq!(kv.bulk_load(pairs).unwrap(), 1000);
+    /// assert_eq!(kv.get("key0042").unwrap(), Some("42".to_owned()));
+    /// kv.bulk_load(vec![("key1", "1")]).unwrap_err(); // not empty anymore
+    /// ```
+    pub fn bulk_load<I, K, V>(&mut self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.check_writable()?;
+        if self.has_keys() {
+            return Err(Error::from(ErrorKind::StoreNotEmpty));
+        }
+
+        let (temp_log_file_path, mut new_append_writer, new_reader) = self.create_temp_log()?;
+        let mut new_log_pointer = self.log_pointer().cleared(self.options.ordered_index);
+        let sequence = new_log_pointer.sequence() + 1;
+        let mut offset = file_len(&temp_log_file_path)? + new_append_writer.buffer().len() as u64;
+        let mut count = 0;
+        let mut redundant = 0;
+        for (key, value) in pairs {
+            let kvlog = KvLog::new_set(key.into(), value.into());
+            check_size(&kvlog, &self.options)?;
+            let kvlog = match self.options.value_log_threshold {
+                Some(threshold) => self.separate_value(kvlog, threshold)?,
+                None => kvlog,
+            };
+            let kvlog = KvLog::new_sequenced(sequence, kvlog);
+            let len = self.format.write(&kvlog, &mut new_append_writer)?;
+            redundant += index_log(&mut new_log_pointer, kvlog, offset, len);
+            offset += len;
+            count += 1;
+        }
+        new_append_writer.flush().context(ErrorKind::Io)?;
+
+        self.install_log(
+            &temp_log_file_path,
+            new_append_writer,
+            new_reader,
+            new_log_pointer,
+            None,
+        )?;
+        self.indexes.clear();
+        self.build_indexes()?;
+        self.add_redundant(redundant);
+        Ok(count)
+    }
+
+    /// Writes all live key-value pairs to `writer` in a portable format, in the order they
+    /// appear in the log. Returns the number of pairs written.
+    ///
+    /// Pairs are read one at a time like `iter` does, so the store is never held in memory.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If writing to `writer` failed.
+    /// - InvalidUtf8: If a key or value was set as bytes that are not valid UTF-8.
+    /// - Others: Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{Format, KvStore};
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    ///
+    /// let mut csv = Vec::new();
+    /// kv.export(&mut csv, Format::Csv).unwrap();
+    /// assert_eq!(csv, b"key,value\nkey1,12\n");
+    /// ```
+    pub fn export<W: Write>(&mut self, writer: W, format: Format) -> Result<usize> {
+        export::write_pairs(self.iter(), writer, format)
+    }
+
+    /// Reads key-value pairs in a portable format from `reader` and sets them, as written by
+    /// `export`. Returns the number of pairs read.
+    ///
+    /// Pairs are set in large batches through a large write buffer, like `multi_set` does.
+    /// They are made durable once at the end as `Options` asks, and compaction runs at most
+    /// once at the end. With write hooks, each batch is made durable before the hooks are
+    /// called with it instead. A pair read later wins over an earlier one with the same key.
+    /// If the input turns out malformed, the pairs before it have been set.
+    ///
+    /// # Errors
+    ///
+    /// - Io: If reading from `reader` failed.
+    /// - Serde: If the input is malformed, or a pair is missing its key or value.
+    /// - Others: Same as `set`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{Format, KvStore};
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// let csv = "key,value\nkey1,12\nkey2,13\n";
+    /// assert_eq!(kv.import(csv.as_bytes(), Format::Csv).unwrap(), 2);
+    /// assert_eq!(kv.get("key2").unwrap(), Some("13".to_owned()));
+    /// ```
+    pub fn import<R: Read>(&mut self, reader: R, format: Format) -> Result<usize> {
+        self.check_writable()?;
+        self.resize_write_buffer(IMPORT_BUFFER_SIZE)?;
+        self.importing = true;
+        let mut pairs = export::read_pairs(reader, format);
+        let mut import = || -> Result<usize> {
+            let mut count = 0;
+            loop {
+                let mut batch = Vec::with_capacity(IMPORT_BATCH_LEN);
+                let mut failed = None;
+                for pair in pairs.by_ref().take(IMPORT_BATCH_LEN) {
+                    match pair {
+                        Ok(pair) => batch.push(pair),
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
+                }
+                if batch.is_empty() && failed.is_none() {
+                    return Ok(count);
+                }
+                count += batch.len();
+                self.multi_set(batch)?;
+                if !self.pending_hooks.is_empty() {
+                    self.commit()?;
+                    let hooked = std::mem::take(&mut self.pending_hooks);
+                    self.call_hooks(hooked);
+                }
+                if let Some(e) = failed {
+                    return Err(e);
+                }
+            }
+        };
+        let imported = import();
+
+        // whatever was set is committed, and compacted if needed
+        self.importing = false;
+        let hooked = std::mem::take(&mut self.pending_hooks);
+        self.resize_write_buffer(self.options.write_buffer_size)?;
+        self.commit()?;
+        self.call_hooks(hooked);
+        self.add_redundant(0);
+        imported
+    }
+
+    /// Returns an iterator over live key-value pairs whose key starts with `prefix`,
+    /// in lexicographic order of keys.
+    ///
+    /// With an ordered index (see `Options::ordered_index`) only matching keys are visited,
+    /// otherwise the whole log pointer map is filtered.
+    ///
+    /// Matching keys are taken when the iterator is created and each value is read when
+    /// the iterator reaches it.
+    ///
+    /// # Errors
+    ///
+    /// Each item has the same errors as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("user:1:name".to_owned(), "Alice".to_owned()).unwrap();
+    /// kv.set("user:1:age".to_owned(), "20".to_owned()).unwrap();
+    /// kv.set("user:2:name".to_owned(), "Bob".to_owned()).unwrap();
+    /// let user1 = kv.scan_prefix("user:1:").collect::<kvs::Result<Vec<_>>>().unwrap();
+    /// assert_eq!(
+    ///     user1,
+    ///     vec![
+    ///         ("user:1:age".to_owned(), "20".to_owned()),
+    ///         ("user:1:name".to_owned(), "Alice".to_owned()),
+    ///     ]
+    /// );
+    /// ```
+    pub fn scan_prefix<K: AsRef<[u8]>>(&mut self, prefix: K) -> Iter<'_> {
+        let pointers = self.log_pointer().prefix(prefix.as_ref(), now_millis());
+        Iter::new(self, pointers)
+    }
+
+    /// Returns an iterator over live key-value pairs whose key is within `range`,
+    /// in lexicographic order of keys.
+    ///
+    /// With an ordered index (see `Options::ordered_index`) only keys in the range are visited,
+    /// otherwise the whole log pointer map is filtered.
+    ///
+    /// # Errors
+    ///
+    /// Each item has the same errors as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, Options};
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let options = Options {
+    ///     ordered_index: true,
+    ///     ..Options::default()
+    /// };
+    /// let mut kv = KvStore::open_with_options(tempdir.path(), options).unwrap();
+    ///
+    /// kv.set("a".to_owned(), "1".to_owned()).unwrap();
+    /// kv.set("b".to_owned(), "2".to_owned()).unwrap();
+    /// kv.set("c".to_owned(), "3".to_owned()).unwrap();
+    /// let pairs = kv.range("a".to_owned().."c".to_owned());
+    /// assert_eq!(pairs.map(|pair| pair.unwrap().0).collect::<Vec<_>>(), vec!["a", "b"]);
+    /// ```
+    pub fn range<R: RangeBounds<String>>(&mut self, range: R) -> Iter<'_> {
+        // UTF-8 preserves the order of strings, so the range holds for their bytes too
+        let range = (
+            range.start_bound().map(String::as_bytes),
+            range.end_bound().map(String::as_bytes),
+        );
+        let pointers = self.log_pointer().range(range, now_millis());
+        Iter::new(self, pointers)
+    }
+
+    /// Estimate the bytes of live data of the keys within `range`, without reading the log.
+    ///
+    /// It sums the lengths of the live records of those keys as recorded in the log pointer
+    /// map, including values in the value log and the earlier records of append and merge
+    /// chains. Commands of a batch share its record equally. Records are encoded as
+    /// `Options::compression` and `Options::encryption` ask, so this is their size on disk,
+    /// not the size of the keys and values. It is meant to compare ranges, such as to split
+    /// a store into parts of similar sizes.
+    ///
+    /// With an ordered index (see `Options::ordered_index`) only keys in the range are visited,
+    /// otherwise the whole log pointer map is filtered.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("a".to_owned(), "1".repeat(100)).unwrap();
+    /// kv.set("b".to_owned(), "2".repeat(1000)).unwrap();
+    /// let small = kv.approximate_size(.."b".to_owned());
+    /// let large = kv.approximate_size("b".to_owned()..);
+    /// assert!(small > 100 && large > 1000 && small < large);
+    /// ```
+    pub fn approximate_size<R: RangeBounds<String>>(&self, range: R) -> u64 {
+        let range = (
+            range.start_bound().map(String::as_bytes),
+            range.end_bound().map(String::as_bytes),
+        );
+        self.log_pointer().range_len(range, now_millis())
+    }
+
+    /// Returns the live key-value pairs whose index key is `index_key` in the secondary
+    /// index `name`, in lexicographic order of keys.
+    ///
+    /// # Errors
+    ///
+    /// - IndexNotFound: The store was not opened with an index named `name`.
+    /// - InvalidUtf8: If a key or value was set as bytes that are not valid UTF-8.
+    /// - Others: Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// See `SecondaryIndex`.
+    pub fn get_by_index<I: AsRef<[u8]>>(
+        &mut self,
+        name: &str,
+        index_key: I,
+    ) -> Result<Vec<(String, String)>> {
+        let keys = self
+            .indexes
+            .get(name, index_key.as_ref())
+            .ok_or_else(|| Error::from(ErrorKind::IndexNotFound))?;
+        let now = now_millis();
+        let pointers = keys
+            .into_iter()
+            .filter_map(|key| {
+                let offset = self.log_pointer().get_live(&key, now)?.offset;
+                Some((key, offset))
+            })
+            .collect();
+        Iter::new(self, pointers).collect()
+    }
+
+    /// Returns a page of at most `limit` live key-value pairs following `cursor`, in
+    /// lexicographic order of keys, with the cursor of the next page.
+    ///
+    /// Scanning page by page reads a store of any size without holding its keys, and the
+    /// cursor can be kept to resume after the store is reopened. See `ScanCursor`.
+    /// With an ordered index (see `Options::ordered_index`) only the keys of the page are
+    /// visited, otherwise the whole log pointer map is filtered for each page.
+    ///
+    /// # Errors
+    ///
+    /// - InvalidUtf8: If a key or value was set as bytes that are not valid UTF-8.
+    /// - Others: Same as `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, ScanCursor};
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// for key in ["c", "a", "b"] {
+    ///     kv.set(key.to_owned(), "1".to_owned()).unwrap();
+    /// }
+    ///
+    /// let page = kv.scan_from(&ScanCursor::default(), 2).unwrap();
+    /// assert_eq!(page.entries.len(), 2);
+    /// let page = kv.scan_from(&page.cursor.unwrap(), 2).unwrap();
+    /// assert_eq!(page.entries, vec![("c".to_owned(), "1".to_owned())]);
+    /// assert!(page.cursor.is_none());
+    /// ```
+    pub fn scan_from(&mut self, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        self.scan_prefix_from("", cursor, limit)
+    }
+
+    /// Returns a page of at most `limit` live key-value pairs whose key starts with
+    /// `prefix` following `cursor`, like `scan_from` for the keys of `scan_prefix`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `scan_from`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, ScanCursor};
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// for key in ["user:2", "post:1", "user:1"] {
+    ///     kv.set(key.to_owned(), "1".to_owned()).unwrap();
+    /// }
+    ///
+    /// let page = kv.scan_prefix_from("user:", &ScanCursor::default(), 1).unwrap();
+    /// assert_eq!(page.entries[0].0, "user:1");
+    /// let page = kv.scan_prefix_from("user:", &page.cursor.unwrap(), 1).unwrap();
+    /// assert_eq!(page.entries[0].0, "user:2");
+    /// assert!(page.cursor.is_none());
+    /// ```
+    pub fn scan_prefix_from<K: AsRef<[u8]>>(
+        &mut self,
+        prefix: K,
+        cursor: &ScanCursor,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        // one more key tells if there is a next page
+        let mut pointers = self.log_pointer().page(
+            prefix.as_ref(),
+            cursor.last_key(),
+            limit.saturating_add(1),
+            now_millis(),
+        );
+        let has_next = pointers.len() > limit;
+        pointers.truncate(limit);
+        let cursor = match pointers.last() {
+            Some((key, _)) if has_next => Some(ScanCursor::after(key.clone())),
+            _ if has_next => Some(cursor.clone()),
+            _ => None,
+        };
+        let entries = Iter::new(self, pointers).collect::<Result<_>>()?;
+        Ok(ScanPage { entries, cursor })
+    }
+
+    /// Removes all keys.
+    ///
+    /// Like compaction, an empty log file is written and swapped in for the current one,
+    /// so the store is either fully cleared or left untouched.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to create the new log file or to rename it to the log file.
+    /// - ReadOnly: The store was opened read-only.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// kv.clear().unwrap();
+    /// assert!(kv.is_empty());
+    /// ```
+    pub fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let (temp_log_file_path, new_append_writer, new_reader) = self.create_temp_log()?;
+        let new_log_pointer = self.log_pointer().cleared(self.options.ordered_index);
+        self.install_log(
+            &temp_log_file_path,
+            new_append_writer,
+            new_reader,
+            new_log_pointer,
+            None,
+        )?;
+        self.indexes.clear();
+        Ok(())
+    }
+
+    /// Removes the files of the store in the given directory.
+    ///
+    /// The directory itself is removed too if nothing else is left in it.
+    ///
+    /// # Errors
+    ///
+    /// - StoreLocked: The store is open.
+    /// - Io: Failed to remove a file of the store.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let path = tempdir.path().join("store");
+    /// let mut kv = KvStore::open(&path).unwrap();
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// drop(kv);
+    ///
+    /// KvStore::destroy(&path).unwrap();
+    /// assert!(!path.exists());
+    /// ```
+    pub fn destroy(path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        if !path.exists() {
+            return Ok(());
+        }
+        let _lock_file = lock_store(&path, None)?;
+        for file_name in STORE_FILE_NAMES.iter() {
+            let file_path = path.join(file_name);
+            if file_path.exists() {
+                remove_file(&file_path).context(ErrorKind::Io)?;
+            }
+        }
+        // fails if the directory is not empty, which is fine
+        let _ = remove_dir(&path);
+        Ok(())
+    }
+
+    /// Upgrades the log file of the store in the given directory to the current format.
+    ///
+    /// Log files of version 1, written before log files had a header, are rewritten with one.
+    /// Later versions only get the version in their header rewritten, which `open` also
+    /// does. Returns whether the log file was upgraded, or `false` if it is already current.
+    ///
+    /// # Errors
+    ///
+    /// - StoreLocked: The store is open.
+    /// - UnsupportedVersion: The log file has an unknown format version.
+    /// - Io: Failed to read the log file or to write the upgraded one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// drop(KvStore::open(tempdir.path()).unwrap());
+    /// assert!(!KvStore::migrate(tempdir.path()).unwrap());
+    /// ```
+    pub fn migrate(path: impl Into<PathBuf>) -> Result<bool> {
+        let path = path.into();
+        let _lock_file = lock_store(&path, None)?;
+        let log_file_path = path.join(LOG_FILE_NAME);
+        let mut log_file = File::open(&log_file_path).context(ErrorKind::Io)?;
+        match format::read_version(&mut log_file)? {
+            Some(HEADERLESS_VERSION) => {}
+            Some(FORMAT_VERSION) | None => return Ok(false),
+            Some(version) if format::is_readable(version) => {
+                format::upgrade_header(&log_file_path)?;
+                write_manifest(&path, None, None)?;
+                return Ok(true);
+            }
+            Some(_) => return Err(Error::from(ErrorKind::UnsupportedVersion)),
+        }
+
+        // copy the records after a header, then replace the old file with the copy
+        write_manifest(&path, None, Some(TEMP_LOG_FILE_NAME))?;
+        let temp_log_file_path = path.join(TEMP_LOG_FILE_NAME);
+        let mut writer = BufWriter::new(File::create(&temp_log_file_path).context(ErrorKind::Io)?);
+        format::write_header(&mut writer)?;
+        log_file.seek(SeekFrom::Start(0)).context(ErrorKind::Io)?;
+        io::copy(&mut log_file, &mut writer).context(ErrorKind::Io)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .context(ErrorKind::Io)?;
+        rename(&temp_log_file_path, &log_file_path).context(ErrorKind::Io)?;
+        write_manifest(&path, None, None)?;
+        Ok(true)
+    }
 
-impl KvStore {
-    /// Set a key-value pair.
+    /// Salvages the readable records of a damaged log file into a new one.
     ///
-    /// If the KvStore did have this key present, the value is updated via a new set command appended.
-    /// The new command is not necessarily writen to log file immediately due to buffer.
+    /// Damaged regions are skipped byte by byte until a record can be read again. After
+    /// damage, a record is only trusted if the next one can be read too. The live keys are
+    /// then written to a new log file like compaction does, dropping keys whose value
+    /// depended on lost records. Values in the value log are moved into the new log file.
+    /// The old log file is copied to a backup first.
+    ///
+    /// The whole log file is read into memory. Stores with encrypted, compressed or merged
+    /// records need `repair_with_options`.
     ///
     /// # Errors
     ///
-    /// - Io: Failed to open log file or failed to read metadata of log file
-    /// - Serde: Failed to serialize the set command
+    /// - StoreLocked: The store is open.
+    /// - UnsupportedVersion: The log file has an unknown format version or needs `migrate`.
+    /// - Io: Failed to read the log file or to write the backup or the new log file.
+    /// - Serde: Failed to serialize a salvaged record.
     ///
     /// # Examples
     ///
@@ -127,40 +3509,156 @@ impl KvStore {
     /// use tempfile::TempDir;
     ///
     /// let tempdir = TempDir::new().unwrap();
-    /// let path = tempdir.path();
-    /// let mut kv = KvStore::open(path).unwrap();
-    ///
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
     /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
-    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("12".to_owned()));
+    /// drop(kv);
     ///
-    /// kv.set("key1".to_owned(), "11".to_owned()).unwrap();
-    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("11".to_owned()));
+    /// let report = KvStore::repair(tempdir.path()).unwrap();
+    /// assert_eq!(report.salvaged_records, 1);
+    /// assert_eq!(report.dropped_bytes, 0);
     /// ```
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        // record current offset
-        let new_offset = file_len(&self.log_file_path)? + self.append_writer.buffer().len() as u64;
+    pub fn repair(path: impl Into<PathBuf>) -> Result<RepairReport> {
+        KvStore::repair_with_options(path, &Options::default())
+    }
 
-        // append log
-        let kvlog = KvLog::new_set(key, value);
-        kvlog.serialize_to_writer(&mut self.append_writer)?;
+    /// Salvages a damaged log file like `repair`, reading and writing records with the
+    /// codec, compression, encryption and merge operator of `options`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `repair`.
+    pub fn repair_with_options(
+        path: impl Into<PathBuf>,
+        options: &Options,
+    ) -> Result<RepairReport> {
+        let path = path.into();
+        let _lock_file = lock_store(&path, None)?;
+        let log_file_path = path.join(LOG_FILE_NAME);
+        let bytes = read(&log_file_path).context(ErrorKind::Io)?;
+        match format::read_version(bytes.as_slice())? {
+            Some(version) if format::is_readable(version) => {}
+            None => {}
+            Some(_) => return Err(Error::from(ErrorKind::UnsupportedVersion)),
+        }
 
-        // update log pointer map
-        if let Some(_) = self.log_pointer.insert(kvlog.into_key(), new_offset) {
-            self.increment_redundant();
+        let format = RecordFormat::new(options);
+        let read_at = |offset: u64| -> Result<(KvLog, u64)> {
+            let record = bytes
+                .get(offset as usize..)
+                .ok_or_else(|| Error::from(ErrorKind::Corruption))?;
+            let (kvlog, len) = format.read_slice(record)?;
+            Ok((kvlog, len as u64))
         };
 
-        Ok(())
+        // replay every readable record, skipping damaged regions
+        let mut report = RepairReport {
+            salvaged_records: 0,
+            damaged_regions: 0,
+            dropped_bytes: 0,
+            dropped_keys: 0,
+            backup_path: path.join(format!("{}.{}.bak", LOG_FILE_NAME, now_millis())),
+        };
+        let mut log_pointer = LogPointerMap::new(options.ordered_index);
+        let mut salvaged = HashSet::new();
+        let mut offset = HEADER_LEN;
+        let mut damaged = false;
+        while offset < bytes.len() as u64 {
+            let record = read_at(offset).ok().filter(|(_, len)| {
+                let next = offset + len;
+                !damaged || next == bytes.len() as u64 || read_at(next).is_ok()
+            });
+            match record {
+                Some((kvlog, len)) => {
+                    index_log(&mut log_pointer, kvlog, offset, len);
+                    salvaged.insert(offset);
+                    report.salvaged_records += 1;
+                    offset += len;
+                    damaged = false;
+                }
+                None => {
+                    if !damaged {
+                        report.damaged_regions += 1;
+                        damaged = true;
+                    }
+                    report.dropped_bytes += 1;
+                    offset += 1;
+                }
+            }
+        }
+
+        // write the live keys to a new log file, moving values back from the value log
+        let mut value_log = ValueLog::new(path.join(VALUE_LOG_FILE_NAME));
+        write_manifest(&path, None, Some(TEMP_LOG_FILE_NAME))?;
+        let temp_log_file_path = path.join(TEMP_LOG_FILE_NAME);
+        let mut writer = BufWriter::new(File::create(&temp_log_file_path).context(ErrorKind::Io)?);
+        format::write_header(&mut writer)?;
+        let marker = KvLog::new_sequenced(log_pointer.sequence(), KvLog::Batch(Vec::new()));
+        format.write(&marker, &mut writer)?;
+        log_pointer.remove_expired(now_millis());
+        let mut log_pointers = log_pointer.iter().collect::<Vec<_>>();
+        log_pointers.sort_unstable_by_key(|(_, pointer)| pointer.offset);
+        for (key, pointer) in log_pointers {
+            let value = read_value_chain(
+                key,
+                pointer.offset,
+                options.merge_operator.as_ref(),
+                |offset| {
+                    if !salvaged.contains(&offset) {
+                        return Err(Error::from(ErrorKind::Corruption));
+                    }
+                    value_log.resolve(read_at(offset)?.0)
+                },
+            );
+            let value = match value {
+                Ok(value) => value,
+                Err(_) => {
+                    report.dropped_keys += 1;
+                    continue;
+                }
+            };
+            let kvlog = match pointer.expires_at {
+                Some(expires_at) => KvLog::new_set_ex(key.clone(), value, expires_at),
+                None => KvLog::new_set(key.clone(), value),
+            };
+            format.write(&KvLog::new_sequenced(pointer.sequence, kvlog), &mut writer)?;
+        }
+        for (namespace, log_pointer) in log_pointer.namespaces_mut() {
+            let mut log_pointers = log_pointer.iter().collect::<Vec<_>>();
+            log_pointers.sort_unstable_by_key(|(_, pointer)| pointer.offset);
+            for (key, pointer) in log_pointers {
+                // pointers only refer to salvaged set commands
+                let kvlog = read_at(pointer.offset)?
+                    .0
+                    .into_namespaced(namespace)?
+                    .into_live_set(key)?;
+                let kvlog = KvLog::new_namespaced(namespace.clone(), kvlog);
+                format.write(&KvLog::new_sequenced(pointer.sequence, kvlog), &mut writer)?;
+            }
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .context(ErrorKind::Io)?;
+
+        // keep the damaged log, then replace it
+        copy(&log_file_path, &report.backup_path).context(ErrorKind::Io)?;
+        rename(&temp_log_file_path, &log_file_path).context(ErrorKind::Io)?;
+        write_manifest(&path, None, None)?;
+        Ok(report)
     }
 
-    /// Returns the value corresponding to the key.
+    /// Restores a store from a full backup made by `backup` into the given directory.
     ///
-    /// The returned value is a copy of the value stored in `KvStore` if present.
+    /// The backup is checked against its manifest before the store is created. The
+    /// directory must not exist or be empty, see `restore_with_options` to replace a store.
     ///
     /// # Errors
     ///
-    /// - Io: If log file or its metadata failed to be read
-    /// - Serde: If log deserialization failed when reading log file.
-    /// - Corruption: If log file is different from log pointer map in memory.
+    /// - StoreExists: The directory is not empty.
+    /// - InvalidBackup: The backup has no manifest or its checksum does not match.
+    /// - UnsupportedVersion: The backup is of an unknown format version.
+    /// - Io: Failed to read the backup or to write the store.
     ///
     /// # Examples
     ///
@@ -169,73 +3667,120 @@ impl KvStore {
     /// use tempfile::TempDir;
     ///
     /// let tempdir = TempDir::new().unwrap();
-    /// let path = tempdir.path();
-    /// let mut kv = KvStore::open(path).unwrap();
-    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), None);
-    ///
+    /// let mut kv = KvStore::open(tempdir.path().join("store")).unwrap();
     /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
-    /// let returned_opt = kv.get("key1".to_owned()).unwrap();
-    /// assert_eq!(returned_opt, Some("12".to_owned()));
+    /// kv.backup(tempdir.path().join("backup.tar")).unwrap();
     ///
-    /// kv.set("key1".to_owned(), "11".to_owned()).unwrap();
-    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("11".to_owned()));
-    /// assert_eq!(returned_opt, Some("12".to_owned()));
+    /// KvStore::restore(tempdir.path().join("backup.tar"), tempdir.path().join("restored")).unwrap();
+    /// let mut restored = KvStore::open(tempdir.path().join("restored")).unwrap();
+    /// assert_eq!(restored.get("key1").unwrap(), Some("12".to_owned()));
     /// ```
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.log_pointer.get(&key) {
-            None => Ok(None),
-            Some(&offset) => match self.get_kvlog_from_offset(offset)? {
-                KvLog::Set(_k, v) => {
-                    if CORRUPTION_CHECK {
-                        if key != _k {
-                            return Err(Error::from(ErrorKind::Corruption));
-                        }
-                    }
-                    Ok(Some(v))
-                }
-                _ => Err(Error::from(ErrorKind::Corruption)),
-            },
-        }
+    pub fn restore(backup_path: impl Into<PathBuf>, path: impl Into<PathBuf>) -> Result<()> {
+        KvStore::restore_with_options(backup_path, path, &RestoreOptions::default())
     }
 
-    /// Underlying implementation for get
-    /// Please refer to `get`
-    fn get_kvlog_from_offset(&mut self, offset: u64) -> Result<KvLog> {
-        let log_len = file_len(&self.log_file_path)?;
+    /// Restores a store like `restore`, applying the incremental backups of `options` after
+    /// the full backup and replacing an existing store if it is forced to.
+    ///
+    /// # Errors
+    ///
+    /// - StoreLocked: The store to replace is open.
+    /// - InvalidBackup: An incremental backup does not continue the backup before it.
+    /// - Others: Same as `restore`.
+    pub fn restore_with_options(
+        backup_path: impl Into<PathBuf>,
+        path: impl Into<PathBuf>,
+        options: &RestoreOptions,
+    ) -> Result<()> {
+        let backup_path = backup_path.into();
+        let path = path.into();
+        if !options.force
+            && path.exists()
+            && read_dir(&path).context(ErrorKind::Io)?.next().is_some()
+        {
+            return Err(Error::from(ErrorKind::StoreExists));
+        }
+        create_dir_all(&path).context(ErrorKind::Io)?;
+        let _lock_file = lock_store(&path, None)?;
 
-        let kvlog = if offset >= log_len {
-            // log is still in buffer
-            let buffer = self.append_writer.buffer();
-            let mut reader = Cursor::new(buffer);
-            reader
-                .seek(SeekFrom::Start(offset - log_len))
+        // write the log files aside, so nothing is replaced if the backup turns out damaged
+        write_manifest(&path, None, Some(TEMP_LOG_FILE_NAME))?;
+        let temp_log_file_path = path.join(TEMP_LOG_FILE_NAME);
+        let temp_value_log_path = path.join(TEMP_VALUE_LOG_FILE_NAME);
+        let mut writer = BufWriter::new(File::create(&temp_log_file_path).context(ErrorKind::Io)?);
+        let mut value_log_writer =
+            BufWriter::new(File::create(&temp_value_log_path).context(ErrorKind::Io)?);
+        let restored = backup::restore_log(
+            &backup_path,
+            &options.incremental,
+            &mut writer,
+            &mut value_log_writer,
+        )
+        .and_then(|bloom| {
+            backup::check_restored_log(&temp_log_file_path)?;
+            Ok(bloom)
+        });
+        let bloom = match restored {
+            Ok(bloom) => bloom,
+            Err(e) => {
+                drop(writer);
+                drop(value_log_writer);
+                let _ = remove_file(&temp_log_file_path);
+                let _ = remove_file(&temp_value_log_path);
+                return Err(e);
+            }
+        };
+        let mut value_log_len = 0;
+        for writer in [writer, value_log_writer] {
+            let file = writer
+                .into_inner()
+                .map_err(|e| e.into_error())
                 .context(ErrorKind::Io)?;
-            KvLog::deserialize_from_reader(reader)?
+            file.sync_all().context(ErrorKind::Io)?;
+            value_log_len = file.metadata().context(ErrorKind::Io)?.len();
+        }
+
+        // values of a replaced store must not be taken for the restored ones
+        let value_log_path = path.join(VALUE_LOG_FILE_NAME);
+        if value_log_len > 0 {
+            rename(&temp_value_log_path, &value_log_path).context(ErrorKind::Io)?;
         } else {
-            // log is in file
-            let reader = &mut self.reader;
-            reader
-                .seek(SeekFrom::Start(offset))
-                .context(ErrorKind::Io)?;
-            KvLog::deserialize_from_reader(reader)?
-        };
+            remove_file(&temp_value_log_path).context(ErrorKind::Io)?;
+            if value_log_path.exists() {
+                remove_file(&value_log_path).context(ErrorKind::Io)?;
+            }
+        }
 
-        Ok(kvlog)
+        // the bloom filter of a replaced store must not be taken for the new log file's
+        let bloom_file_path = path.join(BLOOM_FILE_NAME);
+        if bloom_file_path.exists() {
+            remove_file(&bloom_file_path).context(ErrorKind::Io)?;
+        }
+        rename(&temp_log_file_path, path.join(LOG_FILE_NAME)).context(ErrorKind::Io)?;
+        if let Some(bloom) = bloom {
+            write(&bloom_file_path, bloom).context(ErrorKind::Io)?;
+        }
+        write_manifest(&path, None, None)
     }
 
-    /// Removes a key from the map if the key is present.
+    /// Opens a KvStore from given directory and setup the in-memory log pointer map.
     ///
-    /// If the KvStore did have this key present, the value is "removed" via a new remove command appended.
-    /// The new command is not necessarily writen to log file immediately due to buffer.
+    /// The directory will be created if not exist.
+    /// An incomplete log at the end of the log file, left by a crash during a write, is discarded.
+    /// A log file of an older format version with a header is upgraded to the current one,
+    /// unless the store is opened read-only.
     ///
     /// # Errors
     ///
-    /// - KeyNotFound: If the key does not exist.
-    /// - Serde: If log serialization failed.
+    /// - Io: If creation of directory failed or file failed to open.
+    /// - StoreLocked: If the store is already open for writing, in this or another process.
+    /// - BadEncryptionKey: If the log has encrypted records. See `open_with_options`.
+    /// - UnsupportedVersion: If the log file has an unknown format version, or needs `migrate`.
+    /// - Serde: If log deserialization failed when reading log file.
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```rust
     /// use kvs::KvStore;
     /// use tempfile::TempDir;
     ///
@@ -243,39 +3788,61 @@ impl KvStore {
     /// let path = tempdir.path();
     /// let mut kv = KvStore::open(path).unwrap();
     ///
-    /// kv.remove("key1".to_owned()).unwrap_err(); // missing key
+    /// kv.set("key1".to_owned(), "42".to_owned()).unwrap();
+    /// // Do other things ...
+    /// ```
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, Options::default())
+    }
+
+    /// Opens the store named `name` in the given directory, or creates it.
     ///
-    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
-    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("12".to_owned()));
+    /// A directory can hold several independent stores side by side: the store opened by
+    /// `open` and any number of named stores. The files of a named store start with its
+    /// name, like `sessions.bin` for its log file. Each store has its own lock, so they
+    /// can be open at the same time. The name can have ASCII letters, digits, `-` and `_`.
     ///
-    /// kv.remove("key1".to_owned()).unwrap();
-    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), None);
+    /// Opening a named store with options is done with `Options::store_name`. `destroy`,
+    /// `migrate`, `repair` and `restore` work on the default store of a directory, and a
+    /// backup of a named store is restored as one.
+    ///
+    /// # Errors
+    ///
+    /// - InvalidStoreName: The name is empty, is "0", or has other characters.
+    /// - Others: Same as `open`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// let mut sessions = KvStore::open_named(tempdir.path(), "sessions").unwrap();
     ///
+    /// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+    /// sessions.set("key1".to_owned(), "34".to_owned()).unwrap();
+    /// assert_eq!(kv.get("key1".to_owned()).unwrap(), Some("12".to_owned()));
+    /// assert_eq!(sessions.get("key1".to_owned()).unwrap(), Some("34".to_owned()));
     /// ```
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.log_pointer.contains_key(&key) {
-            // update log file
-            let kvlog = KvLog::new_rm(key);
-            kvlog.serialize_to_writer(&mut self.append_writer)?;
-
-            // update log pointer map
-            if let Some(_) = self.log_pointer.remove(&kvlog.into_key()) {
-                self.increment_redundant();
-            };
-
-            Ok(())
-        } else {
-            Err(Error::from(ErrorKind::KeyNotFound))
-        }
+    pub fn open_named(path: impl Into<PathBuf>, name: &str) -> Result<KvStore> {
+        let options = Options {
+            store_name: Some(name.to_owned()),
+            ..Options::default()
+        };
+        KvStore::open_with_options(path, options)
     }
 
-    /// Opens a KvStore from given directory and setup the in-memory log pointer map.
+    /// Opens an existing KvStore for reading only.
     ///
-    /// The directory will be created if not exist.
+    /// Nothing in the directory is created or modified, so this works for stores on
+    /// read-only filesystems. An incomplete log at the end of the log file is ignored
+    /// instead of discarded. Every method that modifies the store fails with ReadOnly.
     ///
     /// # Errors
     ///
-    /// - Io: If creation of directory failed or file failed to open.
+    /// - Io: If the log file failed to open, for example because the store does not exist.
     /// - Serde: If log deserialization failed when reading log file.
     ///
     /// # Examples
@@ -285,61 +3852,240 @@ impl KvStore {
     /// use tempfile::TempDir;
     ///
     /// let tempdir = TempDir::new().unwrap();
-    /// let path = tempdir.path();
-    /// let mut kv = KvStore::open(path).unwrap();
+    /// let mut kv = KvStore::open(tempdir.path()).unwrap();
+    /// kv.set("key1".to_owned(), "42".to_owned()).unwrap();
+    /// drop(kv);
+    ///
+    /// let mut kv = KvStore::open_read_only(tempdir.path()).unwrap();
+    /// assert_eq!(kv.get("key1").unwrap(), Some("42".to_owned()));
+    /// assert!(kv.set("key1".to_owned(), "43".to_owned()).is_err());
+    /// ```
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        KvStore::open_with_options(path, options)
+    }
+
+    /// Returns a builder to open a KvStore with custom settings. See `KvStoreBuilder`.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    /// Opens a KvStore like `open`, but with the given options.
+    ///
+    /// # Errors
+    ///
+    /// Same as `open`, and:
+    ///
+    /// - CompressionUnavailable: If `Options::compression` is a codec not enabled in this build.
+    /// - BadEncryptionKey: If the log has records encrypted with another key than
+    ///   `Options::encryption`, or it is not set.
+    /// - Unsupported: If both `Options::encryption` and `Options::value_log_threshold` are
+    ///   set, as values in the value log would be stored in clear.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use kvs::{GroupCommit, KvStore, Options};
+    /// use tempfile::TempDir;
+    ///
+    /// let tempdir = TempDir::new().unwrap();
+    /// let options = Options {
+    ///     group_commit: Some(GroupCommit::default()),
+    ///     ..Options::default()
+    /// };
+    /// let mut kv = KvStore::open_with_options(tempdir.path(), options).unwrap();
     ///
     /// kv.set("key1".to_owned(), "42".to_owned()).unwrap();
-    /// // Do other things ...
     /// ```
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+    pub fn open_with_options(path: impl Into<PathBuf>, options: Options) -> Result<KvStore> {
+        if !options.compression.is_available() {
+            return Err(Error::from(ErrorKind::CompressionUnavailable));
+        }
+        if options.encryption.is_some() && options.value_log_threshold.is_some() {
+            return Err(Error::from(ErrorKind::Unsupported));
+        }
+        let name = options.store_name.as_deref();
+        if let Some(name) = name {
+            check_store_name(name)?;
+        }
         let path = path.into();
         let dir_path = path.as_path();
-        if !dir_path.exists() {
+        if !options.read_only && !dir_path.exists() {
             create_dir(dir_path).context(ErrorKind::Io)?;
         }
+        let lock_file = if options.read_only {
+            None
+        } else {
+            Some(lock_store(dir_path, name)?)
+        };
+        recover_manifest(dir_path, name, options.read_only)?;
 
         // set up log file path
-        let log_file_path = dir_path.join(LOG_FILE_NAME);
+        let log_file_path = dir_path.join(store_file_name(name, LOG_FILE_NAME));
 
         // set up append_writer used by set and rm
-        let append_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&log_file_path)
+        let mut append_writer = if options.read_only {
+            None
+        } else {
+            let append_file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_file_path)
+                .context(ErrorKind::Io)?;
+            Some(BufWriter::with_capacity(
+                options.write_buffer_size,
+                append_file,
+            ))
+        };
+
+        let mut reader = BufReader::with_capacity(
+            options.read_ahead_size.max(1),
+            File::open(&log_file_path).context(ErrorKind::Io)?,
+        );
+
+        // check the format version, writing the header of a new log file
+        match format::read_version(&mut reader)? {
+            Some(FORMAT_VERSION) => {}
+            // older records are read the same, new ones may only follow a new version
+            Some(version) if format::is_readable(version) => {
+                if !options.read_only {
+                    format::upgrade_header(&log_file_path)?;
+                }
+            }
+            Some(_) => return Err(Error::from(ErrorKind::UnsupportedVersion)),
+            None => {
+                if let Some(append_writer) = append_writer.as_mut() {
+                    append_writer.get_ref().set_len(0).context(ErrorKind::Io)?;
+                    format::write_header(&mut *append_writer)?;
+                    append_writer.flush().context(ErrorKind::Io)?;
+                }
+            }
+        }
+        reader
+            .seek(SeekFrom::Start(HEADER_LEN))
             .context(ErrorKind::Io)?;
-        let append_writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, append_file);
 
         // build log pointer map
-        let mut reader = BufReader::new(File::open(&log_file_path).context(ErrorKind::Io)?);
-        let mut log_pointer: LogPointerMap = HashMap::new();
+        let format = RecordFormat::new(&options);
+        let mut log_pointer = LogPointerMap::new(options.ordered_index);
         let mut redundant_count = 0;
         while has_more(&mut reader)? {
             let pos = position(&mut reader)?;
-            let update_result = match KvLog::deserialize_from_reader(&mut reader)? {
-                KvLog::Set(log_key, _) => log_pointer.insert(log_key, pos),
-                KvLog::Rm(log_key) => log_pointer.remove(&log_key),
-            };
-            if let Some(_) = update_result {
-                redundant_count += 1;
+            match format.read(&mut reader) {
+                Ok(kvlog) => {
+                    let len = position(&mut reader)? - pos;
+                    redundant_count += index_log(&mut log_pointer, kvlog, pos, len);
+                }
+                // A log cut short by a crash is discarded, as if it was never written.
+                Err(e) if e.kind() != ErrorKind::Serde => return Err(e),
+                Err(_) if !has_more(&mut reader)? && options.read_only => {
+                    eprintln!("Ignoring incomplete log at the end of log file");
+                }
+                Err(_) if !has_more(&mut reader)? => {
+                    eprintln!("Discarding incomplete log at the end of log file");
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&log_file_path)
+                        .and_then(|file| file.set_len(pos))
+                        .context(ErrorKind::Io)?;
+                }
+                Err(e) => return Err(e),
             }
         }
 
-        Ok(KvStore {
+        // reuse the persisted bloom filter if it is up to date
+        let bloom_file_path = dir_path.join(store_file_name(name, BLOOM_FILE_NAME));
+        let (log_len, checksum) = log_stamp(&log_file_path)?;
+        let bloom = BloomFilter::load(&bloom_file_path, log_len, checksum)
+            .unwrap_or_else(|| BloomFilter::from_index(&log_pointer));
+
+        let flusher = match &options.group_commit {
+            Some(config) if !options.read_only => Some(spawn_flusher(&log_file_path, config)?),
+            _ => None,
+        };
+
+        let mut store = KvStore {
             log_file_path,
-            reader,
+            bloom_file_path,
+            reader: LogReader::new(reader.into_inner(), options.read_ahead_size, format.clone()),
+            format,
+            value_log: ValueLog::new(dir_path.join(store_file_name(name, VALUE_LOG_FILE_NAME))),
             append_writer,
-            log_pointer,
+            log_pointer: Arc::new(RwLock::new(log_pointer)),
+            cache: ValueCache::new(options.value_cache_bytes),
+            bloom,
             redundant_count,
-        })
+            compactions: 0,
+            log_generation: 0,
+            replicated: false,
+            previous_log: None,
+            full_copies: Vec::new(),
+            next_copy_id: 0,
+            importing: false,
+            pending_hooks: Vec::new(),
+            flusher,
+            watchers: Watchers::default(),
+            indexes: Indexes::new(&options.indexes),
+            options,
+            _lock_file: lock_file,
+        };
+        // readers open the value log when they start, so it has to exist before the first
+        // value is appended to it for them to keep the one compaction replaces
+        if store.options.value_log_threshold.is_some() && !store.options.read_only {
+            store.value_log.writer()?;
+        }
+        store.build_indexes()?;
+        Ok(store)
+    }
+
+    /// Check that the store can be modified.
+    ///
+    /// # Errors
+    ///
+    /// - ReadOnly: The store was opened read-only.
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            Err(Error::from(ErrorKind::ReadOnly))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Log pointer map.
+    pub(crate) fn log_pointer(&self) -> RwLockReadGuard<'_, LogPointerMap> {
+        self.log_pointer.read().unwrap()
+    }
+
+    /// Log pointer map, borrowed for as long as the store.
+    fn log_pointer_ref(&self) -> &LogPointerMap {
+        let log_pointer: *const LogPointerMap = &*self.log_pointer();
+        // SAFETY: the map is only written through `log_pointer_mut` and replaced, both of
+        // which borrow the store mutably, while snapshots only read it. So it is neither
+        // changed nor dropped while the store is borrowed.
+        unsafe { &*log_pointer }
+    }
+
+    /// Log pointer map for modification. The store is the only writer of the map.
+    fn log_pointer_mut(&mut self) -> RwLockWriteGuard<'_, LogPointerMap> {
+        self.log_pointer.write().unwrap()
     }
 
-    /// Increment redundant count and compact the log file if needed.
+    /// Increase redundant count and compact the log file if needed.
     /// If compaction failed, will print an error message without panicking.
     /// See `compact` for more information.
-    fn increment_redundant(&mut self) {
-        self.redundant_count += 1;
-        if self.redundant_count >= COMPACT_REDUNDANT_THRESHOLD {
-            match self.compact() {
+    fn add_redundant(&mut self, count: usize) {
+        self.redundant_count += count;
+        if self.importing {
+            return;
+        }
+        let records = self.redundant_count + self.log_pointer().len();
+        if self.redundant_count >= COMPACT_REDUNDANT_THRESHOLD
+            && self.redundant_count as f64 >= self.options.compaction_garbage_ratio * records as f64
+        {
+            match self.compact_log() {
                 Ok(_) => {}
                 Err(e) => {
                     eprintln!("Failed to compact: {:?}", e);
@@ -349,7 +4095,8 @@ impl KvStore {
     }
 
     /// Compact the log file. Only keep the latest set records for each key.
-    /// If latest record for a key is rm, the key will not be present at all after compaction.
+    /// If latest record for a key is rm, the key will not be present at all after compaction,
+    /// unless its tombstone is retained by `Options::tombstone_retention`.
     ///
     /// It will create a new file and write the new compacted log in it.
     /// If anything failed, the in-memory KvStore and log file will not be modified
@@ -368,10 +4115,156 @@ impl KvStore {
     /// - Io: Failed to open/write to/read metadata of the temp file or failed to rename the temp file to log file.
     /// - Serde: Failed to serialize or deserialize `KvLog` entries.
     /// - Corruption: If log file is different from log pointer map in memory.
-    fn compact(&mut self) -> Result<()> {
+    fn compact_log(&mut self) -> Result<()> {
+        let (temp_log_file_path, mut new_append_writer, new_reader) = self.create_temp_log()?;
+        // live values move to a new value log, or back to the log file without a threshold
+        let threshold = self.options.value_log_threshold;
+        let mut new_value_log = match threshold {
+            Some(_) => Some(ValueLog::create(temp_log_file_path.with_file_name(
+                store_file_name(
+                    self.options.store_name.as_deref(),
+                    TEMP_COMPACT_VALUE_LOG_FILE_NAME,
+                ),
+            ))?),
+            None => None,
+        };
+        // values shared by several keys are moved once
+        let mut moved_values = HashMap::new();
+
+        // Make sure the original log pointer map is not modified.
+        let mut new_log_pointer = self.log_pointer().clone();
+        // Earlier versions and expired keys are dropped.
+        new_log_pointer.drop_versions();
+        let now = now_millis();
+        new_log_pointer.remove_expired(now);
+        let removed_until = match self.options.tombstone_retention {
+            TombstoneRetention::UntilCompaction => u64::MAX,
+            TombstoneRetention::For(retention) => now.saturating_sub(retention.as_millis() as u64),
+            // no key was removed at the UNIX epoch
+            TombstoneRetention::Forever => 0,
+        };
+        new_log_pointer.drop_tombstones(removed_until);
+        let mut log_pointers = new_log_pointer.iter_mut().collect::<Vec<_>>();
+        // Sort by log pointer to ensure original order in log file is preserved.
+        log_pointers.sort_unstable_by_key(|x| x.1.offset);
+        for (key, pointer) in log_pointers {
+            // Batches are split up, only the live set command of each key is kept, with the
+            // sequence number of the batch.
+            let kvlog = match self.read_live_log(key, pointer.offset)? {
+                // Chains of appends and merges are folded into one set command.
+                KvLog::Append(..) | KvLog::Merge(..) => {
+                    let value = self.read_value(key, pointer.offset)?;
+                    match pointer.expires_at {
+                        Some(expires_at) => KvLog::new_set_ex(key.clone(), value, expires_at),
+                        None => KvLog::new_set(key.clone(), value),
+                    }
+                }
+                kvlog => kvlog,
+            };
+            let kvlog = match (kvlog, &mut new_value_log) {
+                (KvLog::SetSeparated(key, offset, len, expires_at), Some(new_value_log)) => {
+                    let new_offset = match moved_values.get(&offset) {
+                        Some(&new_offset) => new_offset,
+                        None => {
+                            let value = self.value_log.read(offset, len)?;
+                            let new_offset = new_value_log.append(&value, false)?;
+                            moved_values.insert(offset, new_offset);
+                            new_offset
+                        }
+                    };
+                    KvLog::SetSeparated(key, new_offset, len, expires_at)
+                }
+                (kvlog, Some(new_value_log)) => {
+                    let threshold = threshold.expect("value log is only rewritten with one");
+                    new_value_log.separate(self.value_log.resolve(kvlog)?, threshold, false)?
+                }
+                (kvlog, None) => self.value_log.resolve(kvlog)?,
+            };
+            // Update log pointer map right away
+            pointer.offset =
+                file_len(&temp_log_file_path)? + new_append_writer.buffer().len() as u64;
+            let value_len = match &kvlog {
+                KvLog::SetSeparated(_, _, value_len, _) => *value_len,
+                _ => 0,
+            };
+            let kvlog = KvLog::new_sequenced(pointer.sequence, kvlog);
+            pointer.len = self.format.write(&kvlog, &mut new_append_writer)? + value_len;
+        }
+        for (key, tombstone) in new_log_pointer.tombstones() {
+            let kvlog = KvLog::new_sequenced(tombstone.sequence, KvLog::new_rm(key.clone()));
+            self.format.write(&kvlog, &mut new_append_writer)?;
+        }
+        // Namespaces only have set commands and tombstones, which are copied as they are.
+        for (namespace, log_pointer) in new_log_pointer.namespaces_mut() {
+            log_pointer.drop_versions();
+            log_pointer.remove_expired(now);
+            log_pointer.drop_tombstones(removed_until);
+            let mut log_pointers = log_pointer.iter_mut().collect::<Vec<_>>();
+            log_pointers.sort_unstable_by_key(|x| x.1.offset);
+            for (key, pointer) in log_pointers {
+                let kvlog = self
+                    .get_kvlog_from_offset(pointer.offset)?
+                    .into_namespaced(namespace)?
+                    .into_live_set(key)?;
+                pointer.offset =
+                    file_len(&temp_log_file_path)? + new_append_writer.buffer().len() as u64;
+                let kvlog = KvLog::new_namespaced(namespace.clone(), kvlog);
+                let kvlog = KvLog::new_sequenced(pointer.sequence, kvlog);
+                pointer.len = self.format.write(&kvlog, &mut new_append_writer)?;
+            }
+            for (key, tombstone) in log_pointer.tombstones() {
+                let kvlog = KvLog::new_namespaced(namespace.clone(), KvLog::new_rm(key.clone()));
+                let kvlog = KvLog::new_sequenced(tombstone.sequence, kvlog);
+                self.format.write(&kvlog, &mut new_append_writer)?;
+            }
+        }
+
+        // replicas may not have read the end of the log file yet
+        let previous_log = match self.replicated {
+            true => Some((
+                File::open(&self.log_file_path).context(ErrorKind::Io)?,
+                ValueLog::open(self.value_log.path().to_owned())?,
+            )),
+            false => None,
+        };
+        let new_value_log_path = match &new_value_log {
+            Some(new_value_log) => {
+                if self.syncs() {
+                    new_value_log.sync()?;
+                }
+                Some(new_value_log.path().to_owned())
+            }
+            None => None,
+        };
+        drop(new_value_log);
+        self.install_log(
+            &temp_log_file_path,
+            new_append_writer,
+            new_reader,
+            new_log_pointer,
+            new_value_log_path.as_deref(),
+        )?;
+        // every value is back in the log file
+        if new_value_log_path.is_none() && self.value_log.path().exists() {
+            remove_file(self.value_log.path()).context(ErrorKind::Io)?;
+        }
+        self.previous_log = previous_log;
+        self.compactions += 1;
+        Ok(())
+    }
+
+    /// Create an empty temp log file, with a writer and a reader of it.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to create or open the temp file.
+    fn create_temp_log(&self) -> Result<(PathBuf, BufWriter<File>, LogReader)> {
         let mut temp_log_file_path = self.log_file_path.clone();
         temp_log_file_path.pop();
-        temp_log_file_path = temp_log_file_path.join(TEMP_LOG_FILE_NAME);
+        let name = self.options.store_name.as_deref();
+        let temp_log_file_name = store_file_name(name, TEMP_LOG_FILE_NAME);
+        write_manifest(&temp_log_file_path, name, Some(&temp_log_file_name))?;
+        temp_log_file_path = temp_log_file_path.join(temp_log_file_name);
 
         // set up append_writer used by set and rm
         let new_append_file = OpenOptions::new()
@@ -380,41 +4273,80 @@ impl KvStore {
             .truncate(true)
             .open(&temp_log_file_path)
             .context(ErrorKind::Io)?;
-        let mut new_append_writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, new_append_file);
+        let mut new_append_writer =
+            BufWriter::with_capacity(self.options.write_buffer_size, new_append_file);
+        format::write_header(&mut new_append_writer)?;
+        // an empty batch carries the sequence number over to the new log file
+        let sequence = self.log_pointer().sequence();
+        if sequence > 0 {
+            let marker = KvLog::new_sequenced(sequence, KvLog::Batch(Vec::new()));
+            self.format.write(&marker, &mut new_append_writer)?;
+        }
 
         // create reader in advance so we can rollback if this fails
-        let new_reader = BufReader::new(File::open(&temp_log_file_path).context(ErrorKind::Io)?);
+        let new_reader = LogReader::new(
+            File::open(&temp_log_file_path).context(ErrorKind::Io)?,
+            self.options.read_ahead_size,
+            self.format.clone(),
+        );
 
-        // Make sure the original log pointer map is not modified.
-        let mut new_log_pointer: LogPointerMap = self.log_pointer.clone();
-        let mut log_pointers = new_log_pointer.iter_mut().collect::<Vec<_>>();
-        // Sort by log pointer to ensure original order in log file is preserved.
-        log_pointers.sort_unstable_by_key(|x| *x.1);
-        for (_key, val) in log_pointers {
-            let kvlog = self.get_kvlog_from_offset(*val)?;
-            if CORRUPTION_CHECK {
-                match kvlog {
-                    KvLog::Set(ref k, _) => {
-                        if k != _key {
-                            return Err(Error::from(ErrorKind::Corruption));
-                        }
-                    }
-                    _ => return Err(Error::from(ErrorKind::Corruption)),
-                }
-            }
-            // Update log pointer map right away
-            *val = file_len(&temp_log_file_path)? + new_append_writer.buffer().len() as u64;
-            kvlog.serialize_to_writer(&mut new_append_writer)?;
+        Ok((temp_log_file_path, new_append_writer, new_reader))
+    }
+
+    /// Replace the log file with a temp log file and switch to its writer, reader and log pointer map.
+    /// The value log is replaced too with `new_value_log` if given, which the temp log
+    /// file refers to.
+    ///
+    /// If this fails before the rename, the in-memory KvStore and log file are not modified.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to sync the temp file in group commit mode, or failed to rename it to log file.
+    fn install_log(
+        &mut self,
+        temp_log_file_path: &PathBuf,
+        mut new_append_writer: BufWriter<File>,
+        new_reader: LogReader,
+        new_log_pointer: LogPointerMap,
+        new_value_log: Option<&Path>,
+    ) -> Result<()> {
+        // If records are synced, the new log must be durable before it replaces the old one.
+        let group_commit = self.options.group_commit.as_ref();
+        if self.syncs() {
+            new_append_writer.flush().context(ErrorKind::Io)?;
+            new_append_writer
+                .get_ref()
+                .sync_data()
+                .context(ErrorKind::Io)?;
         }
+        let new_flusher = match group_commit {
+            Some(config) => Some(spawn_flusher(temp_log_file_path, config)?),
+            None => None,
+        };
 
         // New file is ready, overwrite the old file. Rollback after this is impossible.
-        rename(&temp_log_file_path, &self.log_file_path).context(ErrorKind::Io)?;
+        rename(temp_log_file_path, &self.log_file_path).context(ErrorKind::Io)?;
+        // finished by `recover_manifest` after a crash
+        if let Some(new_value_log) = new_value_log {
+            rename(new_value_log, self.value_log.path()).context(ErrorKind::Io)?;
+        }
+        let dir_path = self
+            .log_file_path
+            .parent()
+            .expect("log file is in a directory");
+        write_manifest(dir_path, self.options.store_name.as_deref(), None)?;
 
         // Update in-memory components
         self.reader = new_reader;
-        self.append_writer = new_append_writer;
-        self.log_pointer = new_log_pointer;
+        self.append_writer = Some(new_append_writer);
+        self.value_log = ValueLog::new(self.value_log.path().to_owned());
+        self.log_pointer = Arc::new(RwLock::new(new_log_pointer));
+        self.cache.clear();
+        self.bloom = BloomFilter::from_index(&self.log_pointer.read().unwrap());
         self.redundant_count = 0;
+        self.flusher = new_flusher;
+        self.log_generation += 1;
+        self.previous_log = None;
 
         Ok(())
     }
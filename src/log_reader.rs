@@ -0,0 +1,109 @@
+#![deny(missing_docs)]
+//! Random access reader of a log file.
+//!
+//! By default records are read through a `BufReader` that seeks to the requested offset.
+//! Seeks are relative, so reading records in offset order is served from its buffer.
+//! With the `mmap` feature the log file is memory-mapped instead and records are
+//! deserialized directly from the mapping, which avoids a seek and a read syscall per `get`.
+
+use crate::codec::RecordFormat;
+use crate::error::ErrorKind;
+use crate::KvLog;
+use crate::Result;
+use failure::ResultExt;
+use std::fs::File;
+#[cfg(not(feature = "mmap"))]
+use std::io::{BufReader, Seek};
+
+#[cfg(feature = "mmap")]
+use crate::error::Error;
+#[cfg(feature = "mmap")]
+use memmap::{Mmap, MmapOptions};
+
+/// Reads a `KvLog` at a given offset of a log file.
+pub(crate) struct LogReader {
+    #[cfg(not(feature = "mmap"))]
+    reader: BufReader<File>,
+    #[cfg(feature = "mmap")]
+    file: File,
+    /// Mapping of the log file. It only covers the file length at the time of mapping,
+    /// and is remapped when a record after its end is requested.
+    #[cfg(feature = "mmap")]
+    map: Option<Mmap>,
+    /// Format of the records.
+    format: RecordFormat,
+}
+
+impl LogReader {
+    /// Create a reader of the given log file, reading `read_ahead_size` bytes at a time
+    /// and decoding records of `format`.
+    pub(crate) fn new(file: File, read_ahead_size: usize, format: RecordFormat) -> LogReader {
+        #[cfg(not(feature = "mmap"))]
+        {
+            LogReader {
+                reader: BufReader::with_capacity(read_ahead_size.max(1), file),
+                format,
+            }
+        }
+        #[cfg(feature = "mmap")]
+        {
+            // the mapping takes the place of the read-ahead buffer
+            let _ = read_ahead_size;
+            LogReader {
+                file,
+                map: None,
+                format,
+            }
+        }
+    }
+
+    /// Read the log starting at `offset`. The log must be already written to the file.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to seek or map the log file.
+    /// - Others: Same as `RecordFormat::read`.
+    pub(crate) fn read_at(&mut self, offset: u64) -> Result<KvLog> {
+        self.read_len_at(offset).map(|(kvlog, _)| kvlog)
+    }
+
+    /// Read the log starting at `offset` like `read_at`, returning it with the length of
+    /// its record.
+    ///
+    /// # Errors
+    ///
+    /// Same as `read_at`.
+    #[cfg(not(feature = "mmap"))]
+    pub(crate) fn read_len_at(&mut self, offset: u64) -> Result<(KvLog, u64)> {
+        let position = self.reader.stream_position().context(ErrorKind::Io)?;
+        self.reader
+            .seek_relative(offset as i64 - position as i64)
+            .context(ErrorKind::Io)?;
+        let kvlog = self.format.read(&mut self.reader)?;
+        let end = self.reader.stream_position().context(ErrorKind::Io)?;
+        Ok((kvlog, end - offset))
+    }
+
+    /// Read the log starting at `offset` like `read_at`, returning it with the length of
+    /// its record.
+    ///
+    /// # Errors
+    ///
+    /// Same as `read_at`.
+    #[cfg(feature = "mmap")]
+    pub(crate) fn read_len_at(&mut self, offset: u64) -> Result<(KvLog, u64)> {
+        let mapped_len = self.map.as_ref().map_or(0, |map| map.len() as u64);
+        if offset >= mapped_len {
+            // Safety: the log file is append-only and compaction writes a new file instead
+            // of rewriting this one, so mapped bytes are never modified while mapped.
+            let map = unsafe { MmapOptions::new().map(&self.file) }.context(ErrorKind::Io)?;
+            self.map = Some(map);
+        }
+        let map = self.map.as_ref().expect("log file is mapped");
+        if offset >= map.len() as u64 {
+            return Err(Error::from(ErrorKind::Corruption));
+        }
+        let (kvlog, len) = self.format.read_slice(&map[offset as usize..])?;
+        Ok((kvlog, len as u64))
+    }
+}
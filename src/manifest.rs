@@ -0,0 +1,80 @@
+#![deny(missing_docs)]
+//! Manifest describing the layout of a store, kept next to its log file.
+//!
+//! The manifest names the format version and the segments of the log, and records a
+//! compaction while it runs. It is replaced as a whole by renaming a new copy over it, so
+//! it is never seen half written. A store whose manifest says a compaction was running when
+//! it stopped discards what the compaction wrote if the log file was not replaced yet, and
+//! otherwise moves the value log the compaction wrote in place.
+
+use crate::error::{Error, ErrorKind};
+use crate::format::FORMAT_VERSION;
+use crate::Result;
+use failure::{Fail, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Layout of a store, as kept in its manifest file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StoreManifest {
+    /// Format version of the log segments.
+    pub(crate) format_version: u32,
+    /// File names of the log segments in the store directory, oldest first. There is only
+    /// one so far.
+    pub(crate) segments: Vec<String>,
+    /// Compaction writing a new log, if one was running.
+    pub(crate) compaction: Option<CompactionState>,
+}
+
+/// A compaction, or another rewrite of the log, that was running.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CompactionState {
+    /// File name of the new log being written.
+    pub(crate) temp_file: String,
+}
+
+impl StoreManifest {
+    /// Manifest of a store with a single log segment and no compaction running.
+    pub(crate) fn new(segment: String) -> StoreManifest {
+        StoreManifest {
+            format_version: FORMAT_VERSION,
+            segments: vec![segment],
+            compaction: None,
+        }
+    }
+
+    /// Read the manifest at `path`, or None if there is none yet.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to read the manifest file.
+    /// - Corruption: The manifest file is malformed.
+    pub(crate) fn load(path: &Path) -> Result<Option<StoreManifest>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.context(ErrorKind::Io).into()),
+        };
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|_| Error::from(ErrorKind::Corruption))
+    }
+
+    /// Replace the manifest at `path` with this one, writing and syncing it to `temp_path`
+    /// first.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to write, sync or rename the manifest file.
+    /// - Serde: Failed to serialize the manifest.
+    pub(crate) fn store(&self, path: &Path, temp_path: &Path) -> Result<()> {
+        let bytes = serde_json::to_vec_pretty(self).context(ErrorKind::Serde)?;
+        let mut file = File::create(temp_path).context(ErrorKind::Io)?;
+        file.write_all(&bytes).context(ErrorKind::Io)?;
+        file.sync_all().context(ErrorKind::Io)?;
+        fs::rename(temp_path, path).context(ErrorKind::Io)?;
+        Ok(())
+    }
+}
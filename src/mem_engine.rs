@@ -0,0 +1,85 @@
+#![deny(missing_docs)]
+//! Storage engine keeping everything in memory.
+
+use crate::error::{Error, ErrorKind};
+use crate::{KvsEngine, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A `KvsEngine` backed by a `HashMap`, without any files.
+///
+/// Everything is lost when it is dropped, which suits unit tests of code generic over the
+/// engine and ephemeral caches. Clones share the same map, so it can be served by a
+/// `KvsServer`.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::{KvsEngine, MemKvsEngine};
+///
+/// let mut engine = MemKvsEngine::new();
+/// engine.set("key1".to_owned(), "42".to_owned()).unwrap();
+/// assert_eq!(engine.get("key1".to_owned()).unwrap(), Some("42".to_owned()));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MemKvsEngine {
+    map: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl MemKvsEngine {
+    /// Creates an empty engine.
+    pub fn new() -> MemKvsEngine {
+        MemKvsEngine::default()
+    }
+
+    /// Lock the map shared by the clones.
+    fn map(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        self.map.lock().unwrap()
+    }
+}
+
+impl KvsEngine for MemKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map().get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.map()
+            .remove(&key)
+            .map(|_| ())
+            .ok_or_else(|| Error::from(ErrorKind::KeyNotFound))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut map = self.map();
+        if map.get(&key).map(String::as_str) != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+        Ok(true)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs: Vec<_> = self
+            .map()
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+}
@@ -0,0 +1,191 @@
+#![deny(missing_docs)]
+//! The memcached text protocol, served by `KvsServer` with `Protocol::Memcached`.
+//!
+//! The storage commands `get`, `set`, `delete`, `incr` and `decr` are supported, with
+//! `version` and `quit`, so applications speaking memcached can use the store. Flags are
+//! not kept and always read as 0, and expiration times are ignored, so keys never expire.
+//! Keys and values have to be valid UTF-8. `incr` and `decr` need an engine swapping
+//! values atomically, see `KvsEngine::compare_and_swap`.
+
+use crate::acl::Restricted;
+use crate::audit::Auditor;
+use crate::error::{Error, ErrorKind};
+use crate::{KvsEngine, Result};
+use failure::ResultExt;
+use std::io::{self, BufRead, Read, Write};
+
+/// Longest key memcached accepts.
+const MAX_KEY_LEN: usize = 250;
+/// Largest value accepted, the default item size limit of memcached.
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+
+/// Answer the commands read from `reader` on `writer`, until the client quits or closes the
+/// connection. Changes are recorded with `audit`.
+///
+/// # Errors
+///
+/// - Io: Failed to read a command or write a reply.
+/// - Serde: Received a value not ending with a line break.
+pub(crate) fn serve<E, R, W>(
+    engine: &mut E,
+    audit: Option<Auditor<'_>>,
+    mut reader: R,
+    mut writer: W,
+) -> Result<()>
+where
+    E: KvsEngine,
+    R: BufRead,
+    W: Write,
+{
+    let engine = &mut Restricted::new(engine, None).audit(audit);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).context(ErrorKind::Io)? == 0 {
+            return Ok(());
+        }
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        let noreply = words.last() == Some(&"noreply");
+        let reply = match words.as_slice() {
+            ["quit"] => return Ok(()),
+            ["version"] => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")),
+            ["get", keys @ ..] if !keys.is_empty() => get(engine, keys),
+            ["set", key, _flags, _exptime, len]
+            | ["set", key, _flags, _exptime, len, "noreply"] => {
+                let value = match len.parse() {
+                    Ok(len) => read_value(&mut reader, len)?,
+                    Err(_) => Err("CLIENT_ERROR bad command line format"),
+                };
+                match value {
+                    Ok(value) => set(engine, key, value),
+                    Err(reply) => format!("{}\r\n", reply),
+                }
+            }
+            ["delete", key] | ["delete", key, "noreply"] => delete(engine, key),
+            ["incr", key, delta] | ["incr", key, delta, "noreply"] => add(engine, key, delta, true),
+            ["decr", key, delta] | ["decr", key, delta, "noreply"] => {
+                add(engine, key, delta, false)
+            }
+            _ => "ERROR\r\n".to_owned(),
+        };
+        if !noreply {
+            writer.write_all(reply.as_bytes()).context(ErrorKind::Io)?;
+            writer.flush().context(ErrorKind::Io)?;
+        }
+    }
+}
+
+/// Tell a client connecting to a server which has as many connections as it takes that it
+/// is refused, as memcached does.
+///
+/// # Errors
+///
+/// - Io: Failed to write the error reply.
+pub(crate) fn write_busy<W: Write>(mut writer: W) -> Result<()> {
+    writer
+        .write_all(b"ERROR Too many open connections\r\n")
+        .context(ErrorKind::Io)?;
+    writer.flush().context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// Read a value of `len` bytes followed by a line break, or the reply rejecting it.
+/// A value too large is skipped.
+///
+/// # Errors
+///
+/// - Io: Failed to read the value.
+/// - Serde: The value does not end with a line break.
+fn read_value<R: BufRead>(
+    reader: &mut R,
+    len: usize,
+) -> Result<std::result::Result<String, &'static str>> {
+    if len > MAX_VALUE_LEN {
+        let skip = len as u64 + 2;
+        if io::copy(&mut reader.take(skip), &mut io::sink()).context(ErrorKind::Io)? < skip {
+            return Err(Error::from(ErrorKind::Io));
+        }
+        return Ok(Err("SERVER_ERROR object too large for cache"));
+    }
+    let mut value = vec![0; len + 2];
+    reader.read_exact(&mut value).context(ErrorKind::Io)?;
+    if !value.ends_with(b"\r\n") {
+        return Err(Error::from(ErrorKind::Serde));
+    }
+    value.truncate(len);
+    Ok(String::from_utf8(value).map_err(|_| "CLIENT_ERROR value is not valid UTF-8"))
+}
+
+/// Reply with the values of the keys that are present.
+fn get<E: KvsEngine>(engine: &mut E, keys: &[&str]) -> String {
+    let mut reply = String::new();
+    for key in keys {
+        match engine.get((*key).to_owned()) {
+            Ok(Some(value)) => {
+                reply += &format!("VALUE {} 0 {}\r\n{}\r\n", key, value.len(), value)
+            }
+            Ok(None) => {}
+            Err(e) => return server_error(e),
+        }
+    }
+    reply + "END\r\n"
+}
+
+/// Set a key.
+fn set<E: KvsEngine>(engine: &mut E, key: &str, value: String) -> String {
+    if key.len() > MAX_KEY_LEN {
+        return "CLIENT_ERROR key too long\r\n".to_owned();
+    }
+    match engine.set(key.to_owned(), value) {
+        Ok(()) => "STORED\r\n".to_owned(),
+        Err(e) => server_error(e),
+    }
+}
+
+/// Remove a key.
+fn delete<E: KvsEngine>(engine: &mut E, key: &str) -> String {
+    match engine.remove(key.to_owned()) {
+        Ok(()) => "DELETED\r\n".to_owned(),
+        Err(e) if e.kind() == ErrorKind::KeyNotFound => "NOT_FOUND\r\n".to_owned(),
+        Err(e) => server_error(e),
+    }
+}
+
+/// Add `delta` to the value of a key, or subtract it if not `increment`.
+///
+/// Values are unsigned 64-bit integers as in memcached: incrementing wraps around, and
+/// decrementing stops at 0. The new value is swapped in only if the value read is still
+/// current, and the value is read again otherwise, so concurrent updates are not lost.
+fn add<E: KvsEngine>(engine: &mut E, key: &str, delta: &str, increment: bool) -> String {
+    let delta: u64 = match delta.parse() {
+        Ok(delta) => delta,
+        Err(_) => return "CLIENT_ERROR invalid numeric delta argument\r\n".to_owned(),
+    };
+    loop {
+        let current = match engine.get(key.to_owned()) {
+            Ok(Some(current)) => current,
+            Ok(None) => return "NOT_FOUND\r\n".to_owned(),
+            Err(e) => return server_error(e),
+        };
+        let value: u64 = match current.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                return "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+                    .to_owned()
+            }
+        };
+        let value = match increment {
+            true => value.wrapping_add(delta),
+            false => value.saturating_sub(delta),
+        };
+        match engine.compare_and_swap(key.to_owned(), Some(&current), Some(value.to_string())) {
+            Ok(true) => return format!("{}\r\n", value),
+            Ok(false) => continue,
+            Err(e) => return server_error(e),
+        }
+    }
+}
+
+/// Reply reporting an error of the engine.
+fn server_error(e: Error) -> String {
+    format!("SERVER_ERROR {}\r\n", e)
+}
@@ -0,0 +1,67 @@
+#![deny(missing_docs)]
+//! User-defined merge operators, see `KvStore::merge`.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Function combining an existing value with a merge operand into a new value.
+type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// A merge operator, set with `Options::merge_operator`.
+///
+/// It is called with the key, the existing value if any and one operand, and returns the
+/// new value. Operands are applied one at a time in the order they were merged, when the
+/// value is read or when compaction folds them into a set command. A store with merge
+/// commands in its log must always be opened with the same merge operator.
+///
+/// # Examples
+///
+/// A counter that adds up operands:
+///
+/// ```rust
+/// use kvs::{KvStore, MergeOperator, Options};
+/// use tempfile::TempDir;
+///
+/// let add = MergeOperator::new(|_key, existing, operand| {
+///     let parse = |bytes: &[u8]| String::from_utf8_lossy(bytes).parse::<i64>().unwrap_or(0);
+///     let sum = existing.map_or(0, parse) + parse(operand);
+///     sum.to_string().into_bytes()
+/// });
+/// let options = Options {
+///     merge_operator: Some(add),
+///     ..Options::default()
+/// };
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::open_with_options(tempdir.path(), options).unwrap();
+/// kv.merge("hits", "3").unwrap();
+/// kv.merge("hits", "4").unwrap();
+/// assert_eq!(kv.get("hits").unwrap(), Some("7".to_owned()));
+/// ```
+#[derive(Clone)]
+pub struct MergeOperator {
+    merge_fn: Arc<MergeFn>,
+}
+
+impl MergeOperator {
+    /// Create a merge operator from a function of key, existing value and operand.
+    pub fn new<F>(merge_fn: F) -> MergeOperator
+    where
+        F: Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        MergeOperator {
+            merge_fn: Arc::new(merge_fn),
+        }
+    }
+
+    /// Apply one operand to the existing value of `key`.
+    pub(crate) fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        (self.merge_fn)(key, existing, operand)
+    }
+}
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
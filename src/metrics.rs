@@ -0,0 +1,227 @@
+#![deny(missing_docs)]
+//! Metrics of a `KvsServer`, in the text format of Prometheus.
+
+use crate::Stats;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the buckets of command durations, in seconds.
+const DURATION_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.1, 1.0,
+];
+
+/// Commands run on the engine of a server, measured apart.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Command {
+    Get,
+    Set,
+    Remove,
+    ScanPrefix,
+    Changes,
+}
+
+impl Command {
+    /// Every command, in the order of their metrics.
+    const ALL: [Command; 5] = [
+        Command::Get,
+        Command::Set,
+        Command::Remove,
+        Command::ScanPrefix,
+        Command::Changes,
+    ];
+
+    /// Value of the `command` label of the metrics of the command.
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Command::Get => "get",
+            Command::Set => "set",
+            Command::Remove => "remove",
+            Command::ScanPrefix => "scan_prefix",
+            Command::Changes => "changes",
+        }
+    }
+}
+
+/// Counters of a server, updated by its connections.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Metrics of each command, in the order of `Command::ALL`.
+    commands: [CommandMetrics; 5],
+    /// Connections accepted since the server started.
+    connections: AtomicU64,
+    /// Connections being served.
+    active_connections: AtomicU64,
+}
+
+/// Counters of a command.
+#[derive(Default)]
+struct CommandMetrics {
+    count: AtomicU64,
+    errors: AtomicU64,
+    /// Total duration of the commands in nanoseconds.
+    nanos: AtomicU64,
+    /// Commands by the first bucket of `DURATION_BUCKETS` they fit in, not cumulative.
+    buckets: [AtomicU64; 10],
+}
+
+/// A connection counted as active until dropped.
+pub(crate) struct ActiveConnection<'a>(&'a Metrics);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Count `command`, which took `elapsed` and failed if `failed`.
+    pub(crate) fn record(&self, command: Command, elapsed: Duration, failed: bool) {
+        let metrics = &self.commands[command as usize];
+        metrics.count.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            metrics.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics
+            .nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|&le| seconds <= le) {
+            metrics.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a new connection, active until the returned guard is dropped.
+    pub(crate) fn connection(&self) -> ActiveConnection<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self)
+    }
+
+    /// The metrics in the text format of Prometheus, along with `stats` of the store if
+    /// the engine has them.
+    pub(crate) fn render(&self, stats: Option<Stats>) -> String {
+        let mut text = String::new();
+        // writing to a string cannot fail
+        let out = &mut text;
+        header(
+            out,
+            "kvs_command_duration_seconds",
+            "histogram",
+            "Time to run commands, including waiting for the engine.",
+        );
+        for command in Command::ALL.iter() {
+            let metrics = &self.commands[*command as usize];
+            let label = command.label();
+            let mut cumulative = 0;
+            for (le, bucket) in DURATION_BUCKETS.iter().zip(&metrics.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "kvs_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    label, le, cumulative
+                );
+            }
+            let count = metrics.count.load(Ordering::Relaxed);
+            let seconds = metrics.nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(
+                out,
+                "kvs_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                label, count
+            );
+            let _ = writeln!(
+                out,
+                "kvs_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                label, seconds
+            );
+            let _ = writeln!(
+                out,
+                "kvs_command_duration_seconds_count{{command=\"{}\"}} {}",
+                label, count
+            );
+        }
+        header(
+            out,
+            "kvs_command_errors_total",
+            "counter",
+            "Commands which failed.",
+        );
+        for command in Command::ALL.iter() {
+            let errors = self.commands[*command as usize]
+                .errors
+                .load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "kvs_command_errors_total{{command=\"{}\"}} {}",
+                command.label(),
+                errors
+            );
+        }
+        let connections = self.connections.load(Ordering::Relaxed);
+        let active_connections = self.active_connections.load(Ordering::Relaxed);
+        metric(
+            out,
+            "kvs_connections_total",
+            "counter",
+            "Connections accepted.",
+            connections,
+        );
+        metric(
+            out,
+            "kvs_connections_active",
+            "gauge",
+            "Connections being served.",
+            active_connections,
+        );
+        if let Some(stats) = stats {
+            metric(
+                out,
+                "kvs_live_keys",
+                "gauge",
+                "Live keys in the store.",
+                stats.live_keys as u64,
+            );
+            metric(
+                out,
+                "kvs_log_bytes",
+                "gauge",
+                "Length of the log.",
+                stats.log_bytes,
+            );
+            metric(
+                out,
+                "kvs_dead_bytes",
+                "gauge",
+                "Estimated length of the redundant records in the log.",
+                stats.dead_bytes,
+            );
+            metric(
+                out,
+                "kvs_compactions_total",
+                "counter",
+                "Compactions since the store was opened.",
+                stats.compactions,
+            );
+            metric(
+                out,
+                "kvs_index_bytes",
+                "gauge",
+                "Estimated memory used by the index.",
+                stats.index_bytes as u64,
+            );
+        }
+        text
+    }
+}
+
+/// Write the help and type lines of a metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write a metric without labels.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
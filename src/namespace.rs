@@ -0,0 +1,123 @@
+#![deny(missing_docs)]
+//! Namespaces of keys sharing the log of a KvStore.
+
+use crate::error::{Error, ErrorKind};
+use crate::kvlog::into_string;
+use crate::{now_millis, KvLog, KvStore, Result};
+
+/// A namespace of keys, created by `KvStore::namespace`.
+///
+/// Keys of a namespace are separate from the keys of the store and of other namespaces.
+/// Their records share the log file of the store, but each namespace has its own log
+/// pointer map, so `KvStore::drop_namespace` removes all of its keys with a single record
+/// and the next compaction reclaims their space. A namespace exists as long as it has keys.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::KvStore;
+/// use tempfile::TempDir;
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::open(tempdir.path()).unwrap();
+/// kv.set("key1".to_owned(), "12".to_owned()).unwrap();
+///
+/// let mut sessions = kv.namespace("sessions");
+/// sessions.set("key1", "34".to_owned()).unwrap();
+/// assert_eq!(sessions.get("key1").unwrap(), Some("34".to_owned()));
+///
+/// assert_eq!(kv.drop_namespace("sessions").unwrap(), 1);
+/// assert_eq!(kv.get("key1").unwrap(), Some("12".to_owned()));
+/// ```
+pub struct Namespace<'a> {
+    store: &'a mut KvStore,
+    name: String,
+}
+
+impl<'a> Namespace<'a> {
+    /// Open the namespace `name` of `store`.
+    pub(crate) fn new(store: &'a mut KvStore, name: &str) -> Namespace<'a> {
+        Namespace {
+            store,
+            name: name.to_owned(),
+        }
+    }
+
+    /// Name of the namespace.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set a key-value pair in the namespace.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::set`.
+    pub fn set<K: Into<Vec<u8>>>(&mut self, key: K, value: String) -> Result<()> {
+        let kvlog = KvLog::new_set(key.into(), value.into_bytes());
+        self.store
+            .apply_log(KvLog::new_namespaced(self.name.clone(), kvlog))
+    }
+
+    /// Returns the value of a key in the namespace.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get`.
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<String>> {
+        let key = key.as_ref();
+        let offset = match self
+            .store
+            .log_pointer()
+            .namespace(&self.name)
+            .and_then(|log_pointer| log_pointer.get_live(key, now_millis()))
+        {
+            Some(pointer) => pointer.offset,
+            None => return Ok(None),
+        };
+        let value = self
+            .store
+            .get_kvlog_from_offset(offset)?
+            .into_namespaced(&self.name)?
+            .into_live_set(key)?
+            .into_value()
+            .ok_or_else(|| Error::from(ErrorKind::Corruption))?;
+        into_string(value).map(Some)
+    }
+
+    /// Removes a key from the namespace.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::remove`.
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<()> {
+        let key = key.into();
+        if !self.contains_key(&key) {
+            return Err(Error::from(ErrorKind::KeyNotFound));
+        }
+        self.store
+            .apply_log(KvLog::new_namespaced(self.name.clone(), KvLog::new_rm(key)))
+    }
+
+    /// Returns whether the namespace has a key.
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.store
+            .log_pointer()
+            .namespace(&self.name)
+            .and_then(|log_pointer| log_pointer.get_live(key.as_ref(), now_millis()))
+            .is_some()
+    }
+
+    /// Returns the number of keys in the namespace.
+    pub fn len(&self) -> usize {
+        self.store
+            .log_pointer()
+            .namespace(&self.name)
+            .map_or(0, |log_pointer| log_pointer.live_len(now_millis()))
+    }
+
+    /// Returns whether the namespace has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
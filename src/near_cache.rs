@@ -0,0 +1,312 @@
+#![deny(missing_docs)]
+//! Caching of the values read by a `KvsClient`, invalidated by the changes the server
+//! pushes to a subscription.
+
+use crate::client::Subscription;
+use crate::stream::Socket;
+use crate::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a value is served from the cache by default.
+const DEFAULT_MAX_STALENESS: Duration = Duration::from_secs(5);
+/// Silence of the server after which the subscription is deemed cut. The server sends a
+/// heartbeat every second.
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(3);
+/// Wait before subscribing again after the subscription was cut.
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a `KvsClient` caches the values it reads, see `KvsClient::set_near_cache`.
+///
+/// Reads of cached keys are answered without a round trip to the server. The client
+/// subscribes to the changes of the cached prefixes on a connection of its own, see
+/// `KvsClient::subscribe`, and drops the values of the keys changed on the server as their
+/// changes arrive. A value may still be read from the cache between a change on the server
+/// and the arrival of its event, but never longer than `max_staleness` after it was read
+/// from the server. While the subscription is cut, the cache is emptied and reads go to the
+/// server, until subscribing again succeeds.
+///
+/// Only values present on the server are cached. When full, the cache drops the values
+/// read the longest ago.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvsClient, NearCache};
+/// use std::time::Duration;
+///
+/// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+/// let near_cache = NearCache::new(10_000)
+///     .prefixes(vec!["config:".to_owned()])
+///     .max_staleness(Duration::from_secs(30));
+/// client.set_near_cache(near_cache).unwrap();
+/// // the second read is answered by the cache
+/// client.get("config:flags".to_owned()).unwrap();
+/// client.get("config:flags".to_owned()).unwrap();
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NearCache {
+    capacity: usize,
+    max_staleness: Duration,
+    prefixes: Vec<String>,
+}
+
+impl NearCache {
+    /// Cache the values of up to `capacity` keys, each for at most 5 s, whatever their
+    /// prefix.
+    pub fn new(capacity: usize) -> NearCache {
+        NearCache {
+            capacity,
+            max_staleness: DEFAULT_MAX_STALENESS,
+            prefixes: vec![String::new()],
+        }
+    }
+
+    /// Set how long a value is served from the cache at most after it was read from the
+    /// server.
+    pub fn max_staleness(mut self, max_staleness: Duration) -> NearCache {
+        self.max_staleness = max_staleness;
+        self
+    }
+
+    /// Only cache the keys starting with one of `prefixes`, so that the server only pushes
+    /// the changes of those keys.
+    pub fn prefixes(mut self, prefixes: Vec<String>) -> NearCache {
+        self.prefixes = prefixes;
+        self
+    }
+
+    /// Whether the values of `key` are cached.
+    fn covers(&self, key: &str) -> bool {
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+}
+
+/// The values cached by a client, shared with the thread invalidating them.
+struct CachedValues {
+    near_cache: NearCache,
+    /// Key to its value, when it was read from the server, and its position in `order`.
+    values: HashMap<String, (String, Instant, u64)>,
+    /// Position to key, the first one was read the longest ago.
+    order: BTreeMap<u64, String>,
+    /// Keys being read from the server, to the ID of the read. A key changed meanwhile is
+    /// removed, so that the value read, which may predate the change, is not cached.
+    reads: HashMap<String, u64>,
+    /// The next position or read ID.
+    next: u64,
+    /// The socket of the subscription, absent while it is cut.
+    socket: Option<Socket>,
+    /// Whether the client dropped the cache.
+    closed: bool,
+}
+
+impl CachedValues {
+    /// Drop the value of `key` and any read of it in flight.
+    fn invalidate(&mut self, key: &str) {
+        if let Some((_, _, position)) = self.values.remove(key) {
+            self.order.remove(&position);
+        }
+        self.reads.remove(key);
+    }
+
+    /// Drop every value, as changes may have been missed.
+    fn clear(&mut self) {
+        self.values.clear();
+        self.order.clear();
+        self.reads.clear();
+    }
+}
+
+/// The cache of a client, ending its subscription when dropped.
+pub(crate) struct NearCacheHandle(Arc<Mutex<CachedValues>>);
+
+impl NearCacheHandle {
+    /// Subscribe with `subscribe` to the changes of the prefixes of `near_cache`, and
+    /// start a thread invalidating the cached values as changes arrive. The thread
+    /// subscribes again with `subscribe` when the subscription is cut.
+    ///
+    /// # Errors
+    ///
+    /// Same as `subscribe`.
+    pub(crate) fn start<F>(near_cache: NearCache, subscribe: F) -> Result<NearCacheHandle>
+    where
+        F: Fn(Vec<String>) -> Result<Subscription> + Send + 'static,
+    {
+        let prefixes = near_cache.prefixes.clone();
+        let subscribe = move || subscribe(prefixes.clone());
+        let (subscription, socket) = open(&subscribe)?;
+        let values = Arc::new(Mutex::new(CachedValues {
+            near_cache,
+            values: HashMap::new(),
+            order: BTreeMap::new(),
+            reads: HashMap::new(),
+            next: 0,
+            socket: Some(socket),
+            closed: false,
+        }));
+        let weak = Arc::downgrade(&values);
+        thread::spawn(move || invalidate(weak, subscription, subscribe));
+        Ok(NearCacheHandle(values))
+    }
+
+    fn values(&self) -> MutexGuard<'_, CachedValues> {
+        self.0.lock().unwrap()
+    }
+
+    /// The cached value of `key`, unless it is older than the staleness bound.
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        let mut values = self.values();
+        let max_staleness = values.near_cache.max_staleness;
+        match values.values.get(key) {
+            Some((value, read, _)) if read.elapsed() < max_staleness => Some(value.clone()),
+            Some(_) => {
+                values.invalidate(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Start reading `key` from the server, returning the ID of the read if its value may
+    /// be cached, see `finish_read` and `cancel_read`.
+    pub(crate) fn start_read(&self, key: &str) -> Option<u64> {
+        let mut values = self.values();
+        if values.socket.is_none() || values.near_cache.capacity == 0 {
+            return None;
+        }
+        if !values.near_cache.covers(key) {
+            return None;
+        }
+        let id = values.next;
+        values.next += 1;
+        values.reads.insert(key.to_owned(), id);
+        Some(id)
+    }
+
+    /// Cache `value` read from the server by the read `id` of `key`, unless the key was
+    /// changed since the read started.
+    pub(crate) fn finish_read(&self, key: &str, id: u64, value: &str) {
+        let mut values = self.values();
+        if values.reads.get(key) != Some(&id) {
+            return;
+        }
+        values.reads.remove(key);
+        while values.values.len() >= values.near_cache.capacity {
+            let oldest = match values.order.keys().next() {
+                Some(&position) => values.order.remove(&position).unwrap(),
+                None => break,
+            };
+            values.values.remove(&oldest);
+        }
+        let position = values.next;
+        values.next += 1;
+        values.order.insert(position, key.to_owned());
+        let cached = (value.to_owned(), Instant::now(), position);
+        values.values.insert(key.to_owned(), cached);
+    }
+
+    /// Forget the read `id` of `key`, which found no value or failed.
+    pub(crate) fn cancel_read(&self, key: &str, id: u64) {
+        let mut values = self.values();
+        if values.reads.get(key) == Some(&id) {
+            values.reads.remove(key);
+        }
+    }
+
+    /// The number of reads in flight whose value may be cached.
+    pub(crate) fn reads(&self) -> usize {
+        self.values().reads.len()
+    }
+
+    /// Drop the cached value of `key`, changed by the client.
+    pub(crate) fn invalidate(&self, key: &str) {
+        self.values().invalidate(key);
+    }
+}
+
+impl Drop for NearCacheHandle {
+    fn drop(&mut self) {
+        let mut values = self.values();
+        values.closed = true;
+        // wakes the thread up, which then sees the cache is gone
+        if let Some(socket) = values.socket.take() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Drop the cached values of the keys changed on the server, until the client drops the
+/// cache. The cache is emptied when the subscription is cut, and used again once
+/// subscribing again with `subscribe` succeeds.
+fn invalidate<F>(weak: Weak<Mutex<CachedValues>>, mut subscription: Subscription, subscribe: F)
+where
+    F: Fn() -> Result<Subscription>,
+{
+    loop {
+        let event = subscription.next_event();
+        let cut = {
+            let shared = match weak.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            let mut values = shared.lock().unwrap();
+            if values.closed {
+                return;
+            }
+            match event {
+                Ok(event) => {
+                    values.invalidate(&String::from_utf8_lossy(&event.key));
+                    false
+                }
+                Err(_) => {
+                    values.clear();
+                    values.socket = None;
+                    true
+                }
+            }
+        };
+        if cut {
+            subscription = match resubscribe(&weak, &subscribe) {
+                Some(subscription) => subscription,
+                None => return,
+            };
+        }
+    }
+}
+
+/// Subscribe with `subscribe` every second until it succeeds, returning the subscription
+/// once the cache is used again, or `None` if the client dropped the cache meanwhile.
+fn resubscribe<F>(weak: &Weak<Mutex<CachedValues>>, subscribe: &F) -> Option<Subscription>
+where
+    F: Fn() -> Result<Subscription>,
+{
+    loop {
+        thread::sleep(RESUBSCRIBE_INTERVAL);
+        let shared = weak.upgrade()?;
+        if shared.lock().unwrap().closed {
+            return None;
+        }
+        if let Ok((subscription, socket)) = open(subscribe) {
+            let mut values = shared.lock().unwrap();
+            if values.closed {
+                return None;
+            }
+            values.socket = Some(socket);
+            return Some(subscription);
+        }
+    }
+}
+
+/// Subscribe with `subscribe`, returning the subscription with a handle to its socket.
+fn open<F>(subscribe: &F) -> Result<(Subscription, Socket)>
+where
+    F: Fn() -> Result<Subscription>,
+{
+    let mut subscription = subscribe()?;
+    subscription.set_timeout(Some(SUBSCRIPTION_TIMEOUT))?;
+    let socket = subscription.socket()?;
+    Ok((subscription, socket))
+}
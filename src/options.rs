@@ -0,0 +1,138 @@
+#![deny(missing_docs)]
+//! Options used when opening a KvStore.
+
+use crate::{
+    BincodeCodec, Compression, Encryption, GroupCommit, LogCodec, MergeOperator, SecondaryIndex,
+    WriteHook,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Options for `KvStore::open_with_options`. They can also be set with `KvStoreBuilder`.
+///
+/// `Options::default()` gives the same behavior as `KvStore::open`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Enable group commit mode. See `GroupCommit`.
+    pub group_commit: Option<GroupCommit>,
+    /// Byte budget of the LRU cache of values read by `get`. 0 disables the cache.
+    pub value_cache_bytes: usize,
+    /// Keep the log pointer map ordered by key. This makes `range` and `scan_prefix` visit
+    /// only matching keys, at the cost of slower point queries.
+    pub ordered_index: bool,
+    /// Merge operator resolving the operands of `KvStore::merge`. See `MergeOperator`.
+    pub merge_operator: Option<MergeOperator>,
+    /// Open the store without writing to its directory. See `KvStore::open_read_only`.
+    pub read_only: bool,
+    /// Capacity of the buffer in front of the log file. 0 writes every record right away.
+    pub write_buffer_size: usize,
+    /// Capacity of the buffer reading ahead of a record read from the log file. Larger buffers
+    /// speed up reading records in log order, as iterators and compaction do, at the cost
+    /// of reading more than needed for a single `get`. At least 1 byte is used.
+    /// Unused with the `mmap` feature.
+    pub read_ahead_size: usize,
+    /// Fraction of redundant records in the log, between 0 and 1, needed for compaction.
+    /// Compaction also waits for a minimum number of redundant records, so with 0 it runs
+    /// as soon as that number is reached.
+    pub compaction_garbage_ratio: f64,
+    /// When written records reach the disk. Ignored in group commit mode.
+    pub durability: Durability,
+    /// Codec compressing new records. Records already in the log are read whatever codec
+    /// they were written with, and compaction rewrites them with this one.
+    pub compression: Compression,
+    /// Encrypt new records. Records already in the log are read with the same key, and
+    /// compaction rewrites them encrypted. See `Encryption`. The value log is not
+    /// encrypted, so this cannot be set together with `value_log_threshold`.
+    pub encryption: Option<Encryption>,
+    /// Wire format of records, bincode by default. See `LogCodec`.
+    pub codec: Arc<dyn LogCodec>,
+    /// Keep values of at least this many bytes in a value log apart from the log file, so
+    /// that reading the records in log order does not read them. Values in batches stay in
+    /// the log file. Compaction moves the live values to a new value log, reclaiming the
+    /// space of overwritten ones, or back to the log file once this is unset.
+    pub value_log_threshold: Option<usize>,
+    /// How long compaction keeps the remove records of removed keys.
+    pub tombstone_retention: TombstoneRetention,
+    /// Largest key in bytes that can be written. Larger keys fail with KeyTooLarge.
+    pub max_key_size: Option<usize>,
+    /// Largest value in bytes that can be written, failing with ValueTooLarge otherwise.
+    /// An append is checked with the value it makes, the operand of a merge on its own.
+    pub max_value_size: Option<usize>,
+    /// Callbacks run after every write, in order. See `WriteHook`.
+    pub write_hooks: Vec<WriteHook>,
+    /// Secondary indexes kept for `KvStore::get_by_index`. See `SecondaryIndex`.
+    pub indexes: Vec<SecondaryIndex>,
+    /// Name of the store among the stores of its directory. See `KvStore::open_named`.
+    pub store_name: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            group_commit: None,
+            value_cache_bytes: 0,
+            ordered_index: false,
+            merge_operator: None,
+            read_only: false,
+            write_buffer_size: crate::DEFAULT_BUFFER_SIZE,
+            read_ahead_size: crate::DEFAULT_BUFFER_SIZE,
+            compaction_garbage_ratio: 0.0,
+            durability: Durability::default(),
+            compression: Compression::default(),
+            encryption: None,
+            codec: Arc::new(BincodeCodec),
+            value_log_threshold: None,
+            tombstone_retention: TombstoneRetention::default(),
+            max_key_size: None,
+            max_value_size: None,
+            write_hooks: Vec::new(),
+            indexes: Vec::new(),
+            store_name: None,
+        }
+    }
+}
+
+/// The options of an open `KvStore` which can change without reopening it, see
+/// `KvStore::set_runtime_options`. Each has the meaning of the field of `Options` of the
+/// same name.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct RuntimeOptions {
+    /// Fraction of redundant records in the log needed for compaction.
+    pub compaction_garbage_ratio: f64,
+    /// Largest key in bytes that can be written.
+    pub max_key_size: Option<usize>,
+    /// Largest value in bytes that can be written.
+    pub max_value_size: Option<usize>,
+}
+
+/// When written records reach the disk, from fastest to safest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Records are kept in the write buffer until it is full or the store is dropped.
+    #[default]
+    Buffered,
+    /// Records are handed to the OS after every command. They survive a crash of the process
+    /// but not of the machine.
+    Flush,
+    /// Records are synced to disk after every command. This is the slowest, see `GroupCommit`
+    /// for syncing many commands at once.
+    Sync,
+}
+
+/// How long compaction keeps the remove records of removed keys, called tombstones.
+///
+/// Readers of the raw log, such as replicas following `KvStore::tail`, only learn that a
+/// key was removed from its tombstone. Retaining tombstones gives them time to see it.
+///
+/// The age of a tombstone is counted from when the store indexed it, so it starts over when
+/// the store is opened again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TombstoneRetention {
+    /// Tombstones are dropped by the next compaction.
+    #[default]
+    UntilCompaction,
+    /// Tombstones are kept by compactions until they are older than this.
+    For(Duration),
+    /// Tombstones are kept by every compaction.
+    Forever,
+}
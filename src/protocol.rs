@@ -0,0 +1,466 @@
+#![deny(missing_docs)]
+//! The wire protocol between `KvsServer` and `KvsClient`, see `Request`.
+//!
+//! A command failing on the server is answered with the `ErrorKind` of its error, so the
+//! client returns an error of the same kind, such as KeyNotFound for removing a missing key.
+//!
+//! A connection starts with a handshake, so that clients and servers of different versions
+//! either agree on a version of the protocol or fail with IncompatibleVersion. The client
+//! sends `HANDSHAKE_MAGIC` and the oldest and newest versions it speaks, as big-endian
+//! `u32`s. The server answers with `HANDSHAKE_MAGIC` and the newest version both speak, or
+//! before closing the connection, 0 if there is none or `u32::MAX` if the server is busy.
+//! The client does not wait for the answer before sending its first request. Clients predating the handshake start with a request
+//! frame instead, and are served as speaking version 1.
+
+use crate::error::{Error, ErrorKind};
+use crate::replication::{Changes, ReplicationCursor};
+use crate::{ChangeEvent, Result, ScanCursor, ScanPage, Stats};
+use failure::{Fail, ResultExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest frame accepted, to not allocate for a corrupted length prefix.
+const MAX_FRAME_LEN: u32 = 1 << 30;
+
+/// The newest version of the protocol, raised when `Request` or `Response` change in a way
+/// that older peers would misread.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// The oldest version of the protocol still spoken.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Starts the handshake. As the length prefix of a frame it is larger than `MAX_FRAME_LEN`,
+/// so servers predating the handshake close the connection instead of misreading it.
+const HANDSHAKE_MAGIC: [u8; 4] = *b"KVS\0";
+
+/// Version answered by a server refusing a connection because it has as many as it takes.
+const BUSY_VERSION: u32 = u32::MAX;
+
+/// How a client started a connection.
+pub(crate) enum Greeting {
+    /// The client sent the handshake, and agreed on a version with the server.
+    Handshake,
+    /// The client predates the handshake, and sent this request first.
+    Legacy(Request),
+}
+
+/// A command sent to the server.
+///
+/// A connection carries frames. Each frame is a message encoded with bincode, prefixed by
+/// its length in bytes as a big-endian `u32`. The client sends a `Request` frame, and the
+/// server answers it with a `Response` frame. Clients may send several requests before
+/// reading their responses, which come in the order of the requests.
+///
+/// A server requiring authentication answers every request but `Ping` with `Unauthenticated`
+/// until the client sends an `Auth` request with the token of one of its users.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    /// Get the value of a key.
+    Get {
+        /// The key.
+        key: String,
+    },
+    /// Set the value of a key.
+    Set {
+        /// The key.
+        key: String,
+        /// The value.
+        value: String,
+    },
+    /// Remove a key.
+    Remove {
+        /// The key.
+        key: String,
+    },
+    /// Authenticate the connection. Succeeds if the token is the one of a user of the
+    /// server, or if the server does not require authentication.
+    Auth {
+        /// The token of the user.
+        token: String,
+    },
+    /// Check that the connection works, without running a command.
+    Ping,
+    /// Run requests in order, answered together by a `Response::Batch` with the same ID.
+    /// A request failing does not stop the ones after it.
+    Batch {
+        /// The sequence ID of the batch, chosen by the client.
+        id: u64,
+        /// The requests.
+        requests: Vec<Request>,
+    },
+    /// Get the changes of the store for a replica, answered by a `Response::Changes`.
+    /// Needs permission to read every key. The cursor changed in version 5 of the protocol
+    /// to send full copies a page at a time, so a replica and its primary have to both
+    /// speak version 5.
+    Replicate {
+        /// Where the replica is, or `None` for a new replica.
+        cursor: Option<ReplicationCursor>,
+    },
+    /// Get a page of the key-value pairs whose key starts with a prefix, answered by a
+    /// `Response::Page`. The server bounds the length of pages, and leaves out the pairs the
+    /// user cannot read. Since version 2 of the protocol.
+    Scan {
+        /// The prefix, empty for every key.
+        prefix: String,
+        /// Where the page starts, `ScanCursor::default()` for the first page.
+        cursor: ScanCursor,
+        /// The largest number of pairs in the page.
+        limit: u32,
+    },
+    /// Receive the changes of the keys starting with any of the prefixes, see
+    /// `KvStore::watch_prefixes`, leaving out the keys the user cannot read. The server
+    /// answers `Response::Ok`, then sends a `Response::Event` for each change, and
+    /// `Response::Ok` as a heartbeat every second without changes, until either end closes
+    /// the connection. It reads no more requests. Since version 3 of the protocol.
+    Subscribe {
+        /// The prefixes, empty for every key.
+        prefixes: Vec<String>,
+    },
+    /// Run a command managing the server. The server only takes it from admin users, see
+    /// `User::admin`, unless it allows it to every client, see `KvsServer::allow_admin`.
+    /// Since version 4 of the protocol.
+    Admin(AdminCommand),
+}
+
+/// A command managing a server, sent in a `Request::Admin`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// Compact the store now, see `KvStore::compact`.
+    Compact,
+    /// Get statistics about the store, answered by a `Response::Stats`.
+    Stats,
+    /// Make every write so far durable, see `KvStore::sync`.
+    Flush,
+    /// Back the store up in the background, see `KvStore::start_backup`. The server answers
+    /// once the backup started, and reports on stderr whether it succeeded.
+    Backup {
+        /// Where to write the backup on the server, a directory or a `.tar` archive, relative
+        /// to the backup directory of the server, see `KvsServer::backup_dir`.
+        path: String,
+    },
+}
+
+/// The answer of the server to a request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// The command succeeded, with the value of the key for a get.
+    Ok(Option<String>),
+    /// The command failed with an error of this kind.
+    Err(ErrorKind),
+    /// The responses to the requests of a `Request::Batch`, in the same order.
+    Batch {
+        /// The sequence ID of the batch.
+        id: u64,
+        /// The responses.
+        responses: Vec<Response>,
+    },
+    /// The changes for a replica asked by a `Request::Replicate`.
+    Changes(Changes),
+    /// The page of key-value pairs asked by a `Request::Scan`.
+    Page(ScanPage),
+    /// A change of a key subscribed to by a `Request::Subscribe`.
+    Event(ChangeEvent),
+    /// The statistics asked by an `AdminCommand::Stats`.
+    Stats(Stats),
+}
+
+impl Response {
+    /// The result of the command answered by a response that is neither a batch, changes,
+    /// a page, an event nor statistics.
+    pub(crate) fn into_result(self) -> Result<Option<String>> {
+        match self {
+            Response::Ok(value) => Ok(value),
+            Response::Err(kind) => Err(Error::from(kind)),
+            Response::Batch { .. }
+            | Response::Changes(_)
+            | Response::Page(_)
+            | Response::Event(_)
+            | Response::Stats(_) => Err(Error::from(ErrorKind::Serde)),
+        }
+    }
+}
+
+/// Write the handshake of a client, answered by the server as `read_handshake` reads.
+///
+/// # Errors
+///
+/// - Io: Failed to write the handshake.
+pub(crate) fn write_handshake<W: Write>(mut writer: W) -> Result<()> {
+    writer.write_all(&client_hello()).context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// Read the answer of the server to the handshake, returning the version agreed on.
+///
+/// # Errors
+///
+/// - Io: Failed to read the answer.
+/// - IncompatibleVersion: The server speaks none of the versions of the client, or closed
+///   the connection without answering, as servers predating the handshake do.
+/// - ServerBusy: The server refused the connection, having as many as it takes.
+pub(crate) fn read_handshake<R: Read>(mut reader: R) -> Result<u32> {
+    let mut answer = [0; 8];
+    match reader.read_exact(&mut answer) {
+        Ok(()) => check_answer(answer),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(Error::from(ErrorKind::IncompatibleVersion))
+        }
+        Err(e) => Err(e.context(ErrorKind::Io).into()),
+    }
+}
+
+/// Read how a client starts the connection and answer its handshake, or None if it closed
+/// the connection first.
+///
+/// # Errors
+///
+/// - Io: Failed to read the handshake or write the answer.
+/// - Serde: The first request of a client predating the handshake is malformed.
+/// - IncompatibleVersion: The client speaks none of the versions of the server.
+pub(crate) fn accept_handshake<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+) -> Result<Option<Greeting>> {
+    let prefix = match read_prefix(&mut reader)? {
+        Some(prefix) => prefix,
+        None => return Ok(None),
+    };
+    if prefix != HANDSHAKE_MAGIC {
+        return Ok(Some(Greeting::Legacy(read_body(reader, prefix)?)));
+    }
+    let mut versions = [0; 8];
+    reader.read_exact(&mut versions).context(ErrorKind::Io)?;
+    let version = agree(versions);
+    writer
+        .write_all(&server_answer(version))
+        .context(ErrorKind::Io)?;
+    writer.flush().context(ErrorKind::Io)?;
+    if version == 0 {
+        return Err(Error::from(ErrorKind::IncompatibleVersion));
+    }
+    Ok(Some(Greeting::Handshake))
+}
+
+/// Answer the handshake of a client, without reading it, refusing the connection because
+/// the server has as many as it takes.
+///
+/// # Errors
+///
+/// - Io: Failed to write the answer.
+pub(crate) fn write_busy<W: Write>(mut writer: W) -> Result<()> {
+    writer
+        .write_all(&server_answer(BUSY_VERSION))
+        .context(ErrorKind::Io)?;
+    writer.flush().context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// Write `message` as a frame.
+///
+/// # Errors
+///
+/// - Io: Failed to write the frame.
+/// - Serde: Failed to encode the message, or it is too large.
+pub(crate) fn write_frame<W: Write, T: Serialize>(mut writer: W, message: &T) -> Result<()> {
+    let (len, bytes) = encode(message)?;
+    writer
+        .write_all(&len.to_be_bytes())
+        .context(ErrorKind::Io)?;
+    writer.write_all(&bytes).context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// Read a frame and decode its message, or None if the connection was closed before it.
+///
+/// # Errors
+///
+/// - Io: Failed to read the frame, or the connection was closed in the middle of it.
+/// - Serde: The frame is malformed.
+pub(crate) fn read_frame<R: Read, T: DeserializeOwned>(mut reader: R) -> Result<Option<T>> {
+    match read_prefix(&mut reader)? {
+        Some(prefix) => Ok(Some(read_body(reader, prefix)?)),
+        None => Ok(None),
+    }
+}
+
+/// Read the length prefix of a frame, or None if the connection was closed before it.
+fn read_prefix<R: Read>(mut reader: R) -> Result<Option<[u8; 4]>> {
+    let mut prefix = [0; 4];
+    match reader.read_exact(&mut prefix) {
+        Ok(()) => Ok(Some(prefix)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.context(ErrorKind::Io).into()),
+    }
+}
+
+/// Read the rest of the frame with the length prefix `prefix`, and decode its message.
+fn read_body<R: Read, T: DeserializeOwned>(mut reader: R, prefix: [u8; 4]) -> Result<T> {
+    let mut bytes = vec![0; frame_len(prefix)?];
+    reader.read_exact(&mut bytes).context(ErrorKind::Io)?;
+    Ok(bincode::deserialize(&bytes).context(ErrorKind::Serde)?)
+}
+
+/// Write the handshake of a client, like `write_handshake`.
+#[cfg(feature = "async")]
+pub(crate) async fn write_handshake_async<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<()> {
+    writer
+        .write_all(&client_hello())
+        .await
+        .context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// Read the answer of the server to the handshake, like `read_handshake`.
+#[cfg(feature = "async")]
+pub(crate) async fn read_handshake_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<u32> {
+    let mut answer = [0; 8];
+    match reader.read_exact(&mut answer).await {
+        Ok(_) => check_answer(answer),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(Error::from(ErrorKind::IncompatibleVersion))
+        }
+        Err(e) => Err(e.context(ErrorKind::Io).into()),
+    }
+}
+
+/// Read how a client starts the connection and answer its handshake, like
+/// `accept_handshake`.
+#[cfg(feature = "async")]
+pub(crate) async fn accept_handshake_async<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<Greeting>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut prefix = [0; 4];
+    match reader.read_exact(&mut prefix).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.context(ErrorKind::Io).into()),
+    }
+    if prefix != HANDSHAKE_MAGIC {
+        let mut bytes = vec![0; frame_len(prefix)?];
+        reader.read_exact(&mut bytes).await.context(ErrorKind::Io)?;
+        let request = bincode::deserialize(&bytes).context(ErrorKind::Serde)?;
+        return Ok(Some(Greeting::Legacy(request)));
+    }
+    let mut versions = [0; 8];
+    reader
+        .read_exact(&mut versions)
+        .await
+        .context(ErrorKind::Io)?;
+    let version = agree(versions);
+    writer
+        .write_all(&server_answer(version))
+        .await
+        .context(ErrorKind::Io)?;
+    writer.flush().await.context(ErrorKind::Io)?;
+    if version == 0 {
+        return Err(Error::from(ErrorKind::IncompatibleVersion));
+    }
+    Ok(Some(Greeting::Handshake))
+}
+
+/// Write `message` as a frame, like `write_frame`.
+#[cfg(feature = "async")]
+pub(crate) async fn write_frame_async<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let (len, bytes) = encode(message)?;
+    writer
+        .write_all(&len.to_be_bytes())
+        .await
+        .context(ErrorKind::Io)?;
+    writer.write_all(&bytes).await.context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// Read a frame and decode its message, like `read_frame`.
+#[cfg(feature = "async")]
+pub(crate) async fn read_frame_async<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.context(ErrorKind::Io).into()),
+    }
+    let mut bytes = vec![0; frame_len(len)?];
+    reader.read_exact(&mut bytes).await.context(ErrorKind::Io)?;
+    Ok(Some(
+        bincode::deserialize(&bytes).context(ErrorKind::Serde)?,
+    ))
+}
+
+/// The handshake of a client: the magic, then the oldest and newest versions it speaks.
+fn client_hello() -> [u8; 12] {
+    let mut hello = [0; 12];
+    hello[..4].copy_from_slice(&HANDSHAKE_MAGIC);
+    hello[4..8].copy_from_slice(&MIN_PROTOCOL_VERSION.to_be_bytes());
+    hello[8..].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    hello
+}
+
+/// The newest version spoken by both the server and a client sending the oldest and newest
+/// versions it speaks, or 0 if there is none.
+fn agree(versions: [u8; 8]) -> u32 {
+    let min = u32::from_be_bytes([versions[0], versions[1], versions[2], versions[3]]);
+    let max = u32::from_be_bytes([versions[4], versions[5], versions[6], versions[7]]);
+    let version = max.min(PROTOCOL_VERSION);
+    if version < min.max(MIN_PROTOCOL_VERSION) {
+        return 0;
+    }
+    version
+}
+
+/// The answer of the server to a handshake: the magic, then the version agreed on.
+fn server_answer(version: u32) -> [u8; 8] {
+    let mut answer = [0; 8];
+    answer[..4].copy_from_slice(&HANDSHAKE_MAGIC);
+    answer[4..].copy_from_slice(&version.to_be_bytes());
+    answer
+}
+
+/// The version agreed on in the answer of the server to the handshake of this client.
+fn check_answer(answer: [u8; 8]) -> Result<u32> {
+    let version = u32::from_be_bytes([answer[4], answer[5], answer[6], answer[7]]);
+    if answer[..4] == HANDSHAKE_MAGIC && version == BUSY_VERSION {
+        return Err(Error::from(ErrorKind::ServerBusy));
+    }
+    if answer[..4] != HANDSHAKE_MAGIC
+        || !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+    {
+        return Err(Error::from(ErrorKind::IncompatibleVersion));
+    }
+    Ok(version)
+}
+
+/// Encode a message with the length prefix of its frame.
+fn encode<T: Serialize>(message: &T) -> Result<(u32, Vec<u8>)> {
+    let bytes = bincode::serialize(message).context(ErrorKind::Serde)?;
+    let len = u32::try_from(bytes.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| Error::from(ErrorKind::Serde))?;
+    Ok((len, bytes))
+}
+
+/// Decode the length prefix of a frame, failing if it is too large.
+fn frame_len(prefix: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(prefix);
+    if len > MAX_FRAME_LEN {
+        return Err(Error::from(ErrorKind::Serde));
+    }
+    Ok(len as usize)
+}
@@ -0,0 +1,1314 @@
+#![deny(missing_docs)]
+//! Consensus between servers on the writes to a store, with Raft.
+//!
+//! The nodes of a cluster elect a leader, which appends every write to the raft log as a
+//! `KvLog` and sends it to the other nodes. A write is committed once it is in the raft
+//! log of a majority of the nodes, and is then applied to the store of every node in the
+//! order of the raft log. A cluster of `2f + 1` nodes takes writes, and keeps those it
+//! committed, as long as at most `f` of them are down.
+//!
+//! The store keeps the index of the last entry applied to it, written along with the entry,
+//! so applied entries are dropped from the raft log once there are enough of them. A node
+//! missing entries the leader dropped gets a full copy of the store of the leader instead,
+//! see `KvStore::changes`.
+//!
+//! Nodes talk to each other over TCP with messages framed like those of the kvs protocol.
+
+use crate::error::{Error, ErrorKind};
+use crate::protocol::{read_frame, write_frame};
+use crate::replication::{Changes, ReplicationCursor};
+use crate::{
+    BackupJob, KvLog, KvStore, KvsEngine, Result, RuntimeOptions, ScanCursor, ScanPage, Stats,
+};
+use failure::{Fail, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, Seek, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Name of the file keeping the raft log, in the directory of the store.
+const RAFT_LOG_FILE_NAME: &str = "raft.log";
+/// Name of the file keeping the term and vote of a node, in the directory of the store.
+const RAFT_STATE_FILE_NAME: &str = "raft.state";
+/// Time between two rounds of appended entries from the leader, which are empty when
+/// there is nothing new, to keep the other nodes from starting an election.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// Shortest time a node waits to hear from a leader before starting an election. Each
+/// wait is picked at random between it and twice it, so that nodes rarely start at once.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+/// Time between two checks for an election timeout.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+/// Time to wait for another node to reply to a vote request or appended entries.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+/// Time to wait for a write to be applied, including one forwarded to the leader.
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of entries sent to a node at a time.
+const MAX_ENTRIES: usize = 256;
+/// Number of applied entries in the raft log after which they are dropped from it.
+const COMPACT_ENTRIES: u64 = 1000;
+/// Namespace of the store keeping the index of the last entry applied to it.
+const RAFT_NAMESPACE: &str = "raft";
+/// Key of the index of the last applied entry, in `RAFT_NAMESPACE`.
+const APPLIED_KEY: &[u8] = b"applied";
+
+/// A node of a raft cluster, serving a store whose writes are agreed on by the cluster.
+/// It can be cloned and shared between threads, e.g. by a server and the connections of
+/// the other nodes.
+///
+/// Writes go through the leader: a write to another node is forwarded to it. A write
+/// returns once the cluster committed it and the node it was sent to applied it. Reads are
+/// served by the store of the node, which may lag behind the leader.
+///
+/// The raft log is kept next to the files of the store, which should only be written
+/// through the node: a node does not start on a store with keys but no raft entry applied
+/// to it. A restarted node goes on from the last entry applied to its store.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvStore, KvsServer, RaftNode};
+///
+/// let nodes = [
+///     (1, "127.0.0.1:5001".parse().unwrap()),
+///     (2, "127.0.0.1:5002".parse().unwrap()),
+///     (3, "127.0.0.1:5003".parse().unwrap()),
+/// ];
+/// let node = RaftNode::start(KvStore::open("node1").unwrap(), 1, &nodes).unwrap();
+/// KvsServer::new(node).run("127.0.0.1:4000").unwrap();
+/// ```
+#[derive(Clone)]
+pub struct RaftNode {
+    node: Arc<Node>,
+}
+
+/// What the clones of a `RaftNode`, and its threads, share.
+struct Node {
+    /// Id of the node in the cluster.
+    id: u64,
+    /// Address the node listens on for the other nodes.
+    addr: SocketAddr,
+    /// Ids and addresses of the other nodes.
+    peers: Vec<(u64, SocketAddr)>,
+    state: Mutex<State>,
+    /// Notified when entries are appended, committed or applied, or the role of the node
+    /// changes.
+    changed: Condvar,
+}
+
+/// Role of a node in the current term.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// An entry of the raft log: a write, and the term of the leader which appended it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    term: u64,
+    command: KvLog,
+}
+
+/// The entry before the first one of the raft log, the last one dropped from it, written
+/// at the start of the raft log file. Both are 0 when no entry was dropped.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct LogStart {
+    index: u64,
+    term: u64,
+}
+
+/// What a node has to remember across restarts besides its raft log.
+#[derive(Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<u64>,
+    /// Whether the store is being replaced by a copy of the store of the leader, and only
+    /// has part of it.
+    installing: bool,
+}
+
+/// Messages between nodes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Message {
+    /// A candidate asking for the vote of a node.
+    RequestVote {
+        term: u64,
+        candidate: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    /// The leader appending entries after the one at `prev_log_index`, if it matches.
+    AppendEntries {
+        term: u64,
+        leader: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// A node forwarding a write to the leader.
+    Propose { command: KvLog },
+    /// The leader sending a page of a copy of its store, with the writes of the entries up
+    /// to `last_index`, to a node missing entries it dropped from its raft log.
+    InstallSnapshot {
+        term: u64,
+        leader: u64,
+        last_index: u64,
+        last_term: u64,
+        changes: Changes,
+    },
+}
+
+/// Replies to messages, in the same order.
+#[derive(Debug, Serialize, Deserialize)]
+enum Reply {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// Whether the entries were appended, and the index of the last entry known to match
+    /// the raft log of the leader.
+    Appended {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    /// Index of the entry of the write, 0 if it was not appended, and its error if it
+    /// failed.
+    Proposed {
+        index: u64,
+        error: Option<ErrorKind>,
+    },
+    Installed {
+        term: u64,
+    },
+}
+
+/// A connection to another node.
+type Connection = (BufReader<TcpStream>, BufWriter<TcpStream>);
+
+/// A copy of the store of the leader being sent to another node.
+struct StoreCopy {
+    /// The last entry whose write is in the copy.
+    last: LogStart,
+    /// Where the node is in the copy.
+    cursor: Option<ReplicationCursor>,
+}
+
+/// State of a node, entries being indexed from 1.
+struct State {
+    term: u64,
+    voted_for: Option<u64>,
+    installing: bool,
+    /// The entry before the first one of `log`.
+    log_start: LogStart,
+    log: Vec<Entry>,
+    /// Appends to the raft log file.
+    log_writer: BufWriter<File>,
+    log_path: PathBuf,
+    state_path: PathBuf,
+    /// Index of the last committed entry.
+    commit: u64,
+    /// Index of the last entry applied to the store.
+    applied: u64,
+    role: Role,
+    leader: Option<u64>,
+    /// When to start an election, unless a leader is heard from before.
+    election_deadline: Instant,
+    /// Nodes which voted for this one in the current term, as a candidate.
+    votes: HashSet<u64>,
+    /// Index of the next entry to send to each other node, as the leader.
+    next_index: HashMap<u64, u64>,
+    /// Index of the last entry known to be replicated on each other node, as the leader.
+    match_index: HashMap<u64, u64>,
+    /// Errors of the applied writes appended in the current term, as the leader, for the
+    /// proposals waiting for them.
+    results: HashMap<u64, Option<ErrorKind>>,
+    /// Number of nodes in the cluster.
+    cluster_size: usize,
+    store: KvStore,
+    stopped: bool,
+}
+
+impl RaftNode {
+    /// Start node `id` of the cluster of `nodes`, given by their id and the address they
+    /// listen on for each other, serving `store`. The node listens on its address, and
+    /// runs in background threads until `shutdown`.
+    ///
+    /// # Errors
+    ///
+    /// - StoreNotEmpty: The store has keys, but no raft entry was applied to it.
+    /// - Io: Failed to read the raft log or state, or to listen on the address of the node.
+    /// - Serde: The raft state is malformed.
+    /// - Corruption: The index of the last entry applied to the store is malformed.
+    /// - Others: Same as `KvStore::get`, or `KvStore::clear` when the store was being
+    ///   replaced by a copy of the store of the leader.
+    ///
+    /// # Panics
+    ///
+    /// If `id` is not one of `nodes`.
+    pub fn start(mut store: KvStore, id: u64, nodes: &[(u64, SocketAddr)]) -> Result<RaftNode> {
+        let addr = match nodes.iter().find(|(node, _)| *node == id) {
+            Some((_, addr)) => *addr,
+            None => panic!("node {} is not in the cluster", id),
+        };
+        let dir = store
+            .log_file_path
+            .parent()
+            .map_or_else(PathBuf::new, Path::to_owned);
+        let log_path = dir.join(RAFT_LOG_FILE_NAME);
+        let state_path = dir.join(RAFT_STATE_FILE_NAME);
+        let hard_state = match fs::read(&state_path) {
+            Ok(bytes) => bincode::deserialize(&bytes).context(ErrorKind::Serde)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.context(ErrorKind::Io).into()),
+        };
+        let applied = match store.namespace(RAFT_NAMESPACE).get(APPLIED_KEY)? {
+            Some(applied) => applied.parse::<u64>().context(ErrorKind::Corruption)?,
+            None if store.has_keys() && !hard_state.installing => {
+                return Err(Error::from(ErrorKind::StoreNotEmpty))
+            }
+            None => 0,
+        };
+        let (log_start, log, log_writer) = load_log(&log_path)?;
+        let listener = TcpListener::bind(addr).context(ErrorKind::Io)?;
+        let peers: Vec<_> = nodes
+            .iter()
+            .filter(|(node, _)| *node != id)
+            .copied()
+            .collect();
+        let mut state = State {
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            installing: hard_state.installing,
+            log_start,
+            log,
+            log_writer,
+            log_path,
+            state_path,
+            commit: applied,
+            applied,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: Instant::now() + election_timeout(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            results: HashMap::new(),
+            cluster_size: peers.len() + 1,
+            store,
+            stopped: false,
+        };
+        if state.installing {
+            // the node stopped in the middle of a copy, it gets a new one
+            state.forget_store()?;
+        } else if applied < log_start.index || applied > state.last_index() {
+            // the raft log does not go on from the store, the leader sends what is missing
+            state.log_start = LogStart {
+                index: applied,
+                term: 0,
+            };
+            state.log.clear();
+            state.rewrite_log()?;
+        }
+        let node = Arc::new(Node {
+            id,
+            addr,
+            peers,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+        });
+        let listening = Arc::clone(&node);
+        thread::spawn(move || listening.listen(listener));
+        let ticking = Arc::clone(&node);
+        thread::spawn(move || ticking.tick());
+        for &(peer, addr) in &node.peers {
+            let replicating = Arc::clone(&node);
+            thread::spawn(move || replicating.replicate(peer, addr));
+        }
+        Ok(RaftNode { node })
+    }
+
+    /// Returns the id of the node.
+    pub fn id(&self) -> u64 {
+        self.node.id
+    }
+
+    /// Returns the id of the leader of the cluster as far as the node knows, or `None`
+    /// during an election.
+    pub fn leader(&self) -> Option<u64> {
+        self.node.state().leader
+    }
+
+    /// Returns whether the node is the leader of the cluster.
+    pub fn is_leader(&self) -> bool {
+        self.node.state().role == Role::Leader
+    }
+
+    /// Returns the current term of the node, increasing with each election.
+    pub fn term(&self) -> u64 {
+        self.node.state().term
+    }
+
+    /// Set the value of a key, once committed by the cluster.
+    ///
+    /// # Errors
+    ///
+    /// - NotLeader: No leader is known, or it could not commit the write in time.
+    /// - Io: Failed to forward the write to the leader.
+    /// - Others: Same as `KvStore::set`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.node
+            .write(KvLog::Set(key.into_bytes(), value.into_bytes()))
+    }
+
+    /// Get the value of a key from the store of the node, or `None` if it is not present.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get`.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.node.state().store.get(key)
+    }
+
+    /// Remove a key, once committed by the cluster.
+    ///
+    /// # Errors
+    ///
+    /// - NotLeader: No leader is known, or it could not commit the write in time.
+    /// - Io: Failed to forward the write to the leader.
+    /// - Others: Same as `KvStore::remove`.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.node.write(KvLog::Rm(key.into_bytes()))
+    }
+
+    /// Get the key-value pairs whose key starts with `prefix` from the store of the node,
+    /// in lexicographic order of keys.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::scan_prefix`.
+    pub fn scan_prefix(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        KvsEngine::scan_prefix(&mut self.node.state().store, prefix)
+    }
+
+    /// Stop the node, which no longer takes part in the cluster or takes writes. Pending
+    /// writes fail with NotLeader.
+    pub fn shutdown(&self) {
+        self.node.state().stopped = true;
+        self.node.changed.notify_all();
+        // wake the listener up, so that it sees the node is stopped
+        let _ = TcpStream::connect(self.node.addr);
+    }
+}
+
+impl Node {
+    /// Lock the state of the node.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Accept connections of the other nodes until the node is stopped.
+    fn listen(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            if self.state().stopped {
+                return;
+            }
+            match stream {
+                Ok(stream) => {
+                    let node = Arc::clone(&self);
+                    // a failed connection is made again by the other node
+                    thread::spawn(move || node.serve(stream));
+                }
+                Err(e) => eprintln!("Failed to accept a raft connection: {}", e),
+            }
+        }
+    }
+
+    /// Reply to the messages of a connection until it is closed or the node is stopped.
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone().context(ErrorKind::Io)?);
+        let mut writer = BufWriter::new(stream);
+        while let Some(message) = read_frame(&mut reader)? {
+            if self.state().stopped {
+                break;
+            }
+            let reply = self.handle(message)?;
+            write_frame(&mut writer, &reply)?;
+            writer.flush().context(ErrorKind::Io)?;
+        }
+        Ok(())
+    }
+
+    /// The reply to `message`.
+    fn handle(&self, message: Message) -> Result<Reply> {
+        match message {
+            Message::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => self.vote(term, candidate, last_log_index, last_log_term),
+            Message::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.append_entries(
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            ),
+            // a forwarded write is not forwarded again
+            Message::Propose { command } => Ok(match self.propose(command) {
+                Ok((index, error)) => Reply::Proposed { index, error },
+                Err(e) => Reply::Proposed {
+                    index: 0,
+                    error: Some(e.kind()),
+                },
+            }),
+            Message::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                changes,
+            } => self.install_snapshot(term, leader, last_index, last_term, changes),
+        }
+    }
+
+    /// Vote for `candidate` in `term`, unless the node voted for another one, or has
+    /// entries the candidate is missing.
+    fn vote(
+        &self,
+        term: u64,
+        candidate: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Result<Reply> {
+        let mut state = self.state();
+        if term > state.term {
+            state.step_down(term)?;
+            self.changed.notify_all();
+        }
+        let last_index = state.last_index();
+        let up_to_date = (last_log_term, last_log_index) >= (state.term_at(last_index), last_index);
+        let granted = term == state.term
+            && state
+                .voted_for
+                .is_none_or(|voted_for| voted_for == candidate)
+            && up_to_date;
+        if granted {
+            state.voted_for = Some(candidate);
+            state.save_hard_state()?;
+            state.election_deadline = Instant::now() + election_timeout();
+        }
+        Ok(Reply::Vote {
+            term: state.term,
+            granted,
+        })
+    }
+
+    /// Append the entries of the leader of `term` after the one at `prev_log_index`,
+    /// replacing those which conflict with them, and apply those it committed.
+    fn append_entries(
+        &self,
+        term: u64,
+        leader: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<Reply> {
+        let mut state = self.state();
+        let failed = |state: &State, match_index| Reply::Appended {
+            term: state.term,
+            success: false,
+            match_index,
+        };
+        if term < state.term {
+            return Ok(failed(&state, 0));
+        }
+        if term > state.term || state.role != Role::Follower {
+            state.step_down(term)?;
+            self.changed.notify_all();
+        }
+        state.leader = Some(leader);
+        state.election_deadline = Instant::now() + election_timeout();
+        if state.installing {
+            // the copy of the store came from another leader
+            state.forget_store()?;
+        }
+        if prev_log_index > state.last_index() {
+            let last_index = state.last_index();
+            return Ok(failed(&state, last_index));
+        }
+        if prev_log_index >= state.log_start.index && state.term_at(prev_log_index) != prev_log_term
+        {
+            return Ok(failed(&state, prev_log_index - 1));
+        }
+        let mut index = prev_log_index;
+        let mut appended = Vec::new();
+        for entry in entries {
+            index += 1;
+            // dropped entries were committed, so they match those of the leader
+            if index <= state.log_start.index {
+                continue;
+            }
+            if appended.is_empty() && index <= state.last_index() {
+                if state.term_at(index) == entry.term {
+                    continue;
+                }
+                state.truncate(index - 1)?;
+            }
+            appended.push(entry);
+        }
+        state.append(appended)?;
+        if leader_commit > state.commit {
+            state.commit = state.commit.max(leader_commit.min(index));
+            state.apply_committed();
+            self.changed.notify_all();
+        }
+        Ok(Reply::Appended {
+            term: state.term,
+            success: true,
+            match_index: index,
+        })
+    }
+
+    /// Apply a page of a copy of the store of the leader of `term`, which has the writes of
+    /// the entries up to `last_index`. The copy replaces the store, and the entries up to
+    /// `last_index` are dropped from the raft log once it has all of it.
+    fn install_snapshot(
+        &self,
+        term: u64,
+        leader: u64,
+        last_index: u64,
+        last_term: u64,
+        changes: Changes,
+    ) -> Result<Reply> {
+        let mut state = self.state();
+        if term < state.term {
+            return Ok(Reply::Installed { term: state.term });
+        }
+        if term > state.term || state.role != Role::Follower {
+            state.step_down(term)?;
+            self.changed.notify_all();
+        }
+        state.leader = Some(leader);
+        state.election_deadline = Instant::now() + election_timeout();
+        if changes.is_full() && !state.installing {
+            // a node which applied the entries already has no use for the copy
+            if state.applied >= last_index {
+                return Ok(Reply::Installed { term: state.term });
+            }
+            state.installing = true;
+            state.save_hard_state()?;
+        }
+        if !state.installing {
+            return Ok(Reply::Installed { term: state.term });
+        }
+        let done = changes.cursor.copy.is_none();
+        state.store.apply_changes(changes)?;
+        if done {
+            state.store.sync()?;
+            let follows = last_index >= state.log_start.index
+                && last_index <= state.last_index()
+                && state.term_at(last_index) == last_term;
+            // entries after the copy are kept if they follow it
+            state.log = match follows {
+                true => state.log[(last_index - state.log_start.index) as usize..].to_vec(),
+                false => Vec::new(),
+            };
+            state.log_start = LogStart {
+                index: last_index,
+                term: last_term,
+            };
+            state.rewrite_log()?;
+            state.installing = false;
+            state.save_hard_state()?;
+            state.applied = last_index;
+            state.commit = state.commit.max(last_index);
+            state.apply_committed();
+            self.changed.notify_all();
+        }
+        Ok(Reply::Installed { term: state.term })
+    }
+
+    /// Have the cluster commit `command`, and wait for the node to apply it. Writes to
+    /// another node than the leader are forwarded to it.
+    fn write(&self, command: KvLog) -> Result<()> {
+        let state = self.state();
+        if state.stopped {
+            return Err(Error::from(ErrorKind::NotLeader));
+        }
+        let error = match state.role {
+            Role::Leader => {
+                drop(state);
+                self.propose(command)?.1
+            }
+            _ => {
+                let addr = self
+                    .peers
+                    .iter()
+                    .find(|(peer, _)| Some(*peer) == state.leader)
+                    .map(|(_, addr)| *addr)
+                    .ok_or_else(|| Error::from(ErrorKind::NotLeader))?;
+                drop(state);
+                let message = Message::Propose { command };
+                let (index, error) = match call(&mut None, addr, &message, PROPOSE_TIMEOUT)? {
+                    Reply::Proposed { index, error } => (index, error),
+                    _ => return Err(Error::from(ErrorKind::Serde)),
+                };
+                // the write is committed, the node learns it with the next entries
+                let deadline = Instant::now() + PROPOSE_TIMEOUT;
+                let mut state = self.state();
+                while state.applied < index && !state.stopped && Instant::now() < deadline {
+                    state = self.changed.wait_timeout(state, TICK_INTERVAL).unwrap().0;
+                }
+                error
+            }
+        };
+        match error {
+            Some(kind) => Err(Error::from(kind)),
+            None => Ok(()),
+        }
+    }
+
+    /// Append `command` to the raft log as the leader, and wait for it to be applied.
+    /// Returns its index, and the error of applying it if it failed.
+    ///
+    /// # Errors
+    ///
+    /// - NotLeader: The node is not the leader, or it could not commit the write in time.
+    /// - Others: Same as `State::append`.
+    fn propose(&self, command: KvLog) -> Result<(u64, Option<ErrorKind>)> {
+        let mut state = self.state();
+        if state.role != Role::Leader || state.stopped {
+            return Err(Error::from(ErrorKind::NotLeader));
+        }
+        let term = state.term;
+        let index = state.last_index() + 1;
+        state.append(vec![Entry { term, command }])?;
+        state.advance_commit();
+        self.changed.notify_all();
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        loop {
+            if state.applied >= index {
+                // another leader replaced the entry, which may be dropped from the raft log
+                let replaced = match index > state.log_start.index {
+                    true => state.term_at(index) != term,
+                    false => state.term != term,
+                };
+                if replaced {
+                    return Err(Error::from(ErrorKind::NotLeader));
+                }
+                return Ok((index, state.results.remove(&index).flatten()));
+            }
+            let now = Instant::now();
+            if state.stopped || state.term != term || now >= deadline {
+                return Err(Error::from(ErrorKind::NotLeader));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Start elections when no leader was heard from in time, until the node is stopped.
+    fn tick(self: Arc<Self>) {
+        loop {
+            thread::sleep(TICK_INTERVAL);
+            let mut state = self.state();
+            if state.stopped {
+                return;
+            }
+            if state.role != Role::Leader && Instant::now() >= state.election_deadline {
+                if let Err(e) = self.start_election(&mut state) {
+                    eprintln!("Failed to start a raft election: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Become a candidate in a new term, and ask the other nodes for their votes.
+    fn start_election(self: &Arc<Self>, state: &mut State) -> Result<()> {
+        state.term += 1;
+        state.voted_for = Some(self.id);
+        state.save_hard_state()?;
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.votes = [self.id].iter().copied().collect();
+        state.election_deadline = Instant::now() + election_timeout();
+        if state.votes.len() * 2 > state.cluster_size {
+            return self.become_leader(state);
+        }
+        let last_log_index = state.last_index();
+        let message = Message::RequestVote {
+            term: state.term,
+            candidate: self.id,
+            last_log_index,
+            last_log_term: state.term_at(last_log_index),
+        };
+        for &(peer, addr) in &self.peers {
+            let node = Arc::clone(self);
+            let message = message.clone();
+            let term = state.term;
+            thread::spawn(move || node.request_vote(peer, addr, message, term));
+        }
+        Ok(())
+    }
+
+    /// Ask `peer` for its vote in `term`, and become the leader with a majority of votes.
+    fn request_vote(&self, peer: u64, addr: SocketAddr, message: Message, term: u64) {
+        let reply = call(&mut None, addr, &message, RPC_TIMEOUT);
+        let mut state = self.state();
+        let result = match reply {
+            Ok(Reply::Vote { term: current, .. }) if current > state.term => {
+                let result = state.step_down(current);
+                self.changed.notify_all();
+                result
+            }
+            Ok(Reply::Vote { granted: true, .. })
+                if state.role == Role::Candidate && state.term == term =>
+            {
+                state.votes.insert(peer);
+                match state.votes.len() * 2 > state.cluster_size {
+                    true => self.become_leader(&mut state),
+                    false => Ok(()),
+                }
+            }
+            // the node is down, or the vote is lost
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to count a raft vote: {}", e);
+        }
+    }
+
+    /// Become the leader of the current term, appending an entry without writes for the
+    /// entries of previous terms to be committed along with it.
+    fn become_leader(&self, state: &mut State) -> Result<()> {
+        state.role = Role::Leader;
+        state.leader = Some(self.id);
+        let next_index = state.last_index() + 1;
+        for &(peer, _) in &self.peers {
+            state.next_index.insert(peer, next_index);
+            state.match_index.insert(peer, 0);
+        }
+        let term = state.term;
+        state.append(vec![Entry {
+            term,
+            command: KvLog::Batch(Vec::new()),
+        }])?;
+        state.advance_commit();
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Send the entries `peer` is missing while the node is the leader, or a copy of the
+    /// store when some of them were dropped from the raft log, until the node is stopped.
+    fn replicate(&self, peer: u64, addr: SocketAddr) {
+        let mut connection = None;
+        let mut copy: Option<StoreCopy> = None;
+        let mut state = self.state();
+        loop {
+            if state.stopped {
+                return;
+            }
+            if state.role != Role::Leader {
+                copy = None;
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+            let term = state.term;
+            let prev_log_index = state.next_index[&peer] - 1;
+            let message = if prev_log_index < state.log_start.index {
+                let cursor = copy.as_ref().and_then(|copy| copy.cursor);
+                let changes = match state.store.changes(cursor) {
+                    Ok(changes) => changes,
+                    Err(e) => {
+                        eprintln!("Failed to copy the store for raft node {}: {}", peer, e);
+                        copy = None;
+                        state = self
+                            .changed
+                            .wait_timeout(state, HEARTBEAT_INTERVAL)
+                            .unwrap()
+                            .0;
+                        continue;
+                    }
+                };
+                // a copy starts over when the store dropped it, from the store as it is now
+                let last = match (&copy, changes.is_full()) {
+                    (Some(copy), false) => copy.last,
+                    _ => LogStart {
+                        index: state.applied,
+                        term: state.term_at(state.applied),
+                    },
+                };
+                copy = Some(StoreCopy {
+                    last,
+                    cursor: Some(changes.cursor),
+                });
+                Message::InstallSnapshot {
+                    term,
+                    leader: self.id,
+                    last_index: last.index,
+                    last_term: last.term,
+                    changes,
+                }
+            } else {
+                copy = None;
+                let first = (prev_log_index - state.log_start.index) as usize;
+                Message::AppendEntries {
+                    term,
+                    leader: self.id,
+                    prev_log_index,
+                    prev_log_term: state.term_at(prev_log_index),
+                    entries: state.log[first..]
+                        .iter()
+                        .take(MAX_ENTRIES)
+                        .cloned()
+                        .collect(),
+                    leader_commit: state.commit,
+                }
+            };
+            drop(state);
+            let reply = call(&mut connection, addr, &message, RPC_TIMEOUT);
+            state = self.state();
+            if let Ok(Reply::Installed { term: current }) = reply {
+                if current > state.term {
+                    if let Err(e) = state.step_down(current) {
+                        eprintln!("Failed to step down as the raft leader: {}", e);
+                    }
+                    self.changed.notify_all();
+                    continue;
+                }
+                if state.role != Role::Leader || state.term != term {
+                    continue;
+                }
+                let last = copy.as_ref().filter(|copy| match copy.cursor {
+                    Some(cursor) => cursor.copy.is_none(),
+                    None => false,
+                });
+                if let Some(&StoreCopy { last, .. }) = last {
+                    copy = None;
+                    let matched = state.match_index[&peer].max(last.index);
+                    state.match_index.insert(peer, matched);
+                    state.next_index.insert(peer, matched + 1);
+                    if state.advance_commit() {
+                        self.changed.notify_all();
+                    }
+                }
+                // send the rest right away
+                continue;
+            }
+            if reply.is_err() {
+                copy = None;
+            }
+            if let Ok(Reply::Appended {
+                term: current,
+                success,
+                match_index,
+            }) = reply
+            {
+                if current > state.term {
+                    if let Err(e) = state.step_down(current) {
+                        eprintln!("Failed to step down as the raft leader: {}", e);
+                    }
+                    self.changed.notify_all();
+                    continue;
+                }
+                if state.role != Role::Leader || state.term != term {
+                    continue;
+                }
+                if success {
+                    let matched = state.match_index[&peer].max(match_index);
+                    state.match_index.insert(peer, matched);
+                    state.next_index.insert(peer, matched + 1);
+                    if state.advance_commit() {
+                        self.changed.notify_all();
+                    }
+                } else {
+                    let next_index = state.next_index[&peer] - 1;
+                    let next_index = next_index.min(match_index + 1).max(1);
+                    state.next_index.insert(peer, next_index);
+                }
+                // send the rest right away
+                if !success || state.next_index[&peer] <= state.last_index() {
+                    continue;
+                }
+            }
+            state = self
+                .changed
+                .wait_timeout(state, HEARTBEAT_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl State {
+    /// Index of the last entry, 0 if there is none.
+    fn last_index(&self) -> u64 {
+        self.log_start.index + self.log.len() as u64
+    }
+
+    /// The entry at `index`, which is in the raft log.
+    fn entry(&self, index: u64) -> &Entry {
+        &self.log[(index - self.log_start.index) as usize - 1]
+    }
+
+    /// Term of the entry at `index`, which is in the raft log or the one before it.
+    fn term_at(&self, index: u64) -> u64 {
+        match index == self.log_start.index {
+            true => self.log_start.term,
+            false => self.entry(index).term,
+        }
+    }
+
+    /// Write the term and vote of the node, and whether it is getting a copy of the store,
+    /// to the raft state file.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to write the raft state file.
+    /// - Serde: Failed to encode the raft state.
+    fn save_hard_state(&self) -> Result<()> {
+        let hard_state = HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+            installing: self.installing,
+        };
+        let bytes = bincode::serialize(&hard_state).context(ErrorKind::Serde)?;
+        let temp_path = self.state_path.with_extension("tmp");
+        let mut file = File::create(&temp_path).context(ErrorKind::Io)?;
+        file.write_all(&bytes).context(ErrorKind::Io)?;
+        file.sync_data().context(ErrorKind::Io)?;
+        fs::rename(&temp_path, &self.state_path).context(ErrorKind::Io)?;
+        Ok(())
+    }
+
+    /// Append `entries` to the raft log, once they are on disk.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to write the raft log file.
+    /// - Serde: Failed to encode an entry.
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        for entry in &entries {
+            write_frame(&mut self.log_writer, entry)?;
+        }
+        self.log_writer.flush().context(ErrorKind::Io)?;
+        self.log_writer
+            .get_ref()
+            .sync_data()
+            .context(ErrorKind::Io)?;
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    /// Drop the entries after `index` from the raft log, rewriting its file.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to write the raft log file.
+    /// - Serde: Failed to encode an entry.
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.log.truncate((index - self.log_start.index) as usize);
+        self.rewrite_log()
+    }
+
+    /// Drop the applied entries from the raft log once there are `COMPACT_ENTRIES` of
+    /// them, the store being synced first so that it keeps their writes.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::sync` and `rewrite_log`.
+    fn compact_log(&mut self) -> Result<()> {
+        if self.applied - self.log_start.index < COMPACT_ENTRIES {
+            return Ok(());
+        }
+        self.store.sync()?;
+        let start = LogStart {
+            index: self.applied,
+            term: self.term_at(self.applied),
+        };
+        self.log
+            .drain(..(start.index - self.log_start.index) as usize);
+        self.log_start = start;
+        self.rewrite_log()
+    }
+
+    /// Empty the store and the raft log, for the leader to send the entries again.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::clear`, `rewrite_log` and `save_hard_state`.
+    fn forget_store(&mut self) -> Result<()> {
+        self.store.clear()?;
+        self.log_start = LogStart::default();
+        self.log.clear();
+        self.rewrite_log()?;
+        self.commit = 0;
+        self.applied = 0;
+        self.installing = false;
+        self.save_hard_state()
+    }
+
+    /// Write the raft log file again from `log_start` and the entries.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to write the raft log file.
+    /// - Serde: Failed to encode an entry.
+    fn rewrite_log(&mut self) -> Result<()> {
+        let temp_path = self.log_path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path).context(ErrorKind::Io)?);
+        write_frame(&mut writer, &self.log_start)?;
+        for entry in &self.log {
+            write_frame(&mut writer, entry)?;
+        }
+        writer.flush().context(ErrorKind::Io)?;
+        writer.get_ref().sync_data().context(ErrorKind::Io)?;
+        fs::rename(&temp_path, &self.log_path).context(ErrorKind::Io)?;
+        self.log_writer = BufWriter::new(open_log(&self.log_path)?);
+        Ok(())
+    }
+
+    /// Become a follower in `term`, forgetting the vote of a previous term.
+    ///
+    /// # Errors
+    ///
+    /// Same as `save_hard_state`.
+    fn step_down(&mut self, term: u64) -> Result<()> {
+        self.role = Role::Follower;
+        self.votes.clear();
+        self.results.clear();
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+            self.save_hard_state()?;
+        }
+        Ok(())
+    }
+
+    /// Commit the entries of the current term replicated on a majority of the nodes,
+    /// along with those before them, as the leader. Returns whether any were.
+    fn advance_commit(&mut self) -> bool {
+        // entries of previous terms may be on a majority and still be replaced
+        let committed = (self.commit + 1..=self.last_index()).rev().find(|&index| {
+            let replicas = self.match_index.values().filter(|&&m| m >= index).count();
+            self.term_at(index) == self.term && (replicas + 1) * 2 > self.cluster_size
+        });
+        match committed {
+            Some(index) => {
+                self.commit = index;
+                self.apply_committed();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Apply the committed entries to the store, in order, then compact the raft log.
+    fn apply_committed(&mut self) {
+        while self.applied < self.commit {
+            self.applied += 1;
+            let entry = self.entry(self.applied);
+            let (term, command) = (entry.term, entry.command.clone());
+            let result = apply(&mut self.store, self.applied, command)
+                .err()
+                .map(|e| e.kind());
+            if self.role == Role::Leader && term == self.term {
+                self.results.insert(self.applied, result);
+            }
+        }
+        if let Err(e) = self.compact_log() {
+            eprintln!("Failed to compact the raft log: {}", e);
+        }
+    }
+}
+
+impl KvsEngine for RaftNode {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        RaftNode::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        RaftNode::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        RaftNode::remove(self, key)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        RaftNode::scan_prefix(self, prefix)
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        let store = &mut self.node.state().store;
+        store.scan_prefix_from(prefix, cursor, limit)
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        self.node.state().store.stats()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.node.state().store.compact()
+    }
+
+    fn start_backup(&mut self, dest: PathBuf) -> Result<BackupJob> {
+        self.node.state().store.start_backup(dest)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.node.state().store.sync()
+    }
+
+    fn set_runtime_options(&mut self, options: RuntimeOptions) -> Result<()> {
+        self.node.state().store.set_runtime_options(options);
+        Ok(())
+    }
+}
+
+/// Apply the committed write of the entry at `index` to `store`, along with the index. The
+/// index is written alone when the write fails.
+fn apply(store: &mut KvStore, index: u64, command: KvLog) -> Result<()> {
+    let logs = match command {
+        KvLog::Set(key, value) => Ok(vec![KvLog::Set(key, value)]),
+        KvLog::Rm(key) => match store.contains_key(&key)? {
+            true => Ok(vec![KvLog::Rm(key)]),
+            false => Err(Error::from(ErrorKind::KeyNotFound)),
+        },
+        KvLog::Batch(logs) => Ok(logs),
+        _ => Err(Error::from(ErrorKind::Corruption)),
+    };
+    let applied = KvLog::new_namespaced(
+        RAFT_NAMESPACE.to_owned(),
+        KvLog::Set(APPLIED_KEY.to_vec(), index.to_string().into_bytes()),
+    );
+    let result = logs.and_then(|mut logs| {
+        logs.push(applied.clone());
+        store.apply_log(KvLog::Batch(logs))
+    });
+    if result.is_err() {
+        store.apply_log(applied)?;
+    }
+    result
+}
+
+/// Read the raft log at `path`, returning the entry before its first one, its entries and a
+/// writer appending to it. A torn entry at the end, left by a crash while appending it, is
+/// dropped.
+///
+/// # Errors
+///
+/// - Io: Failed to read or open the raft log file.
+/// - Serde: An entry is malformed.
+fn load_log(path: &Path) -> Result<(LogStart, Vec<Entry>, BufWriter<File>)> {
+    let file = open_log(path)?;
+    let mut reader = BufReader::new(file);
+    let log_start = match read_frame(&mut reader) {
+        Ok(Some(log_start)) => log_start,
+        Err(e) if e.kind() != ErrorKind::Io => return Err(e),
+        // a new raft log, or one torn while it was created
+        Ok(None) | Err(_) => {
+            let mut file = reader.into_inner();
+            file.set_len(0).context(ErrorKind::Io)?;
+            write_frame(&mut file, &LogStart::default())?;
+            file.sync_data().context(ErrorKind::Io)?;
+            return Ok((LogStart::default(), Vec::new(), BufWriter::new(file)));
+        }
+    };
+    let mut log = Vec::new();
+    let mut valid_len = reader.stream_position().context(ErrorKind::Io)?;
+    loop {
+        match read_frame(&mut reader) {
+            Ok(Some(entry)) => log.push(entry),
+            Err(e) if e.kind() != ErrorKind::Io => return Err(e),
+            Ok(None) | Err(_) => break,
+        }
+        valid_len = reader.stream_position().context(ErrorKind::Io)?;
+    }
+    let file = reader.into_inner();
+    file.set_len(valid_len).context(ErrorKind::Io)?;
+    Ok((log_start, log, BufWriter::new(file)))
+}
+
+/// Open the raft log file at `path` for reading and appending, creating it if needed.
+fn open_log(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .context(ErrorKind::Io)?;
+    Ok(file)
+}
+
+/// Send `message` to the node at `addr` through `connection`, connecting first if there is
+/// none, and wait at most `timeout` for its reply. The connection is dropped on failure.
+///
+/// # Errors
+///
+/// - Io: Failed to connect, send the message, or get the reply in time.
+/// - Serde: The reply is malformed.
+fn call(
+    connection: &mut Option<Connection>,
+    addr: SocketAddr,
+    message: &Message,
+    timeout: Duration,
+) -> Result<Reply> {
+    let result = exchange(connection, addr, message, timeout);
+    if result.is_err() {
+        *connection = None;
+    }
+    result
+}
+
+/// Send `message` and read its reply, see `call`.
+fn exchange(
+    connection: &mut Option<Connection>,
+    addr: SocketAddr,
+    message: &Message,
+    timeout: Duration,
+) -> Result<Reply> {
+    if connection.is_none() {
+        let stream = TcpStream::connect_timeout(&addr, RPC_TIMEOUT).context(ErrorKind::Io)?;
+        stream
+            .set_read_timeout(Some(timeout))
+            .context(ErrorKind::Io)?;
+        stream.set_nodelay(true).context(ErrorKind::Io)?;
+        let reader = BufReader::new(stream.try_clone().context(ErrorKind::Io)?);
+        *connection = Some((reader, BufWriter::new(stream)));
+    }
+    let (reader, writer) = connection.as_mut().unwrap();
+    write_frame(&mut *writer, message)?;
+    writer.flush().context(ErrorKind::Io)?;
+    read_frame(&mut *reader)?.ok_or_else(|| Error::from(ErrorKind::Io))
+}
+
+/// A random election timeout between `ELECTION_TIMEOUT` and twice it.
+fn election_timeout() -> Duration {
+    // the keys of a new `RandomState` are random enough to spread elections
+    let random = RandomState::new().build_hasher().finish();
+    ELECTION_TIMEOUT + Duration::from_millis(random % ELECTION_TIMEOUT.as_millis() as u64)
+}
@@ -0,0 +1,19 @@
+#![deny(missing_docs)]
+//! Report of salvaging a damaged log, returned by `KvStore::repair`.
+
+use std::path::PathBuf;
+
+/// What `KvStore::repair` salvaged and what it had to drop.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepairReport {
+    /// Number of records read from the damaged log.
+    pub salvaged_records: usize,
+    /// Number of damaged regions, runs of bytes that are not part of any readable record.
+    pub damaged_regions: usize,
+    /// Total length of the damaged regions.
+    pub dropped_bytes: u64,
+    /// Number of keys whose value could not be rebuilt, because part of it was lost.
+    pub dropped_keys: usize,
+    /// Copy of the log file as it was before the repair.
+    pub backup_path: PathBuf,
+}
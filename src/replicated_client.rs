@@ -0,0 +1,233 @@
+#![deny(missing_docs)]
+//! A client reading from the replicas of a `KvsServer` and writing to its primary.
+
+use crate::client::KvsClient;
+use crate::retry::RetryPolicy;
+use crate::{KvsEngine, Result, ScanCursor, ScanPage};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Latency counted for a read failing on a replica, so that `ReadBalance::LeastLatency`
+/// avoids it while the others answer faster.
+const FAILURE_LATENCY: Duration = Duration::from_secs(1);
+
+/// How a `ReplicatedKvsClient` picks the replica to read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadBalance {
+    /// Each replica in turn.
+    RoundRobin,
+    /// The replica which answered the fastest lately, by a moving average of the time its
+    /// reads took.
+    LeastLatency,
+}
+
+/// Connections to a primary and its read-only replicas, see `Replica`: writes go to the
+/// primary and reads are spread over the replicas, so that reads scale with replicas.
+///
+/// Replication is asynchronous, so a read may not see a write made just before. With
+/// `read_your_writes`, reads go to the primary for a while after each write, long enough
+/// for the replicas to catch up. A read failing on a replica with a transient error, see
+/// `RetryPolicy`, is read from the primary instead. Without replicas, everything goes to
+/// the primary.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{ReadBalance, ReplicatedKvsClient};
+/// use std::time::Duration;
+///
+/// let replicas = ["127.0.0.1:4001".parse().unwrap(), "127.0.0.1:4002".parse().unwrap()];
+/// let mut client = ReplicatedKvsClient::connect("127.0.0.1:4000".parse().unwrap(), &replicas)
+///     .unwrap()
+///     .balance(ReadBalance::LeastLatency)
+///     .read_your_writes(Duration::from_secs(1));
+/// client.set("key1".to_owned(), "42".to_owned()).unwrap();
+/// assert_eq!(client.get("key1".to_owned()).unwrap(), Some("42".to_owned()));
+/// ```
+pub struct ReplicatedKvsClient {
+    primary: (SocketAddr, KvsClient),
+    /// Addresses of the replicas with a connection to each of them, and the moving average
+    /// of the time their reads took.
+    replicas: Vec<(SocketAddr, KvsClient, Duration)>,
+    balance: ReadBalance,
+    /// Index in `replicas` of the next one to read from, for `ReadBalance::RoundRobin`.
+    next: usize,
+    /// How long reads go to the primary after a write, if they do.
+    pin: Option<Duration>,
+    /// Until when reads go to the primary.
+    pinned_until: Option<Instant>,
+}
+
+impl ReplicatedKvsClient {
+    /// Connect to the primary listening on `primary` and to the replicas listening on
+    /// `replicas`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to connect to a server.
+    pub fn connect(primary: SocketAddr, replicas: &[SocketAddr]) -> Result<ReplicatedKvsClient> {
+        let replicas = replicas
+            .iter()
+            .map(|&addr| Ok((addr, KvsClient::connect(addr)?, Duration::from_secs(0))))
+            .collect::<Result<_>>()?;
+        Ok(ReplicatedKvsClient {
+            primary: (primary, KvsClient::connect(primary)?),
+            replicas,
+            balance: ReadBalance::RoundRobin,
+            next: 0,
+            pin: None,
+            pinned_until: None,
+        })
+    }
+
+    /// Pick the replica to read from with `balance`. Default to `ReadBalance::RoundRobin`.
+    pub fn balance(mut self, balance: ReadBalance) -> ReplicatedKvsClient {
+        self.balance = balance;
+        self
+    }
+
+    /// Read from the primary for `pin` after each write, so that reads see the writes of
+    /// this client if the replicas catch up within `pin`. Default to reading from the
+    /// replicas right away.
+    pub fn read_your_writes(mut self, pin: Duration) -> ReplicatedKvsClient {
+        self.pin = Some(pin);
+        self
+    }
+
+    /// Authenticate the connections with the token of the servers, see
+    /// `KvsClient::authenticate`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::authenticate`.
+    pub fn authenticate(&mut self, token: String) -> Result<()> {
+        self.primary.1.authenticate(token.clone())?;
+        for (_, client, _) in &mut self.replicas {
+            client.authenticate(token.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the primary.
+    pub fn primary(&self) -> SocketAddr {
+        self.primary.0
+    }
+
+    /// Returns the addresses of the replicas.
+    pub fn replicas(&self) -> Vec<SocketAddr> {
+        self.replicas.iter().map(|(addr, _, _)| *addr).collect()
+    }
+
+    /// Get the value of a key from a replica, or `None` if it is not present.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::get`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.read(|client| client.get(key.clone()))
+    }
+
+    /// Set the value of a key on the primary, overwriting any previous value.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::set`.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(|client| client.set(key, value))
+    }
+
+    /// Remove a key from the primary.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::remove`.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.write(|client| client.remove(key))
+    }
+
+    /// Get a page of the keys starting with `prefix` from a replica, see
+    /// `KvsClient::scan`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::scan`.
+    pub fn scan(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        self.read(|client| client.scan(prefix.clone(), cursor, limit))
+    }
+
+    /// Run `write` on the primary, then pin reads to it if asked to.
+    fn write<T>(&mut self, write: impl FnOnce(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let result = write(&mut self.primary.1);
+        // a failed write may still have been done
+        if let Some(pin) = self.pin {
+            self.pinned_until = Some(Instant::now() + pin);
+        }
+        result
+    }
+
+    /// Run `read` on a replica, or on the primary if reads are pinned to it, there are no
+    /// replicas, or the replica failed with a transient error.
+    fn read<T>(&mut self, mut read: impl FnMut(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let pinned = self
+            .pinned_until
+            .is_some_and(|until| Instant::now() < until);
+        let index = match self.pick() {
+            Some(index) if !pinned => index,
+            _ => return read(&mut self.primary.1),
+        };
+        let (_, client, latency) = &mut self.replicas[index];
+        let start = Instant::now();
+        let result = read(client);
+        let elapsed = match &result {
+            Ok(_) => start.elapsed(),
+            Err(_) => FAILURE_LATENCY,
+        };
+        *latency = (*latency * 3 + elapsed) / 4;
+        match result {
+            Err(e) if RetryPolicy::is_transient(e.kind()) => read(&mut self.primary.1),
+            result => result,
+        }
+    }
+
+    /// Index in `replicas` of the replica to read from, if there are any.
+    fn pick(&mut self) -> Option<usize> {
+        if self.replicas.is_empty() {
+            return None;
+        }
+        match self.balance {
+            ReadBalance::RoundRobin => {
+                let index = self.next % self.replicas.len();
+                self.next = index + 1;
+                Some(index)
+            }
+            ReadBalance::LeastLatency => self
+                .replicas
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (_, _, latency))| *latency)
+                .map(|(index, _)| index),
+        }
+    }
+}
+
+impl KvsEngine for ReplicatedKvsClient {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        ReplicatedKvsClient::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        ReplicatedKvsClient::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        ReplicatedKvsClient::remove(self, key)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.read(|client| KvsEngine::scan_prefix(client, prefix.clone()))
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        ReplicatedKvsClient::scan(self, prefix, cursor, limit)
+    }
+}
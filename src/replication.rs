@@ -0,0 +1,332 @@
+#![deny(missing_docs)]
+//! Asynchronous replication of a store to read-only replicas.
+//!
+//! A replica pulls the changes of its primary from where it left, see `KvStore::changes`,
+//! and applies them in order. The changes are the records appended to the log file of the
+//! primary, so replicas do not slow down writes on the primary. A new replica, or one left
+//! behind by a compaction of the primary, gets a full copy of the store instead, a page at a
+//! time.
+
+use crate::client::KvsClient;
+use crate::error::{Error, ErrorKind};
+use crate::index::LogPointer;
+use crate::kvlog::read_value_chain;
+use crate::log_reader::LogReader;
+use crate::merge::MergeOperator;
+use crate::shared::SharedKvStore;
+use crate::value_log::ValueLog;
+use crate::{
+    BackupJob, KvLog, KvStore, KvsEngine, Result, RuntimeOptions, ScanCursor, ScanPage, Stats,
+    Watch, REPLICATION_BATCH_LEN,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Time a replica waits before pulling again once it caught up with its primary.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Time a replica waits before connecting again to its primary after failing to.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a replica is in the log file of its primary, returned with the changes it got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationCursor {
+    /// Offset in the log file after the last record the replica got.
+    pub(crate) offset: u64,
+    /// CRC-32 of the bytes of the log file just before `offset`, to tell if the log file is
+    /// still the one the cursor was taken from.
+    pub(crate) tail_checksum: u32,
+    /// Sequence number of the last write the replica got.
+    pub(crate) sequence: u64,
+    /// Where the replica is in the full copy it gets, `None` once it has all of it. The
+    /// fields above are then where the copy was taken.
+    pub(crate) copy: Option<CopyCursor>,
+}
+
+/// Where a replica is in a full copy of its primary, see `FullCopy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CopyCursor {
+    /// Tells the copy apart from the others of the primary.
+    pub(crate) id: u64,
+    /// Number of keys of the copy the replica got.
+    pub(crate) position: u64,
+}
+
+impl ReplicationCursor {
+    /// Sequence number on the primary of the last write the replica got.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+/// Changes of a store for a replica to catch up with it, returned by `KvStore::changes`
+/// and applied by `KvStore::apply_changes`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Changes {
+    /// Whether the logs start a full copy of the store, replacing the data of the replica.
+    pub(crate) full: bool,
+    /// Logs to apply in order, with their values inline.
+    pub(crate) logs: Vec<KvLog>,
+    /// Where the replica is once the logs are applied.
+    pub(crate) cursor: ReplicationCursor,
+}
+
+impl Changes {
+    /// Whether the changes start a full copy of the store, replacing the data of the
+    /// replica. The rest of the copy comes with the next changes.
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// The logs to apply in order.
+    pub fn logs(&self) -> &[KvLog] {
+        &self.logs
+    }
+
+    /// Where the replica is once the changes are applied, to get the next ones from.
+    pub fn cursor(&self) -> ReplicationCursor {
+        self.cursor
+    }
+}
+
+/// A full copy of a store for a replica, sent a page at a time by `KvStore::changes`.
+///
+/// The keys and their log pointers are taken at once when the copy starts, and their values
+/// are read page by page with file handles of the copy, which keep reading the log files of
+/// the start when compaction replaces them. Pages are read without the store, so that it
+/// goes on with writes meanwhile.
+pub(crate) struct FullCopy {
+    /// Tells the copy apart from the others of the store, see `CopyCursor`.
+    id: u64,
+    /// Keys of the copy in the order they are sent, with their namespace if they are in one.
+    entries: Vec<(Option<String>, Vec<u8>, LogPointer)>,
+    reader: LogReader,
+    value_log: ValueLog,
+    merge_operator: Option<MergeOperator>,
+    /// Where the replica is once it has all of the copy.
+    end: ReplicationCursor,
+}
+
+impl FullCopy {
+    /// A copy of `entries`, read from `reader` and `value_log`, after which the replica is at
+    /// `end`.
+    pub(crate) fn new(
+        id: u64,
+        entries: Vec<(Option<String>, Vec<u8>, LogPointer)>,
+        reader: LogReader,
+        value_log: ValueLog,
+        merge_operator: Option<MergeOperator>,
+        end: ReplicationCursor,
+    ) -> FullCopy {
+        FullCopy {
+            id,
+            entries,
+            reader,
+            value_log,
+            merge_operator,
+            end,
+        }
+    }
+
+    /// Returns the changes setting the keys of the copy from `position` on, up to
+    /// `REPLICATION_BATCH_LEN` of them. The first page is full.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get`.
+    pub(crate) fn page(&mut self, position: u64) -> Result<Changes> {
+        let start = (position as usize).min(self.entries.len());
+        let end = (start + REPLICATION_BATCH_LEN).min(self.entries.len());
+        let reader = &mut self.reader;
+        let value_log = &mut self.value_log;
+        let mut logs = Vec::with_capacity(end - start);
+        for (namespace, key, pointer) in &self.entries[start..end] {
+            let kvlog = match namespace {
+                Some(namespace) => {
+                    let kvlog = reader
+                        .read_at(pointer.offset)?
+                        .into_namespaced(namespace)?
+                        .into_live_set(key)?;
+                    KvLog::new_namespaced(namespace.clone(), value_log.resolve(kvlog)?)
+                }
+                None => {
+                    let merge_operator = self.merge_operator.as_ref();
+                    let value = read_value_chain(key, pointer.offset, merge_operator, |offset| {
+                        value_log.resolve(reader.read_at(offset)?)
+                    })?;
+                    match pointer.expires_at {
+                        Some(expires_at) => KvLog::new_set_ex(key.clone(), value, expires_at),
+                        None => KvLog::new_set(key.clone(), value),
+                    }
+                }
+            };
+            logs.push(kvlog);
+        }
+        let copy = match end < self.entries.len() {
+            true => Some(CopyCursor {
+                id: self.id,
+                position: end as u64,
+            }),
+            false => None,
+        };
+        Ok(Changes {
+            full: start == 0,
+            logs,
+            cursor: ReplicationCursor { copy, ..self.end },
+        })
+    }
+}
+
+/// The next changes for a replica, see `KvStore::next_changes`.
+pub(crate) enum NextChanges {
+    /// Changes read from the log file.
+    Logs(Changes),
+    /// A page of a full copy from a position, to read without the store.
+    Copy(Arc<Mutex<FullCopy>>, u64),
+}
+
+/// A read-only copy of a store served by a `KvsServer`, kept up to date by pulling the
+/// changes of its primary. It can be cloned and shared between threads, e.g. by the server
+/// and the thread following the primary.
+///
+/// Replication is asynchronous: a write is acknowledged by the primary before it reaches
+/// the replicas, so they may briefly lag behind. Writes to a replica fail with ReadOnly.
+///
+/// The replica keeps where it is in memory, so it starts with a full copy of its primary
+/// every time it is created. Merges are applied with the merge operator of the replica,
+/// which has to be the one of the primary.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvStore, KvsClient, KvsServer, Replica};
+/// use std::thread;
+///
+/// let replica = Replica::new(KvStore::open("replica").unwrap()).unwrap();
+/// let follower = replica.clone();
+/// thread::spawn(move || follower.follow(|| KvsClient::connect("127.0.0.1:4000")));
+/// KvsServer::new(replica).run("127.0.0.1:4001").unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Replica {
+    store: SharedKvStore,
+    /// Where the replica is, `None` until its first changes.
+    cursor: Arc<Mutex<Option<ReplicationCursor>>>,
+}
+
+impl Replica {
+    /// Make `store` a replica, replacing its data with a copy of the primary on the first
+    /// `sync`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `SharedKvStore::new`.
+    pub fn new(store: KvStore) -> Result<Replica> {
+        Ok(Replica {
+            store: SharedKvStore::new(store)?,
+            cursor: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Where the replica is in the log of its primary, `None` until its first changes.
+    pub fn cursor(&self) -> Option<ReplicationCursor> {
+        *self.cursor.lock().unwrap()
+    }
+
+    /// Get the next changes of the primary through `client` and apply them. Returns
+    /// whether there were any, otherwise the replica has caught up.
+    ///
+    /// # Errors
+    ///
+    /// - Others: Same as `KvsClient::changes` and `KvStore::apply_changes`.
+    pub fn sync(&self, client: &mut KvsClient) -> Result<bool> {
+        let mut cursor = self.cursor.lock().unwrap();
+        let changes = client.changes(*cursor)?;
+        let changed = changes.full || !changes.logs.is_empty();
+        let next = changes.cursor;
+        self.store.apply_changes(changes)?;
+        *cursor = Some(next);
+        Ok(changed)
+    }
+
+    /// Follow the primary, connecting to it with `connect` and applying its changes as they
+    /// come, forever. Failures are reported on stderr, and the replica connects again.
+    pub fn follow<F>(&self, connect: F) -> !
+    where
+        F: Fn() -> Result<KvsClient>,
+    {
+        loop {
+            let mut client = match connect() {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("Failed to connect to the primary: {}", e);
+                    thread::sleep(RECONNECT_INTERVAL);
+                    continue;
+                }
+            };
+            loop {
+                match self.sync(&mut client) {
+                    Ok(true) => {}
+                    Ok(false) => thread::sleep(POLL_INTERVAL),
+                    Err(e) => {
+                        eprintln!("Failed to replicate the primary: {}", e);
+                        thread::sleep(RECONNECT_INTERVAL);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl KvsEngine for Replica {
+    fn set(&mut self, _key: String, _value: String) -> Result<()> {
+        Err(Error::from(ErrorKind::ReadOnly))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn remove(&mut self, _key: String) -> Result<()> {
+        Err(Error::from(ErrorKind::ReadOnly))
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.store.scan_prefix(prefix)
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        self.store.scan_page(prefix, cursor, limit)
+    }
+
+    fn changes(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        self.store.changes(cursor)
+    }
+
+    fn subscribe(&mut self, prefixes: Vec<String>) -> Result<Watch> {
+        self.store.subscribe(prefixes)
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        KvsEngine::stats(&mut self.store)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.store.compact()
+    }
+
+    fn start_backup(&mut self, dest: PathBuf) -> Result<BackupJob> {
+        self.store.start_backup(dest)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        KvsEngine::sync(&mut self.store)
+    }
+
+    fn set_runtime_options(&mut self, options: RuntimeOptions) -> Result<()> {
+        self.store.set_runtime_options(options)
+    }
+}
@@ -0,0 +1,233 @@
+#![deny(missing_docs)]
+//! The Redis serialization protocol, version 2, served by `KvsServer` with `Protocol::Resp`.
+//!
+//! Commands are arrays of bulk strings, or inline commands of words separated by spaces.
+//! GET, SET, DEL, EXISTS, PING and QUIT are supported, so `redis-cli` and Redis client
+//! libraries can use the store. Keys and values have to be valid UTF-8.
+//!
+//! A server with an ACL answers `NOAUTH` to every command but AUTH and QUIT, until the
+//! client sends `AUTH token`, and `NOPERM` to commands on keys the user has no access to.
+
+use crate::acl::{Acl, Restricted, User};
+use crate::audit::Auditor;
+use crate::error::{Error, ErrorKind};
+use crate::{KvsEngine, Result};
+use failure::ResultExt;
+use std::io::{BufRead, Write};
+
+/// Largest bulk string or array accepted, as Redis does.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// A reply to a command.
+enum Reply {
+    /// Status reply, like `OK`.
+    Simple(&'static str),
+    /// Error reply.
+    Error(String),
+    /// Integer reply.
+    Integer(i64),
+    /// Bulk string reply, or the null bulk string.
+    Bulk(Option<String>),
+}
+
+/// Answer the commands read from `reader` on `writer`, until the client quits or closes the
+/// connection. Commands are refused until the client authenticates if there is an `acl`.
+/// Changes are recorded with `audit`.
+///
+/// # Errors
+///
+/// - Io: Failed to read a command or write a reply.
+/// - Serde: Received a malformed command.
+pub(crate) fn serve<E, R, W>(
+    engine: &mut E,
+    acl: Option<&Acl>,
+    audit: Option<Auditor<'_>>,
+    mut reader: R,
+    mut writer: W,
+) -> Result<()>
+where
+    E: KvsEngine,
+    R: BufRead,
+    W: Write,
+{
+    let mut user = None;
+    while let Some(args) = read_command(&mut reader)? {
+        let name = args.first().map(|name| name.to_ascii_uppercase());
+        let quit = name.as_deref() == Some(b"QUIT");
+        let reply = match name.as_deref() {
+            Some(b"QUIT") => Reply::Simple("OK"),
+            Some(b"AUTH") => auth(acl, &args[1..], &mut user),
+            _ if acl.is_some() && user.is_none() => {
+                Reply::Error("NOAUTH Authentication required.".to_owned())
+            }
+            _ => execute(&mut Restricted::new(engine, user).audit(audit), args),
+        };
+        write_reply(&mut writer, &reply)?;
+        writer.flush().context(ErrorKind::Io)?;
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Authenticate the connection as a user with the arguments of AUTH.
+fn auth<'a>(acl: Option<&'a Acl>, args: &[Vec<u8>], user: &mut Option<&'a User>) -> Reply {
+    let token = match args {
+        [token] => token,
+        _ => return Reply::Error("ERR wrong number of arguments for 'auth' command".to_owned()),
+    };
+    let acl = match acl {
+        Some(acl) => acl,
+        None => return Reply::Error("ERR Client sent AUTH, but no password is set".to_owned()),
+    };
+    match acl.authenticate(token) {
+        Some(authenticated) => {
+            *user = Some(authenticated);
+            Reply::Simple("OK")
+        }
+        None => Reply::Error("WRONGPASS invalid username-password pair".to_owned()),
+    }
+}
+
+/// Run a command against the engine.
+fn execute<E: KvsEngine>(engine: &mut E, args: Vec<Vec<u8>>) -> Reply {
+    let mut args = args.into_iter();
+    let name = match args.next() {
+        Some(name) => String::from_utf8_lossy(&name).to_ascii_uppercase(),
+        None => return Reply::Error("ERR empty command".to_owned()),
+    };
+    let args = match args
+        .map(String::from_utf8)
+        .collect::<std::result::Result<Vec<_>, _>>()
+    {
+        Ok(args) => args,
+        Err(_) => return Reply::Error("ERR arguments are not valid UTF-8".to_owned()),
+    };
+    let result = match (name.as_str(), args.as_slice()) {
+        ("PING", []) => return Reply::Simple("PONG"),
+        ("PING", [message]) => return Reply::Bulk(Some(message.clone())),
+        ("GET", [key]) => engine.get(key.clone()).map(Reply::Bulk),
+        ("SET", [key, value]) => engine
+            .set(key.clone(), value.clone())
+            .map(|_| Reply::Simple("OK")),
+        ("DEL", keys) if !keys.is_empty() => keys
+            .iter()
+            .map(|key| match engine.remove(key.clone()) {
+                Ok(()) => Ok(1),
+                Err(e) if e.kind() == ErrorKind::KeyNotFound => Ok(0),
+                Err(e) => Err(e),
+            })
+            .sum::<Result<i64>>()
+            .map(Reply::Integer),
+        ("EXISTS", keys) if !keys.is_empty() => keys
+            .iter()
+            .map(|key| engine.get(key.clone()).map(|value| value.is_some() as i64))
+            .sum::<Result<i64>>()
+            .map(Reply::Integer),
+        ("PING", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) => {
+            return Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ))
+        }
+        _ => return Reply::Error(format!("ERR unknown command '{}'", name)),
+    };
+    result.unwrap_or_else(|e| match e.kind() {
+        ErrorKind::PermissionDenied => Reply::Error(
+            "NOPERM this user has no permissions to access one of the keys used as arguments"
+                .to_owned(),
+        ),
+        _ => Reply::Error(format!("ERR {}", e)),
+    })
+}
+
+/// Tell a client connecting to a server which has as many connections as it takes that it
+/// is refused, as Redis does.
+///
+/// # Errors
+///
+/// - Io: Failed to write the error reply.
+pub(crate) fn write_busy<W: Write>(mut writer: W) -> Result<()> {
+    let reply = Reply::Error("ERR max number of clients reached".to_owned());
+    write_reply(&mut writer, &reply)?;
+    writer.flush().context(ErrorKind::Io)?;
+    Ok(())
+}
+
+/// Read the next command as its arguments, or None if the connection was closed.
+///
+/// # Errors
+///
+/// - Io: Failed to read the command, or the connection was closed in the middle of it.
+/// - Serde: The command is malformed.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        // an inline command
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+    let count = parse_len(&line[1..])?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| Error::from(ErrorKind::Io))?;
+        if line.first() != Some(&b'$') {
+            return Err(Error::from(ErrorKind::Serde));
+        }
+        let len = parse_len(&line[1..])?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).context(ErrorKind::Io)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(Error::from(ErrorKind::Serde));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a line without its line ending, or None at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).context(ErrorKind::Io)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Parse the length of an array or bulk string.
+fn parse_len(bytes: &[u8]) -> Result<usize> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|len| len.parse::<usize>().ok())
+        .filter(|&len| len <= MAX_BULK_LEN)
+        .ok_or_else(|| Error::from(ErrorKind::Serde))
+}
+
+/// Write a reply.
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> Result<()> {
+    match reply {
+        Reply::Simple(status) => write!(writer, "+{}\r\n", status),
+        // line breaks would end the reply early
+        Reply::Error(message) => write!(writer, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+        Reply::Bulk(Some(value)) => write!(writer, "${}\r\n{}\r\n", value.len(), value),
+        Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+    }
+    .context(ErrorKind::Io)?;
+    Ok(())
+}
@@ -0,0 +1,93 @@
+#![deny(missing_docs)]
+//! Retries of requests failing on transient network errors.
+
+use crate::ErrorKind;
+use std::time::Duration;
+
+/// Wait before the first retry by default.
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+/// Longest wait between two retries by default.
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How a `KvsClient` retries requests that failed on the network, with exponential backoff:
+/// the wait before each retry doubles, from `initial_backoff` up to `max_backoff`.
+///
+/// Only requests that can safely run twice are retried: `get`, `set`, `ping`,
+/// `authenticate` and `changes`. A `remove` which reached the server before failing would
+/// fail with KeyNotFound if retried, and batches and pipelines may hold removes, so they
+/// are not. A request is retried on a new connection, authenticated again if it was, after
+/// failing with Io, Timeout or ServerBusy.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvsClient, RetryPolicy};
+/// use std::time::Duration;
+///
+/// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+/// client.set_timeout(Some(Duration::from_secs(1))).unwrap();
+/// client.set_retry_policy(RetryPolicy::new(3).max_backoff(Duration::from_millis(500)));
+/// client.set("key1".to_owned(), "42".to_owned()).unwrap();
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Retry requests up to `max_retries` times, waiting 50 ms before the first retry and
+    /// at most 2 s between two of them.
+    pub fn new(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    /// Never retry requests, the default of a `KvsClient`.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new(0)
+    }
+
+    /// Set the wait before the first retry.
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> RetryPolicy {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Set the longest wait between two retries.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> RetryPolicy {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Returns the number of retries of a request at most.
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// The wait before retry `retry`, counted from 0.
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    /// Whether a request failing with `kind` may succeed if retried.
+    pub(crate) fn is_transient(kind: ErrorKind) -> bool {
+        matches!(
+            kind,
+            ErrorKind::Io | ErrorKind::Timeout | ErrorKind::ServerBusy
+        )
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::none()
+    }
+}
@@ -0,0 +1,37 @@
+#![deny(missing_docs)]
+//! Iteration of a KvStore in pages, see `KvStore::scan_from`.
+
+use serde::{Deserialize, Serialize};
+
+/// Position of a scan in the order of keys, returned by `KvStore::scan_from` to resume it.
+///
+/// It only holds the last key returned, so it stays valid across compactions and
+/// reopening the store, and can be serialized to be kept until then. Keys set or removed
+/// after it in the order of keys are seen by the rest of the scan. `ScanCursor::default()`
+/// starts from the first key.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanCursor {
+    /// Last key returned by the scan, absent at the start.
+    after: Option<Vec<u8>>,
+}
+
+impl ScanCursor {
+    /// Cursor resuming a scan after `key`.
+    pub(crate) fn after(key: Vec<u8>) -> ScanCursor {
+        ScanCursor { after: Some(key) }
+    }
+
+    /// Last key returned by the scan, absent at the start.
+    pub(crate) fn last_key(&self) -> Option<&[u8]> {
+        self.after.as_deref()
+    }
+}
+
+/// A page of key-value pairs returned by `KvStore::scan_from`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanPage {
+    /// Key-value pairs in lexicographic order of keys.
+    pub entries: Vec<(String, String)>,
+    /// Cursor of the next page, absent if this page is the last one.
+    pub cursor: Option<ScanCursor>,
+}
@@ -0,0 +1,150 @@
+#![deny(missing_docs)]
+//! Secondary indexes of the keys of a KvStore, see `Options::indexes`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+/// Function deriving the index key of a key-value pair.
+type ExtractFn = dyn Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+/// A secondary index, set with `Options::indexes` and looked up with `KvStore::get_by_index`.
+///
+/// Its extractor is called with each key and value of the store, and returns the index key
+/// to find the key by, if any. Several keys may share an index key.
+///
+/// The index is held in memory. It is built from every value when the store is opened,
+/// then kept up to date by each write before the write returns, so lookups always agree
+/// with the store. The values a write makes are worked out before it is committed, so a
+/// write either fails before it is committed or updates the index. Keys of namespaces are not indexed.
+///
+/// # Examples
+///
+/// Finding users by city, with values like `name,city`:
+///
+/// ```rust
+/// use kvs::{KvStore, SecondaryIndex};
+/// use tempfile::TempDir;
+///
+/// let by_city = SecondaryIndex::new("city", |_key, value| {
+///     let value = String::from_utf8_lossy(value);
+///     value.split(',').nth(1).map(|city| city.as_bytes().to_vec())
+/// });
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::builder()
+///     .index(by_city)
+///     .open(tempdir.path())
+///     .unwrap();
+/// kv.set("user:1".to_owned(), "alice,paris".to_owned()).unwrap();
+/// kv.set("user:2".to_owned(), "bob,oslo".to_owned()).unwrap();
+///
+/// let parisians = kv.get_by_index("city", "paris").unwrap();
+/// assert_eq!(parisians, vec![("user:1".to_owned(), "alice,paris".to_owned())]);
+/// ```
+#[derive(Clone)]
+pub struct SecondaryIndex {
+    name: String,
+    extract_fn: Arc<ExtractFn>,
+}
+
+impl SecondaryIndex {
+    /// Create an index named `name` from a function of key and value returning the index key.
+    pub fn new<F>(name: &str, extract_fn: F) -> SecondaryIndex
+    where
+        F: Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        SecondaryIndex {
+            name: name.to_owned(),
+            extract_fn: Arc::new(extract_fn),
+        }
+    }
+
+    /// Name of the index.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecondaryIndex({:?})", self.name)
+    }
+}
+
+/// Entries of a secondary index.
+#[derive(Default)]
+struct IndexMap {
+    /// Keys of each index key, sorted.
+    keys: HashMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+    /// Index key of each key, to remove its entry when it changes.
+    index_keys: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl IndexMap {
+    /// Remove the entry of `key`.
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(index_key) = self.index_keys.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&index_key) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&index_key);
+                }
+            }
+        }
+    }
+}
+
+/// The secondary indexes of a store with their entries.
+#[derive(Default)]
+pub(crate) struct Indexes {
+    indexes: Vec<(SecondaryIndex, IndexMap)>,
+}
+
+impl Indexes {
+    /// Create empty indexes.
+    pub(crate) fn new(indexes: &[SecondaryIndex]) -> Indexes {
+        Indexes {
+            indexes: indexes
+                .iter()
+                .map(|index| (index.clone(), IndexMap::default()))
+                .collect(),
+        }
+    }
+
+    /// Returns whether there is no index.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Update the entries of `key` to its new value, absent if it was removed.
+    pub(crate) fn update(&mut self, key: &[u8], value: Option<&[u8]>) {
+        for (index, map) in &mut self.indexes {
+            map.remove(key);
+            if let Some(index_key) = value.and_then(|value| (index.extract_fn)(key, value)) {
+                map.keys
+                    .entry(index_key.clone())
+                    .or_default()
+                    .insert(key.to_vec());
+                map.index_keys.insert(key.to_vec(), index_key);
+            }
+        }
+    }
+
+    /// Remove the entries of every key.
+    pub(crate) fn clear(&mut self) {
+        for (_, map) in &mut self.indexes {
+            *map = IndexMap::default();
+        }
+    }
+
+    /// Keys with `index_key` in the index `name`, sorted, or None if there is no such index.
+    pub(crate) fn get(&self, name: &str, index_key: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let (_, map) = self.indexes.iter().find(|(index, _)| index.name == name)?;
+        Some(
+            map.keys
+                .get(index_key)
+                .map_or_else(Vec::new, |keys| keys.iter().cloned().collect()),
+        )
+    }
+}
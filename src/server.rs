@@ -0,0 +1,1127 @@
+#![deny(missing_docs)]
+//! A server giving access to a storage engine over TCP.
+
+use crate::acl::{Acl, Restricted, User};
+use crate::audit::{AuditLog, Auditor};
+use crate::error::{Error, ErrorKind};
+use crate::http;
+use crate::memcached;
+use crate::metrics::{Command, Metrics};
+use crate::protocol::{self, AdminCommand, Greeting, Request, Response};
+use crate::replication::{Changes, ReplicationCursor};
+use crate::resp;
+use crate::slow_log::SlowLog;
+use crate::stream::{self, Socket, Stream};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{BackupJob, ChangeEvent, KvsEngine, Result, ScanCursor, ScanPage, Stats, Watch};
+use failure::ResultExt;
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Protocol spoken by a `KvsServer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// The protocol of `KvsClient`, see `Request`.
+    #[default]
+    Kvs,
+    /// The Redis serialization protocol, version 2, for `redis-cli` and Redis client
+    /// libraries. GET, SET, DEL, EXISTS, PING and QUIT are supported.
+    Resp,
+    /// The memcached text protocol. `get`, `set`, `delete`, `incr`, `decr`, `version` and
+    /// `quit` are supported. Flags are not kept and expiration times are ignored.
+    Memcached,
+    /// A REST API over HTTP/1.1 with JSON bodies: `GET`, `PUT` and `DELETE` on
+    /// `/keys/{key}`, and `GET /keys?prefix={prefix}` to list keys.
+    Http,
+}
+
+/// Threads serving connections by default.
+const DEFAULT_THREADS: u32 = 32;
+
+/// Time between removals of expired keys, see `KvsServer::expire_keys`.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time after which a connection subscribed to changes is sent a heartbeat if there were
+/// none, see `Request::Subscribe`.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest number of key-value pairs in a page answering a `Request::Scan`, to keep its
+/// frame small.
+const MAX_PAGE_LEN: usize = 1000;
+
+/// How long a connection refused by a server with as many as it takes is kept open after
+/// being told, for the client to read the answer before it is closed.
+const REFUSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A server answering the requests of clients with a storage engine it keeps open, see
+/// `Request` for the protocol.
+///
+/// Connections are served concurrently by the threads of a `ThreadPool`, a
+/// `SharedQueueThreadPool` of 32 threads by default. A thread serves a connection until it
+/// is closed, so connections beyond the number of threads wait for one to be free.
+/// Each connection runs its commands on a clone of the engine, so what runs at the same
+/// time is up to the engine: a `SharedKvStore` reads while it writes or compacts, and a
+/// `ShardedKvsEngine` writes to different shards at once. The engine is opened once for
+/// the lifetime of the server, instead of replaying the log for every command like the
+/// `kvs` command line tool does.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvStore, KvsServer, NaiveThreadPool, SharedKvStore, ThreadPool};
+///
+/// let store = SharedKvStore::new(KvStore::open(".").unwrap()).unwrap();
+/// KvsServer::new(store).run("127.0.0.1:4000").unwrap();
+///
+/// // with a thread for each connection
+/// let store = SharedKvStore::new(KvStore::open(".").unwrap()).unwrap();
+/// let pool = NaiveThreadPool::new(0).unwrap();
+/// KvsServer::new(store)
+///     .thread_pool(pool)
+///     .run("127.0.0.1:4000")
+///     .unwrap();
+/// ```
+pub struct KvsServer<E: KvsEngine, P: ThreadPool = SharedQueueThreadPool> {
+    engine: Arc<E>,
+    protocol: Protocol,
+    acl: Option<Acl>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    pool: Option<P>,
+    threads: u32,
+    metrics_addr: Option<SocketAddr>,
+    slow_log: Option<SlowLog>,
+    max_connections: Option<usize>,
+    max_in_flight: Option<usize>,
+    idle_timeout: Option<Duration>,
+    expire_rate: Option<usize>,
+    audit_log: Option<AuditLog>,
+    admin: AdminPolicy,
+    connections: Arc<Connections>,
+}
+
+impl<E: KvsEngine + Clone> KvsServer<E> {
+    /// Create a server of `engine`, cloned for each connection.
+    pub fn new(engine: E) -> KvsServer<E> {
+        KvsServer {
+            engine: Arc::new(engine),
+            protocol: Protocol::default(),
+            acl: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            pool: None,
+            threads: DEFAULT_THREADS,
+            metrics_addr: None,
+            slow_log: None,
+            max_connections: None,
+            max_in_flight: None,
+            idle_timeout: None,
+            expire_rate: None,
+            audit_log: None,
+            admin: AdminPolicy::default(),
+            connections: Arc::default(),
+        }
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Speak `protocol` instead of the protocol of `KvsClient`.
+    pub fn protocol(mut self, protocol: Protocol) -> KvsServer<E, P> {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Require clients to authenticate with `token` before running commands, giving access
+    /// to every key. Same as an `acl` with a single user reading and writing every key.
+    ///
+    /// With the protocol of `KvsClient`, clients send a `Request::Auth`. Redis clients send
+    /// `AUTH token`, and HTTP clients an `Authorization: Bearer token` header with every
+    /// request. The memcached text protocol has no authentication, so it cannot be served
+    /// with a token.
+    ///
+    /// The token is sent as is, so it should only cross untrusted networks over TLS.
+    pub fn auth_token(self, token: String) -> KvsServer<E, P> {
+        self.acl(Acl::new().user(User::new("default", &token).read("").write("")))
+    }
+
+    /// Require clients to authenticate as one of the users of `acl`, and restrict their
+    /// commands to the keys the user has access to. Commands on other keys fail with
+    /// PermissionDenied. The users are added to those of previous calls.
+    pub fn acl(mut self, acl: Acl) -> KvsServer<E, P> {
+        self.acl.get_or_insert_with(Acl::new).extend(acl);
+        self
+    }
+
+    /// Accept only TLS connections, with `config` as made by `kvs::tls::server_config`.
+    /// Needs the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<rustls::ServerConfig>) -> KvsServer<E, P> {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Serve connections with the threads of `pool`.
+    pub fn thread_pool<Q: ThreadPool>(self, pool: Q) -> KvsServer<E, Q> {
+        KvsServer {
+            engine: self.engine,
+            protocol: self.protocol,
+            acl: self.acl,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            pool: Some(pool),
+            threads: self.threads,
+            metrics_addr: self.metrics_addr,
+            slow_log: self.slow_log,
+            max_connections: self.max_connections,
+            max_in_flight: self.max_in_flight,
+            idle_timeout: self.idle_timeout,
+            expire_rate: self.expire_rate,
+            audit_log: self.audit_log,
+            admin: self.admin,
+            connections: self.connections,
+        }
+    }
+
+    /// Serve connections with a pool of `threads` threads, unless a pool is given with
+    /// `thread_pool`. Default to 32.
+    pub fn threads(mut self, threads: u32) -> KvsServer<E, P> {
+        self.threads = threads;
+        self
+    }
+
+    /// Serve metrics for Prometheus at `http://{addr}/metrics`, on a port of their own:
+    /// commands run and how long they took, by command, connections, and statistics of the
+    /// store if the engine has them, see `KvsEngine::stats`.
+    ///
+    /// Commands are counted whatever the protocol, but those of a batch count apart.
+    pub fn metrics(mut self, addr: SocketAddr) -> KvsServer<E, P> {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Log the commands taking longer than the threshold of `slow_log` to stderr. Like
+    /// metrics, commands are logged whatever the protocol, and those of a batch apart.
+    pub fn slow_log(mut self, slow_log: SlowLog) -> KvsServer<E, P> {
+        self.slow_log = Some(slow_log);
+        self
+    }
+
+    /// Take at most `max` connections at once, counting those waiting for a thread. Clients
+    /// connecting beyond it are told the server is busy in its protocol and disconnected:
+    /// `KvsClient` fails with ServerBusy, Redis clients get an error reply, and HTTP
+    /// clients a 503 response. Over TLS, they are disconnected without an answer.
+    pub fn max_connections(mut self, max: usize) -> KvsServer<E, P> {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Take at most `max` requests at once from a connection: a `Request::Batch` of more
+    /// requests fails with ServerBusy without running any of them. Pipelined requests are
+    /// read one at a time, so a client sending them faster than they are answered is slowed
+    /// down by the connection.
+    pub fn max_in_flight(mut self, max: usize) -> KvsServer<E, P> {
+        self.max_in_flight = Some(max);
+        self
+    }
+
+    /// Close the connections sending nothing for `timeout`, or not reading their responses
+    /// for as long. Clients keep idle connections open by pinging them, see
+    /// `KvsClientPool::keep_alive`. Default to never.
+    pub fn idle_timeout(mut self, timeout: Duration) -> KvsServer<E, P> {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Remove up to `per_second` expired keys every second in the background, see
+    /// `KvsEngine::expire_keys`, so that the space of keys expiring without being read or
+    /// written again is reclaimed by compaction. Removing them takes turns with the writes
+    /// of the connections, so the rate bounds how long it holds them up. Default to leaving expired
+    /// keys to compaction.
+    pub fn expire_keys(mut self, per_second: usize) -> KvsServer<E, P> {
+        self.expire_rate = Some(per_second);
+        self
+    }
+
+    /// Record who changed which key and when to `audit_log`. Default to no audit log.
+    pub fn audit_log(mut self, audit_log: AuditLog) -> KvsServer<E, P> {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Run the commands managing the server, see `AdminCommand`, for clients not
+    /// authenticated as an admin user, e.g. every client of a server without an ACL.
+    /// Default to refusing them with PermissionDenied, as they can stop the writes for a
+    /// compaction or make the server write backups.
+    pub fn allow_admin(mut self) -> KvsServer<E, P> {
+        self.admin.allowed = true;
+        self
+    }
+
+    /// Write the backups asked by `AdminCommand::Backup` in `dir`, their path being relative
+    /// to it. Backups to a path leaving it fail with PermissionDenied, as do all backups
+    /// by default.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> KvsServer<E, P> {
+        self.admin.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// Returns a handle shutting the server down from another thread, see `run`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(Arc::clone(&self.connections))
+    }
+
+    /// Returns a handle reaching the engine of the server from another thread, for example
+    /// to change its options while it runs.
+    pub fn engine_handle(&self) -> EngineHandle<E> {
+        EngineHandle(Arc::downgrade(&self.engine))
+    }
+}
+
+impl<E: KvsEngine + Clone + Send + Sync + 'static, P: ThreadPool> KvsServer<E, P> {
+    /// Listen on `addr` and serve the clients connecting to it, until listening fails or the
+    /// server is shut down through a `ShutdownHandle`.
+    ///
+    /// A connection failing is reported on stderr and does not stop the server.
+    ///
+    /// On shutdown the server stops accepting connections, lets each connection finish the
+    /// request it is answering before closing it, and waits for all of them to be closed.
+    /// It then syncs the engine, see `KvsEngine::sync`, and drops it, which releases the
+    /// lock of a `KvStore`, before returning.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to listen on `addr` or on the address of the metrics, to accept a
+    ///   connection, or to start the threads.
+    /// - Others: Failed to sync the engine on shutdown, depending on the engine.
+    /// - Unsupported: An auth token or ACL is set with `Protocol::Memcached`.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(ErrorKind::Io)?;
+        self.serve(Listener::Tcp(listener))
+    }
+
+    /// Listen on a Unix domain socket at `path` instead of TCP, for clients on the same
+    /// host, see `run`. Who can connect is up to the permissions of the socket file, which
+    /// are those of new files, and of its directory.
+    ///
+    /// A socket file left at `path` by a server which did not shut down is replaced, and
+    /// the socket file is removed when the server returns.
+    ///
+    /// # Errors
+    ///
+    /// - Io: `path` exists and is not a socket, or another server listens on it.
+    /// - Others: Same as `run`.
+    #[cfg(unix)]
+    pub fn run_unix<Q: AsRef<Path>>(self, path: Q) -> Result<()> {
+        let path = path.as_ref();
+        let stale = fs::symlink_metadata(path)
+            .map(|metadata| metadata.file_type().is_socket())
+            .unwrap_or(false);
+        if stale && UnixStream::connect(path).is_err() {
+            fs::remove_file(path).context(ErrorKind::Io)?;
+        }
+        let listener = UnixListener::bind(path).context(ErrorKind::Io)?;
+        let served = self.serve(Listener::Unix(listener, path.to_owned()));
+        let _ = fs::remove_file(path);
+        served
+    }
+
+    /// Serve the clients connecting to `listener`, see `run`.
+    fn serve(self, listener: Listener) -> Result<()> {
+        if self.acl.is_some() && self.protocol == Protocol::Memcached {
+            return Err(Error::from(ErrorKind::Unsupported));
+        }
+        let pool = match self.pool {
+            Some(pool) => pool,
+            None => P::new(self.threads)?,
+        };
+        let shared = Arc::new(Shared {
+            engine: self.engine,
+            protocol: self.protocol,
+            acl: self.acl,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            metrics: Metrics::default(),
+            slow_log: self.slow_log,
+            max_in_flight: self.max_in_flight,
+            idle_timeout: self.idle_timeout,
+            audit_log: self.audit_log,
+            admin: self.admin,
+            connections: self.connections,
+        });
+        let connections = &shared.connections;
+        connections.listen(listener.wake_address()?);
+        let mut metrics_thread = None;
+        if let Some(metrics_addr) = self.metrics_addr {
+            let metrics_listener = TcpListener::bind(metrics_addr).context(ErrorKind::Io)?;
+            connections.listen(WakeAddress::tcp(&metrics_listener)?);
+            let shared = Arc::clone(&shared);
+            metrics_thread = Some(thread::spawn(move || {
+                shared.serve_metrics(metrics_listener)
+            }));
+        }
+        let sweeper = self.expire_rate.map(|rate| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || shared.expire_keys(rate))
+        });
+        loop {
+            let stream = listener.accept().context(ErrorKind::Io)?;
+            // the connection waking the listener up on shutdown is dropped
+            let pending = match Pending::new(connections) {
+                Some(pending) => pending,
+                None => break,
+            };
+            if self
+                .max_connections
+                .is_some_and(|max| pending.count() > max)
+            {
+                drop(pending);
+                shared.refuse(stream);
+                continue;
+            }
+            let shared = Arc::clone(&shared);
+            pool.spawn(move || {
+                let _pending = pending;
+                let peer = stream.peer_addr();
+                match shared.accept(stream) {
+                    // closed for being idle
+                    Err(e) if stream::is_timeout(&e) => {}
+                    Err(e) => eprintln!("Error serving client {:?}: {}", peer, e),
+                    Ok(()) => {}
+                }
+                // before `_pending`, so that `run` holds the last reference to the engine
+                // once the connections are drained
+                drop(shared);
+            });
+        }
+        connections.drain();
+        if let Some(metrics_thread) = metrics_thread {
+            let _ = metrics_thread.join();
+        }
+        if let Some(sweeper) = sweeper {
+            let _ = sweeper.join();
+        }
+        E::clone(&shared.engine).sync()
+    }
+}
+
+/// Shuts a `KvsServer` down from another thread, for example on a signal.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvStore, KvsServer, SharedKvStore};
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let store = SharedKvStore::new(KvStore::open(".").unwrap()).unwrap();
+/// let server = KvsServer::new(store);
+/// let shutdown = server.shutdown_handle();
+/// thread::spawn(move || {
+///     thread::sleep(Duration::from_secs(60));
+///     shutdown.shutdown();
+/// });
+/// server.run("127.0.0.1:4000").unwrap();
+/// ```
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<Connections>);
+
+impl ShutdownHandle {
+    /// Shut the server down: `KvsServer::run` returns once the connections are done. Shutting
+    /// a server down before it runs makes it return at once, and shutting it down again
+    /// does nothing.
+    pub fn shutdown(&self) {
+        let mut state = self.0.state();
+        if state.closing {
+            return;
+        }
+        state.closing = true;
+        self.0.done.notify_all();
+        for stream in state.served.values() {
+            // the connection reads no more requests after the one it is answering
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let listeners = state.listeners.clone();
+        drop(state);
+        for addr in listeners {
+            // wake the listener up, so that it sees the server is closing
+            addr.wake();
+        }
+    }
+}
+
+/// Listens for the connections of a server.
+enum Listener {
+    Tcp(TcpListener),
+    /// Along with the path of its socket.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Wait for a client to connect.
+    fn accept(&self) -> io::Result<Socket> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Socket::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.accept().map(|(stream, _)| Socket::Unix(stream))
+            }
+        }
+    }
+
+    /// Where to connect to wake the listener up.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to get the address of the listener.
+    fn wake_address(&self) -> Result<WakeAddress> {
+        match self {
+            Listener::Tcp(listener) => WakeAddress::tcp(listener),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(WakeAddress::Unix(path.clone())),
+        }
+    }
+}
+
+/// An address to connect to in order to wake up a listener blocked on accepting.
+#[derive(Clone)]
+enum WakeAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl WakeAddress {
+    /// The address of a TCP listener, on the loopback interface if it listens on all of
+    /// them.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to get the address of the listener.
+    fn tcp(listener: &TcpListener) -> Result<WakeAddress> {
+        let mut addr = listener.local_addr().context(ErrorKind::Io)?;
+        if addr.ip().is_unspecified() {
+            match addr {
+                SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+                SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+        Ok(WakeAddress::Tcp(addr))
+    }
+
+    /// Connect to the listener, which then accepts the connection.
+    fn wake(&self) {
+        match self {
+            WakeAddress::Tcp(addr) => {
+                let _ = TcpStream::connect(addr);
+            }
+            #[cfg(unix)]
+            WakeAddress::Unix(path) => {
+                let _ = UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+/// Reaches the engine of a `KvsServer` from another thread, until the server is done
+/// with it.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvStore, KvsEngine, KvsServer, RuntimeOptions, SharedKvStore};
+/// use std::thread;
+///
+/// let store = SharedKvStore::new(KvStore::open(".").unwrap()).unwrap();
+/// let server = KvsServer::new(store);
+/// let engine = server.engine_handle();
+/// thread::spawn(move || server.run("127.0.0.1:4000").unwrap());
+///
+/// let options = RuntimeOptions {
+///     max_value_size: Some(1024),
+///     ..RuntimeOptions::default()
+/// };
+/// engine.with(|engine| engine.set_runtime_options(options));
+/// ```
+pub struct EngineHandle<E>(Weak<E>);
+
+impl<E: Clone> EngineHandle<E> {
+    /// Run `f` on a clone of the engine, like the commands of a connection. Returns `None`
+    /// without running it if the server has shut down.
+    pub fn with<T>(&self, f: impl FnOnce(&mut E) -> T) -> Option<T> {
+        let engine = self.0.upgrade()?;
+        Some(f(&mut E::clone(&engine)))
+    }
+}
+
+impl<E> Clone for EngineHandle<E> {
+    fn clone(&self) -> EngineHandle<E> {
+        EngineHandle(Weak::clone(&self.0))
+    }
+}
+
+/// Connections of a server, tracked to shut it down.
+#[derive(Default)]
+struct Connections {
+    state: Mutex<ConnectionsState>,
+    /// Notified when a connection is done, or the server shuts down.
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct ConnectionsState {
+    /// Whether the server is shutting down.
+    closing: bool,
+    /// Addresses to connect to in order to wake up the listeners of the server.
+    listeners: Vec<WakeAddress>,
+    /// Accepted connections not done yet, waiting for a thread or being served.
+    pending: usize,
+    /// Connections being served, by id.
+    served: HashMap<u64, Socket>,
+    next_id: u64,
+}
+
+impl Connections {
+    /// Lock the state of the connections.
+    fn state(&self) -> MutexGuard<'_, ConnectionsState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Track the listener at `addr`, to wake it up on shutdown, or now if the server is
+    /// already shutting down.
+    fn listen(&self, addr: WakeAddress) {
+        let mut state = self.state();
+        if state.closing {
+            addr.wake();
+        }
+        state.listeners.push(addr);
+    }
+
+    /// Wait for the server to shut down, at most `timeout`. Returns whether it does.
+    fn wait_closing(&self, timeout: Duration) -> bool {
+        let state = self.state();
+        let (state, _) = self
+            .done
+            .wait_timeout_while(state, timeout, |state| !state.closing)
+            .unwrap();
+        state.closing
+    }
+
+    /// Wait for every accepted connection to be done.
+    fn drain(&self) {
+        let mut state = self.state();
+        while state.pending > 0 {
+            state = self.done.wait(state).unwrap();
+        }
+    }
+}
+
+/// An accepted connection, counted as pending until dropped.
+struct Pending(Arc<Connections>);
+
+impl Pending {
+    /// Count a newly accepted connection, or `None` if the server is shutting down.
+    fn new(connections: &Arc<Connections>) -> Option<Pending> {
+        let mut state = connections.state();
+        if state.closing {
+            return None;
+        }
+        state.pending += 1;
+        Some(Pending(Arc::clone(connections)))
+    }
+
+    /// The number of pending connections, this one included.
+    fn count(&self) -> usize {
+        self.0.state().pending
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.state().pending -= 1;
+        self.0.done.notify_all();
+    }
+}
+
+/// A connection being served, which shutting down the server closes for reading until
+/// dropped.
+struct Served<'a>(&'a Connections, u64);
+
+impl<'a> Served<'a> {
+    /// Track `stream`, or `None` if the server is shutting down.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to clone the stream.
+    fn new(connections: &'a Connections, stream: &Socket) -> Result<Option<Served<'a>>> {
+        let stream = stream.try_clone().context(ErrorKind::Io)?;
+        let mut state = connections.state();
+        if state.closing {
+            return Ok(None);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.served.insert(id, stream);
+        Ok(Some(Served(connections, id)))
+    }
+}
+
+impl Drop for Served<'_> {
+    fn drop(&mut self) {
+        self.0.state().served.remove(&self.1);
+    }
+}
+
+/// What the connections of a server share.
+struct Shared<E> {
+    engine: Arc<E>,
+    protocol: Protocol,
+    acl: Option<Acl>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    metrics: Metrics,
+    slow_log: Option<SlowLog>,
+    max_in_flight: Option<usize>,
+    idle_timeout: Option<Duration>,
+    audit_log: Option<AuditLog>,
+    admin: AdminPolicy,
+    connections: Arc<Connections>,
+}
+
+impl<E: KvsEngine + Clone + Send + Sync + 'static> Shared<E> {
+    /// Serve the metrics to the clients connecting to `listener`, each on a thread of its
+    /// own, until listening fails or the server shuts down.
+    fn serve_metrics(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            if self.connections.state().closing {
+                return;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Error accepting a metrics connection: {}", e);
+                    return;
+                }
+            };
+            let shared = Arc::clone(&self);
+            thread::spawn(move || {
+                let (reader, writer) = stream::split(stream);
+                let render = || {
+                    let stats = E::clone(&shared.engine).stats().ok();
+                    shared.metrics.render(stats)
+                };
+                if let Err(e) = http::serve_metrics(reader, writer, render) {
+                    eprintln!("Error serving metrics: {}", e);
+                }
+            });
+        }
+    }
+}
+
+impl<E: KvsEngine + Clone> Shared<E> {
+    /// Remove up to `rate` expired keys every second, until the server shuts down. Failures
+    /// are reported on stderr.
+    fn expire_keys(&self, rate: usize) {
+        let mut engine = E::clone(&self.engine);
+        while !self.connections.wait_closing(EXPIRE_INTERVAL) {
+            if let Err(e) = engine.expire_keys(rate) {
+                eprintln!("Failed to expire keys: {}", e);
+            }
+        }
+    }
+
+    /// Tell a client connecting beyond the connections the server takes that it is busy,
+    /// and disconnect it. Runs on the thread accepting connections, so the client only has
+    /// a short time to read the answer.
+    fn refuse(&self, mut stream: Socket) {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return;
+        }
+        let _ = stream.set_timeouts(Some(REFUSE_TIMEOUT));
+        let told = match self.protocol {
+            Protocol::Kvs => protocol::write_busy(&mut stream),
+            Protocol::Resp => resp::write_busy(&mut stream),
+            Protocol::Memcached => memcached::write_busy(&mut stream),
+            Protocol::Http => http::write_busy(&mut stream),
+        };
+        if told.is_err() || stream.shutdown(Shutdown::Write).is_err() {
+            return;
+        }
+        // closing with unread requests would reset the connection, and could discard the
+        // answer before the client reads it, so read them until the client disconnects
+        let deadline = Instant::now() + REFUSE_TIMEOUT;
+        let mut buf = [0; 4096];
+        while Instant::now() < deadline {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Serve a connection, over TLS if the server is configured for it. A connection
+    /// waiting for a thread while the server shuts down is closed at once.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to read a request or write a response, or the TLS handshake failed.
+    /// - Serde: Received a malformed request.
+    /// - Tls: Failed to start a TLS session.
+    fn accept(&self, stream: Socket) -> Result<()> {
+        let _served = match Served::new(&self.connections, &stream)? {
+            Some(served) => served,
+            None => return Ok(()),
+        };
+        if let Some(timeout) = self.idle_timeout {
+            stream.set_timeouts(Some(timeout)).context(ErrorKind::Io)?;
+        }
+        let _connection = self.metrics.connection();
+        let peer = stream.peer_addr();
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let session =
+                rustls::ServerConnection::new(Arc::clone(config)).context(ErrorKind::Tls)?;
+            let mut stream = rustls::StreamOwned::new(session, stream);
+            self.serve(&mut stream, peer)?;
+            // tell the client the connection was not cut short
+            stream.conn.send_close_notify();
+            stream.flush().context(ErrorKind::Io)?;
+            return Ok(());
+        }
+        self.serve(stream, peer)
+    }
+
+    /// Answer the requests of a connection from `peer` until the client closes it.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to read a request or write a response.
+    /// - Serde: Received a malformed request.
+    /// - IncompatibleVersion: The client speaks none of the versions of the protocol.
+    fn serve<S: Stream>(&self, stream: S, peer: Option<SocketAddr>) -> Result<()> {
+        let (mut reader, mut writer) = stream::split(stream);
+        let engine = &mut Measured {
+            engine: &mut E::clone(&self.engine),
+            metrics: &self.metrics,
+            slow_log: self.slow_log.as_ref(),
+        };
+        let acl = self.acl.as_ref();
+        let admin = &self.admin;
+        let audit = self.audit_log.as_ref().map(|log| Auditor::new(log, peer));
+        match self.protocol {
+            Protocol::Kvs => {}
+            Protocol::Resp => return resp::serve(engine, acl, audit, reader, writer),
+            Protocol::Memcached => return memcached::serve(engine, audit, reader, writer),
+            Protocol::Http => return http::serve(engine, acl, audit, reader, writer),
+        }
+        let mut first = match protocol::accept_handshake(&mut reader, &mut writer)? {
+            Some(Greeting::Handshake) => None,
+            Some(Greeting::Legacy(request)) => Some(request),
+            None => return Ok(()),
+        };
+        let mut user = None;
+        loop {
+            let request = match first.take() {
+                Some(request) => request,
+                None => match protocol::read_frame(&mut reader)? {
+                    Some(request) => request,
+                    None => break,
+                },
+            };
+            let response = match (request, self.max_in_flight) {
+                (Request::Batch { requests, .. }, Some(max)) if requests.len() > max => {
+                    Response::Err(ErrorKind::ServerBusy)
+                }
+                (Request::Subscribe { prefixes }, _) if acl.is_none() || user.is_some() => {
+                    match engine.subscribe(prefixes) {
+                        Ok(watch) => return self.publish(watch, user, writer),
+                        Err(e) => Response::Err(e.kind()),
+                    }
+                }
+                (request, _) => respond(engine, acl, admin, audit, &mut user, request),
+            };
+            protocol::write_frame(&mut writer, &response)?;
+            // answer pipelined requests together
+            if reader.buffer().is_empty() {
+                writer.flush().context(ErrorKind::Io)?;
+            }
+        }
+        writer.flush().context(ErrorKind::Io)?;
+        Ok(())
+    }
+
+    /// Send the changes of `watch` the user can read, after accepting the subscription,
+    /// until the connection or the server closes.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to write an event, e.g. the client closed the connection.
+    fn publish<W: Write>(&self, watch: Watch, user: Option<&User>, mut writer: W) -> Result<()> {
+        let readable = |event: &ChangeEvent| match (user, std::str::from_utf8(&event.key)) {
+            (None, _) => true,
+            (Some(user), Ok(key)) => user.can_read(key),
+            (Some(_), Err(_)) => false,
+        };
+        let mut response = Response::Ok(None);
+        loop {
+            protocol::write_frame(&mut writer, &response)?;
+            writer.flush().context(ErrorKind::Io)?;
+            response = loop {
+                if self.connections.state().closing {
+                    return Ok(());
+                }
+                match watch.next_timeout(HEARTBEAT_INTERVAL) {
+                    Some(event) if readable(&event) => break Response::Event(event),
+                    Some(_) => {}
+                    // tells whether the client is still there
+                    None => break Response::Ok(None),
+                }
+            };
+        }
+    }
+}
+
+/// The engine of a connection, measuring its commands.
+struct Measured<'a, E> {
+    engine: &'a mut E,
+    metrics: &'a Metrics,
+    slow_log: Option<&'a SlowLog>,
+}
+
+impl<E> Measured<'_, E> {
+    /// Run `command` on `key` with `run` on the engine, counting it and how long it took,
+    /// and logging it if slow. The key is only needed by the slow log.
+    fn measure<T>(
+        &mut self,
+        command: Command,
+        key: Option<&str>,
+        run: impl FnOnce(&mut E) -> Result<T>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = run(self.engine);
+        let elapsed = start.elapsed();
+        self.metrics.record(command, elapsed, result.is_err());
+        if let Some(slow_log) = self.slow_log {
+            slow_log.record(command, key, elapsed);
+        }
+        result
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Measured<'_, E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let name = self.slow_log.map(|_| key.clone());
+        self.measure(Command::Set, name.as_deref(), |engine| {
+            engine.set(key, value)
+        })
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let name = self.slow_log.map(|_| key.clone());
+        self.measure(Command::Get, name.as_deref(), |engine| engine.get(key))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let name = self.slow_log.map(|_| key.clone());
+        self.measure(Command::Remove, name.as_deref(), |engine| {
+            engine.remove(key)
+        })
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let name = self.slow_log.map(|_| key.clone());
+        self.measure(Command::Set, name.as_deref(), |engine| {
+            engine.compare_and_swap(key, expected, new)
+        })
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let name = self.slow_log.map(|_| prefix.clone());
+        let scan = |engine: &mut E| engine.scan_prefix(prefix);
+        self.measure(Command::ScanPrefix, name.as_deref(), scan)
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        let name = self.slow_log.map(|_| prefix.clone());
+        let scan = |engine: &mut E| engine.scan_page(prefix, cursor, limit);
+        self.measure(Command::ScanPrefix, name.as_deref(), scan)
+    }
+
+    fn changes(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        self.measure(Command::Changes, None, |engine| engine.changes(cursor))
+    }
+
+    fn subscribe(&mut self, prefixes: Vec<String>) -> Result<Watch> {
+        self.engine.subscribe(prefixes)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn start_backup(&mut self, dest: PathBuf) -> Result<BackupJob> {
+        self.engine.start_backup(dest)
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        self.engine.stats()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.engine.sync()
+    }
+}
+
+/// What clients may do with the commands managing a server, see `KvsServer::allow_admin`
+/// and `KvsServer::backup_dir`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AdminPolicy {
+    /// Whether clients not authenticated as an admin user may run them.
+    pub(crate) allowed: bool,
+    /// Directory the backups are written in, or `None` to refuse them.
+    pub(crate) backup_dir: Option<PathBuf>,
+}
+
+impl AdminPolicy {
+    /// Whether a connection authenticated as `user` may run the commands.
+    fn allows(&self, user: Option<&User>) -> bool {
+        self.allowed || user.is_some_and(User::is_admin)
+    }
+
+    /// Where to write a backup to `path`, or `None` if it is not a path in the backup
+    /// directory.
+    fn backup_path(&self, path: &str) -> Option<PathBuf> {
+        let dir = self.backup_dir.as_ref()?;
+        let mut components = Path::new(path).components().peekable();
+        let inside = components.peek().is_some()
+            && components.all(|component| matches!(component, Component::Normal(_)));
+        inside.then(|| dir.join(path))
+    }
+}
+
+/// Answer a request of a connection authenticated as `user`, recording its changes with
+/// `audit`.
+pub(crate) fn respond<'a, E: KvsEngine>(
+    engine: &mut E,
+    acl: Option<&'a Acl>,
+    admin: &AdminPolicy,
+    audit: Option<Auditor<'_>>,
+    user: &mut Option<&'a User>,
+    request: Request,
+) -> Response {
+    let result = match (request, acl) {
+        (Request::Batch { id, requests }, _) => {
+            let responses = requests
+                .into_iter()
+                .map(|request| respond(engine, acl, admin, audit, user, request))
+                .collect();
+            return Response::Batch { id, responses };
+        }
+        (Request::Auth { .. }, None) => Ok(None),
+        (Request::Auth { token }, Some(acl)) => match acl.authenticate(token.as_bytes()) {
+            Some(authenticated) => {
+                *user = Some(authenticated);
+                Ok(None)
+            }
+            None => Err(Error::from(ErrorKind::Unauthenticated)),
+        },
+        (Request::Ping, _) => Ok(None),
+        (_, Some(_)) if user.is_none() => Err(Error::from(ErrorKind::Unauthenticated)),
+        (Request::Replicate { cursor }, _) => {
+            return match Restricted::new(engine, *user).changes(cursor) {
+                Ok(changes) => Response::Changes(changes),
+                Err(e) => Response::Err(e.kind()),
+            };
+        }
+        (
+            Request::Scan {
+                prefix,
+                cursor,
+                limit,
+            },
+            _,
+        ) => {
+            let limit = (limit as usize).min(MAX_PAGE_LEN);
+            return match Restricted::new(engine, *user).scan_page(prefix, &cursor, limit) {
+                Ok(page) => Response::Page(page),
+                Err(e) => Response::Err(e.kind()),
+            };
+        }
+        // only served by `KvsServer` on a connection of its own, see `Shared::publish`
+        (Request::Subscribe { .. }, _) => Err(Error::from(ErrorKind::Unsupported)),
+        (Request::Admin(_), _) if !admin.allows(*user) => {
+            Err(Error::from(ErrorKind::PermissionDenied))
+        }
+        (Request::Admin(command), _) => return administer(engine, admin, command),
+        (request, _) => execute(&mut Restricted::new(engine, *user).audit(audit), request),
+    };
+    match result {
+        Ok(value) => Response::Ok(value),
+        Err(e) => Response::Err(e.kind()),
+    }
+}
+
+/// Run a command managing the server. A backup is copied on a thread of its own.
+fn administer<E: KvsEngine>(
+    engine: &mut E,
+    admin: &AdminPolicy,
+    command: AdminCommand,
+) -> Response {
+    let result = match command {
+        AdminCommand::Compact => engine.compact(),
+        AdminCommand::Stats => {
+            return match engine.stats() {
+                Ok(stats) => Response::Stats(stats),
+                Err(e) => Response::Err(e.kind()),
+            };
+        }
+        AdminCommand::Flush => engine.sync(),
+        AdminCommand::Backup { path } => match admin.backup_path(&path) {
+            Some(dest) => engine.start_backup(dest).map(|job| {
+                thread::spawn(move || match job.run() {
+                    Ok(_) => eprintln!("Backed up to {}", path),
+                    Err(e) => eprintln!("Failed to back up to {}: {}", path, e),
+                });
+            }),
+            None => Err(Error::from(ErrorKind::PermissionDenied)),
+        },
+    };
+    match result {
+        Ok(()) => Response::Ok(None),
+        Err(e) => Response::Err(e.kind()),
+    }
+}
+
+/// Run a request against the engine, returning the value of a get.
+fn execute<E: KvsEngine>(engine: &mut E, request: Request) -> Result<Option<String>> {
+    match request {
+        Request::Get { key } => engine.get(key),
+        Request::Set { key, value } => engine.set(key, value).map(|_| None),
+        Request::Remove { key } => engine.remove(key).map(|_| None),
+        // handled by `respond`
+        Request::Auth { .. }
+        | Request::Ping
+        | Request::Batch { .. }
+        | Request::Replicate { .. }
+        | Request::Scan { .. }
+        | Request::Subscribe { .. }
+        | Request::Admin(_) => Ok(None),
+    }
+}
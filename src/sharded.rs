@@ -0,0 +1,201 @@
+#![deny(missing_docs)]
+//! An engine spreading keys over several engines, each behind a lock of its own.
+
+use crate::{KvStore, KvsEngine, Result, RuntimeOptions, Stats};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Engines spread over shards, each key living in one of them, picked by a hash of the
+/// key. It can be cloned and shared between threads, e.g. by the connections of a server.
+///
+/// Each shard has a lock of its own, so commands on keys of different shards run at the
+/// same time instead of waiting for each other. Scans go through every shard, one after
+/// the other, and are not a consistent view of the whole store.
+///
+/// The shard of a key only depends on the key and the number of shards, so a store has to
+/// be reopened with the same number of shards to find its keys again.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::ShardedKvsEngine;
+/// use std::thread;
+/// use tempfile::TempDir;
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let engine = ShardedKvsEngine::open(tempdir.path(), 4).unwrap();
+///
+/// let handles: Vec<_> = (0..4)
+///     .map(|i| {
+///         let engine = engine.clone();
+///         thread::spawn(move || engine.set(format!("key{}", i), i.to_string()).unwrap())
+///     })
+///     .collect();
+/// for handle in handles {
+///     handle.join().unwrap();
+/// }
+/// assert_eq!(engine.get("key2".to_owned()).unwrap(), Some("2".to_owned()));
+/// ```
+pub struct ShardedKvsEngine<E> {
+    shards: Arc<[Mutex<E>]>,
+}
+
+impl<E> Clone for ShardedKvsEngine<E> {
+    fn clone(&self) -> ShardedKvsEngine<E> {
+        ShardedKvsEngine {
+            shards: Arc::clone(&self.shards),
+        }
+    }
+}
+
+impl ShardedKvsEngine<KvStore> {
+    /// Open a store of `shards` shards in the directory at `path`, each of them a named
+    /// store, see `KvStore::open_named`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::open_named`.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is 0.
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvsEngine<KvStore>> {
+        let path = path.into();
+        let stores = (0..shards)
+            .map(|i| KvStore::open_named(&path, &format!("shard-{}", i)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedKvsEngine::new(stores))
+    }
+}
+
+impl<E: KvsEngine> ShardedKvsEngine<E> {
+    /// Spread keys over `shards`, which should start empty, or have been sharded the same
+    /// way before.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is empty.
+    pub fn new(shards: Vec<E>) -> ShardedKvsEngine<E> {
+        assert!(!shards.is_empty(), "no shards");
+        ShardedKvsEngine {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    /// Returns the number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Set the value of a key, overwriting any previous value.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsEngine::set` of the shards.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    /// Get the value of a key, or `None` if it is not present.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsEngine::get` of the shards.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    /// Remove a key.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsEngine::remove` of the shards.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    /// Get the key-value pairs whose key starts with `prefix` from every shard, in
+    /// lexicographic order of keys.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsEngine::scan_prefix` of the shards.
+    pub fn scan_prefix(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.lock().unwrap().scan_prefix(prefix.clone())?);
+        }
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+
+    /// Lock the shard of `key`.
+    fn shard(&self, key: &str) -> MutexGuard<'_, E> {
+        // a stable hash, so that keys stay in their shard across versions
+        let hash = crc32fast::hash(key.as_bytes()) as usize;
+        self.shards[hash % self.shards.len()].lock().unwrap()
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ShardedKvsEngine<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        ShardedKvsEngine::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        ShardedKvsEngine::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        ShardedKvsEngine::remove(self, key)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.shard(&key).compare_and_swap(key, expected, new)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        ShardedKvsEngine::scan_prefix(self, prefix)
+    }
+
+    /// The statistics of every shard added up.
+    fn stats(&mut self) -> Result<Stats> {
+        let mut total = Stats::default();
+        for shard in self.shards.iter() {
+            let stats = shard.lock().unwrap().stats()?;
+            total.live_keys += stats.live_keys;
+            total.log_bytes += stats.log_bytes;
+            total.dead_bytes += stats.dead_bytes;
+            total.redundant_records += stats.redundant_records;
+            total.compactions += stats.compactions;
+            total.index_bytes += stats.index_bytes;
+        }
+        Ok(total)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().compact()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().sync()?;
+        }
+        Ok(())
+    }
+
+    fn set_runtime_options(&mut self, options: RuntimeOptions) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().set_runtime_options(options.clone())?;
+        }
+        Ok(())
+    }
+}
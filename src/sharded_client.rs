@@ -0,0 +1,357 @@
+#![deny(missing_docs)]
+//! A client spreading keys over several `KvsServer`s by consistent hashing.
+
+use crate::client::KvsClient;
+use crate::error::ErrorKind;
+use crate::kvlog::into_string;
+use crate::protocol::Request;
+use crate::{KvLog, KvsEngine, ReplicationCursor, Result};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// Points of each server on the hash ring by default.
+const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+/// Keys moved between servers by `ShardedKvsClient::rebalance` or
+/// `ShardedKvsClient::remove_server`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MovedKeys {
+    /// Number of keys moved to the server they belong to.
+    pub moved: usize,
+    /// Keys with a time to live, left on a server they no longer belong to as the kvs
+    /// protocol cannot set a time to live. They cannot be read through the client until
+    /// they are set again.
+    pub kept: Vec<String>,
+}
+
+/// Connections to several servers, each key living on one of them, so that a dataset
+/// larger than one server can be spread over many.
+///
+/// Keys are routed by consistent hashing: each server owns the keys hashing just before
+/// its points on a ring of hashes. A server has `virtual_nodes` points, so that keys are
+/// spread evenly, and adding or removing a server only moves the keys of its points. The
+/// points only depend on the addresses of the servers, so every client routes keys the
+/// same way.
+///
+/// Changing the servers does not move keys by itself: `rebalance` moves the keys of every
+/// server to the one they belong to, and `remove_server` moves those of the removed server.
+/// Moving keys reads whole stores a page at a time, see `KvsClient::changes`. Keys with a
+/// time to live are not moved, as the kvs protocol cannot set one, but reported instead.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::ShardedKvsClient;
+///
+/// let addrs = ["127.0.0.1:4000".parse().unwrap(), "127.0.0.1:4001".parse().unwrap()];
+/// let mut client = ShardedKvsClient::connect(&addrs).unwrap();
+/// client.set("key1".to_owned(), "42".to_owned()).unwrap();
+///
+/// client.add_server("127.0.0.1:4002".parse().unwrap()).unwrap();
+/// client.rebalance().unwrap();
+/// assert_eq!(client.get("key1".to_owned()).unwrap(), Some("42".to_owned()));
+/// ```
+pub struct ShardedKvsClient {
+    /// Addresses of the servers with a connection to each of them.
+    servers: Vec<(SocketAddr, KvsClient)>,
+    /// Points of each server on the ring.
+    virtual_nodes: usize,
+    /// Index in `servers` of the server of each point of the ring.
+    ring: BTreeMap<u32, usize>,
+    /// Token authenticating the connections, see `authenticate`.
+    token: Option<String>,
+}
+
+impl ShardedKvsClient {
+    /// Connect to the servers listening on `addrs`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to connect to a server.
+    ///
+    /// # Panics
+    ///
+    /// If `addrs` is empty.
+    pub fn connect(addrs: &[SocketAddr]) -> Result<ShardedKvsClient> {
+        assert!(!addrs.is_empty(), "no servers");
+        let servers = addrs
+            .iter()
+            .map(|&addr| Ok((addr, KvsClient::connect(addr)?)))
+            .collect::<Result<_>>()?;
+        let mut client = ShardedKvsClient {
+            servers,
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            ring: BTreeMap::new(),
+            token: None,
+        };
+        client.build_ring();
+        Ok(client)
+    }
+
+    /// Set the number of points of each server on the ring, 128 by default. More points
+    /// spread keys more evenly, but make routing a key slower.
+    ///
+    /// # Panics
+    ///
+    /// If `virtual_nodes` is 0.
+    pub fn virtual_nodes(mut self, virtual_nodes: usize) -> ShardedKvsClient {
+        assert!(virtual_nodes > 0, "no virtual nodes");
+        self.virtual_nodes = virtual_nodes;
+        self.build_ring();
+        self
+    }
+
+    /// Authenticate the connections with the token of the servers, see
+    /// `KvsClient::authenticate`. Servers added later are authenticated with it too.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::authenticate`.
+    pub fn authenticate(&mut self, token: String) -> Result<()> {
+        for (_, client) in &mut self.servers {
+            client.authenticate(token.clone())?;
+        }
+        self.token = Some(token);
+        Ok(())
+    }
+
+    /// Returns the addresses of the servers.
+    pub fn servers(&self) -> Vec<SocketAddr> {
+        self.servers.iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Returns the address of the server `key` belongs to.
+    pub fn server_for(&self, key: &str) -> SocketAddr {
+        self.servers[self.owner(key)].0
+    }
+
+    /// Get the value of a key from its server, or `None` if it is not present.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::get`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let owner = self.owner(&key);
+        self.servers[owner].1.get(key)
+    }
+
+    /// Set the value of a key on its server, overwriting any previous value.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::set`.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let owner = self.owner(&key);
+        self.servers[owner].1.set(key, value)
+    }
+
+    /// Remove a key from its server.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvsClient::remove`.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let owner = self.owner(&key);
+        self.servers[owner].1.remove(key)
+    }
+
+    /// Add the server listening on `addr`, which takes over the keys of its points on the
+    /// ring. They stay on their previous servers until `rebalance`. Adding a server already
+    /// there does nothing.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to connect to the server.
+    /// - Others: Same as `KvsClient::authenticate`, if the servers need a token.
+    pub fn add_server(&mut self, addr: SocketAddr) -> Result<()> {
+        if self.servers.iter().any(|(server, _)| *server == addr) {
+            return Ok(());
+        }
+        let mut client = KvsClient::connect(addr)?;
+        if let Some(token) = &self.token {
+            client.authenticate(token.clone())?;
+        }
+        self.servers.push((addr, client));
+        self.build_ring();
+        Ok(())
+    }
+
+    /// Remove the server listening on `addr`, moving its keys to the servers they now
+    /// belong to. Returns the keys moved and those left on the removed server, or `None` if
+    /// there is no such server.
+    ///
+    /// # Errors
+    ///
+    /// Same as `rebalance`. The server is removed even if moving its keys fails.
+    ///
+    /// # Panics
+    ///
+    /// If it is the last server.
+    pub fn remove_server(&mut self, addr: SocketAddr) -> Result<Option<MovedKeys>> {
+        let index = match self.servers.iter().position(|(server, _)| *server == addr) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        assert!(self.servers.len() > 1, "cannot remove the last server");
+        let (_, mut client) = self.servers.remove(index);
+        self.build_ring();
+        let mut moved = MovedKeys::default();
+        let mut pages = CopyPages::default();
+        while let Some(logs) = pages.next(&mut client)? {
+            let removals = self.move_keys(logs, None, &mut moved)?;
+            check_removed(client.pipeline(removals)?)?;
+        }
+        Ok(Some(moved))
+    }
+
+    /// Move the keys of every server which belong to another one there. Returns the keys
+    /// moved and those left where they were.
+    ///
+    /// A key is set on its new server before being removed from its previous one, so it
+    /// can always be read from one of them, but a write to it while it is moved may be
+    /// lost.
+    ///
+    /// # Errors
+    ///
+    /// - InvalidUtf8: A key or value was set as bytes, and cannot be sent through the kvs
+    ///   protocol.
+    /// - Others: Same as `KvsClient::changes` and `KvsClient::pipeline`, or a move failed on
+    ///   a server with an error of this kind.
+    pub fn rebalance(&mut self) -> Result<MovedKeys> {
+        let mut moved = MovedKeys::default();
+        for index in 0..self.servers.len() {
+            let mut pages = CopyPages::default();
+            while let Some(logs) = pages.next(&mut self.servers[index].1)? {
+                let removals = self.move_keys(logs, Some(index), &mut moved)?;
+                check_removed(self.servers[index].1.pipeline(removals)?)?;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Set the keys of `logs`, a page of a full copy of the server at `source`, or of a
+    /// removed server if `None`, on the servers they belong to if it is another one, and
+    /// record them in `moved` with the keys with a time to live left behind. Returns the requests removing the moved keys from their
+    /// previous server.
+    fn move_keys(
+        &mut self,
+        logs: Vec<KvLog>,
+        source: Option<usize>,
+        moved: &mut MovedKeys,
+    ) -> Result<Vec<Request>> {
+        let mut moves: Vec<Vec<Request>> = vec![Vec::new(); self.servers.len()];
+        let mut removals = Vec::new();
+        for log in logs {
+            let (key, value) = match log {
+                KvLog::Set(key, value) => (into_string(key)?, into_string(value)?),
+                KvLog::SetEx(key, _, _) => {
+                    let key = into_string(key)?;
+                    if Some(self.owner(&key)) != source {
+                        moved.kept.push(key);
+                    }
+                    continue;
+                }
+                // namespaces cannot be reached through the kvs protocol
+                _ => continue,
+            };
+            let owner = self.owner(&key);
+            if Some(owner) == source {
+                continue;
+            }
+            moved.moved += 1;
+            removals.push(Request::Remove { key: key.clone() });
+            moves[owner].push(Request::Set { key, value });
+        }
+        for (owner, requests) in moves.into_iter().enumerate() {
+            if requests.is_empty() {
+                continue;
+            }
+            for result in self.servers[owner].1.pipeline(requests)? {
+                result?;
+            }
+        }
+        Ok(removals)
+    }
+
+    /// Index in `servers` of the server `key` belongs to: the one of the first point of
+    /// the ring from the hash of the key, wrapping around.
+    fn owner(&self, key: &str) -> usize {
+        let hash = ring_hash(key.as_bytes());
+        let point = self.ring.range(hash..).next();
+        let point = point.or_else(|| self.ring.iter().next());
+        *point.expect("no servers").1
+    }
+
+    /// Place the points of every server on the ring.
+    fn build_ring(&mut self) {
+        self.ring.clear();
+        for (index, (addr, _)) in self.servers.iter().enumerate() {
+            for point in 0..self.virtual_nodes {
+                let hash = ring_hash(format!("{}-{}", addr, point).as_bytes());
+                self.ring.insert(hash, index);
+            }
+        }
+    }
+}
+
+/// Pages of a full copy of the store of a server, read one at a time, see
+/// `KvsClient::changes`.
+#[derive(Default)]
+struct CopyPages {
+    /// Where the copy is, `None` before the first page.
+    cursor: Option<ReplicationCursor>,
+    /// Whether the last page was read.
+    done: bool,
+}
+
+impl CopyPages {
+    /// Read the logs of the next page from the server of `client`, `None` once the whole
+    /// copy was read.
+    fn next(&mut self, client: &mut KvsClient) -> Result<Option<Vec<KvLog>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let changes = client.changes(self.cursor)?;
+        self.done = changes.cursor.copy.is_none();
+        self.cursor = Some(changes.cursor);
+        Ok(Some(changes.logs))
+    }
+}
+
+/// Check the results of removing moved keys. A key removed meanwhile is fine.
+fn check_removed(results: Vec<Result<Option<String>>>) -> Result<()> {
+    for result in results {
+        match result {
+            Err(e) if e.kind() != ErrorKind::KeyNotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Position of `bytes` on the ring. A stable hash, so that clients of any version route
+/// keys the same way.
+fn ring_hash(bytes: &[u8]) -> u32 {
+    // CRC-32 alone spreads similar names unevenly, so its bits are mixed like the last
+    // step of MurmurHash3
+    let mut hash = crc32fast::hash(bytes);
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+impl KvsEngine for ShardedKvsClient {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        ShardedKvsClient::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        ShardedKvsClient::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        ShardedKvsClient::remove(self, key)
+    }
+}
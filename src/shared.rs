@@ -0,0 +1,438 @@
+#![deny(missing_docs)]
+//! A `KvStore` shared between threads, reading without waiting for writes.
+
+use crate::codec::RecordFormat;
+use crate::error::ErrorKind;
+use crate::index::LogPointer;
+use crate::kvlog::{into_string, read_value_chain};
+use crate::log_reader::LogReader;
+use crate::merge::MergeOperator;
+use crate::replication::{Changes, NextChanges, ReplicationCursor};
+use crate::value_log::ValueLog;
+use crate::{
+    now_millis, BackupJob, KvLog, KvStore, KvsEngine, Result, RuntimeOptions, ScanCursor, ScanPage,
+    Stats, Watch,
+};
+use crossbeam_skiplist::SkipMap;
+use failure::ResultExt;
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A `KvStore` that can be cloned and shared between threads, e.g. by the connections of
+/// a server.
+///
+/// Reads do not wait for writes. They look keys up in an index shared by every clone,
+/// which keeps a version of a key for each write instead of changing it in place, and read
+/// values through a file handle of their own. Writes go through the store one at
+/// a time, and update the index once their record is in the log file, so a read sees
+/// every write finished before it started.
+///
+/// The index belongs to the log file it points into. Compaction replaces the log file,
+/// and with it the index; reads started before it finish on the old log file, which stays
+/// open until they are done.
+///
+/// Only the keys of the store itself are shared, not those of its namespaces.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::{KvStore, SharedKvStore};
+/// use std::thread;
+/// use tempfile::TempDir;
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let store = SharedKvStore::new(KvStore::open(tempdir.path()).unwrap()).unwrap();
+/// store.set("key1".to_owned(), "42".to_owned()).unwrap();
+///
+/// let reader = store.clone();
+/// let value = thread::spawn(move || reader.get("key1".to_owned()).unwrap());
+/// assert_eq!(value.join().unwrap(), Some("42".to_owned()));
+/// ```
+#[derive(Clone)]
+pub struct SharedKvStore {
+    shared: Arc<Shared>,
+}
+
+/// What the clones of a `SharedKvStore` share.
+struct Shared {
+    /// The store, taking writes one at a time.
+    store: Mutex<KvStore>,
+    /// Index of the current log file. Only replaced along with the log file.
+    epoch: RwLock<Arc<Epoch>>,
+    /// Path to the log file, opened by new readers.
+    log_file_path: PathBuf,
+    /// Path to the value log file.
+    value_log_path: PathBuf,
+    /// Format of the records in the log file.
+    format: RecordFormat,
+    /// Bytes read at a time by readers.
+    read_ahead_size: usize,
+    /// Merge operator of the store.
+    merge_operator: Option<MergeOperator>,
+}
+
+/// A log file with its index.
+struct Epoch {
+    /// `KvStore::log_generation` of the log file.
+    generation: u64,
+    /// Versions of the keys by sequence number, the latest first: their log pointers,
+    /// including expired ones, or `None` once removed. Earlier versions are dropped after
+    /// a new one is added, so that a read always finds one.
+    index: SkipMap<(Vec<u8>, Reverse<u64>), Option<LogPointer>>,
+    /// Idle readers of the log file.
+    readers: Mutex<Vec<Reader>>,
+}
+
+/// Handles to read values from a log file, and from the value log.
+struct Reader {
+    log: LogReader,
+    value_log: ValueLog,
+}
+
+impl SharedKvStore {
+    /// Share `store` between threads.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to flush the write buffer of the store.
+    pub fn new(mut store: KvStore) -> Result<SharedKvStore> {
+        flush(&mut store)?;
+        let shared = Shared {
+            epoch: RwLock::new(Arc::new(Epoch::new(&store))),
+            log_file_path: store.log_file_path.clone(),
+            value_log_path: store.value_log.path().to_owned(),
+            format: store.format.clone(),
+            read_ahead_size: store.options.read_ahead_size,
+            merge_operator: store.options.merge_operator.clone(),
+            store: Mutex::new(store),
+        };
+        Ok(SharedKvStore {
+            shared: Arc::new(shared),
+        })
+    }
+
+    /// Set the value of a key, see `KvStore::set`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::set`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        let mut store = self.shared.store.lock().unwrap();
+        let result = store.set(key.clone(), value);
+        self.publish(&mut store, key.as_bytes())?;
+        result
+    }
+
+    /// Get the value of a key, see `KvStore::get`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get`.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let key = key.into_bytes();
+        loop {
+            let epoch = self.epoch();
+            let pointer = match epoch.latest(&key) {
+                Some(pointer) if !pointer.is_expired(now_millis()) => pointer,
+                _ => return Ok(None),
+            };
+            if let Some(value) = self.read_value(&epoch, &key, pointer.offset)? {
+                return into_string(value).map(Some);
+            }
+        }
+    }
+
+    /// Remove a key, see `KvStore::remove`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::remove`.
+    pub fn remove(&self, key: String) -> Result<()> {
+        let mut store = self.shared.store.lock().unwrap();
+        let result = store.remove(key.clone());
+        self.publish(&mut store, key.as_bytes())?;
+        result
+    }
+
+    /// Compact the log file now, see `KvStore::compact`. Reads started before keep reading
+    /// the previous log file.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::compact`.
+    pub fn compact(&self) -> Result<()> {
+        let mut store = self.shared.store.lock().unwrap();
+        let result = store.compact();
+        if self.epoch().generation != store.log_generation {
+            *self.shared.epoch.write().unwrap() = Arc::new(Epoch::new(&store));
+        }
+        result
+    }
+
+    /// Remove at most `limit` expired keys, see `KvStore::expire_keys`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::expire_keys`.
+    pub fn expire_keys(&self, limit: usize) -> Result<usize> {
+        let mut store = self.shared.store.lock().unwrap();
+        let expired = store.expire(limit)?;
+        for key in &expired {
+            self.publish(&mut store, key)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Get the key-value pairs whose key starts with `prefix`, in lexicographic order of
+    /// keys, see `KvStore::scan_prefix`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get`.
+    pub fn scan_prefix(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let prefix = prefix.into_bytes();
+        'retry: loop {
+            let epoch = self.epoch();
+            let now = now_millis();
+            let mut pairs: Vec<(String, String)> = Vec::new();
+            let mut last_key: Option<&[u8]> = None;
+            let entries = epoch.index.range((prefix.clone(), Reverse(u64::MAX))..);
+            let entries: Vec<_> = entries
+                .take_while(|entry| entry.key().0.starts_with(&prefix))
+                .collect();
+            for entry in &entries {
+                let key = &entry.key().0;
+                // only the latest version of each key counts
+                if last_key == Some(key.as_slice()) {
+                    continue;
+                }
+                last_key = Some(key);
+                let pointer = match entry.value() {
+                    Some(pointer) if !pointer.is_expired(now) => pointer,
+                    _ => continue,
+                };
+                match self.read_value(&epoch, key, pointer.offset)? {
+                    Some(value) => pairs.push((into_string(key.clone())?, into_string(value)?)),
+                    None => continue 'retry,
+                }
+            }
+            return Ok(pairs);
+        }
+    }
+
+    /// Get the changes for a replica at `cursor`, see `KvStore::changes`. The pages of a
+    /// full copy are read without holding up the writes.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::changes`.
+    pub fn changes(&self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        let next = self.shared.store.lock().unwrap().next_changes(cursor)?;
+        let (copy, position) = match next {
+            NextChanges::Logs(changes) => return Ok(changes),
+            NextChanges::Copy(copy, position) => (copy, position),
+        };
+        // without the store, so that writes go on while the values are read
+        let changes = copy.lock().unwrap().page(position)?;
+        if changes.cursor.copy.is_none() {
+            self.shared.store.lock().unwrap().end_full_copy(&copy);
+        }
+        Ok(changes)
+    }
+
+    /// Apply the changes of a primary, see `KvStore::apply_changes`.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::apply_changes`.
+    pub(crate) fn apply_changes(&self, changes: Changes) -> Result<()> {
+        let keys: Vec<Vec<u8>> = changes
+            .logs
+            .iter()
+            .flat_map(KvLog::commands)
+            .filter_map(KvLog::key)
+            .map(<[u8]>::to_vec)
+            .collect();
+        let mut store = self.shared.store.lock().unwrap();
+        let result = store.apply_changes(changes);
+        for key in keys {
+            self.publish(&mut store, &key)?;
+        }
+        result
+    }
+
+    /// The current epoch.
+    fn epoch(&self) -> Arc<Epoch> {
+        Arc::clone(&self.shared.epoch.read().unwrap())
+    }
+
+    /// Read the value of `key` at `offset` of the log file of `epoch`, or `None` if the log
+    /// file was replaced before a reader of it could be opened.
+    fn read_value(&self, epoch: &Epoch, key: &[u8], offset: u64) -> Result<Option<Vec<u8>>> {
+        let idle = epoch.readers.lock().unwrap().pop();
+        let mut reader = match idle {
+            Some(reader) => reader,
+            None => match self.open_reader(epoch)? {
+                Some(reader) => reader,
+                None => return Ok(None),
+            },
+        };
+        let merge_operator = self.shared.merge_operator.as_ref();
+        let log = &mut reader.log;
+        let value_log = &mut reader.value_log;
+        let value = read_value_chain(key, offset, merge_operator, |offset| {
+            value_log.resolve(log.read_at(offset)?)
+        });
+        epoch.readers.lock().unwrap().push(reader);
+        value.map(Some)
+    }
+
+    /// Open a reader of the log file of `epoch`, or `None` if it was replaced.
+    ///
+    /// The store is locked meanwhile, so that the log file is not replaced while being
+    /// opened.
+    fn open_reader(&self, epoch: &Epoch) -> Result<Option<Reader>> {
+        let store = self.shared.store.lock().unwrap();
+        if store.log_generation != epoch.generation {
+            return Ok(None);
+        }
+        let file = File::open(&self.shared.log_file_path).context(ErrorKind::Io)?;
+        Ok(Some(Reader {
+            log: LogReader::new(
+                file,
+                self.shared.read_ahead_size,
+                self.shared.format.clone(),
+            ),
+            value_log: ValueLog::open(self.shared.value_log_path.clone())?,
+        }))
+    }
+
+    /// Make a write of `key` to `store` visible to reads.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to flush the write buffer of the store.
+    fn publish(&self, store: &mut KvStore, key: &[u8]) -> Result<()> {
+        flush(store)?;
+        let epoch = self.epoch();
+        if epoch.generation != store.log_generation {
+            *self.shared.epoch.write().unwrap() = Arc::new(Epoch::new(store));
+            return Ok(());
+        }
+        let pointer = store.log_pointer().get(key).copied();
+        // the write failed without changing the key
+        if pointer == epoch.latest(key) {
+            return Ok(());
+        }
+        let sequence = store.sequence();
+        epoch
+            .index
+            .insert((key.to_vec(), Reverse(sequence)), pointer);
+        let earlier = (key.to_vec(), Reverse(sequence - 1))..=(key.to_vec(), Reverse(0));
+        for entry in epoch.index.range(earlier) {
+            entry.remove();
+        }
+        Ok(())
+    }
+}
+
+impl Epoch {
+    /// Index the current log file of `store`.
+    fn new(store: &KvStore) -> Epoch {
+        let index = SkipMap::new();
+        for (key, pointer) in store.log_pointer().iter() {
+            index.insert((key.clone(), Reverse(pointer.sequence)), Some(*pointer));
+        }
+        Epoch {
+            generation: store.log_generation,
+            index,
+            readers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The log pointer of the latest version of `key`, or `None` if it is absent.
+    fn latest(&self, key: &[u8]) -> Option<LogPointer> {
+        let latest = (key.to_vec(), Reverse(u64::MAX))..=(key.to_vec(), Reverse(0));
+        *self.index.range(latest).next()?.value()
+    }
+}
+
+/// Write the buffered records of `store` to its log file, where readers can see them.
+fn flush(store: &mut KvStore) -> Result<()> {
+    if let Some(append_writer) = &mut store.append_writer {
+        append_writer.flush().context(ErrorKind::Io)?;
+    }
+    Ok(())
+}
+
+impl KvsEngine for SharedKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        SharedKvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        SharedKvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        SharedKvStore::remove(self, key)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut store = self.shared.store.lock().unwrap();
+        let result = store.compare_and_swap(key.clone(), expected, new);
+        self.publish(&mut store, key.as_bytes())?;
+        result
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        SharedKvStore::scan_prefix(self, prefix)
+    }
+
+    fn scan_page(&mut self, prefix: String, cursor: &ScanCursor, limit: usize) -> Result<ScanPage> {
+        let mut store = self.shared.store.lock().unwrap();
+        store.scan_prefix_from(prefix, cursor, limit)
+    }
+
+    fn changes(&mut self, cursor: Option<ReplicationCursor>) -> Result<Changes> {
+        SharedKvStore::changes(self, cursor)
+    }
+
+    fn subscribe(&mut self, prefixes: Vec<String>) -> Result<Watch> {
+        Ok(self.shared.store.lock().unwrap().watch_prefixes(prefixes))
+    }
+
+    fn expire_keys(&mut self, limit: usize) -> Result<usize> {
+        SharedKvStore::expire_keys(self, limit)
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        self.shared.store.lock().unwrap().stats()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        SharedKvStore::compact(self)
+    }
+
+    fn start_backup(&mut self, dest: PathBuf) -> Result<BackupJob> {
+        self.shared.store.lock().unwrap().start_backup(dest)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.shared.store.lock().unwrap().sync()
+    }
+
+    fn set_runtime_options(&mut self, options: RuntimeOptions) -> Result<()> {
+        let mut store = self.shared.store.lock().unwrap();
+        store.set_runtime_options(options);
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+#![deny(missing_docs)]
+//! Storage engine backed by sled, to compare `KvStore` against a mature engine.
+
+use crate::error::{Error, ErrorKind};
+use crate::kvlog::into_string;
+use crate::{KvsEngine, Result};
+use failure::ResultExt;
+use std::path::PathBuf;
+
+/// A `KvsEngine` storing keys and values in a sled database. Needs the `sled` feature.
+///
+/// Every write is flushed to disk before it returns, so it survives the process exiting.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::{KvsEngine, SledKvsEngine};
+/// use tempfile::TempDir;
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut engine = SledKvsEngine::open(tempdir.path()).unwrap();
+/// engine.set("key1".to_owned(), "42".to_owned()).unwrap();
+/// assert_eq!(engine.get("key1".to_owned()).unwrap(), Some("42".to_owned()));
+/// ```
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: sled::Db,
+}
+
+impl SledKvsEngine {
+    /// Opens the sled database in the given directory, creating it if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// - Sled: sled failed to open the database.
+    pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        let db = sled::open(path.into()).context(ErrorKind::Sled)?;
+        Ok(SledKvsEngine::new(db))
+    }
+
+    /// Wraps an open sled database.
+    pub fn new(db: sled::Db) -> SledKvsEngine {
+        SledKvsEngine { db }
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.db
+            .insert(key, value.into_bytes())
+            .context(ErrorKind::Sled)?;
+        self.db.flush().context(ErrorKind::Sled)?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.db.get(key).context(ErrorKind::Sled)? {
+            Some(value) => Ok(Some(into_string(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.db
+            .remove(key)
+            .context(ErrorKind::Sled)?
+            .ok_or_else(|| Error::from(ErrorKind::KeyNotFound))?;
+        self.db.flush().context(ErrorKind::Sled)?;
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let swapped = self
+            .db
+            .compare_and_swap(key, expected, new.map(String::into_bytes))
+            .context(ErrorKind::Sled)?
+            .is_ok();
+        if swapped {
+            self.db.flush().context(ErrorKind::Sled)?;
+        }
+        Ok(swapped)
+    }
+
+    fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.db
+            .scan_prefix(prefix)
+            .map(|pair| {
+                let (key, value) = pair.context(ErrorKind::Sled)?;
+                Ok((into_string(key.to_vec())?, into_string(value.to_vec())?))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,67 @@
+#![deny(missing_docs)]
+//! Logging of the commands of a `KvsServer` taking too long.
+
+use crate::metrics::Command;
+use std::time::Duration;
+
+/// Logs the commands of a `KvsServer` which take longer than a threshold to stderr, with
+/// the key they ran on and how long they took, to find the keys and the moments, such as
+/// compactions, making the server slow.
+///
+/// The time of a command includes waiting for the engine, e.g. for the writes of other
+/// connections to be done. Keys may be sensitive, so they can be logged as a hash instead,
+/// which still tells whether slow commands run on the same key.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use kvs::{KvStore, KvsServer, SharedKvStore, SlowLog};
+/// use std::time::Duration;
+///
+/// let slow_log = SlowLog::new(Duration::from_millis(10)).hash_keys(true);
+/// KvsServer::new(SharedKvStore::new(KvStore::open(".").unwrap()).unwrap())
+///     .slow_log(slow_log)
+///     .run("127.0.0.1:4000")
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowLog {
+    threshold: Duration,
+    hash_keys: bool,
+}
+
+impl SlowLog {
+    /// Log the commands taking longer than `threshold`.
+    pub fn new(threshold: Duration) -> SlowLog {
+        SlowLog {
+            threshold,
+            hash_keys: false,
+        }
+    }
+
+    /// Log the CRC-32 of keys instead of the keys themselves.
+    pub fn hash_keys(mut self, hash_keys: bool) -> SlowLog {
+        self.hash_keys = hash_keys;
+        self
+    }
+
+    /// Log `command` on `key`, or on the prefix of a scan, if it took longer than the
+    /// threshold.
+    pub(crate) fn record(&self, command: Command, key: Option<&str>, elapsed: Duration) {
+        if elapsed <= self.threshold {
+            return;
+        }
+        let key = match key {
+            Some(key) if self.hash_keys => format!("#{:08x}", crc32fast::hash(key.as_bytes())),
+            // quoted, so that a key cannot forge lines of the log
+            Some(key) => format!("{:?}", key),
+            None => "-".to_owned(),
+        };
+        eprintln!(
+            "Slow command: {} {} took {:?}",
+            command.label(),
+            key,
+            elapsed
+        );
+    }
+}
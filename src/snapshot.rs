@@ -0,0 +1,135 @@
+#![deny(missing_docs)]
+//! Point-in-time snapshots of a KvStore.
+
+use crate::index::{LogPointer, LogPointerMap};
+use crate::iter::{Iter, ReadValue};
+use crate::kvlog::{into_string, read_value_chain};
+use crate::log_reader::LogReader;
+use crate::merge::MergeOperator;
+use crate::value_log::ValueLog;
+use crate::Result;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+/// A consistent read-only view of a `KvStore`, created by `KvStore::snapshot`.
+///
+/// The snapshot shares the log pointer map of the store and reads the version of each key
+/// as of its sequence number, see `sequence`, with its own handle to the log file. New
+/// writes and compactions of the store are not visible through the snapshot, and do not
+/// break it: a compacted log file replaces the old one by a rename, together with the log
+/// pointer map, and the snapshot keeps reading the old ones.
+///
+/// Taking a snapshot is cheap, and so is writing while it is alive: the store keeps the
+/// earlier versions of keys until the next compaction anyway, and a snapshot alive across a
+/// compaction keeps the old log pointer map, so drop snapshots when they are no longer
+/// needed. Until the next compaction, `KvStore::get_at` with the `sequence` of the
+/// snapshot reads the same values without keeping a snapshot.
+pub struct Snapshot {
+    log_pointer: Arc<RwLock<LogPointerMap>>,
+    /// Sequence number of the last write before the snapshot.
+    sequence: u64,
+    reader: LogReader,
+    value_log: ValueLog,
+    /// Time the snapshot was taken, keys are expired as of this time.
+    taken_at: u64,
+    /// Merge operator of the store.
+    merge_operator: Option<MergeOperator>,
+}
+
+impl Snapshot {
+    /// Create a snapshot of a log pointer map at `sequence`, reading values from a log file
+    /// of which every pointed log is already written.
+    pub(crate) fn new(
+        log_pointer: Arc<RwLock<LogPointerMap>>,
+        sequence: u64,
+        reader: LogReader,
+        value_log: ValueLog,
+        taken_at: u64,
+        merge_operator: Option<MergeOperator>,
+    ) -> Snapshot {
+        Snapshot {
+            log_pointer,
+            sequence,
+            reader,
+            value_log,
+            taken_at,
+            merge_operator,
+        }
+    }
+
+    /// Returns the sequence number of the last write before the snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn log_pointer(&self) -> RwLockReadGuard<'_, LogPointerMap> {
+        self.log_pointer.read().unwrap()
+    }
+
+    /// Log pointer of a key at the time of the snapshot.
+    fn get_pointer(&self, key: &[u8]) -> Option<LogPointer> {
+        self.log_pointer()
+            .get_at(key, self.sequence)
+            .filter(|pointer| !pointer.is_expired(self.taken_at))
+    }
+
+    /// Returns the value of a key at the time of the snapshot.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get`.
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<String>> {
+        let key = key.as_ref();
+        match self.get_pointer(key) {
+            None => Ok(None),
+            Some(pointer) => self
+                .read_value(key, pointer.offset)
+                .and_then(into_string)
+                .map(Some),
+        }
+    }
+
+    /// Returns true if the key existed at the time of the snapshot.
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.get_pointer(key.as_ref()).is_some()
+    }
+
+    /// Returns the number of keys at the time of the snapshot.
+    pub fn len(&self) -> usize {
+        self.log_pointer()
+            .iter_at(self.sequence, self.taken_at)
+            .len()
+    }
+
+    /// Returns true if the store was empty at the time of the snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over all key-value pairs at the time of the snapshot,
+    /// in the order they appear in the log.
+    ///
+    /// # Errors
+    ///
+    /// Each item has the same errors as `KvStore::get`.
+    pub fn iter(&mut self) -> Iter<'_> {
+        let mut pointers: Vec<_> = self
+            .log_pointer()
+            .iter_at(self.sequence, self.taken_at)
+            .into_iter()
+            .map(|(key, pointer)| (key, pointer.offset))
+            .collect();
+        // Sort by log pointer so values are read sequentially.
+        pointers.sort_unstable_by_key(|x| x.1);
+        Iter::new(self, pointers)
+    }
+}
+
+impl ReadValue for Snapshot {
+    fn read_value(&mut self, key: &[u8], offset: u64) -> Result<Vec<u8>> {
+        let reader = &mut self.reader;
+        let value_log = &mut self.value_log;
+        read_value_chain(key, offset, self.merge_operator.as_ref(), |offset| {
+            value_log.resolve(reader.read_at(offset)?)
+        })
+    }
+}
@@ -0,0 +1,44 @@
+#![deny(missing_docs)]
+//! Statistics about the health of a store, returned by `KvStore::stats` and
+//! `KvStore::disk_usage`.
+
+use serde::{Deserialize, Serialize};
+
+/// Statistics about the size of a store and how much of it is garbage.
+///
+/// Compaction runs on its own once enough records are redundant, these numbers show how far
+/// a store is from it and how much it would reclaim.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Number of live keys, the same as `KvStore::len`.
+    pub live_keys: usize,
+    /// Length of the log, including records still in the write buffer.
+    pub log_bytes: u64,
+    /// Estimated length of the redundant records in the log, assuming they are as long as
+    /// the others on average.
+    pub dead_bytes: u64,
+    /// Number of records in the log made redundant by later records, which compaction drops.
+    pub redundant_records: usize,
+    /// Number of compactions since the store was opened.
+    pub compactions: u64,
+    /// Estimated memory used by the index, counting keys and log pointers but not the
+    /// overhead of the map holding them.
+    pub index_bytes: usize,
+}
+
+/// Space used by a store on disk and how much of it holds live data, returned by
+/// `KvStore::disk_usage`.
+///
+/// Unlike `Stats::dead_bytes`, which is estimated, the live bytes are measured by reading
+/// every live record. Remove records are counted as garbage, even those compaction retains.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskUsage {
+    /// Length of the log file and the value log.
+    pub disk_bytes: u64,
+    /// Length of the records and values holding live keys, and of the header of the log
+    /// file. A value built by appends or merges counts all the records it is built from.
+    pub live_bytes: u64,
+    /// Ratio of `disk_bytes` to `live_bytes`. 1 means there is no garbage, and compaction
+    /// brings it close to that.
+    pub space_amplification: f64,
+}
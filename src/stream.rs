@@ -0,0 +1,140 @@
+#![deny(missing_docs)]
+//! Connections of `KvsServer` and `KvsClient`, over plain TCP, a Unix domain socket, or
+//! TLS.
+
+use crate::error::Error;
+use failure::Fail;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A byte stream in both directions, like a TCP stream or a TLS stream over it.
+pub(crate) trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+/// A connected socket, over TCP or a Unix domain socket.
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    /// Another handle to the same socket.
+    pub(crate) fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            Socket::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.try_clone().map(Socket::Unix),
+        }
+    }
+
+    /// Shut down the reading or writing half of the socket, or both.
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// Set the read and write timeouts of the socket.
+    pub(crate) fn set_timeouts(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            #[cfg(unix)]
+            Socket::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    /// The address of the other end, if it is a TCP socket.
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Socket::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Socket::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A stream shared by the reading and the writing half of a connection.
+///
+/// A TLS stream cannot be cloned like a TCP stream, as both directions go through the
+/// same session, so the halves take turns using it.
+pub(crate) struct SharedStream<S>(Arc<Mutex<S>>);
+
+impl<S: Read> Read for SharedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl<S: Write> Write for SharedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Split `stream` into a buffered reader and a buffered writer.
+pub(crate) fn split<S: Read + Write>(
+    stream: S,
+) -> (BufReader<SharedStream<S>>, BufWriter<SharedStream<S>>) {
+    let stream = Arc::new(Mutex::new(stream));
+    (
+        BufReader::new(SharedStream(Arc::clone(&stream))),
+        BufWriter::new(SharedStream(stream)),
+    )
+}
+
+/// Whether `e` is an Io error caused by a timeout of the socket, see `Socket::set_timeouts`.
+pub(crate) fn is_timeout(e: &Error) -> bool {
+    let io_error = e
+        .cause()
+        .and_then(|cause| cause.downcast_ref::<io::Error>());
+    matches!(
+        io_error.map(io::Error::kind),
+        Some(io::ErrorKind::WouldBlock) | Some(io::ErrorKind::TimedOut)
+    )
+}
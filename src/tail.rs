@@ -0,0 +1,76 @@
+#![deny(missing_docs)]
+//! Reading the raw records of a log file in order, for change data capture.
+
+use crate::codec::RecordFormat;
+use crate::error::ErrorKind;
+use crate::{KvLog, Result};
+use failure::ResultExt;
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+
+/// Iterator over the records of the log file from an offset, created by `KvStore::tail`.
+///
+/// It yields each record with its offset, in the order they were appended, and stops at
+/// the end of the log file as of its creation. `position` tells where the next call to
+/// `KvStore::tail` should resume. A batch is yielded as one record. Records are yielded
+/// stamped with their sequence number, see `KvLog::into_unsequenced`.
+///
+/// Offsets only hold until the log file is compacted or cleared, which rewrites it. The
+/// iterator keeps its own handle to the log file, so it reads the old file to its end.
+pub struct Tail {
+    reader: BufReader<File>,
+    format: RecordFormat,
+    /// Offset of the next record.
+    position: u64,
+    /// Length of the log file when the iterator was created.
+    end: u64,
+    /// Whether reading a record failed, which ends the iteration.
+    failed: bool,
+}
+
+impl Tail {
+    /// Create an iterator over the records of `file` between `from` and `end`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to seek to `from`.
+    pub(crate) fn new(
+        file: File,
+        read_ahead_size: usize,
+        format: RecordFormat,
+        from: u64,
+        end: u64,
+    ) -> Result<Tail> {
+        let mut reader = BufReader::with_capacity(read_ahead_size.max(1), file);
+        reader.seek(SeekFrom::Start(from)).context(ErrorKind::Io)?;
+        Ok(Tail {
+            reader,
+            format,
+            position: from,
+            end,
+            failed: false,
+        })
+    }
+
+    /// Offset after the last record yielded, where tailing resumes.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<(u64, KvLog)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.position >= self.end {
+            return None;
+        }
+        let offset = self.position;
+        let record = self.format.read(&mut self.reader).and_then(|kvlog| {
+            self.position = self.reader.stream_position().context(ErrorKind::Io)?;
+            Ok((offset, kvlog))
+        });
+        self.failed = record.is_err();
+        Some(record)
+    }
+}
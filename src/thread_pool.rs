@@ -0,0 +1,150 @@
+#![deny(missing_docs)]
+//! Thread pools running the connections of `KvsServer`, usable for other jobs too.
+
+use crate::error::ErrorKind;
+use crate::Result;
+use failure::ResultExt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A pool of threads running jobs.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::{SharedQueueThreadPool, ThreadPool};
+/// use std::sync::mpsc;
+///
+/// let pool = SharedQueueThreadPool::new(4).unwrap();
+/// let (sender, receiver) = mpsc::channel();
+/// for i in 0..8 {
+///     let sender = sender.clone();
+///     pool.spawn(move || sender.send(i * i).unwrap());
+/// }
+/// let mut squares: Vec<i32> = receiver.iter().take(8).collect();
+/// squares.sort();
+/// assert_eq!(squares, vec![0, 1, 4, 9, 16, 25, 36, 49]);
+/// ```
+pub trait ThreadPool {
+    /// Create a pool of `threads` threads.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to start the threads.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Run `job` on a thread of the pool, as soon as one is free.
+    ///
+    /// A job panicking does not take a thread away from the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// A pool starting a thread for each job, however many are running. Mostly for comparing
+/// with the other pools.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<NaiveThreadPool> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A pool of a fixed number of threads taking jobs from a shared queue.
+///
+/// A thread whose job panics is replaced by a new one. The threads stop once the pool is
+/// dropped and the queued jobs are done.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            Worker(Arc::clone(&receiver)).start()?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // the workers only stop when the pool is dropped
+        self.sender.send(Box::new(job)).unwrap();
+    }
+}
+
+/// A thread of a `SharedQueueThreadPool`, starting its replacement if its job panics.
+struct Worker(Arc<Mutex<Receiver<Job>>>);
+
+impl Worker {
+    fn start(self) -> Result<()> {
+        thread::Builder::new()
+            .spawn(move || self.run())
+            .context(ErrorKind::Io)?;
+        Ok(())
+    }
+
+    fn run(&self) {
+        loop {
+            // the lock is released before running the job
+            let job = self.0.lock().unwrap().recv();
+            match job {
+                Ok(job) => job(),
+                // the pool was dropped
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(e) = Worker(Arc::clone(&self.0)).start() {
+                eprintln!("Failed to replace a thread of the pool: {}", e);
+            }
+        }
+    }
+}
+
+/// A pool backed by a rayon thread pool, stealing work between its threads. Needs the
+/// `rayon` feature.
+#[cfg(feature = "rayon")]
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+#[cfg(feature = "rayon")]
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<RayonThreadPool> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.max(1) as usize)
+            // keep the thread of a panicking job, like the other pools
+            .panic_handler(|_| {})
+            .build()
+            .context(ErrorKind::Io)?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job);
+    }
+}
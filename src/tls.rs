@@ -0,0 +1,96 @@
+#![deny(missing_docs)]
+//! TLS for `KvsServer` and `KvsClient` with rustls, so keys and values do not cross the
+//! network in plaintext. Needs the `tls` feature.
+//!
+//! Certificates and private keys are read from PEM files. The server presents its
+//! certificate chain, and the client trusts the servers whose certificates are signed by
+//! one of its certificate authorities.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use kvs::{KvStore, KvsClient, KvsServer, SharedKvStore};
+//! use std::path::Path;
+//!
+//! let config = kvs::tls::server_config(Path::new("cert.pem"), Path::new("key.pem")).unwrap();
+//! let store = SharedKvStore::new(KvStore::open(".").unwrap()).unwrap();
+//! KvsServer::new(store).tls(config).run("127.0.0.1:4000").unwrap();
+//!
+//! // elsewhere
+//! let config = kvs::tls::client_config(Path::new("ca.pem")).unwrap();
+//! let mut client = KvsClient::connect_tls("127.0.0.1:4000", "localhost", config).unwrap();
+//! ```
+
+use crate::error::{Error, ErrorKind};
+use crate::Result;
+use failure::ResultExt;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+pub use rustls;
+
+/// The configuration of a server presenting the certificate chain in `cert` and
+/// authenticating with the private key in `key`.
+///
+/// # Errors
+///
+/// - Io: Failed to read `cert` or `key`.
+/// - Tls: A file is not valid PEM, holds no certificate or key, or the key does not fit
+///   the certificate.
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = read_certs(cert)?;
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut open(key)?)
+        .context(ErrorKind::Tls)?
+        .ok_or_else(|| Error::from(ErrorKind::Tls))?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .context(ErrorKind::Tls)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context(ErrorKind::Tls)?;
+    Ok(Arc::new(config))
+}
+
+/// The configuration of a client trusting the certificate authorities in `ca`.
+///
+/// # Errors
+///
+/// - Io: Failed to read `ca`.
+/// - Tls: `ca` is not valid PEM, or holds no certificate.
+pub fn client_config(ca: &Path) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(ca)? {
+        roots.add(cert).context(ErrorKind::Tls)?;
+    }
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .context(ErrorKind::Tls)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// The certificates of a PEM file, failing if there are none.
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context(ErrorKind::Tls)?;
+    if certs.is_empty() {
+        return Err(Error::from(ErrorKind::Tls));
+    }
+    Ok(certs)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path).context(ErrorKind::Io)?))
+}
+
+/// The cryptography of rustls, chosen explicitly so that another provider enabled in the
+/// dependency graph does not make the default ambiguous.
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
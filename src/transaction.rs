@@ -0,0 +1,116 @@
+#![deny(missing_docs)]
+//! Multi-key transactions on a KvStore.
+
+use crate::error::{Error, ErrorKind};
+use crate::{KvStore, Result, WriteBatch};
+use std::collections::HashMap;
+
+/// A transaction, created by `KvStore::transaction`.
+///
+/// Writes are staged in memory and are visible to reads of the same transaction.
+/// `commit` applies them as one `WriteBatch`, so they survive a crash all together or not at all.
+/// Dropping the transaction without committing rolls it back.
+///
+/// The transaction borrows the store mutably, so nothing else can modify the store before
+/// it is committed or rolled back.
+///
+/// # Examples
+///
+/// ```rust
+/// use kvs::KvStore;
+/// use tempfile::TempDir;
+///
+/// let tempdir = TempDir::new().unwrap();
+/// let mut kv = KvStore::open(tempdir.path()).unwrap();
+/// kv.set("balance:a".to_owned(), "10".to_owned()).unwrap();
+///
+/// let mut tx = kv.transaction();
+/// let a: u32 = tx.get("balance:a".to_owned()).unwrap().unwrap().parse().unwrap();
+/// tx.set("balance:a".to_owned(), (a - 3).to_string());
+/// tx.set("balance:b".to_owned(), "3".to_owned());
+/// tx.commit().unwrap();
+///
+/// assert_eq!(kv.get("balance:a".to_owned()).unwrap(), Some("7".to_owned()));
+/// ```
+pub struct Transaction<'a> {
+    store: &'a mut KvStore,
+    /// Staged writes, `None` stands for a removal.
+    writes: HashMap<String, Option<String>>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Start a transaction on `store`.
+    pub(crate) fn new(store: &'a mut KvStore) -> Transaction<'a> {
+        Transaction {
+            store,
+            writes: HashMap::new(),
+        }
+    }
+
+    /// Returns the value of a key as seen by this transaction.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::get`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.writes.get(&key) {
+            Some(staged) => Ok(staged.clone()),
+            None => self.store.get(key),
+        }
+    }
+
+    /// Returns true if the key exists as seen by this transaction.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::contains_key`.
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        match self.writes.get(key) {
+            Some(staged) => Ok(staged.is_some()),
+            None => self.store.contains_key(key),
+        }
+    }
+
+    /// Stages a set command.
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Stages a remove command.
+    ///
+    /// # Errors
+    ///
+    /// - KeyNotFound: If the key does not exist as seen by this transaction.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if !self.contains_key(&key)? {
+            return Err(Error::from(ErrorKind::KeyNotFound));
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Applies all staged writes atomically.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::write`. Nothing is applied if it fails.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, staged) in self.writes {
+            match staged {
+                Some(value) => {
+                    batch.set(key, value);
+                }
+                // keys created and removed within the transaction need no command
+                None if self.store.contains_key(&key)? => {
+                    batch.remove(key);
+                }
+                None => {}
+            }
+        }
+        self.store.write(batch)
+    }
+
+    /// Discards all staged writes.
+    pub fn rollback(self) {}
+}
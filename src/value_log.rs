@@ -0,0 +1,204 @@
+#![deny(missing_docs)]
+//! Value log holding large values apart from the log file, see `Options::value_log_threshold`.
+//!
+//! Values are appended to the value log as raw bytes, and the log file only gets a
+//! `KvLog::SetSeparated` record pointing to them. Compaction copies the live values to a new
+//! value log replacing this one, so that the space of overwritten values is reclaimed.
+//! Readers open the value log file before it can be replaced, and keep reading the file
+//! the offsets they hold refer to.
+
+use crate::error::{Error, ErrorKind};
+use crate::{KvLog, Result};
+use failure::{Fail, ResultExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Appends values to and reads values from a value log file.
+///
+/// The file is only created when the first value is appended.
+pub(crate) struct ValueLog {
+    path: PathBuf,
+    /// Handle for appending values, opened on the first append.
+    writer: Option<File>,
+    /// Handle for reading values, opened on the first read.
+    reader: Option<File>,
+}
+
+impl ValueLog {
+    /// Create a value log of the file at `path`.
+    pub(crate) fn new(path: PathBuf) -> ValueLog {
+        ValueLog {
+            path,
+            writer: None,
+            reader: None,
+        }
+    }
+
+    /// Create a value log of the file at `path` like `new`, opening the file for reading
+    /// right away if it exists, so that values are read from it even after it is replaced.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to open the value log file.
+    pub(crate) fn open(path: PathBuf) -> Result<ValueLog> {
+        let reader = match File::open(&path) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.context(ErrorKind::Io).into()),
+        };
+        Ok(ValueLog {
+            path,
+            writer: None,
+            reader,
+        })
+    }
+
+    /// Create an empty value log file at `path`, replacing any file there.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to create the value log file.
+    pub(crate) fn create(path: PathBuf) -> Result<ValueLog> {
+        let writer = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .context(ErrorKind::Io)?;
+        Ok(ValueLog {
+            path,
+            writer: Some(writer),
+            reader: None,
+        })
+    }
+
+    /// Path of the value log file.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The handle for appending values, opening it if needed, which creates the file if it
+    /// does not exist.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to open the value log file.
+    pub(crate) fn writer(&mut self) -> Result<&mut File> {
+        Ok(match &mut self.writer {
+            Some(writer) => writer,
+            writer => writer.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .context(ErrorKind::Io)?,
+            ),
+        })
+    }
+
+    /// Append a value, returning its offset. The value is written through to the OS, and
+    /// synced to disk if `sync` is set.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to open, write or sync the value log file.
+    pub(crate) fn append(&mut self, value: &[u8], sync: bool) -> Result<u64> {
+        let writer = self.writer()?;
+        let offset = writer.metadata().context(ErrorKind::Io)?.len();
+        writer.write_all(value).context(ErrorKind::Io)?;
+        if sync {
+            writer.sync_data().context(ErrorKind::Io)?;
+        }
+        Ok(offset)
+    }
+
+    /// Move the value of a set command to the value log if it has at least `threshold`
+    /// bytes, appending it like `append`. Other logs, including the set commands of a batch,
+    /// are returned as they are.
+    ///
+    /// # Errors
+    ///
+    /// Same as `append`.
+    pub(crate) fn separate(&mut self, kvlog: KvLog, threshold: usize, sync: bool) -> Result<KvLog> {
+        let (key, value, expires_at) = match kvlog {
+            KvLog::Set(key, value) if value.len() >= threshold => (key, value, None),
+            KvLog::SetEx(key, value, expires_at) if value.len() >= threshold => {
+                (key, value, Some(expires_at))
+            }
+            kvlog => return Ok(kvlog),
+        };
+        let offset = self.append(&value, sync)?;
+        Ok(KvLog::SetSeparated(
+            key,
+            offset,
+            value.len() as u64,
+            expires_at,
+        ))
+    }
+
+    /// Sync the values appended so far to disk.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to sync the value log file.
+    pub(crate) fn sync(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.sync_data().context(ErrorKind::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Read the value of `len` bytes at `offset`.
+    ///
+    /// # Errors
+    ///
+    /// - Io: Failed to open or read the value log file.
+    /// - Corruption: The value log file ends before the value.
+    pub(crate) fn read(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            reader => reader.insert(File::open(&self.path).context(ErrorKind::Io)?),
+        };
+        reader
+            .seek(SeekFrom::Start(offset))
+            .context(ErrorKind::Io)?;
+        let mut value = Vec::with_capacity(len as usize);
+        reader
+            .take(len)
+            .read_to_end(&mut value)
+            .context(ErrorKind::Io)?;
+        if value.len() as u64 != len {
+            return Err(Error::from(ErrorKind::Corruption));
+        }
+        Ok(value)
+    }
+
+    /// Turn a set command with its value in the value log into one with the value inline,
+    /// keeping its sequence number. The set commands of a batch are turned too. Other logs
+    /// are returned as they are.
+    ///
+    /// # Errors
+    ///
+    /// Same as `read`.
+    pub(crate) fn resolve(&mut self, kvlog: KvLog) -> Result<KvLog> {
+        match kvlog {
+            KvLog::SetSeparated(key, offset, len, expires_at) => {
+                let value = self.read(offset, len)?;
+                Ok(match expires_at {
+                    Some(expires_at) => KvLog::new_set_ex(key, value, expires_at),
+                    None => KvLog::new_set(key, value),
+                })
+            }
+            KvLog::Sequenced(sequence, kvlog) => {
+                Ok(KvLog::new_sequenced(sequence, self.resolve(*kvlog)?))
+            }
+            KvLog::Batch(logs) => Ok(KvLog::Batch(
+                logs.into_iter()
+                    .map(|kvlog| self.resolve(kvlog))
+                    .collect::<Result<_>>()?,
+            )),
+            kvlog => Ok(kvlog),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+#![deny(missing_docs)]
+//! Integrity report of a store, returned by `KvStore::verify`.
+
+use crate::ErrorKind;
+
+/// Result of checking the log file of a store against its index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Number of records read from the log file.
+    pub records: usize,
+    /// Length of the log file.
+    pub bytes: u64,
+    /// Problems found, in the order they were found. Empty if the store is sound.
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// Whether no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// A problem found by `KvStore::verify`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyIssue {
+    /// The record at `offset` cannot be read. Records after it cannot be found, so the scan
+    /// stops there.
+    UnreadableRecord {
+        /// Offset of the record in the log file.
+        offset: u64,
+        /// Why it cannot be read.
+        kind: ErrorKind,
+    },
+    /// The index and the log file disagree on where the live record of `key` is.
+    IndexMismatch {
+        /// The key.
+        key: Vec<u8>,
+        /// Offset of the record in the index, `None` if the key is not in it.
+        indexed: Option<u64>,
+        /// Offset of the last record of the key in the log, `None` if it has been removed.
+        logged: Option<u64>,
+    },
+    /// The value of `key` cannot be read, for example because its chain of appends is broken.
+    UnreadableValue {
+        /// The key.
+        key: Vec<u8>,
+        /// Offset of its live record.
+        offset: u64,
+        /// Why it cannot be read.
+        kind: ErrorKind,
+    },
+}
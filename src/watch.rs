@@ -0,0 +1,114 @@
+#![deny(missing_docs)]
+//! Subscriptions to changes of keys, see `KvStore::watch`.
+
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+/// Kind of change of a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeOp {
+    /// The key was set, appended to or merged into.
+    Set,
+    /// The key was removed.
+    Remove,
+}
+
+/// A change of a key, received from a `Watch`, or from a `Subscription` to a server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// The key that changed.
+    pub key: Vec<u8>,
+    /// Value of the key before the change, if it had one.
+    pub old_value: Option<Vec<u8>>,
+    /// Value of the key after the change, absent if it was removed.
+    pub new_value: Option<Vec<u8>>,
+    /// Kind of change.
+    pub op: ChangeOp,
+    /// Sequence number of the write, see `KvStore::sequence`.
+    pub sequence: u64,
+}
+
+/// Stream of changes of the keys with a prefix, created by `KvStore::watch`.
+///
+/// Events are queued in the order of the writes, each write of a batch as its own event,
+/// even if the batch writes a key several times. As
+/// an iterator it blocks until the next event, and ends once the store is dropped and every
+/// queued event was received.
+///
+/// Only writes to keys of the store through `set`, `remove` and the other commands are
+/// reported. Keys of namespaces, keys expiring and `KvStore::clear` are not, though expired
+/// keys removed by `KvStore::expire_keys` are.
+pub struct Watch {
+    receiver: Receiver<ChangeEvent>,
+}
+
+impl Watch {
+    /// Returns the next queued event without blocking.
+    pub fn try_next(&self) -> Option<ChangeEvent> {
+        match self.receiver.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// Returns the next event, waiting at most `timeout` for it.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<ChangeEvent> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+impl Iterator for Watch {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<ChangeEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Watches of a store and the prefixes they watch.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watchers: Vec<(Vec<u8>, Sender<ChangeEvent>)>,
+}
+
+impl Watchers {
+    /// Add a watch of the keys starting with `prefix`.
+    pub(crate) fn add(&mut self, prefix: Vec<u8>) -> Watch {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.push((prefix, sender));
+        Watch { receiver }
+    }
+
+    /// Add a watch of the keys starting with any of `prefixes`, reporting each change once
+    /// even if its key starts with several of them.
+    pub(crate) fn add_all(&mut self, mut prefixes: Vec<Vec<u8>>) -> Watch {
+        // a prefix starting with another one adds nothing to it
+        prefixes.sort();
+        prefixes.dedup_by(|prefix, shorter| prefix.starts_with(shorter));
+        let (sender, receiver) = mpsc::channel();
+        for prefix in prefixes {
+            self.watchers.push((prefix, sender.clone()));
+        }
+        Watch { receiver }
+    }
+
+    /// Returns whether nothing is watched.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.watchers.is_empty()
+    }
+
+    /// Returns whether any watch reports changes of `key`.
+    pub(crate) fn is_watched(&self, key: &[u8]) -> bool {
+        self.watchers
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    /// Send an event to the watches of its key, dropping the watches no longer received from.
+    pub(crate) fn send(&mut self, event: ChangeEvent) {
+        self.watchers.retain(|(prefix, sender)| {
+            !event.key.starts_with(prefix) || sender.send(event.clone()).is_ok()
+        });
+    }
+}
@@ -1,8 +1,24 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    AuditRecord, BincodeCodec, ChangeEvent, ChangeOp, Compression, Durability, Encryption,
+    ErrorKind, Format, GroupCommit, JsonCodec, KeyVersion, KvLog, KvStore, KvsClient,
+    KvsClientPool, KvsEngine, KvsServer, LogCodec, MemKvsEngine, MergeOperator, MessagePackCodec,
+    NaiveThreadPool, NearCache, Options, ReadBalance, ReplicatedKvsClient, Request, Response,
+    RestoreOptions, Result, RetryPolicy, ScanCursor, SecondaryIndex, ShardedKvsClient,
+    ShardedKvsEngine, SharedKvStore, SharedQueueThreadPool, ThreadPool, TombstoneRetention,
+    VerifyIssue, WriteBatch, WriteHook, PROTOCOL_VERSION,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use std::process::Command;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::process::{Child, Command};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +33,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +44,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +57,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +70,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +88,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +96,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +116,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +124,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +137,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +152,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +173,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +188,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -299,3 +315,4954 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Writes in group commit mode should be durable and survive compaction.
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        group_commit: Some(GroupCommit {
+            interval: Duration::from_millis(1),
+            wait_for_sync: true,
+            ..GroupCommit::default()
+        }),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..3 {
+        for key_id in 0..600 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("2".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..600 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Cached values should follow overwrites, removals and compactions.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        value_cache_bytes: 64,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Far more data than the cache budget, with compactions in between.
+    for iter in 0..3 {
+        for key_id in 0..600 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), format!("{}", iter))?;
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+    }
+    for key_id in 0..600 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// The bloom filter should be persisted next to the log and never hide stored keys.
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3000 {
+        store.set(format!("key{}", key_id), format!("{}", key_id))?;
+    }
+    drop(store);
+    assert!(temp_dir.path().join("0.bloom").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", key_id))
+        );
+        assert_eq!(store.get(format!("absent{}", key_id))?, None);
+    }
+    store.set("key3000".to_owned(), "3000".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3000".to_owned())?, Some("3000".to_owned()));
+
+    Ok(())
+}
+
+// A bloom filter of another log of the same length should be rebuilt instead of hiding keys.
+#[test]
+fn bloom_filter_of_other_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut other = KvStore::open(other_dir.path())?;
+    other.set("key2".to_owned(), "value2".to_owned())?;
+    drop(other);
+    std::fs::copy(
+        other_dir.path().join("0.bloom"),
+        temp_dir.path().join("0.bloom"),
+    )
+    .expect("unable to copy bloom filter");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should list live keys only.
+#[test]
+fn list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().count(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let mut keys: Vec<_> = store.keys().map(<[u8]>::to_vec).collect();
+    keys.sort();
+    assert_eq!(keys, vec![b"key1", b"key3"]);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let mut keys: Vec<_> = store.keys().collect();
+    keys.sort_unstable();
+    assert_eq!(keys, vec![b"key1", b"key3"]);
+
+    Ok(())
+}
+
+// Should iterate live pairs, including those still in the write buffer.
+#[test]
+fn iterate_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let pairs = store.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 999);
+    assert!(pairs.contains(&("key0".to_owned(), "overwritten".to_owned())));
+    assert!(pairs.contains(&("key999".to_owned(), "value999".to_owned())));
+    assert!(!pairs.iter().any(|(key, _)| key == "key1"));
+
+    Ok(())
+}
+
+// Should return pairs under a prefix in key order.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:123:name".to_owned(), "Alice".to_owned())?;
+    store.set("user:123:email".to_owned(), "alice@example.com".to_owned())?;
+    store.set("user:1234:name".to_owned(), "Bob".to_owned())?;
+    store.set("user:12:name".to_owned(), "Carol".to_owned())?;
+    store.set("user:123:age".to_owned(), "20".to_owned())?;
+    store.remove("user:123:age".to_owned())?;
+
+    let pairs = store.scan_prefix("user:123:").collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("user:123:email".to_owned(), "alice@example.com".to_owned()),
+            ("user:123:name".to_owned(), "Alice".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan_prefix("user:").count(), 4);
+    assert_eq!(store.scan_prefix("admin:").count(), 0);
+
+    Ok(())
+}
+
+// Range and prefix queries should agree between hash and ordered index.
+#[test]
+fn range_scan() -> Result<()> {
+    for &ordered_index in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            ordered_index,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key_id in (0..100).rev() {
+            store.set(format!("key{:02}", key_id), format!("{}", key_id))?;
+        }
+        store.remove("key15".to_owned())?;
+        drop(store);
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let keys = store
+            .range("key10".to_owned().."key20".to_owned())
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (10..20)
+            .filter(|&key_id| key_id != 15)
+            .map(|key_id| format!("key{:02}", key_id))
+            .collect();
+        assert_eq!(keys, expected);
+
+        assert_eq!(store.range(.."key05".to_owned()).count(), 5);
+        assert_eq!(store.range("key95".to_owned()..).count(), 5);
+        let pairs = store.scan_prefix("key9").collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs.len(), 10);
+        assert_eq!(pairs[0], ("key90".to_owned(), "90".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should count live keys.
+#[test]
+fn len_and_is_empty() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.len(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.len(), 2);
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.len(), 1);
+    assert!(!store.is_empty());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    store.remove("key1".to_owned())?;
+    assert!(store.is_empty());
+
+    Ok(())
+}
+
+// Should check existence of keys.
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("key1")?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.contains_key("key1")?);
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1")?);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("key1")?);
+    assert!(store.contains_key("key2")?);
+
+    Ok(())
+}
+
+// Should remove all keys, and all files with destroy.
+#[test]
+fn clear_and_destroy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let mut store = KvStore::open(&path)?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("{}", key_id))?;
+    }
+    store.clear()?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(&path)?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    KvStore::destroy(&path)?;
+    assert!(!path.exists());
+    let store = KvStore::open(&path)?;
+    assert!(store.is_empty());
+
+    Ok(())
+}
+
+// Should apply all commands of a batch, or none if any of them is invalid.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key1".to_owned())
+        .set("key2".to_owned(), "value4".to_owned());
+    store.write(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key4".to_owned(), "value4".to_owned())
+        .remove("key2".to_owned())
+        .remove("key2".to_owned());
+    assert!(store.write(batch).is_err());
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Batches should be split up by compaction.
+    for iter in 0..3 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..600 {
+            batch.set(format!("key{}", key_id), format!("{}", iter));
+        }
+        store.write(batch)?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..600 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A batch cut short by a crash should be discarded as a whole.
+#[test]
+fn torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned());
+    store.write(batch)?;
+    drop(store);
+
+    // Cut the last few bytes of the batch, as if the process crashed while writing it.
+    let log_file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("0.bin"))
+        .expect("unable to open log file");
+    let len = log_file.metadata().expect("unable to read metadata").len();
+    log_file
+        .set_len(len - 3)
+        .expect("unable to truncate log file");
+    drop(log_file);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Transactions should see their own writes and apply them only on commit.
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut tx = store.transaction();
+    tx.set("key3".to_owned(), "value3".to_owned());
+    tx.remove("key1".to_owned())?;
+    assert_eq!(tx.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(tx.get("key1".to_owned())?, None);
+    assert_eq!(tx.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(tx.remove("key1".to_owned()).is_err());
+    tx.rollback();
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    let mut tx = store.transaction();
+    tx.set("key3".to_owned(), "value3".to_owned());
+    tx.remove("key1".to_owned())?;
+    tx.set("key4".to_owned(), "value4".to_owned());
+    tx.remove("key4".to_owned())?;
+    tx.commit()?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// Snapshots should keep seeing the same data across writes and compactions.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..600 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    let mut snapshot = store.snapshot()?;
+
+    store.remove("key0".to_owned())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    for iter in 1..3 {
+        for key_id in 1..600 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("2".to_owned()));
+
+    assert_eq!(snapshot.len(), 600);
+    assert_eq!(snapshot.get("key0")?, Some("0".to_owned()));
+    assert_eq!(snapshot.get("key599")?, Some("0".to_owned()));
+    assert!(!snapshot.contains_key("new"));
+    let pairs = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 600);
+    assert!(pairs.iter().all(|(_, value)| value == "0"));
+
+    store.clear()?;
+    assert_eq!(snapshot.get("key1")?, Some("0".to_owned()));
+
+    Ok(())
+}
+
+// Reads of a shared store should find the records appended after its reader mapped the
+// log, the mmap reader mapping the log again once it grew past the mapping.
+#[test]
+fn shared_store_read_after_log_grows() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for key_id in 1..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        let value = store.get(format!("key{}", key_id))?;
+        assert_eq!(value, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// Reading a record past the end of the log should fail instead of reading out of bounds.
+#[test]
+fn shared_store_read_past_log_end() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    // the index of the store points past the end of the log cut short behind its back
+    let log = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.file_name() == "0.bin")
+        .unwrap();
+    let log = OpenOptions::new().write(true).open(log.path()).unwrap();
+    log.set_len(1).unwrap();
+    drop(log);
+    let e = store.get("key99".to_owned()).unwrap_err();
+    if cfg!(feature = "mmap") {
+        assert_eq!(e.kind(), ErrorKind::Corruption);
+    }
+    Ok(())
+}
+
+// Clones of a shared store should read while another thread writes and compacts.
+#[test]
+fn shared_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    for key_id in 0..100 {
+                        let value = store.get(format!("key{}", key_id))?;
+                        let iter: u32 = value.expect("key is present").parse().unwrap();
+                        assert!(iter < 30);
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    // enough overwrites for several compactions
+    for iter in 1..30 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("key42".to_owned())?, Some("29".to_owned()));
+    store.remove("key42".to_owned())?;
+    assert_eq!(store.get("key42".to_owned())?, None);
+    assert!(store.remove("key42".to_owned()).is_err());
+    let pairs = store.scan_prefix("key1".to_owned())?;
+    assert_eq!(pairs.len(), 11);
+    assert_eq!(pairs[0], ("key1".to_owned(), "29".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("29".to_owned()));
+    assert_eq!(store.get("key42".to_owned())?, None);
+
+    Ok(())
+}
+
+// Writers on different threads should spread their keys over the shards.
+#[test]
+fn sharded_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = ShardedKvsEngine::open(temp_dir.path(), 4)?;
+    assert_eq!(engine.shard_count(), 4);
+
+    let writers: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    engine.set(format!("key{}-{}", thread_id, key_id), key_id.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    assert_eq!(engine.get("key3-42".to_owned())?, Some("42".to_owned()));
+    engine.remove("key3-42".to_owned())?;
+    assert_eq!(engine.get("key3-42".to_owned())?, None);
+    let pairs = engine.scan_prefix("key1-".to_owned())?;
+    assert_eq!(pairs.len(), 100);
+    assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    drop(engine);
+
+    let engine = ShardedKvsEngine::open(temp_dir.path(), 4)?;
+    assert_eq!(engine.scan_prefix("key".to_owned())?.len(), 399);
+    assert_eq!(engine.get("key0-99".to_owned())?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+// Replicas should catch up with their primary from a full copy, also across compactions.
+#[test]
+fn replication() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut primary = KvStore::open(temp_dir.path().join("primary"))?;
+    let mut replica = KvStore::open(temp_dir.path().join("replica"))?;
+    replica.set("stale".to_owned(), "value".to_owned())?;
+
+    primary.set("key0".to_owned(), "0".to_owned())?;
+    let ttl = Duration::from_secs(60);
+    primary.set_with_ttl("session".to_owned(), "abc".to_owned(), ttl)?;
+    let changes = primary.changes(None)?;
+    assert!(changes.is_full());
+    let mut cursor = changes.cursor();
+    replica.apply_changes(changes)?;
+    assert_eq!(replica.get("stale".to_owned())?, None);
+    assert_eq!(replica.get("session".to_owned())?, Some("abc".to_owned()));
+    assert!(replica.ttl("session")?.is_some());
+
+    // enough writes for a compaction, read in several changes
+    for iter in 0..3 {
+        for key_id in 0..600 {
+            primary.set(format!("key{}", key_id), iter.to_string())?;
+        }
+    }
+    primary.append("key0", "-appended")?;
+    primary.remove("key1".to_owned())?;
+    assert_eq!(primary.stats()?.compactions, 1);
+    loop {
+        let changes = primary.changes(Some(cursor))?;
+        assert!(!changes.is_full());
+        cursor = changes.cursor();
+        let caught_up = changes.logs().is_empty();
+        replica.apply_changes(changes)?;
+        if caught_up {
+            break;
+        }
+    }
+    assert_eq!(cursor.sequence(), primary.sequence());
+    assert_eq!(
+        replica.get("key0".to_owned())?,
+        Some("2-appended".to_owned())
+    );
+    assert_eq!(replica.get("key1".to_owned())?, None);
+    assert_eq!(replica.get("key599".to_owned())?, Some("2".to_owned()));
+    assert_eq!(replica.len(), primary.len());
+
+    // a replica left behind by two compactions gets a full copy again
+    for iter in 3..7 {
+        for key_id in 0..600 {
+            primary.set(format!("key{}", key_id), iter.to_string())?;
+        }
+    }
+    assert_eq!(primary.stats()?.compactions, 3);
+    let changes = primary.changes(Some(cursor))?;
+    assert!(changes.is_full());
+    replica.apply_changes(changes)?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("6".to_owned()));
+    assert_eq!(replica.len(), primary.len());
+
+    // a full copy comes in pages as of when it started, also across a compaction
+    let mut primary = KvStore::open(temp_dir.path().join("large"))?;
+    let mut replica = KvStore::open(temp_dir.path().join("large-replica"))?;
+    for key_id in 0..2500 {
+        primary.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    let changes = primary.changes(None)?;
+    assert!(changes.is_full());
+    assert_eq!(changes.logs().len(), 1024);
+    let mut cursor = changes.cursor();
+    replica.apply_changes(changes)?;
+    for key_id in 0..1500 {
+        primary.set(format!("key{}", key_id), "1".to_owned())?;
+    }
+    assert_eq!(primary.stats()?.compactions, 1);
+    for len in [1024, 452].iter() {
+        let changes = primary.changes(Some(cursor))?;
+        assert!(!changes.is_full());
+        assert_eq!(changes.logs().len(), *len);
+        cursor = changes.cursor();
+        replica.apply_changes(changes)?;
+    }
+    assert_eq!(replica.get("key1200".to_owned())?, Some("0".to_owned()));
+    assert_eq!(replica.len(), 2500);
+    loop {
+        let changes = primary.changes(Some(cursor))?;
+        assert!(!changes.is_full());
+        cursor = changes.cursor();
+        let caught_up = changes.logs().is_empty();
+        replica.apply_changes(changes)?;
+        if caught_up {
+            break;
+        }
+    }
+    assert_eq!(cursor.sequence(), primary.sequence());
+    assert_eq!(replica.get("key1200".to_owned())?, Some("1".to_owned()));
+    assert_eq!(replica.get("key2499".to_owned())?, Some("0".to_owned()));
+
+    Ok(())
+}
+
+// Keys set with a TTL should expire, also across reopens and compactions.
+#[test]
+fn ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("forever".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(600),
+    )?;
+    store.set_with_ttl(
+        "persisted".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+
+    assert_eq!(store.ttl("forever")?, None);
+    assert!(store.ttl("long")?.unwrap() > Duration::from_secs(500));
+    assert!(store.persist("persisted")?);
+    assert!(!store.persist("persisted")?);
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.len(), 4);
+
+    drop(store);
+    std::thread::sleep(Duration::from_millis(300));
+
+    // Open from disk again and check expiration
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(store.ttl("short").is_err());
+    assert!(store.remove("short".to_owned()).is_err());
+    assert_eq!(store.get("persisted".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.len(), 3);
+
+    // Compaction drops expired keys, the rest stay readable
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    std::thread::sleep(Duration::from_millis(10));
+    for iter in 0..1100 {
+        store.set("filler".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert!(store.keys().all(|key| key != b"short"));
+
+    Ok(())
+}
+
+// Binary values should be stored as-is and rejected by the string API if not UTF-8.
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let image = vec![0x89, 0x50, 0x4e, 0x47, 0x00, 0xff];
+    store.set_bytes("image".to_owned(), image.clone())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set_bytes("empty".to_owned(), Vec::new());
+    store.write(batch)?;
+
+    assert_eq!(store.get_bytes("image".to_owned())?, Some(image.clone()));
+    assert_eq!(store.get_bytes("text".to_owned())?, Some(b"value".to_vec()));
+    assert_eq!(store.get("empty".to_owned())?, Some(String::new()));
+    assert_eq!(
+        store.get("image".to_owned()).unwrap_err().kind(),
+        ErrorKind::InvalidUtf8
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("image".to_owned())?, Some(image));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Point {
+    x: i64,
+    y: i64,
+    label: Option<String>,
+}
+
+// Typed values should round-trip through bincode.
+#[test]
+fn typed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let point = Point {
+        x: -3,
+        y: 7,
+        label: Some("origin".to_owned()),
+    };
+    store.set_typed("point".to_owned(), &point)?;
+    store.set_typed("list".to_owned(), &vec![1u32, 2, 3])?;
+
+    assert_eq!(store.get_typed::<Point>("point".to_owned())?, Some(point));
+    assert_eq!(store.get_typed::<Point>("missing".to_owned())?, None);
+    assert_eq!(
+        store
+            .get_typed::<Point>("list".to_owned())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::Serde
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_typed::<Vec<u32>>("list".to_owned())?,
+        Some(vec![1, 2, 3])
+    );
+
+    Ok(())
+}
+
+// Keys may be arbitrary bytes, and string keys are the same as their UTF-8 bytes.
+#[test]
+fn byte_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let hash = vec![0xde, 0xad, 0xbe, 0xef, 0x00];
+    store.set_bytes(hash.clone(), b"blob".to_vec())?;
+    store.set_bytes(vec![0xde, 0xad, 0xff], b"other".to_vec())?;
+    store.set("text", "value".to_owned())?;
+
+    assert_eq!(store.get(hash.clone())?, Some("blob".to_owned()));
+    assert_eq!(store.get_bytes(b"text".to_vec())?, Some(b"value".to_vec()));
+    assert!(store.contains_key(&hash)?);
+    assert!(store.contains_key("text")?);
+    assert_eq!(store.scan_prefix([0xde, 0xad]).count(), 2);
+    assert_eq!(
+        store
+            .scan_prefix([0xde])
+            .next()
+            .unwrap()
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidUtf8
+    );
+    store.remove(&hash[..])?;
+    assert!(!store.contains_key(&hash)?);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(hash)?, None);
+    assert_eq!(
+        store.get_bytes(vec![0xde, 0xad, 0xff])?,
+        Some(b"other".to_vec())
+    );
+    let mut keys: Vec<_> = store.keys().collect();
+    keys.sort_unstable();
+    assert_eq!(keys, vec![&b"text"[..], &[0xde, 0xad, 0xff][..]]);
+
+    Ok(())
+}
+
+// compare_and_swap should only write when the current value matches.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.compare_and_swap("key1", None, Some("value1".to_owned()))?);
+    assert!(!store.compare_and_swap("key1", None, Some("value2".to_owned()))?);
+    assert!(!store.compare_and_swap("key1", Some("value0"), Some("value2".to_owned()))?);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(store.compare_and_swap("key1", Some("value1"), Some("value2".to_owned()))?);
+    assert!(store.compare_and_swap("key2", None, None)?);
+    assert!(!store.contains_key("key2")?);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    assert!(store.compare_and_swap("key1", Some("value2"), None)?);
+    assert_eq!(store.get("key1")?, None);
+
+    Ok(())
+}
+
+// incr and decr should update integer values and reject others.
+#[test]
+fn incr_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter", 5)?, 5);
+    assert_eq!(store.decr("counter", 7)?, -2);
+    store.set("text", "abc".to_owned())?;
+    assert_eq!(
+        store.incr("text", 1).unwrap_err().kind(),
+        ErrorKind::NotAnInteger
+    );
+    store.set("max", i64::MAX.to_string())?;
+    assert_eq!(
+        store.incr("max", 1).unwrap_err().kind(),
+        ErrorKind::Overflow
+    );
+    assert_eq!(
+        store.decr("counter", i64::MIN).unwrap_err().kind(),
+        ErrorKind::Overflow
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter", 0)?, -2);
+    assert_eq!(store.get("max")?, Some(i64::MAX.to_string()));
+
+    Ok(())
+}
+
+// append should extend values, survive reopening and be folded by compaction.
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.append("log", "a")?;
+    store.append("log", "b")?;
+    let mut snapshot = store.snapshot()?;
+    store.append("log", b"c".to_vec())?;
+    store.set_with_ttl("session", "x".to_owned(), Duration::from_secs(600))?;
+    store.append("session", "y")?;
+    assert_eq!(store.get("log")?, Some("abc".to_owned()));
+    assert_eq!(snapshot.get("log")?, Some("ab".to_owned()));
+    assert_eq!(store.get("session")?, Some("xy".to_owned()));
+    assert!(store.ttl("session")?.is_some());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("log")?, Some("abc".to_owned()));
+    assert!(store.ttl("session")?.is_some());
+
+    // Appends are folded into one set command by compaction
+    for iter in 0..1100 {
+        store.append("log", format!("{}", iter % 10))?;
+    }
+    let expected: String = std::iter::once("abc".to_owned())
+        .chain((0..1100).map(|iter| format!("{}", iter % 10)))
+        .collect();
+    assert_eq!(store.get("log")?, Some(expected.clone()));
+    drop(store);
+    let log_len = std::fs::metadata(temp_dir.path().join("0.bin"))
+        .expect("unable to read log file metadata")
+        .len();
+    assert!(log_len < 8 * 1024);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("log")?, Some(expected));
+    assert_eq!(store.get("session")?, Some("xy".to_owned()));
+
+    Ok(())
+}
+
+// Merge operands should be resolved on read and folded by compaction.
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.merge("set", "a").unwrap_err().kind(),
+        ErrorKind::NoMergeOperator
+    );
+    drop(store);
+
+    // a set of comma separated members
+    let union = MergeOperator::new(|_key, existing, operand| {
+        let mut members: Vec<&[u8]> = existing
+            .map(|existing| existing.split(|&byte| byte == b',').collect())
+            .unwrap_or_default();
+        if !members.contains(&operand) {
+            members.push(operand);
+        }
+        members.join(&b","[..])
+    });
+    let options = Options {
+        merge_operator: Some(union),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.merge("set", "a")?;
+    store.merge("set", "b")?;
+    store.merge("set", "a")?;
+    store.set("other", "x".to_owned())?;
+    store.merge("other", "y")?;
+    assert_eq!(store.get("set")?, Some("a,b".to_owned()));
+    assert_eq!(store.get("other")?, Some("x,y".to_owned()));
+    assert!(store.contains_key("set")?);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("set")?, Some("a,b".to_owned()));
+
+    // Operands are folded into one set command by compaction
+    for iter in 0..1100 {
+        store.merge("set", format!("{}", iter % 3))?;
+    }
+    assert_eq!(store.get("set")?, Some("a,b,0,1,2".to_owned()));
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("set")?, Some("a,b,0,1,2".to_owned()));
+
+    Ok(())
+}
+
+// get_or_insert_with should only compute and store missing values.
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1".to_owned())?;
+
+    let mut calls = 0;
+    let mut compute = || {
+        calls += 1;
+        "computed".to_owned()
+    };
+    assert_eq!(store.get_or_insert_with("key1", &mut compute)?, "value1");
+    assert_eq!(store.get_or_insert_with("key2", &mut compute)?, "computed");
+    assert_eq!(store.get_or_insert_with("key2", &mut compute)?, "computed");
+    assert_eq!(calls, 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("computed".to_owned()));
+
+    Ok(())
+}
+
+// take should remove keys and return their values.
+#[test]
+fn take() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1".to_owned())?;
+    store.set_bytes("binary", vec![0xff])?;
+
+    assert_eq!(store.take("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.take("key1")?, None);
+    assert_eq!(
+        store.take("binary").unwrap_err().kind(),
+        ErrorKind::InvalidUtf8
+    );
+    assert!(store.contains_key("binary")?);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+
+    Ok(())
+}
+
+// multi_get should return values in the order of the keys.
+#[test]
+fn multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key500")?;
+
+    let keys: Vec<String> = (0..1100)
+        .rev()
+        .map(|key_id| format!("key{}", key_id))
+        .collect();
+    let values = store.multi_get(&keys)?;
+    assert_eq!(values.len(), 1100);
+    for (key_id, value) in (0..1100).rev().zip(values) {
+        let expected = if key_id < 1000 && key_id != 500 {
+            Some(format!("value{}", key_id))
+        } else {
+            None
+        };
+        assert_eq!(value, expected);
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.multi_get(&["key1", "key1", "key500"])?,
+        vec![Some("value1".to_owned()), Some("value1".to_owned()), None]
+    );
+
+    Ok(())
+}
+
+// multi_set and multi_remove should behave like repeated set and remove.
+#[test]
+fn multi_set_and_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.multi_set((0..2000).map(|key_id| (format!("key{}", key_id), format!("{}", key_id))))?;
+    store.multi_set(vec![("key0", "overwritten".to_owned())])?;
+    assert_eq!(store.len(), 2000);
+    assert_eq!(store.get("key0")?, Some("overwritten".to_owned()));
+    assert_eq!(store.get("key1999")?, Some("1999".to_owned()));
+
+    let removed = store.multi_remove(
+        (1000..3000)
+            .chain(1000..1010)
+            .map(|key_id| format!("key{}", key_id)),
+    )?;
+    assert_eq!(removed, 1000);
+    assert_eq!(store.len(), 1000);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1000);
+    assert_eq!(store.get("key999")?, Some("999".to_owned()));
+    assert_eq!(store.get("key1000")?, None);
+
+    Ok(())
+}
+
+// A store opened read-only should be readable but never modified.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+    assert!(!temp_dir.path().join("missing").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1".to_owned())?;
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("0.bloom")).expect("unable to remove bloom filter");
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.iter().count(), 1);
+    assert_eq!(
+        store.set("key2", "value2".to_owned()).unwrap_err().kind(),
+        ErrorKind::ReadOnly
+    );
+    assert_eq!(
+        store.remove("key1").unwrap_err().kind(),
+        ErrorKind::ReadOnly
+    );
+    assert_eq!(store.clear().unwrap_err().kind(), ErrorKind::ReadOnly);
+    let mut snapshot = store.snapshot()?;
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    assert!(!temp_dir.path().join("0.bloom").exists());
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    Ok(())
+}
+
+// A store can only be opened for writing once at a time
+#[test]
+fn store_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StoreLocked),
+        Ok(_) => panic!("store opened twice"),
+    }
+    assert_eq!(
+        KvStore::destroy(temp_dir.path()).unwrap_err().kind(),
+        ErrorKind::StoreLocked
+    );
+    // read-only stores take no lock
+    drop(KvStore::open_read_only(temp_dir.path())?);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+    KvStore::destroy(temp_dir.path())?;
+    assert!(!temp_dir.path().exists());
+
+    Ok(())
+}
+
+// Settings of the builder are applied to the opened store
+#[test]
+fn builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .write_buffer_size(0)
+        .read_ahead_size(0)
+        .durability(Durability::Flush)
+        .compaction_garbage_ratio(1.0)
+        .open(temp_dir.path())?;
+    store.set("key1", "value1".to_owned())?;
+
+    // records are written right away, so a reader sees them while the store is open
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1")?, Some("value1".to_owned()));
+    drop(reader);
+
+    // a live key keeps the garbage ratio below 1, so the log is never compacted
+    for iter in 0..2000 {
+        store.set("key2", format!("{}", iter))?;
+    }
+    let log_len = std::fs::metadata(temp_dir.path().join("0.bin"))
+        .expect("unable to read log file metadata")
+        .len();
+    assert!(log_len > 2000 * 10);
+    assert_eq!(store.get("key2")?, Some("1999".to_owned()));
+    assert_eq!(store.iter().count(), 2);
+
+    Ok(())
+}
+
+// Compressed records are readable after reopening and compaction, with or without compression
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn check_compression(compression: Compression) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "compressible ".repeat(100);
+    let mut store = KvStore::builder()
+        .compression(compression)
+        .open(temp_dir.path())?;
+    store.set("small", "1".to_owned())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    drop(store);
+
+    let log_len = std::fs::metadata(temp_dir.path().join("0.bin"))
+        .expect("unable to read log file metadata")
+        .len();
+    assert!(log_len < 100 * value.len() as u64 / 2);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("small")?, Some("1".to_owned()));
+    assert_eq!(store.get("key42")?, Some(value.clone()));
+    for iter in 0..2000 {
+        store.set("small", format!("{}", iter))?;
+    }
+    assert_eq!(store.get("key42")?, Some(value));
+
+    Ok(())
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn compression_lz4() -> Result<()> {
+    check_compression(Compression::Lz4)
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn compression_zstd() -> Result<()> {
+    check_compression(Compression::Zstd)
+}
+
+// A store cannot be opened with a codec that is not enabled
+#[test]
+fn compression_unavailable() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for compression in [Compression::Lz4, Compression::Zstd].iter() {
+        if !compression.is_available() {
+            match KvStore::builder()
+                .compression(*compression)
+                .open(temp_dir.path())
+            {
+                Err(e) => assert_eq!(e.kind(), ErrorKind::CompressionUnavailable),
+                Ok(_) => panic!("store opened with an unavailable codec"),
+            }
+        }
+    }
+}
+
+// Values are not readable from disk without the encryption key
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = [42; 32];
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("plain", "old value".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .encryption(Encryption::new(&key))
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("plain")?, Some("old value".to_owned()));
+    for iter in 0..2000 {
+        store.set("secret", format!("top secret {}", iter))?;
+    }
+    drop(store);
+
+    // compaction rewrote the old value encrypted too
+    let log = std::fs::read(temp_dir.path().join("0.bin")).expect("unable to read log file");
+    let contains = |needle: &[u8]| log.windows(needle.len()).any(|window| window == needle);
+    assert!(!contains(b"old value"));
+    assert!(!contains(b"top secret"));
+
+    for options in [
+        Options::default(),
+        Options {
+            encryption: Some(Encryption::new(&[0; 32])),
+            ..Options::default()
+        },
+    ] {
+        match KvStore::open_with_options(temp_dir.path(), options) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::BadEncryptionKey),
+            Ok(_) => panic!("store opened without its key"),
+        }
+    }
+
+    let mut store = KvStore::builder()
+        .encryption(Encryption::new(&key))
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("plain")?, Some("old value".to_owned()));
+    assert_eq!(store.get("secret")?, Some("top secret 1999".to_owned()));
+    drop(store);
+
+    // the value log would keep large values in clear
+    let opened = KvStore::builder()
+        .encryption(Encryption::new(&key))
+        .value_log_threshold(1024)
+        .open(temp_dir.path());
+    match opened {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Unsupported),
+        Ok(_) => panic!("store opened with an unencrypted value log"),
+    }
+
+    Ok(())
+}
+
+// Headerless logs of version 1 have to be migrated, unknown versions are rejected
+#[test]
+fn format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("0.bin");
+    let mut log = Vec::new();
+    KvLog::new_set(b"key1".to_vec(), b"value1".to_vec()).serialize_to_writer(&mut log)?;
+    std::fs::write(&log_path, &log).expect("unable to write log file");
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::UnsupportedVersion),
+        Ok(_) => panic!("opened a log file without a header"),
+    }
+    assert!(KvStore::migrate(temp_dir.path())?);
+    assert!(!KvStore::migrate(temp_dir.path())?);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    let mut header = std::fs::read(&log_path).expect("unable to read log file");
+    header[4] = 99;
+    std::fs::write(&log_path, &header).expect("unable to write log file");
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::UnsupportedVersion),
+        Ok(_) => panic!("opened a log file of an unknown version"),
+    }
+    assert_eq!(
+        KvStore::migrate(temp_dir.path()).unwrap_err().kind(),
+        ErrorKind::UnsupportedVersion
+    );
+
+    Ok(())
+}
+
+// Log files of version 2 are read as they are, and upgraded when opened for writing
+#[test]
+fn format_version_2() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("0.bin");
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut log = b"KVS\0".to_vec();
+    log.extend_from_slice(&2u32.to_le_bytes());
+    KvLog::new_set(b"key1".to_vec(), b"value1".to_vec()).serialize_to_writer(&mut log)?;
+    std::fs::write(&log_path, &log).expect("unable to write log file");
+    let manifest = r#"{"format_version":2,"segments":["0.bin"],"compaction":null}"#;
+    std::fs::write(&manifest_path, manifest).expect("unable to write manifest");
+    let version = || std::fs::read(&log_path).expect("unable to read log file")[4..8].to_vec();
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(version(), 2u32.to_le_bytes());
+
+    // builds reading version 2 must not take the new records for damage
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(version(), 3u32.to_le_bytes());
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    store.namespace("users").set("key2", "value2".to_owned())?;
+    drop(store);
+    let manifest = std::fs::read(&manifest_path).expect("unable to read manifest");
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+    assert_eq!(manifest["format_version"], 3);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(
+        store.namespace("users").get("key2")?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Stores written with any codec survive reopening and compaction
+#[test]
+fn codecs() -> Result<()> {
+    fn check_codec<C: LogCodec + Copy + 'static>(codec: C) -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder().codec(codec).open(temp_dir.path())?;
+        store.set("key1", "value1".to_owned())?;
+        store.write({
+            let mut batch = WriteBatch::new();
+            batch.set("key2", "value2".to_owned());
+            batch.remove("key1");
+            batch
+        })?;
+        drop(store);
+
+        let mut store = KvStore::builder().codec(codec).open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, None);
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        for iter in 0..2000 {
+            store.set("key3", format!("{}", iter))?;
+        }
+        assert_eq!(store.get("key3")?, Some("1999".to_owned()));
+        drop(store);
+
+        let mut store = KvStore::builder().codec(codec).open(temp_dir.path())?;
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3")?, Some("1999".to_owned()));
+        Ok(())
+    }
+
+    check_codec(BincodeCodec)?;
+    check_codec(JsonCodec)?;
+    check_codec(MessagePackCodec)
+}
+
+// KvStore and MemKvsEngine work through the KvsEngine trait
+#[test]
+fn kvs_engine() -> Result<()> {
+    fn exercise<E: KvsEngine>(engine: &mut E) -> Result<()> {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        engine.remove("key1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, None);
+        match engine.remove("key1".to_owned()) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::KeyNotFound),
+            Ok(_) => panic!("removed a missing key"),
+        }
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise(&mut KvStore::open(temp_dir.path())?)?;
+    exercise(&mut MemKvsEngine::new())
+}
+
+// `kvs --engine sled` stores data with sled, in directories not used by the kvs engine
+#[cfg(feature = "sled")]
+#[test]
+fn cli_sled_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "sled", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "sled", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "sled", "rm", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// SledKvsEngine works through the KvsEngine trait
+#[cfg(feature = "sled")]
+#[test]
+fn sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = kvs::SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    match engine.remove("key1".to_owned()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::KeyNotFound),
+        Ok(_) => panic!("removed a missing key"),
+    }
+    Ok(())
+}
+
+// Stats follow writes and compaction
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 0);
+    assert_eq!(stats.redundant_records, 0);
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(stats.index_bytes, 0);
+
+    store.set("key1", "value1".to_owned())?;
+    store.set("key1", "value2".to_owned())?;
+    store.set("key2", "value1".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.redundant_records, 1);
+    assert!(stats.dead_bytes > 0 && stats.dead_bytes < stats.log_bytes);
+    assert!(stats.index_bytes >= "key1key2".len());
+    assert_eq!(stats.compactions, 0);
+
+    for iter in 0..2000 {
+        store.set("key1", format!("{}", iter))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert!(stats.compactions > 0);
+    assert!(stats.redundant_records < 2000);
+
+    Ok(())
+}
+
+// verify reports records damaged after the store was opened
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.remove("key0")?;
+    let report = store.verify()?;
+    assert!(report.is_ok());
+    assert_eq!(report.records, 101);
+
+    // damage the second half of the log
+    let log_path = temp_dir.path().join("0.bin");
+    let mut log = std::fs::read(&log_path).expect("unable to read log file");
+    let half = log.len() / 2;
+    for byte in &mut log[half..] {
+        *byte = 0xff;
+    }
+    std::fs::write(&log_path, &log).expect("unable to write log file");
+
+    let report = store.verify()?;
+    assert!(!report.is_ok());
+    assert!(report.records < 101);
+    assert!(matches!(
+        report.issues[0],
+        VerifyIssue::UnreadableRecord {
+            kind: ErrorKind::Serde,
+            ..
+        }
+    ));
+    assert!(report.issues[1..]
+        .iter()
+        .all(|issue| matches!(issue, VerifyIssue::IndexMismatch { .. })));
+
+    Ok(())
+}
+
+// repair salvages the records around a damaged region
+#[test]
+fn repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let log_path = temp_dir.path().join("0.bin");
+    let mut log = std::fs::read(&log_path).expect("unable to read log file");
+    let half = log.len() / 2;
+    for byte in &mut log[half..half + 50] {
+        *byte = 0xff;
+    }
+    std::fs::write(&log_path, &log).expect("unable to write log file");
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.damaged_regions, 1);
+    assert!(report.dropped_bytes >= 50);
+    assert!(report.salvaged_records >= 190);
+    assert_eq!(report.dropped_keys, 0);
+    assert_eq!(
+        std::fs::read(&report.backup_path).expect("unable to read backup"),
+        log
+    );
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), report.salvaged_records);
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get("key199")?, Some("value199".to_owned()));
+    assert!(store.verify()?.is_ok());
+
+    Ok(())
+}
+
+// A backup holds the store as it was when the backup was taken
+#[test]
+fn backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("store"))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let cursor = store.backup(temp_dir.path().join("backup"))?;
+    store.backup(temp_dir.path().join("backup.tar"))?;
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    assert_eq!(store.get("key0")?, Some("changed".to_owned()));
+
+    let log_len = std::fs::metadata(temp_dir.path().join("backup").join("0.bin"))
+        .expect("unable to read backup")
+        .len();
+    assert_eq!(cursor.offset(), log_len);
+    assert!(temp_dir.path().join("backup").join("BACKUP").exists());
+    assert!(temp_dir.path().join("backup.tar").metadata().is_ok());
+
+    let mut backup = KvStore::open(temp_dir.path().join("backup"))?;
+    assert_eq!(backup.len(), 100);
+    assert_eq!(backup.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(backup.get("key100")?, None);
+
+    Ok(())
+}
+
+// An incremental backup only holds the records appended since the previous backup
+#[test]
+fn backup_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("store"))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let cursor = store.backup(temp_dir.path().join("full"))?;
+    for key_id in 100..150 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let next_cursor = store.backup_since(&cursor, temp_dir.path().join("incremental"))?;
+    assert!(next_cursor.offset() > cursor.offset());
+
+    let read = |dir: &str| {
+        std::fs::read(temp_dir.path().join(dir).join("0.bin")).expect("unable to read log file")
+    };
+    let mut log = read("full");
+    log.extend(read("incremental"));
+    assert_eq!(log, read("store"));
+    assert!(!temp_dir.path().join("incremental").join("0.bloom").exists());
+
+    // the cursor no longer matches the log file once it is rewritten
+    store.clear()?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    match store.backup_since(&next_cursor, temp_dir.path().join("stale")) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StaleBackupCursor),
+        Ok(_) => panic!("backup from a stale cursor succeeded"),
+    }
+
+    Ok(())
+}
+
+// restore checks a chain of backups and materializes the store it holds
+#[test]
+fn restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let mut store = KvStore::open(path("store"))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let cursor = store.backup(path("full.tar"))?;
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.backup_since(&cursor, path("incremental"))?;
+    store.set("key1".to_owned(), "not backed up".to_owned())?;
+
+    KvStore::restore(path("full.tar"), path("restored"))?;
+    let mut restored = KvStore::open(path("restored"))?;
+    assert_eq!(restored.len(), 100);
+    assert_eq!(restored.get("key0")?, Some("value0".to_owned()));
+    drop(restored);
+
+    // an existing store is only replaced if forced to
+    let incremental = RestoreOptions {
+        incremental: vec![path("incremental")],
+        ..RestoreOptions::default()
+    };
+    match KvStore::restore_with_options(path("full.tar"), path("restored"), &incremental) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StoreExists),
+        Ok(_) => panic!("restore replaced an existing store"),
+    }
+    let forced = RestoreOptions {
+        force: true,
+        ..incremental
+    };
+    KvStore::restore_with_options(path("full.tar"), path("restored"), &forced)?;
+    let mut restored = KvStore::open(path("restored"))?;
+    assert_eq!(restored.get("key0")?, Some("changed".to_owned()));
+    assert_eq!(restored.get("key1")?, Some("value1".to_owned()));
+    drop(restored);
+
+    // an incremental backup needs the backup it continues
+    match KvStore::restore(path("incremental"), path("orphan")) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidBackup),
+        Ok(_) => panic!("restore accepted an incremental backup alone"),
+    }
+
+    // a damaged backup is rejected
+    store.backup(path("damaged"))?;
+    let log_path = path("damaged").join("0.bin");
+    let mut log = std::fs::read(&log_path).expect("unable to read backup");
+    let half = log.len() / 2;
+    log[half] ^= 0xff;
+    std::fs::write(&log_path, &log).expect("unable to write backup");
+    match KvStore::restore(path("damaged"), path("from_damaged")) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidBackup),
+        Ok(_) => panic!("restore accepted a damaged backup"),
+    }
+    assert!(!path("from_damaged").join("0.bin").exists());
+
+    Ok(())
+}
+
+// export writes the live pairs as JSON Lines or CSV
+#[test]
+fn export() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "a \"quoted\", value".to_owned())?;
+    store.set("key3".to_owned(), "removed".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let mut json = Vec::new();
+    assert_eq!(store.export(&mut json, Format::Json)?, 2);
+    assert_eq!(
+        String::from_utf8(json).unwrap(),
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+         {\"key\":\"key2\",\"value\":\"a \\\"quoted\\\", value\"}\n"
+    );
+
+    let mut csv = Vec::new();
+    assert_eq!(store.export(&mut csv, Format::Csv)?, 2);
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "key,value\nkey1,value1\nkey2,\"a \"\"quoted\"\", value\"\n"
+    );
+
+    store.set_bytes("key4", vec![0xff])?;
+    match store.export(Vec::new(), Format::Csv) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidUtf8),
+        Ok(_) => panic!("exported a value that is not UTF-8"),
+    }
+
+    Ok(())
+}
+
+// import reads back what export wrote, compacting at most once
+#[test]
+fn import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("source"))?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "a \"quoted\",\nvalue".to_owned())?;
+
+    for format in [Format::Json, Format::Csv] {
+        let mut exported = Vec::new();
+        store.export(&mut exported, format)?;
+
+        let path = temp_dir.path().join(format!("{:?}", format));
+        let mut imported = KvStore::open(&path)?;
+        // importing the pairs again makes 1000 records redundant, and 1024 are needed for
+        // compaction, so only every other import compacts
+        for _ in 0..5 {
+            assert_eq!(imported.import(exported.as_slice(), format)?, 1000);
+        }
+        assert_eq!(imported.len(), 1000);
+        assert_eq!(imported.stats()?.compactions, 2);
+        assert_eq!(
+            imported.get("key0")?,
+            Some("a \"quoted\",\nvalue".to_owned())
+        );
+        assert_eq!(imported.get("key999")?, Some("value999".to_owned()));
+        drop(imported);
+
+        let mut imported = KvStore::open(&path)?;
+        assert_eq!(imported.len(), 1000);
+        assert_eq!(imported.get("key500")?, Some("value500".to_owned()));
+    }
+
+    // pairs before malformed input are kept
+    let mut partial = KvStore::open(temp_dir.path().join("partial"))?;
+    let json = "{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":\"key2\"}\n";
+    match partial.import(json.as_bytes(), Format::Json) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Serde),
+        Ok(_) => panic!("imported malformed input"),
+    }
+    assert_eq!(partial.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(partial.len(), 1);
+
+    Ok(())
+}
+
+// tail yields the records appended from an offset on
+#[test]
+fn tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut tail = store.tail(0)?;
+    let records = tail.by_ref().collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 2);
+    assert!(records[0].0 < records[1].0);
+    // records are stamped with their sequence number
+    let (_, record) = records.into_iter().nth(1).unwrap();
+    assert!(matches!(&record, KvLog::Sequenced(2, _)));
+    assert!(
+        matches!(record.into_unsequenced(), KvLog::Set(key, value) if key == b"key2" && value == b"value2")
+    );
+
+    store.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3".to_owned());
+    store.write(batch)?;
+    let position = tail.position();
+    let records = store
+        .tail(position)?
+        .map(|record| record.map(|(offset, kvlog)| (offset, kvlog.into_unsequenced())))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].0, position);
+    assert!(matches!(&records[0].1, KvLog::Rm(key) if key == b"key1"));
+    assert!(matches!(records[1].1, KvLog::Batch(_)));
+
+    // nothing new to read
+    let mut tail = store.tail(0)?;
+    assert_eq!(tail.by_ref().count(), 4);
+    assert_eq!(store.tail(tail.position())?.count(), 0);
+
+    // an offset in the middle of a record fails
+    let mut records = store.tail(position + 1)?;
+    assert!(records.next().unwrap().is_err());
+    assert!(records.next().is_none());
+
+    Ok(())
+}
+
+// namespaces keep their keys apart and are dropped at once
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "default".to_owned())?;
+    let mut sessions = store.namespace("sessions");
+    sessions.set("key1", "session".to_owned())?;
+    sessions.set("key2", "session".to_owned())?;
+    sessions.remove("key2")?;
+    match sessions.remove("key2") {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::KeyNotFound),
+        Ok(_) => panic!("removed a missing key"),
+    }
+    store.namespace("users").set("key1", "user".to_owned())?;
+
+    assert_eq!(store.get("key1")?, Some("default".to_owned()));
+    assert_eq!(store.len(), 1);
+    assert_eq!(
+        store.namespace("sessions").get("key1")?,
+        Some("session".to_owned())
+    );
+    assert_eq!(store.namespace("sessions").get("key2")?, None);
+    assert_eq!(store.namespace("users").len(), 1);
+    assert!(store.namespace("missing").is_empty());
+    drop(store);
+
+    // namespaces survive reopening and compaction
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.namespace("users").get("key1")?,
+        Some("user".to_owned())
+    );
+    for iter in 0..1100 {
+        store
+            .namespace("sessions")
+            .set("key1", format!("session{}", iter))?;
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.namespace("sessions").get("key1")?,
+        Some("session1099".to_owned())
+    );
+    assert_eq!(store.drop_namespace("sessions")?, 1);
+    assert_eq!(store.drop_namespace("sessions")?, 0);
+    assert_eq!(store.namespace("sessions").get("key1")?, None);
+    assert_eq!(
+        store.namespace("users").get("key1")?,
+        Some("user".to_owned())
+    );
+    assert_eq!(store.get("key1")?, Some("default".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.namespace("sessions").is_empty());
+    assert_eq!(store.namespace("users").len(), 1);
+
+    Ok(())
+}
+
+// Values above the threshold live in the value log and survive compaction and backups
+#[test]
+fn value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let options = Options {
+        value_log_threshold: Some(64),
+        ..Options::default()
+    };
+    let large = |iter: usize| format!("{:0>1000}", iter);
+    let mut store = KvStore::open_with_options(path("store"), options.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), large(0))?;
+    assert_eq!(store.get("large")?, Some(large(0)));
+    let log_len = |dir: &str, name: &str| {
+        std::fs::metadata(path(dir).join(name))
+            .expect("unable to read log file")
+            .len()
+    };
+    assert!(log_len("store", "0.bin") < 1000);
+    assert!(log_len("store", "0.vlog") >= 1000);
+    drop(store);
+
+    // the value log is read without the option too
+    let mut store = KvStore::open(path("store"))?;
+    assert_eq!(store.get("large")?, Some(large(0)));
+    assert_eq!(store.get("small")?, Some("value".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_with_options(path("store"), options)?;
+    let mut snapshot = store.snapshot()?;
+    for iter in 1..1100 {
+        store.set("large".to_owned(), large(iter))?;
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    assert!(log_len("store", "0.bin") < 100_000);
+    // compaction only kept the live value
+    assert!(log_len("store", "0.vlog") < 500_000);
+    assert_eq!(store.get("large")?, Some(large(1099)));
+    assert_eq!(snapshot.get("large")?, Some(large(0)));
+    drop(snapshot);
+
+    let cursor = store.backup(path("full"))?;
+    store.set("large".to_owned(), large(2000))?;
+    store.backup_since(&cursor, path("incremental.tar"))?;
+    let options = RestoreOptions {
+        incremental: vec![path("incremental.tar")],
+        ..RestoreOptions::default()
+    };
+    KvStore::restore_with_options(path("full"), path("restored"), &options)?;
+    let mut restored = KvStore::open(path("restored"))?;
+    assert_eq!(restored.get("large")?, Some(large(2000)));
+    assert_eq!(restored.get("small")?, Some("value".to_owned()));
+
+    // without the option, compaction moves the values back to the log file
+    restored.compact()?;
+    assert_eq!(restored.get("large")?, Some(large(2000)));
+    drop(restored);
+    assert!(!path("restored").join("0.vlog").exists());
+    assert!(log_len("restored", "0.bin") > 1000);
+
+    Ok(())
+}
+
+// Compaction keeps tombstones as long as the retention policy says
+#[test]
+fn tombstone_retention() -> Result<()> {
+    let tombstones = |retention: TombstoneRetention| -> Result<usize> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .tombstone_retention(retention)
+            .open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+        store.remove("key0".to_owned())?;
+        store.namespace("users").set("key1", "user".to_owned())?;
+        store.namespace("users").remove("key1")?;
+        for iter in 0..1100 {
+            store.set("key1".to_owned(), format!("{}", iter))?;
+        }
+        assert_eq!(store.stats()?.compactions, 1);
+        assert_eq!(store.get("key0")?, None);
+
+        let mut count = 0;
+        for record in store.tail(0)? {
+            match record?.1.into_unsequenced() {
+                KvLog::Rm(key) => {
+                    assert_eq!(key, b"key0");
+                    count += 1;
+                }
+                KvLog::Namespaced(namespace, kvlog) => {
+                    assert_eq!(namespace, "users");
+                    assert!(matches!(*kvlog, KvLog::Rm(key) if key == b"key1"));
+                    count += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(count)
+    };
+    assert_eq!(tombstones(TombstoneRetention::UntilCompaction)?, 0);
+    assert_eq!(tombstones(TombstoneRetention::Forever)?, 2);
+    assert_eq!(
+        tombstones(TombstoneRetention::For(Duration::from_secs(3600)))?,
+        2
+    );
+    assert_eq!(tombstones(TombstoneRetention::For(Duration::ZERO))?, 0);
+
+    Ok(())
+}
+
+// Keys and values over the size limits are rejected without writing anything
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .max_key_size(8)
+        .max_value_size(16)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "a".repeat(16))?;
+    match store.set("k".repeat(9), "value".to_owned()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::KeyTooLarge),
+        Ok(_) => panic!("set a key over the limit"),
+    }
+    match store.set("key2".to_owned(), "a".repeat(17)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ValueTooLarge),
+        Ok(_) => panic!("set a value over the limit"),
+    }
+    match store.namespace("users").set("key2", "a".repeat(17)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ValueTooLarge),
+        Ok(_) => panic!("set a value over the limit in a namespace"),
+    }
+
+    // a batch is rejected as a whole
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value".to_owned());
+    batch.set("key3", "a".repeat(17));
+    match store.write(batch) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ValueTooLarge),
+        Ok(_) => panic!("wrote a value over the limit in a batch"),
+    }
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.tail(0)?.count(), 1);
+
+    // an append is checked with the value it makes
+    store.append("key4", "a".repeat(10))?;
+    match store.append("key4", "a".repeat(7)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ValueTooLarge),
+        Ok(_) => panic!("appended a value over the limit"),
+    }
+    store.append("key4", "a".repeat(6))?;
+    assert_eq!(store.get("key4")?, Some("a".repeat(16)));
+
+    Ok(())
+}
+
+// Earlier versions of keys are read by sequence number until compaction
+#[test]
+fn get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.sequence(), 0);
+    store.set("key1".to_owned(), "1".to_owned())?;
+    store.set("key1".to_owned(), "2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "4".to_owned());
+    batch.set("key2", "4".to_owned());
+    store.write(batch)?;
+    assert_eq!(store.sequence(), 4);
+    assert_eq!(store.snapshot()?.sequence(), 4);
+
+    let versions = |store: &mut KvStore| -> Result<Vec<Option<String>>> {
+        (0..=4)
+            .map(|sequence| store.get_at("key1", sequence))
+            .collect()
+    };
+    let expected = vec![
+        None,
+        Some("1".to_owned()),
+        Some("2".to_owned()),
+        None,
+        Some("4".to_owned()),
+    ];
+    assert_eq!(versions(&mut store)?, expected);
+    assert_eq!(store.get_at("key2", 3)?, None);
+    assert_eq!(store.get_at("key2", 4)?, Some("4".to_owned()));
+    drop(store);
+
+    // versions are rebuilt from the log, and dropped by compaction
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(versions(&mut store)?, expected);
+    for iter in 0..1100 {
+        store.set("key3".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    let compacted = store.sequence();
+    assert_eq!(compacted, 1104);
+    match store.get_at("key1", 2) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::VersionCompacted),
+        Ok(_) => panic!("read a version dropped by compaction"),
+    }
+    assert_eq!(store.get_at("key1", compacted)?, Some("4".to_owned()));
+    drop(store);
+
+    // sequence numbers keep growing after compaction and clearing
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.sequence(), compacted);
+    assert!(store.get_at("key1", 2).is_err());
+    store.clear()?;
+    store.set("key1".to_owned(), "5".to_owned())?;
+    assert_eq!(store.sequence(), compacted + 1);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.sequence(), compacted + 1);
+    assert_eq!(store.get_at("key1", compacted)?, None);
+
+    Ok(())
+}
+
+// history lists the versions of a key since the last compaction, newest first
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.history("key1", 10)?.is_empty());
+    store.set("key1".to_owned(), "1".to_owned())?;
+    store.set("key2".to_owned(), "1".to_owned())?;
+    store.set("key1".to_owned(), "2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "3".to_owned())?;
+
+    let version = |sequence, value: Option<&str>, replaced_at| KeyVersion {
+        sequence,
+        value: value.map(str::to_owned),
+        replaced_at,
+    };
+    let expected = vec![
+        version(5, Some("3"), None),
+        version(4, None, Some(5)),
+        version(3, Some("2"), Some(4)),
+        version(1, Some("1"), Some(3)),
+    ];
+    assert_eq!(store.history("key1", 10)?, expected);
+    assert_eq!(store.history("key1", 2)?, expected[..2].to_vec());
+    assert_eq!(
+        store.history("key2", 10)?,
+        vec![version(2, Some("1"), None)]
+    );
+
+    // compaction only keeps the current version
+    for iter in 0..1100 {
+        store.set("key3".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(store.history("key1", 10)?, expected[..1].to_vec());
+
+    Ok(())
+}
+
+// watch reports the changes of keys with a prefix until the store is dropped
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    let watch = store.watch("user:");
+    let unwatched = store.watch("order:");
+    drop(unwatched);
+
+    store.set("user:1".to_owned(), "b".to_owned())?;
+    store.set("order:1".to_owned(), "c".to_owned())?;
+    store.append("user:1", "c")?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("user:2".to_owned(), "d".to_owned())
+        .remove("user:1".to_owned())
+        .set("user:2".to_owned(), "e".to_owned());
+    store.write(batch)?;
+
+    let event = |key: &str, old: Option<&str>, new: Option<&str>, op, sequence| ChangeEvent {
+        key: key.as_bytes().to_vec(),
+        old_value: old.map(|value| value.as_bytes().to_vec()),
+        new_value: new.map(|value| value.as_bytes().to_vec()),
+        op,
+        sequence,
+    };
+    drop(store);
+    assert_eq!(
+        watch.collect::<Vec<_>>(),
+        vec![
+            event("user:1", Some("a"), Some("b"), ChangeOp::Set, 2),
+            event("user:1", Some("b"), Some("bc"), ChangeOp::Set, 4),
+            event("user:2", None, Some("d"), ChangeOp::Set, 5),
+            event("user:1", Some("bc"), None, ChangeOp::Remove, 5),
+            event("user:2", Some("d"), Some("e"), ChangeOp::Set, 5),
+        ]
+    );
+
+    Ok(())
+}
+
+// write hooks are called with the logs of writes after they are committed
+#[test]
+fn write_hooks() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let (logs_hook, sets_hook, removes_hook) = (logs.clone(), events.clone(), events.clone());
+    let mut store = KvStore::builder()
+        .value_log_threshold(4)
+        .write_hook(WriteHook::new(move |kvlog| {
+            logs_hook.lock().unwrap().push(format!("{:?}", kvlog));
+        }))
+        .write_hook(WriteHook::on_set(move |key, value| {
+            let event = format!("set {:?} {:?}", key, value);
+            sets_hook.lock().unwrap().push(event);
+        }))
+        .write_hook(WriteHook::on_remove(move |key| {
+            removes_hook.lock().unwrap().push(format!("rm {:?}", key));
+        }))
+        .open(temp_dir.path())?;
+
+    store.set("a".to_owned(), "large".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("b".to_owned(), "1".to_owned())
+        .remove("a".to_owned());
+    store.write(batch)?;
+    store.remove("b".to_owned()).unwrap();
+    assert!(store.remove("b".to_owned()).is_err());
+
+    let logs = logs.lock().unwrap();
+    assert_eq!(logs.len(), 3);
+    // hooks get the value even if it went to the value log
+    assert_eq!(
+        logs[0],
+        format!(
+            "{:?}",
+            KvLog::Sequenced(1, Box::new(KvLog::Set(b"a".to_vec(), b"large".to_vec())))
+        )
+    );
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            format!("set {:?} {:?}", b"a", b"large"),
+            format!("set {:?} {:?}", b"b", b"1"),
+            format!("rm {:?}", b"a"),
+            format!("rm {:?}", b"b"),
+        ]
+    );
+    drop(store);
+
+    // the records of an import are flushed by the time hooks are called with them
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("0.bin");
+    let log_lens = Arc::new(Mutex::new(Vec::new()));
+    let (lens_hook, hook_path) = (log_lens.clone(), log_path.clone());
+    let mut store = KvStore::builder()
+        .durability(Durability::Flush)
+        .write_hook(WriteHook::new(move |_| {
+            let len = std::fs::metadata(&hook_path).unwrap().len();
+            lens_hook.lock().unwrap().push(len);
+        }))
+        .open(temp_dir.path())?;
+    let header_len = std::fs::metadata(&log_path).unwrap().len();
+    let csv = "key,value\nkey1,12\nkey2,13\n";
+    assert_eq!(store.import(csv.as_bytes(), Format::Csv)?, 2);
+    let log_lens = log_lens.lock().unwrap();
+    assert!(!log_lens.is_empty());
+    assert!(log_lens.iter().all(|&len| len > header_len));
+
+    Ok(())
+}
+
+// scan_from returns pages in key order with a cursor that survives reopening
+#[test]
+fn scan_from() -> Result<()> {
+    for ordered_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            ordered_index,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key in 0..10 {
+            store.set(format!("key{}", key), format!("{}", key))?;
+        }
+        store.remove("key4".to_owned())?;
+
+        let page = store.scan_from(&ScanCursor::default(), 3)?;
+        let keys = |entries: &[(String, String)]| -> Vec<String> {
+            entries.iter().map(|(key, _)| key.clone()).collect()
+        };
+        assert_eq!(keys(&page.entries), vec!["key0", "key1", "key2"]);
+        assert_eq!(page.entries[1].1, "1");
+        let saved = serde_json::to_string(&page.cursor.unwrap()).unwrap();
+
+        // keys after the cursor written meanwhile are seen, keys before it are not
+        store.set("key0a".to_owned(), "a".to_owned())?;
+        store.set("key3a".to_owned(), "a".to_owned())?;
+        store.remove("key5".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let mut cursor: ScanCursor = serde_json::from_str(&saved).unwrap();
+        let mut rest = Vec::new();
+        loop {
+            let page = store.scan_from(&cursor, 3)?;
+            assert!(page.entries.len() <= 3);
+            rest.extend(keys(&page.entries));
+            match page.cursor {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        assert_eq!(rest, vec!["key3", "key3a", "key6", "key7", "key8", "key9"]);
+
+        // a prefix bounds the pages, whether the cursor is before it or within it
+        let before = ScanCursor::default();
+        let page = store.scan_prefix_from("key3", &before, 1)?;
+        assert_eq!(keys(&page.entries), vec!["key3"]);
+        let page = store.scan_prefix_from("key3", &page.cursor.unwrap(), 1)?;
+        assert_eq!(keys(&page.entries), vec!["key3a"]);
+        assert!(page.cursor.is_none());
+    }
+
+    Ok(())
+}
+
+// secondary indexes follow writes and are rebuilt when the store is reopened
+#[test]
+fn secondary_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // index users by the city after the comma in their value
+    let by_city = SecondaryIndex::new("city", |_key, value| {
+        let value = String::from_utf8_lossy(value);
+        value.split(',').nth(1).map(|city| city.as_bytes().to_vec())
+    });
+    let open = || {
+        KvStore::builder()
+            .index(by_city.clone())
+            .open(temp_dir.path())
+    };
+    let mut store = open()?;
+    store.set("user:1".to_owned(), "alice,paris".to_owned())?;
+    store.set("user:2".to_owned(), "bob,oslo".to_owned())?;
+    store.set("user:3".to_owned(), "carol".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("user:4".to_owned(), "dave,paris".to_owned())
+        .set("user:2".to_owned(), "bob,paris".to_owned())
+        .set("user:5".to_owned(), "eve,oslo".to_owned())
+        .remove("user:5".to_owned());
+    store.write(batch)?;
+    store.append("user:3", ",oslo")?;
+
+    let pair = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+    let parisians = vec![
+        pair("user:1", "alice,paris"),
+        pair("user:2", "bob,paris"),
+        pair("user:4", "dave,paris"),
+    ];
+    assert_eq!(store.get_by_index("city", "paris")?, parisians);
+    assert_eq!(
+        store.get_by_index("city", "oslo")?,
+        vec![pair("user:3", "carol,oslo")]
+    );
+    match store.get_by_index("name", "bob") {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::IndexNotFound),
+        Ok(_) => panic!("lookup of an unknown index should fail"),
+    }
+
+    store.remove("user:1".to_owned())?;
+    drop(store);
+    let mut store = open()?;
+    assert_eq!(
+        store.get_by_index("city", "paris")?,
+        parisians[1..].to_vec()
+    );
+    store.clear()?;
+    assert!(store.get_by_index("city", "oslo")?.is_empty());
+
+    Ok(())
+}
+
+// bulk_load writes pairs to a new log of an empty store in one go
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "1".to_owned())?;
+    match store.bulk_load(vec![("key2", "2")]) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StoreNotEmpty),
+        Ok(_) => panic!("bulk loading a store with keys should fail"),
+    }
+    store.remove("key1".to_owned())?;
+    let sequence = store.sequence();
+
+    let pairs = (0..100)
+        .map(|i| (format!("key{:03}", i), i.to_string()))
+        .chain(Some(("key042".to_owned(), "last".to_owned())));
+    assert_eq!(store.bulk_load(pairs)?, 101);
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.sequence(), sequence + 1);
+    assert_eq!(store.get("key042".to_owned())?, Some("last".to_owned()));
+    drop(store);
+    let mut store = KvStore::builder().max_value_size(3).open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    // a failed bulk load leaves the store as it was
+    store.clear()?;
+    let pairs = vec![("key1", "1"), ("key2", "long")];
+    match store.bulk_load(pairs) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ValueTooLarge),
+        Ok(_) => panic!("bulk loading a value too large should fail"),
+    }
+    assert!(store.is_empty());
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+
+    Ok(())
+}
+
+// disk_usage measures the live part of the log and the value log
+#[test]
+fn disk_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .value_log_threshold(100)
+        .open(temp_dir.path())?;
+    let usage = store.disk_usage()?;
+    assert_eq!(usage.live_bytes, usage.disk_bytes);
+    assert_eq!(usage.space_amplification, 1.0);
+
+    // every record is live, including those an appended value is built from
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "1".to_owned())
+        .set("key2".to_owned(), "2".to_owned());
+    store.write(batch)?;
+    store.append("key1", "1")?;
+    store.set("large".to_owned(), "x".repeat(1000))?;
+    store.namespace("ns").set("key1", "1".to_owned())?;
+    let usage = store.disk_usage()?;
+    assert_eq!(usage.live_bytes, usage.disk_bytes);
+
+    // overwritten records are garbage, the overwritten value in the value log too
+    store.set("large".to_owned(), "y".repeat(1000))?;
+    store.remove("key2".to_owned())?;
+    let usage = store.disk_usage()?;
+    assert!(usage.disk_bytes > usage.live_bytes + 1000);
+    assert!(usage.space_amplification > 1.5);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.disk_usage()?, usage);
+
+    Ok(())
+}
+
+// named stores keep their own files and locks in a shared directory
+#[test]
+fn open_named() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut sessions = KvStore::open_named(temp_dir.path(), "sessions")?;
+    let mut users = KvStore::builder()
+        .store_name("users")
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "0".to_owned())?;
+    sessions.set("key1".to_owned(), "1".to_owned())?;
+    users.set("key2".to_owned(), "2".to_owned())?;
+    match KvStore::open_named(temp_dir.path(), "sessions") {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StoreLocked),
+        Ok(_) => panic!("opening an open named store should fail"),
+    }
+    for name in ["", "0", "a/b", "a.b"] {
+        match KvStore::open_named(temp_dir.path(), name) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidStoreName),
+            Ok(_) => panic!("opening a store named {:?} should fail", name),
+        }
+    }
+
+    // compaction of a named store leaves the others alone
+    for iter in 0..1100 {
+        sessions.set("key3".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(sessions.stats()?.compactions, 1);
+    drop((store, sessions, users));
+    assert!(temp_dir.path().join("sessions.bin").exists());
+    assert!(!temp_dir.path().join("sessions.compact.tmp").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut sessions = KvStore::open_named(temp_dir.path(), "sessions")?;
+    let mut users = KvStore::open_named(temp_dir.path(), "users")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("0".to_owned()));
+    assert_eq!(sessions.get("key1".to_owned())?, Some("1".to_owned()));
+    assert_eq!(sessions.get("key3".to_owned())?, Some("1099".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, None);
+    assert_eq!(users.get("key2".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.len(), 1);
+
+    Ok(())
+}
+
+// the manifest tells opening to discard a compaction a crash interrupted
+#[test]
+fn manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "1".to_owned())?;
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+    assert_eq!(manifest["segments"], serde_json::json!(["0.bin"]));
+    assert_eq!(manifest["compaction"], serde_json::Value::Null);
+    drop(store);
+
+    // a crash while compaction writes its new log
+    let interrupted = serde_json::json!({
+        "format_version": manifest["format_version"],
+        "segments": ["0.bin"],
+        "compaction": { "temp_file": "compact.tmp" },
+    });
+    std::fs::write(&manifest_path, interrupted.to_string()).unwrap();
+    std::fs::write(temp_dir.path().join("compact.tmp"), b"half written").unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("compact.tmp").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("1".to_owned()));
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+    assert_eq!(manifest["compaction"], serde_json::Value::Null);
+    drop(store);
+
+    // several segments are not supported yet
+    let segmented = serde_json::json!({
+        "format_version": manifest["format_version"],
+        "segments": ["0.bin", "1.bin"],
+        "compaction": null,
+    });
+    std::fs::write(&manifest_path, segmented.to_string()).unwrap();
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::UnsupportedVersion),
+        Ok(_) => panic!("opening a store of several segments should fail"),
+    }
+
+    Ok(())
+}
+
+// rename moves a value and its TTL to another key in one batch
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "1".to_owned())?;
+    store.append("key1", "1")?;
+    store.set("key2".to_owned(), "2".to_owned())?;
+    store.set_with_ttl("key3".to_owned(), "3".to_owned(), Duration::from_secs(60))?;
+
+    store.rename("key1", "key2")?;
+    store.rename("key3", "key4")?;
+    store.rename("key4", "key4")?;
+    match store.rename("key1", "key5") {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::KeyNotFound),
+        Ok(_) => panic!("renaming a missing key should fail"),
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("11".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("3".to_owned()));
+    assert!(store.ttl("key4")?.is_some());
+    assert_eq!(store.len(), 2);
+
+    Ok(())
+}
+
+// copy duplicates values, sharing those in the value log
+#[test]
+fn copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .value_log_threshold(100)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "1".to_owned())?;
+    store.append("key1", "1")?;
+    store.set("key2".to_owned(), "2".to_owned())?;
+    store.set("large".to_owned(), "x".repeat(1000))?;
+
+    assert!(!store.copy("key1", "key2", false)?);
+    assert_eq!(store.get("key2".to_owned())?, Some("2".to_owned()));
+    assert!(store.copy("key1", "key2", true)?);
+    assert!(store.copy("key1", "key3", false)?);
+    match store.copy("key4", "key5", true) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::KeyNotFound),
+        Ok(_) => panic!("copying a missing key should fail"),
+    }
+
+    // the copy of a large value only takes a record in the log
+    let value_log_len = |temp_dir: &TempDir| {
+        std::fs::metadata(temp_dir.path().join("0.vlog"))
+            .unwrap()
+            .len()
+    };
+    let before = value_log_len(&temp_dir);
+    assert!(store.copy("large", "large2", false)?);
+    store.rename("large2", "large3")?;
+    assert_eq!(value_log_len(&temp_dir), before);
+    // the shared value is live once
+    let usage = store.disk_usage()?;
+    assert!(usage.live_bytes > 1000 && usage.live_bytes < usage.disk_bytes);
+    store.set("large".to_owned(), "y".to_owned())?;
+
+    for iter in 0..1100 {
+        store.set("key6".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("11".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("11".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("11".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some("y".to_owned()));
+    assert_eq!(store.get("large2".to_owned())?, None);
+    assert_eq!(store.get("large3".to_owned())?, Some("x".repeat(1000)));
+
+    Ok(())
+}
+
+// delete_prefix removes the keys under a prefix with one record
+#[test]
+fn delete_prefix() -> Result<()> {
+    for ordered_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            ordered_index,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key in ["user:1", "user:2", "user:3", "users", "order:1"] {
+            store.set(key.to_owned(), "1".to_owned())?;
+        }
+        store.remove("user:3".to_owned())?;
+        let sequence = store.sequence();
+
+        assert_eq!(store.delete_prefix("user:")?, 2);
+        assert_eq!(store.sequence(), sequence + 1);
+        assert_eq!(store.delete_prefix("user:")?, 0);
+        assert_eq!(store.sequence(), sequence + 1);
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let mut keys: Vec<_> = store.keys().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![&b"order:1"[..], &b"users"[..]]);
+    }
+
+    Ok(())
+}
+
+// Should estimate the live bytes of key ranges from the log pointer map
+#[test]
+fn approximate_size() -> Result<()> {
+    for ordered_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            ordered_index,
+            value_log_threshold: Some(100),
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.approximate_size(..), 0);
+        store.set("a".to_owned(), "1".repeat(10))?;
+        store.set("b".to_owned(), "2".repeat(1000))?;
+        store.set("c".to_owned(), "3".repeat(10))?;
+        store.set("d".to_owned(), "4".to_owned())?;
+        store.remove("d".to_owned())?;
+
+        let a = store.approximate_size(.."b".to_owned());
+        let b = store.approximate_size("b".to_owned().."c".to_owned());
+        let c = store.approximate_size("c".to_owned()..);
+        assert!(a > 10 && b > 1000 && c > 10);
+        assert!(a < 100 && c < 100);
+        assert_eq!(store.approximate_size(..), a + b + c);
+
+        // appends add to the size of their key
+        store.append("a", "1".repeat(50))?;
+        let appended = store.approximate_size(.."b".to_owned());
+        assert!(appended > a + 50);
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.approximate_size(.."b".to_owned()), appended);
+        assert_eq!(store.approximate_size("b".to_owned()..), b + c);
+        drop(store);
+
+        // compaction folds the appends
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for iter in 0..1100 {
+            store.set("e".to_owned(), format!("{}", iter))?;
+        }
+        assert_eq!(store.stats()?.compactions, 1);
+        let folded = store.approximate_size(.."b".to_owned());
+        assert!(folded > 60 && folded < appended);
+        assert_eq!(
+            store.approximate_size("b".to_owned().."e".to_owned()),
+            b + c
+        );
+    }
+
+    Ok(())
+}
+
+/// A `kvs-server` process, killed when dropped.
+struct TestServer(Child);
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start `kvs-server` on `addr` in `dir` with extra `args`, and wait until it accepts
+/// connections.
+fn start_server(dir: &TempDir, addr: &str, args: &[&str]) -> TestServer {
+    let server = TestServer(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr])
+            .args(args)
+            .current_dir(dir)
+            .spawn()
+            .expect("unable to start kvs-server"),
+    );
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("kvs-server did not start listening on {}", addr);
+}
+
+/// Connect to the server listening on `addr`, or on the Unix domain socket of a `unix://`
+/// address, waiting until it accepts connections.
+fn connect_when_listening(addr: &str) -> KvsClient {
+    for _ in 0..100 {
+        let client = match addr.strip_prefix("unix://") {
+            #[cfg(unix)]
+            Some(path) => KvsClient::connect_unix(path),
+            _ => KvsClient::connect(addr),
+        };
+        if let Ok(client) = client {
+            return client;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server did not start listening on {}", addr);
+}
+
+/// Holds the writes of the key `slow` in a write hook, until the test lets them finish.
+struct HeldWrite(Arc<Barrier>);
+
+impl HeldWrite {
+    fn new() -> HeldWrite {
+        HeldWrite(Arc::new(Barrier::new(2)))
+    }
+
+    /// A write hook holding the write of `slow` in the store it is added to.
+    fn hook(&self) -> WriteHook {
+        let held = Arc::clone(&self.0);
+        WriteHook::on_set(move |key, _| {
+            if key == b"slow" {
+                held.wait();
+                held.wait();
+            }
+        })
+    }
+
+    /// Wait for the write of `slow` to hold its store.
+    fn wait_held(&self) {
+        self.0.wait();
+    }
+
+    /// Let the write of `slow` finish.
+    fn release(&self) {
+        self.0.wait();
+    }
+}
+
+// kvs-server should keep the store open and answer requests over TCP
+#[test]
+fn server_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4101";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    // frames are bincode messages prefixed by their length as a big-endian u32
+    let mut stream = TcpStream::connect(addr).expect("unable to connect");
+    let mut send = |request: Request| {
+        let bytes = bincode::serialize(&request).unwrap();
+        stream
+            .write_all(&(bytes.len() as u32).to_be_bytes())
+            .unwrap();
+        stream.write_all(&bytes).unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut bytes).unwrap();
+        bincode::deserialize::<Response>(&bytes).unwrap()
+    };
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    assert_eq!(send(set), Response::Ok(None));
+    let get = Request::Get {
+        key: "key1".to_owned(),
+    };
+    assert_eq!(send(get.clone()), Response::Ok(Some("value1".to_owned())));
+    let remove = Request::Remove {
+        key: "key1".to_owned(),
+    };
+    assert_eq!(send(remove.clone()), Response::Ok(None));
+    assert_eq!(send(get), Response::Ok(None));
+    assert_eq!(send(remove), Response::Err(ErrorKind::KeyNotFound));
+    drop(server);
+
+    // the store is left to the other engine
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("holds data of another engine"));
+
+    Ok(())
+}
+
+// kvs-client should run commands on kvs-server with the exit codes of kvs
+#[test]
+fn client_cli() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4102";
+    let server = start_server(&temp_dir, addr, &[]);
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command.assert()
+    };
+
+    client(&["set", "key1", "value1"])
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["get", "key2"])
+        .success()
+        .stdout(eq("Key not found").trim());
+    client(&["rm", "key1"]).success().stdout(is_empty());
+    client(&["rm", "key1"])
+        .failure()
+        .stdout(eq("Key not found").trim());
+    drop(server);
+
+    // the values were written to the store of the server
+    let server = start_server(&temp_dir, addr, &[]);
+    client(&["set", "key2", "value2"]).success();
+    drop(server);
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+}
+
+// KvsClient should be usable as an engine
+#[test]
+fn client_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4103";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    match client.remove("key1".to_owned()) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::KeyNotFound),
+        Ok(_) => panic!("removed a missing key"),
+    }
+    // the connection is still usable after a failed command
+    assert_eq!(KvsEngine::get(&mut client, "key1".to_owned())?, None);
+    drop(server);
+
+    Ok(())
+}
+
+// kvs-server should speak RESP for Redis clients
+#[test]
+fn server_resp() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4104";
+    let server = start_server(&temp_dir, addr, &["--protocol", "resp"]);
+
+    let mut stream = TcpStream::connect(addr).expect("unable to connect");
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    let mut send = |command: &str, replies: usize| {
+        stream.write_all(command.as_bytes()).unwrap();
+        let mut reply = String::new();
+        for _ in 0..replies {
+            std::io::BufRead::read_line(&mut reader, &mut reply).unwrap();
+        }
+        reply
+    };
+    assert_eq!(send("PING\r\n", 1), "+PONG\r\n");
+    assert_eq!(
+        send("*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$8\r\nvalue\r\n1\r\n", 1),
+        "+OK\r\n"
+    );
+    assert_eq!(
+        send("*2\r\n$3\r\nget\r\n$4\r\nkey1\r\n", 3),
+        "$8\r\nvalue\r\n1\r\n"
+    );
+    assert_eq!(send("GET key2\r\n", 1), "$-1\r\n");
+    assert_eq!(send("SET key2 value2\r\n", 1), "+OK\r\n");
+    assert_eq!(send("EXISTS key1 key2 key3\r\n", 1), ":2\r\n");
+    assert_eq!(send("DEL key1 key3\r\n", 1), ":1\r\n");
+    assert_eq!(send("EXISTS key1\r\n", 1), ":0\r\n");
+    assert_eq!(
+        send("GET\r\n", 1),
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(
+        send("FLUSHALL\r\n", 1),
+        "-ERR unknown command 'FLUSHALL'\r\n"
+    );
+    assert_eq!(send("QUIT\r\n", 1), "+OK\r\n");
+    drop(server);
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
+
+// kvs-server should speak the memcached text protocol
+#[test]
+fn server_memcached() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4105";
+    let server = start_server(&temp_dir, addr, &["--protocol", "memcached"]);
+
+    let mut stream = TcpStream::connect(addr).expect("unable to connect");
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    let mut send = |command: &str, lines: usize| {
+        stream.write_all(command.as_bytes()).unwrap();
+        let mut reply = String::new();
+        for _ in 0..lines {
+            std::io::BufRead::read_line(&mut reader, &mut reply).unwrap();
+        }
+        reply
+    };
+    assert_eq!(send("set key1 0 0 6\r\nvalue1\r\n", 1), "STORED\r\n");
+    assert_eq!(send("set counter 5 60 2\r\n10\r\n", 1), "STORED\r\n");
+    assert_eq!(
+        send("get key1 key2 counter\r\n", 5),
+        "VALUE key1 0 6\r\nvalue1\r\nVALUE counter 0 2\r\n10\r\nEND\r\n"
+    );
+    assert_eq!(send("incr counter 5\r\n", 1), "15\r\n");
+    assert_eq!(send("decr counter 20\r\n", 1), "0\r\n");
+    assert_eq!(send("incr key2 1\r\n", 1), "NOT_FOUND\r\n");
+    assert_eq!(
+        send("incr key1 1\r\n", 1),
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+    );
+    // no reply is sent for noreply, so the next reply is of delete
+    send("set key3 0 0 1 noreply\r\n3\r\n", 0);
+    assert_eq!(send("delete key1\r\n", 1), "DELETED\r\n");
+    assert_eq!(send("delete key1\r\n", 1), "NOT_FOUND\r\n");
+    assert_eq!(send("flush_all\r\n", 1), "ERROR\r\n");
+
+    // concurrent increments are not lost
+    assert_eq!(send("set hits 0 0 1\r\n0\r\n", 1), "STORED\r\n");
+    let clients: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).expect("unable to connect");
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                for _ in 0..50 {
+                    stream.write_all(b"incr hits 1\r\n").unwrap();
+                    let mut reply = String::new();
+                    std::io::BufRead::read_line(&mut reader, &mut reply).unwrap();
+                    assert!(reply.trim_end().parse::<u64>().is_ok(), "{}", reply);
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    assert_eq!(send("get hits\r\n", 3), "VALUE hits 0 3\r\n200\r\nEND\r\n");
+    send("quit\r\n", 0);
+    drop(server);
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        store.get("counter".to_owned()).unwrap(),
+        Some("0".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned()).unwrap(), Some("3".to_owned()));
+}
+
+// kvs-server should serve a REST API over HTTP
+#[test]
+fn server_http() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4106";
+    let server = start_server(&temp_dir, addr, &["--protocol", "http"]);
+
+    // each request on its own connection, closed by the server after the response
+    let request = |method: &str, target: &str, body: &str| {
+        let mut stream = TcpStream::connect(addr).expect("unable to connect");
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status: u16 = head.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = match body {
+            "" => serde_json::Value::Null,
+            body => serde_json::from_str(body).unwrap(),
+        };
+        (status, body)
+    };
+
+    assert_eq!(request("PUT", "/keys/user:1", "alice"), (204, json!(null)));
+    assert_eq!(request("PUT", "/keys/user%3A2", "bob"), (204, json!(null)));
+    assert_eq!(
+        request("PUT", "/keys/order%201", "book"),
+        (204, json!(null))
+    );
+    assert_eq!(
+        request("GET", "/keys/user%3A1", ""),
+        (200, json!({ "key": "user:1", "value": "alice" }))
+    );
+    assert_eq!(
+        request("GET", "/keys/order%201", ""),
+        (200, json!({ "key": "order 1", "value": "book" }))
+    );
+    assert_eq!(
+        request("GET", "/keys?prefix=user%3A", ""),
+        (
+            200,
+            json!([
+                { "key": "user:1", "value": "alice" },
+                { "key": "user:2", "value": "bob" },
+            ])
+        )
+    );
+    assert_eq!(request("GET", "/keys", "").1.as_array().unwrap().len(), 3);
+    assert_eq!(request("DELETE", "/keys/user:1", ""), (204, json!(null)));
+    assert_eq!(
+        request("DELETE", "/keys/user:1", ""),
+        (404, json!({ "error": "Key not found" }))
+    );
+    assert_eq!(
+        request("GET", "/keys/user:1", ""),
+        (404, json!({ "error": "Key not found" }))
+    );
+    assert_eq!(request("POST", "/keys/user:1", "").0, 405);
+    assert_eq!(request("GET", "/other", "").0, 404);
+
+    // connections are kept alive for several requests
+    let mut stream = TcpStream::connect(addr).expect("unable to connect");
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    for _ in 0..2 {
+        stream
+            .write_all(b"GET /keys/user:2 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut status = String::new();
+        std::io::BufRead::read_line(&mut reader, &mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            std::io::BufRead::read_line(&mut reader, &mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                len = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!({ "key": "user:2", "value": "bob" })
+        );
+    }
+    drop(server);
+}
+
+// kvs-server should serve the gRPC service of proto/kvs.proto
+#[cfg(feature = "grpc")]
+#[test]
+fn server_grpc() {
+    use kvs::grpc::proto::kvs_client::KvsClient;
+    use kvs::grpc::proto::{GetRequest, KeyValue, RemoveRequest, ScanRequest, SetRequest};
+    use tonic::Code;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4107";
+    let server = start_server(&temp_dir, addr, &["--protocol", "grpc"]);
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let channel = tonic::transport::Channel::from_static("http://127.0.0.1:4107")
+            .connect()
+            .await
+            .unwrap();
+        let mut client = KvsClient::new(channel);
+        for (key, value) in [("user:1", "alice"), ("user:2", "bob"), ("order:1", "book")] {
+            let request = SetRequest {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            };
+            client.set(request).await.unwrap();
+        }
+        let get = |key: &str| GetRequest {
+            key: key.to_owned(),
+        };
+        let value = client.get(get("user:1")).await.unwrap().into_inner().value;
+        assert_eq!(value, Some("alice".to_owned()));
+        let value = client.get(get("user:3")).await.unwrap().into_inner().value;
+        assert_eq!(value, None);
+
+        let scan = ScanRequest {
+            prefix: "user:".to_owned(),
+        };
+        let mut stream = client.scan(scan).await.unwrap().into_inner();
+        let mut pairs = Vec::new();
+        while let Some(pair) = stream.message().await.unwrap() {
+            pairs.push(pair);
+        }
+        let pair = |key: &str, value: &str| KeyValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        assert_eq!(pairs, vec![pair("user:1", "alice"), pair("user:2", "bob")]);
+
+        let remove = |key: &str| RemoveRequest {
+            key: key.to_owned(),
+        };
+        client.remove(remove("user:1")).await.unwrap();
+        let status = client.remove(remove("user:1")).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    });
+    drop(server);
+}
+
+// kvs-server should accept TLS connections from clients trusting its certificate authority
+#[cfg(feature = "tls")]
+#[test]
+fn server_tls() {
+    use std::path::PathBuf;
+
+    let tls_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let (ca, cert, key) = (
+        tls_dir.join("ca.pem"),
+        tls_dir.join("server.pem"),
+        tls_dir.join("server.key"),
+    );
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4108";
+    let server = start_server(
+        &temp_dir,
+        addr,
+        &[
+            "--cert",
+            cert.to_str().unwrap(),
+            "--key",
+            key.to_str().unwrap(),
+        ],
+    );
+
+    let config = kvs::tls::client_config(&ca).unwrap();
+    let mut client = KvsClient::connect_tls(addr, "localhost", config.clone()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    let e = client.remove("key2".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::KeyNotFound);
+    drop(client);
+
+    // the certificate is valid for the address of the server too
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--ca", ca.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    // but not for another name
+    let mut client = KvsClient::connect_tls(addr, "example.com", config).unwrap();
+    client.get("key1".to_owned()).unwrap_err();
+    drop(client);
+    // a client not trusting the certificate authority is refused
+    let config = kvs::tls::client_config(&cert).unwrap();
+    let mut client = KvsClient::connect_tls(addr, "localhost", config).unwrap();
+    client.get("key1".to_owned()).unwrap_err();
+    drop(client);
+    // and so is a client not speaking TLS
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.get("key1".to_owned()).unwrap_err();
+    drop(client);
+
+    // the server keeps serving after refusing them
+    let config = kvs::tls::client_config(&ca).unwrap();
+    let mut client = KvsClient::connect_tls(addr, "localhost", config).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    drop(server);
+}
+
+// kvs-server should refuse commands until the client authenticates with its token
+#[test]
+fn server_auth() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4109";
+    let server = start_server(&temp_dir, addr, &["--auth-token", "secret"]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let e = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+    let e = client.authenticate("guess".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+    let e = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+    client.authenticate("secret".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    // a wrong token afterwards fails without losing the authentication
+    client.authenticate("guess".to_owned()).unwrap_err();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    drop(client);
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", addr]);
+        command.assert()
+    };
+    client(&["get", "key1"]).failure();
+    client(&["get", "key1", "--auth-token", "secret"])
+        .success()
+        .stdout(eq("value1").trim());
+    drop(server);
+
+    // Redis clients authenticate with AUTH
+    let server = start_server(
+        &temp_dir,
+        addr,
+        &["--protocol", "resp", "--auth-token", "secret"],
+    );
+    let mut stream = TcpStream::connect(addr).expect("unable to connect");
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    let mut send = |command: &str| {
+        stream.write_all(command.as_bytes()).unwrap();
+        let mut reply = String::new();
+        std::io::BufRead::read_line(&mut reader, &mut reply).unwrap();
+        reply
+    };
+    assert_eq!(send("GET key1\r\n"), "-NOAUTH Authentication required.\r\n");
+    assert_eq!(
+        send("AUTH guess\r\n"),
+        "-WRONGPASS invalid username-password pair\r\n"
+    );
+    assert_eq!(send("AUTH secret\r\n"), "+OK\r\n");
+    assert_eq!(send("EXISTS key1\r\n"), ":1\r\n");
+    assert_eq!(send("QUIT\r\n"), "+OK\r\n");
+    drop(server);
+
+    // HTTP clients send a bearer token with every request
+    let server = start_server(
+        &temp_dir,
+        addr,
+        &["--protocol", "http", "--auth-token", "secret"],
+    );
+    let status = |headers: &str| {
+        let mut stream = TcpStream::connect(addr).expect("unable to connect");
+        write!(
+            stream,
+            "GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n{}\r\n",
+            headers
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.split(' ').nth(1).unwrap().to_owned()
+    };
+    assert_eq!(status(""), "401");
+    assert_eq!(status("Authorization: Bearer guess\r\n"), "401");
+    assert_eq!(status("Authorization: Bearer secret\r\n"), "200");
+    drop(server);
+
+    // the memcached text protocol has no authentication
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "--protocol",
+            "memcached",
+            "--auth-token",
+            "x",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// kvs-server should restrict users to the key prefixes of its ACL
+#[test]
+fn server_acl() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acl = temp_dir.path().join("acl.json");
+    let users = json!({
+        "users": [
+            { "name": "billing", "token": "b", "read": ["billing:", "shared:"], "write": ["billing:"] },
+            { "name": "reports", "token": "r", "read": ["billing:"] },
+            { "name": "admin", "token": "a", "read": [""], "write": [""] }
+        ]
+    });
+    std::fs::write(&acl, users.to_string()).unwrap();
+    let addr = "127.0.0.1:4110";
+    let server = start_server(&temp_dir, addr, &["--acl", acl.to_str().unwrap()]);
+
+    let connect = |token: &str| {
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.authenticate(token.to_owned()).unwrap();
+        client
+    };
+    let mut client = connect("a");
+    client.set("shared:1".to_owned(), "s".to_owned()).unwrap();
+    client.set("hr:1".to_owned(), "h".to_owned()).unwrap();
+    drop(client);
+
+    let denied = |result: Result<Option<String>>| {
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    };
+    let mut client = connect("b");
+    client.set("billing:1".to_owned(), "b".to_owned()).unwrap();
+    assert_eq!(
+        client.get("shared:1".to_owned()).unwrap(),
+        Some("s".to_owned())
+    );
+    denied(client.get("hr:1".to_owned()));
+    denied(
+        client
+            .set("shared:1".to_owned(), "x".to_owned())
+            .map(|_| None),
+    );
+    denied(client.remove("hr:1".to_owned()).map(|_| None));
+    drop(client);
+
+    let mut client = connect("r");
+    assert_eq!(
+        client.get("billing:1".to_owned()).unwrap(),
+        Some("b".to_owned())
+    );
+    denied(client.remove("billing:1".to_owned()).map(|_| None));
+    drop(client);
+    drop(server);
+
+    // listing keys only returns the keys the user may read
+    let server = start_server(
+        &temp_dir,
+        addr,
+        &["--protocol", "http", "--acl", acl.to_str().unwrap()],
+    );
+    let request = |target: &str, token: &str| {
+        let mut stream = TcpStream::connect(addr).expect("unable to connect");
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nConnection: close\r\nAuthorization: Bearer {}\r\n\r\n",
+            target, token
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status: u16 = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (
+            status,
+            serde_json::from_str::<serde_json::Value>(body).unwrap(),
+        )
+    };
+    assert_eq!(
+        request("/keys", "b"),
+        (
+            200,
+            json!([
+                { "key": "billing:1", "value": "b" },
+                { "key": "shared:1", "value": "s" }
+            ])
+        )
+    );
+    assert_eq!(request("/keys/hr:1", "r").0, 403);
+    assert_eq!(request("/keys/hr:1", "x").0, 401);
+    drop(server);
+}
+
+// KvsClientPool should reuse its connections, and replace those the server closed
+#[test]
+fn client_pool() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4111";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    let mut pool = KvsClientPool::new(addr)
+        .unwrap()
+        .check_after(Duration::from_secs(0));
+    assert_eq!(pool.idle_count(), 0);
+    pool.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(pool.idle_count(), 1);
+    let mut client = pool.client().unwrap();
+    assert_eq!(pool.idle_count(), 0);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    // errors of commands leave the connection usable
+    client.remove("key2".to_owned()).unwrap_err();
+    drop(client);
+    assert_eq!(pool.idle_count(), 1);
+    drop(server);
+
+    // the idle connection was closed with the server, and is replaced
+    let server = start_server(&temp_dir, addr, &[]);
+    assert_eq!(
+        pool.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(pool.idle_count(), 1);
+    drop(server);
+}
+
+// KvsClient should run batches and pipelines of requests, matching their responses
+#[test]
+fn client_batch() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4112";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let set = |key: &str, value: &str| Request::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    let get = |key: &str| Request::Get {
+        key: key.to_owned(),
+    };
+    let results = client
+        .batch(vec![
+            set("key1", "value1"),
+            Request::Remove {
+                key: "key2".to_owned(),
+            },
+            get("key1"),
+        ])
+        .unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().unwrap(), &None);
+    assert_eq!(
+        results[1].as_ref().unwrap_err().kind(),
+        ErrorKind::KeyNotFound
+    );
+    assert_eq!(results[2].as_ref().unwrap(), &Some("value1".to_owned()));
+    assert!(client.batch(Vec::new()).unwrap().is_empty());
+
+    // more requests than fit in a window
+    let requests = (0..1000)
+        .map(|i| set(&format!("key{}", i), &format!("value{}", i)))
+        .collect();
+    let results = client.pipeline(requests).unwrap();
+    assert_eq!(results.len(), 1000);
+    assert!(results.iter().all(|result| result.is_ok()));
+    let results = client
+        .pipeline((0..1000).map(|i| get(&format!("key{}", i))).collect())
+        .unwrap();
+    for (i, result) in results.into_iter().enumerate() {
+        assert_eq!(result.unwrap(), Some(format!("value{}", i)));
+    }
+    // the connection is still usable for single requests
+    assert_eq!(
+        client.get("key999".to_owned()).unwrap(),
+        Some("value999".to_owned())
+    );
+    drop(client);
+    drop(server);
+}
+
+// kvs-server --async should serve many connections at once
+#[cfg(feature = "async")]
+#[test]
+fn server_async() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4113";
+    let server = start_server(&temp_dir, addr, &["--async", "--auth-token", "secret"]);
+
+    // a server serving one connection at a time would wait for the first one to close
+    let mut first = KvsClient::connect(addr).unwrap();
+    let mut second = KvsClient::connect(addr).unwrap();
+    second.authenticate("secret".to_owned()).unwrap();
+    second.set("key".to_owned(), "value".to_owned()).unwrap();
+    let e = first.get("key".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+    first.authenticate("secret".to_owned()).unwrap();
+    assert_eq!(
+        first.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    let clients: Vec<_> = (0..50)
+        .map(|_| {
+            let mut client = KvsClient::connect(addr).unwrap();
+            client.authenticate("secret".to_owned()).unwrap();
+            client
+        })
+        .collect();
+    let threads: Vec<_> = clients
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut client)| {
+            thread::spawn(move || {
+                for j in 0..20 {
+                    let key = format!("key{}-{}", i, j);
+                    client.set(key.clone(), j.to_string()).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(j.to_string()));
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(
+        first.get("key49-19".to_owned()).unwrap(),
+        Some("19".to_owned())
+    );
+    drop(server);
+}
+
+// AsyncKvsClient should talk to kvs-server from async code
+#[cfg(feature = "async")]
+#[test]
+fn async_client() {
+    use kvs::AsyncKvsClient;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4114";
+    let server = start_server(&temp_dir, addr, &["--auth-token", "secret"]);
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut client = AsyncKvsClient::connect(addr).await.unwrap();
+        client.ping().await.unwrap();
+        let e = client.get("key1".to_owned()).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unauthenticated);
+        client.authenticate("secret".to_owned()).await.unwrap();
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).await.unwrap(),
+            Some("value1".to_owned())
+        );
+        assert_eq!(client.get("key2".to_owned()).await.unwrap(), None);
+        client.remove("key1".to_owned()).await.unwrap();
+        let e = client.remove("key1".to_owned()).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::KeyNotFound);
+    });
+    drop(server);
+}
+
+// the thread pools should run every job, and keep their threads when jobs panic
+fn check_thread_pool<P: ThreadPool>(threads: u32) {
+    let pool = P::new(threads).unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for i in 0..(threads * 4) {
+        let sender = sender.clone();
+        pool.spawn(move || {
+            if i % 2 == 0 {
+                panic!("job {} panics", i);
+            }
+            sender.send(i).unwrap();
+        });
+    }
+    for i in 0..(threads * 4) {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(i).unwrap());
+    }
+    let mut done: Vec<u32> = receiver.iter().take((threads * 6) as usize).collect();
+    done.sort_unstable();
+    let mut expected: Vec<u32> = (0..(threads * 4)).filter(|i| i % 2 == 1).collect();
+    expected.extend(0..(threads * 4));
+    expected.sort_unstable();
+    assert_eq!(done, expected);
+}
+
+#[test]
+fn thread_pools() {
+    check_thread_pool::<NaiveThreadPool>(4);
+    check_thread_pool::<SharedQueueThreadPool>(4);
+    #[cfg(feature = "rayon")]
+    check_thread_pool::<kvs::RayonThreadPool>(4);
+}
+
+// kvs-server should serve connections concurrently with each thread pool
+#[test]
+fn server_thread_pools() {
+    let mut pools = vec!["naive", "shared"];
+    if cfg!(feature = "rayon") {
+        pools.push("rayon");
+    }
+    for pool in pools {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr = "127.0.0.1:4115";
+        let server = start_server(&temp_dir, addr, &["--thread-pool", pool, "--threads", "8"]);
+        // a server serving one connection at a time would wait for the first one to close
+        let mut first = KvsClient::connect(addr).unwrap();
+        let mut second = KvsClient::connect(addr).unwrap();
+        second.set("key".to_owned(), "value".to_owned()).unwrap();
+        assert_eq!(
+            first.get("key".to_owned()).unwrap(),
+            Some("value".to_owned())
+        );
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                thread::spawn(move || {
+                    let mut client = KvsClient::connect(addr).unwrap();
+                    for j in 0..50 {
+                        let key = format!("key{}-{}", i, j);
+                        client.set(key.clone(), j.to_string()).unwrap();
+                        assert_eq!(client.get(key).unwrap(), Some(j.to_string()));
+                    }
+                })
+            })
+            .collect();
+        drop(first);
+        drop(second);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        drop(server);
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(
+            store.get("key7-49".to_owned()).unwrap(),
+            Some("49".to_owned())
+        );
+    }
+}
+
+// kvs-server should serve reads of a replica following a primary, and reject writes
+#[test]
+fn server_replica() {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4116";
+    let replica_addr = "127.0.0.1:4117";
+    let _primary = start_server(&primary_dir, primary_addr, &[]);
+    let mut primary = KvsClient::connect(primary_addr).unwrap();
+    primary.set("key1".to_owned(), "1".to_owned()).unwrap();
+
+    let _replica = start_server(&replica_dir, replica_addr, &["--replica-of", primary_addr]);
+    let mut replica = KvsClient::connect(replica_addr).unwrap();
+    let replicated = |replica: &mut KvsClient, key: &str, value: &str| {
+        (0..100).any(|_| {
+            let found = replica.get(key.to_owned()).unwrap() == Some(value.to_owned());
+            if !found {
+                thread::sleep(Duration::from_millis(50));
+            }
+            found
+        })
+    };
+    assert!(replicated(&mut replica, "key1", "1"));
+    primary.set("key2".to_owned(), "2".to_owned()).unwrap();
+    assert!(replicated(&mut replica, "key2", "2"));
+
+    assert_eq!(
+        replica
+            .set("key3".to_owned(), "3".to_owned())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::ReadOnly
+    );
+    assert_eq!(primary.get("key3".to_owned()).unwrap(), None);
+}
+
+// A raft cluster should commit writes through its leader, and keep them when it fails
+#[cfg(feature = "raft")]
+#[test]
+fn raft_cluster() -> Result<()> {
+    use kvs::RaftNode;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addrs = ["127.0.0.1:4118", "127.0.0.1:4119", "127.0.0.1:4120"];
+    let cluster: Vec<_> = (1..=3)
+        .zip(addrs.iter().map(|addr| addr.parse().unwrap()))
+        .collect();
+    let nodes = cluster
+        .iter()
+        .map(|&(id, _)| {
+            let store = KvStore::open(temp_dir.path().join(format!("node{}", id)))?;
+            RaftNode::start(store, id, &cluster)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let eventually = |condition: &dyn Fn() -> bool| {
+        (0..200).any(|_| {
+            let met = condition();
+            if !met {
+                thread::sleep(Duration::from_millis(50));
+            }
+            met
+        })
+    };
+    let leader = |nodes: &[RaftNode]| nodes.iter().position(RaftNode::is_leader);
+    assert!(eventually(&|| leader(&nodes).is_some()));
+    let leader_index = leader(&nodes).unwrap();
+
+    // a write to a follower goes through the leader
+    let follower = &nodes[(leader_index + 1) % 3];
+    follower.set("key1".to_owned(), "1".to_owned())?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("1".to_owned()));
+    for node in &nodes {
+        assert!(eventually(&|| node
+            .get("key1".to_owned())
+            .unwrap()
+            .is_some()));
+    }
+    let e = nodes[leader_index].remove("key2".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::KeyNotFound);
+
+    // the others elect a new leader, which has the committed writes
+    let old_term = nodes[leader_index].term();
+    nodes[leader_index].shutdown();
+    let remaining: Vec<_> = nodes
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != leader_index)
+        .map(|(_, node)| node.clone())
+        .collect();
+    assert!(eventually(&|| leader(&remaining).is_some()));
+    let new_leader = &remaining[leader(&remaining).unwrap()];
+    assert!(new_leader.term() > old_term);
+    assert_eq!(new_leader.get("key1".to_owned())?, Some("1".to_owned()));
+    new_leader.remove("key1".to_owned())?;
+    new_leader.set("key3".to_owned(), "3".to_owned())?;
+    for node in &remaining {
+        assert!(eventually(&|| node
+            .get("key3".to_owned())
+            .unwrap()
+            .is_some()));
+        assert_eq!(node.get("key1".to_owned())?, None);
+    }
+
+    for node in &remaining {
+        node.shutdown();
+    }
+    let e = remaining[0]
+        .set("key4".to_owned(), "4".to_owned())
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotLeader);
+    Ok(())
+}
+
+// A raft node should refuse a store with keys not written through it, keep its store when it
+// restarts, and get a copy of the store of the leader once the entries it is missing are
+// dropped from the raft log
+#[cfg(feature = "raft")]
+#[test]
+fn raft_restart() -> Result<()> {
+    use kvs::RaftNode;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addrs = ["127.0.0.1:4153", "127.0.0.1:4154", "127.0.0.1:4155"];
+    let cluster: Vec<_> = (1..=3)
+        .zip(addrs.iter().map(|addr| addr.parse().unwrap()))
+        .collect();
+    let dir = |id: u64| temp_dir.path().join(format!("node{}", id));
+    // the threads of a stopped node release its store shortly after
+    let start = |id: u64| {
+        let store = (0..100)
+            .find_map(|_| match KvStore::open(dir(id)) {
+                Ok(store) => Some(store),
+                Err(_) => {
+                    thread::sleep(Duration::from_millis(50));
+                    None
+                }
+            })
+            .expect("store is still locked");
+        RaftNode::start(store, id, &cluster)
+    };
+    let eventually = |condition: &dyn Fn() -> bool| {
+        (0..200).any(|_| {
+            let met = condition();
+            if !met {
+                thread::sleep(Duration::from_millis(50));
+            }
+            met
+        })
+    };
+    let leader = |nodes: &[RaftNode]| nodes.iter().position(RaftNode::is_leader);
+
+    let mut store = KvStore::open(dir(1))?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    match start(1) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::StoreNotEmpty),
+        Ok(_) => panic!("started on a store not written through raft"),
+    }
+    let mut store = KvStore::open(dir(1))?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    store.clear()?;
+    drop(store);
+
+    let mut nodes = (1..=3).map(start).collect::<Result<Vec<_>>>()?;
+    assert!(eventually(&|| leader(&nodes).is_some()));
+    let leader_index = leader(&nodes).unwrap();
+    let follower = nodes.remove((leader_index + 1) % 3);
+    let follower_id = follower.id();
+    follower.shutdown();
+    drop(follower);
+
+    // more writes than the raft log keeps
+    let leader_node = &nodes[leader(&nodes).unwrap()];
+    for i in 0..1100 {
+        leader_node.set(format!("key{}", i), i.to_string())?;
+    }
+    let raft_log = dir(leader_node.id()).join("raft.log");
+    assert!(std::fs::metadata(raft_log).unwrap().len() < 20_000);
+    nodes.push(start(follower_id)?);
+    for node in &nodes {
+        assert!(eventually(&|| node
+            .get("key1099".to_owned())
+            .unwrap()
+            .is_some()));
+        assert_eq!(node.get("key0".to_owned())?, Some("0".to_owned()));
+    }
+
+    // restarted nodes have their keys before a leader is elected
+    for node in &nodes {
+        node.shutdown();
+    }
+    drop(nodes);
+    let nodes = (1..=3).map(start).collect::<Result<Vec<_>>>()?;
+    for node in &nodes {
+        assert_eq!(node.get("key1099".to_owned())?, Some("1099".to_owned()));
+    }
+    assert!(eventually(&|| leader(&nodes).is_some()));
+    nodes[0].set("key1100".to_owned(), "1100".to_owned())?;
+    for node in &nodes {
+        assert!(eventually(&|| node
+            .get("key1100".to_owned())
+            .unwrap()
+            .is_some()));
+    }
+    for node in &nodes {
+        node.shutdown();
+    }
+    Ok(())
+}
+
+// ShardedKvsClient should spread keys over servers, and move them when servers change, even
+// more keys than a page of a full copy, reporting the keys with a TTL instead of moving them
+#[test]
+fn server_sharded_client() {
+    let temp_dirs: Vec<_> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let addrs = ["127.0.0.1:4121", "127.0.0.1:4122", "127.0.0.1:4123"];
+    let mut store = KvStore::open(temp_dirs[0].path()).unwrap();
+    store
+        .set_with_ttl("session", "abc".to_owned(), Duration::from_secs(600))
+        .unwrap();
+    drop(store);
+    let _servers: Vec<_> = temp_dirs
+        .iter()
+        .zip(&addrs)
+        .map(|(temp_dir, addr)| start_server(temp_dir, addr, &["--auth-token", "secret"]))
+        .collect();
+    let addrs: Vec<_> = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+    let mut direct: Vec<_> = addrs
+        .iter()
+        .map(|addr| {
+            let mut client = KvsClient::connect(addr).unwrap();
+            client.authenticate("secret".to_owned()).unwrap();
+            client
+        })
+        .collect();
+
+    let mut client = ShardedKvsClient::connect(&addrs[..2])
+        .unwrap()
+        .virtual_nodes(64);
+    client.authenticate("secret".to_owned()).unwrap();
+    // more than a page of a full copy on each server, even once there are 3
+    let key_count = 5000;
+    for key_id in 0..key_count {
+        client
+            .set(format!("key{}", key_id), key_id.to_string())
+            .unwrap();
+    }
+    // keys are spread evenly, each on its own server only
+    for addr in &addrs[..2] {
+        let owned = (0..key_count)
+            .filter(|key_id| client.server_for(&format!("key{}", key_id)) == *addr)
+            .count();
+        assert!(owned > 2000, "{} keys on {}", owned, addr);
+    }
+    for key_id in 0..key_count {
+        let key = format!("key{}", key_id);
+        let owner = addrs
+            .iter()
+            .position(|addr| *addr == client.server_for(&key));
+        for (i, server) in direct.iter_mut().enumerate() {
+            let value = server.get(key.clone()).unwrap();
+            assert_eq!(value.is_some(), Some(i) == owner);
+        }
+    }
+
+    // a new server takes over some keys once they are moved
+    client.add_server(addrs[2]).unwrap();
+    let moved = client.rebalance().unwrap();
+    let third = (0..key_count)
+        .filter(|key_id| client.server_for(&format!("key{}", key_id)) == addrs[2])
+        .count();
+    assert!(third > 0);
+    assert_eq!(moved.moved, third);
+    let kept = match client.server_for("session") == addrs[0] {
+        true => vec![],
+        false => vec!["session".to_owned()],
+    };
+    assert_eq!(moved.kept, kept);
+    assert_eq!(client.rebalance().unwrap().moved, 0);
+    assert_eq!(
+        direct[0].get("session".to_owned()).unwrap(),
+        Some("abc".to_owned())
+    );
+    for key_id in 0..key_count {
+        let key = format!("key{}", key_id);
+        assert_eq!(client.get(key.clone()).unwrap(), Some(key_id.to_string()));
+        let on_third = direct[2].get(key.clone()).unwrap().is_some();
+        assert_eq!(on_third, client.server_for(&key) == addrs[2]);
+    }
+
+    // removing a server moves its keys to the others
+    let first = (0..key_count)
+        .filter(|key_id| client.server_for(&format!("key{}", key_id)) == addrs[0])
+        .count();
+    assert!(first > 1024, "{} keys on {}", first, addrs[0]);
+    let moved = client.remove_server(addrs[0]).unwrap().unwrap();
+    assert_eq!(moved.moved, first);
+    assert_eq!(moved.kept, vec!["session".to_owned()]);
+    assert_eq!(client.remove_server(addrs[0]).unwrap(), None);
+    assert_eq!(client.servers(), addrs[1..].to_vec());
+    for key_id in 0..key_count {
+        let key = format!("key{}", key_id);
+        assert_eq!(client.get(key.clone()).unwrap(), Some(key_id.to_string()));
+        assert_eq!(direct[0].get(key).unwrap(), None);
+    }
+}
+
+// KvsClient should time out on a silent server, and retry requests on a restarted one
+#[test]
+fn client_timeouts_and_retries() {
+    // a server accepting connections but never answering
+    let silent = std::net::TcpListener::bind("127.0.0.1:4124").unwrap();
+    let mut client = KvsClient::connect("127.0.0.1:4124").unwrap();
+    client
+        .set_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let e = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Timeout);
+    drop(silent);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4125";
+    let args = ["--auth-token", "secret"];
+    let server = start_server(&temp_dir, addr, &args);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set_retry_policy(
+        RetryPolicy::new(5)
+            .initial_backoff(Duration::from_millis(20))
+            .max_backoff(Duration::from_millis(100)),
+    );
+    client.authenticate("secret".to_owned()).unwrap();
+    client.set("key1".to_owned(), "1".to_owned()).unwrap();
+
+    // the connection breaks, and requests go on a new one, authenticated again
+    drop(server);
+    let server = start_server(&temp_dir, addr, &args);
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("1".to_owned()));
+    client.set("key2".to_owned(), "2".to_owned()).unwrap();
+
+    // removes are not retried
+    drop(server);
+    let _server = start_server(&temp_dir, addr, &args);
+    let e = client.remove("key2".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Io);
+    client.set_retry_policy(RetryPolicy::none());
+    assert!(client.get("key2".to_owned()).is_err());
+}
+
+// kvs-server should serve metrics for Prometheus on a port of their own
+#[test]
+fn server_metrics() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4126";
+    let metrics_addr = "127.0.0.1:4127";
+    let _server = start_server(&temp_dir, addr, &["--metrics-addr", metrics_addr]);
+    let scrape = |path: &str| {
+        let mut stream = TcpStream::connect(metrics_addr).expect("unable to connect");
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "1".to_owned()).unwrap();
+    client.set("key1".to_owned(), "2".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("2".to_owned()));
+    assert!(client.remove("key2".to_owned()).is_err());
+
+    let response = scrape("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    let metrics = response.split_once("\r\n\r\n").unwrap().1;
+    for line in &[
+        "# TYPE kvs_command_duration_seconds histogram",
+        "kvs_command_duration_seconds_count{command=\"set\"} 2",
+        "kvs_command_duration_seconds_bucket{command=\"set\",le=\"+Inf\"} 2",
+        "kvs_command_duration_seconds_count{command=\"get\"} 1",
+        "kvs_command_errors_total{command=\"remove\"} 1",
+        "kvs_command_errors_total{command=\"set\"} 0",
+        "kvs_connections_active 1",
+        "kvs_live_keys 1",
+        "kvs_compactions_total 0",
+    ] {
+        assert!(
+            metrics.lines().any(|l| l == *line),
+            "{} in {}",
+            line,
+            metrics
+        );
+    }
+    assert!(scrape("/keys").starts_with("HTTP/1.1 404 "));
+
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+    assert!(scrape("/metrics").contains("\nkvs_connections_active 0\n"));
+}
+
+// A server should answer reads while a write holds the store, instead of running the
+// commands of its connections one at a time
+#[test]
+fn server_concurrent_reads() {
+    use std::sync::mpsc;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4148";
+    let held = HeldWrite::new();
+    let mut store = KvStore::builder()
+        .write_hook(held.hook())
+        .open(temp_dir.path())
+        .unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let server = KvsServer::new(SharedKvStore::new(store).unwrap());
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    let mut writer = connect_when_listening(addr);
+    let mut reader = KvsClient::connect(addr).unwrap();
+    // opens a reader of the log file, which waits for the store, to be reused after
+    assert_eq!(
+        reader.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    let writing = thread::spawn(move || writer.set("slow".to_owned(), "value".to_owned()));
+    held.wait_held();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(reader.get("key1".to_owned()).unwrap()));
+    let read = receiver.recv_timeout(Duration::from_secs(5));
+    held.release();
+    assert_eq!(read, Ok(Some("value1".to_owned())));
+    writing.join().unwrap().unwrap();
+
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+// A server of a `ShardedKvsEngine` should write to a shard while a write holds another
+#[test]
+fn server_sharded_engine() {
+    use std::sync::mpsc;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4149";
+    let held = HeldWrite::new();
+    let shards = (0..2)
+        .map(|i| {
+            KvStore::builder()
+                .write_hook(held.hook())
+                .open(temp_dir.path().join(i.to_string()))
+                .unwrap()
+        })
+        .collect();
+    let server = KvsServer::new(ShardedKvsEngine::new(shards));
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    let mut writer = connect_when_listening(addr);
+    let mut other = KvsClient::connect(addr).unwrap();
+
+    let writing = thread::spawn(move || writer.set("slow".to_owned(), "value".to_owned()));
+    held.wait_held();
+    // `key0` is in the other shard of the two
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        other.set("key0".to_owned(), "value0".to_owned()).unwrap();
+        sender.send(other.get("key0".to_owned()).unwrap())
+    });
+    let written = receiver.recv_timeout(Duration::from_secs(5));
+    held.release();
+    assert_eq!(written, Ok(Some("value0".to_owned())));
+    writing.join().unwrap().unwrap();
+
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+// Shutting a server down should answer the requests in flight, then sync and close the
+// engine, releasing the lock of the store
+#[test]
+fn server_shutdown_handle() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4128";
+    let server =
+        KvsServer::new(SharedKvStore::new(KvStore::open(temp_dir.path()).unwrap()).unwrap());
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    let mut client = connect_when_listening(addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(TcpStream::connect(addr).is_err());
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // shutting down before running returns at once
+    let server = KvsServer::new(SharedKvStore::new(store).unwrap());
+    server.shutdown_handle().shutdown();
+    server.run(addr).unwrap();
+}
+
+// kvs-server should shut down gracefully on SIGTERM
+#[cfg(unix)]
+#[test]
+fn server_shutdown_on_signal() {
+    // the async server syncs the engine and exits instead, as it cannot stop once running
+    let mut servers = vec![("127.0.0.1:4129", Vec::new())];
+    if cfg!(feature = "async") {
+        servers.push(("127.0.0.1:4150", vec!["--async"]));
+    }
+    for (addr, args) in servers {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut server = start_server(&temp_dir, addr, &args);
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+        let killed = Command::new("kill")
+            .args(["-TERM", &server.0.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(killed.success());
+        let mut status = None;
+        for _ in 0..100 {
+            status = server.0.try_wait().unwrap();
+            if status.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(status.expect("kvs-server did not exit").success());
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+}
+
+// kvs-server should read its settings from a config file, and reload the runtime options of
+// the store on SIGHUP
+#[cfg(unix)]
+#[test]
+fn server_config_reload() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4130";
+    let config = temp_dir.path().join("kvs-server.toml");
+    // options on the command line take precedence over the file
+    std::fs::write(
+        &config,
+        "addr = \"127.0.0.1:4131\"\nthreads = 4\nmax_value_size = 4\n",
+    )
+    .unwrap();
+    let server = start_server(&temp_dir, addr, &["--config", config.to_str().unwrap()]);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "1234".to_owned()).unwrap();
+    let e = client
+        .set("key1".to_owned(), "12345".to_owned())
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ValueTooLarge);
+
+    std::fs::write(&config, "threads = 4\nmax_value_size = 8\n").unwrap();
+    let hup = Command::new("kill")
+        .args(["-HUP", &server.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(hup.success());
+    let mut reloaded = false;
+    for _ in 0..100 {
+        if client.set("key1".to_owned(), "12345".to_owned()).is_ok() {
+            reloaded = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(reloaded);
+    let e = client
+        .set("key1".to_owned(), "123456789".to_owned())
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ValueTooLarge);
+
+    // a malformed file is not reloaded
+    std::fs::write(&config, "max_value_size = \"big\"\n").unwrap();
+    Command::new("kill")
+        .args(["-HUP", &server.0.id().to_string()])
+        .status()
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    client.set("key1".to_owned(), "12345".to_owned()).unwrap();
+}
+
+// kvs-server should log the commands taking longer than the threshold of the slow log
+#[test]
+fn server_slow_log() {
+    use std::process::Stdio;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_log = |addr: &str, args: &[&str]| {
+        let mut server = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr])
+            .args(args)
+            .current_dir(&temp_dir)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut client = connect_when_listening(addr);
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        client.get("key1".to_owned()).unwrap();
+        drop(client);
+        thread::sleep(Duration::from_millis(100));
+        server.kill().unwrap();
+        let mut log = String::new();
+        server
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut log)
+            .unwrap();
+        server.wait().unwrap();
+        log
+    };
+
+    let log = read_log("127.0.0.1:4132", &["--slow-log-ms", "0"]);
+    assert!(log.contains("Slow command: set \"key1\" took "), "{}", log);
+    assert!(log.contains("Slow command: get \"key1\" took "), "{}", log);
+
+    let log = read_log(
+        "127.0.0.1:4133",
+        &["--slow-log-ms", "0", "--slow-log-hash-keys"],
+    );
+    let hashed = format!("Slow command: get #{:08x} took ", crc32fast::hash(b"key1"));
+    assert!(log.contains(&hashed), "{}", log);
+    assert!(!log.contains("\"key1\""), "{}", log);
+
+    let log = read_log("127.0.0.1:4134", &["--slow-log-ms", "60000"]);
+    assert!(!log.contains("Slow command"), "{}", log);
+}
+
+// A server should listen on a Unix domain socket, for kvs-client with a unix:// address too
+#[cfg(unix)]
+#[test]
+fn server_unix_socket() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let socket = temp_dir.path().join("kvs.sock");
+    // a socket file left by a server which did not shut down
+    drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+    let server =
+        KvsServer::new(SharedKvStore::new(KvStore::open(temp_dir.path()).unwrap()).unwrap());
+    let shutdown = server.shutdown_handle();
+    let path = socket.clone();
+    let running = thread::spawn(move || server.run_unix(path));
+    let addr = format!("unix://{}", socket.display());
+    let mut client = connect_when_listening(&addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    drop(client);
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+    assert!(!socket.exists());
+
+    // a file which is not a socket is left alone
+    std::fs::write(&socket, "data").unwrap();
+    let server =
+        KvsServer::new(SharedKvStore::new(KvStore::open(temp_dir.path()).unwrap()).unwrap());
+    let e = server.run_unix(&socket).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Io);
+    assert_eq!(std::fs::read_to_string(&socket).unwrap(), "data");
+}
+
+// Connections should start with a handshake agreeing on a version of the protocol, failing
+// with IncompatibleVersion when there is none
+#[test]
+fn protocol_handshake() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4135";
+    let _server = start_server(&temp_dir, addr, &[]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    // a client speaking versions 1 to 5 agrees on the version of the server
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"KVS\0").unwrap();
+    stream.write_all(&1u32.to_be_bytes()).unwrap();
+    stream.write_all(&5u32.to_be_bytes()).unwrap();
+    let mut answer = [0; 8];
+    stream.read_exact(&mut answer).unwrap();
+    assert_eq!(&answer[..4], b"KVS\0");
+    assert_eq!(answer[4..], PROTOCOL_VERSION.to_be_bytes());
+
+    // a client only speaking newer versions is refused
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"KVS\0").unwrap();
+    stream
+        .write_all(&(PROTOCOL_VERSION + 1).to_be_bytes())
+        .unwrap();
+    stream
+        .write_all(&(PROTOCOL_VERSION + 2).to_be_bytes())
+        .unwrap();
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer).unwrap();
+    assert_eq!(answer, b"KVS\0\0\0\0\0");
+
+    // a server predating the handshake closes the connection on it
+    let listener = std::net::TcpListener::bind("127.0.0.1:4136").unwrap();
+    let old_server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // reading all the client sent, as its buffered reader does
+        assert!(stream.read(&mut [0; 1024]).unwrap() > 0);
+    });
+    let mut client = KvsClient::connect("127.0.0.1:4136").unwrap();
+    let e = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::IncompatibleVersion);
+    old_server.join().unwrap();
+}
+
+// Clients should list keys of the server page by page, within the bounds of the server
+#[test]
+fn server_scan() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4137";
+    let _server = start_server(&temp_dir, addr, &[]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for key in 0..1500 {
+        client
+            .set(format!("key{:04}", key), key.to_string())
+            .unwrap();
+    }
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+
+    let page = client
+        .scan("key".to_owned(), &ScanCursor::default(), 2)
+        .unwrap();
+    assert_eq!(
+        page.entries,
+        vec![
+            ("key0000".to_owned(), "0".to_owned()),
+            ("key0001".to_owned(), "1".to_owned())
+        ]
+    );
+    let page = client
+        .scan("key".to_owned(), &page.cursor.unwrap(), 2)
+        .unwrap();
+    assert_eq!(page.entries[0].0, "key0002");
+
+    // pages are bounded by the server
+    let page = client
+        .scan(String::new(), &ScanCursor::default(), 5000)
+        .unwrap();
+    assert_eq!(page.entries.len(), 1000);
+    assert!(page.cursor.is_some());
+
+    // as an engine, the client fetches every page
+    let pairs = KvsEngine::scan_prefix(&mut client, "key1".to_owned()).unwrap();
+    assert_eq!(pairs.len(), 500);
+    assert_eq!(pairs[499], ("key1499".to_owned(), "1499".to_owned()));
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "key14", "--page-size", "7", "--addr", addr])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 100);
+    assert_eq!(lines[0], "key1400\t1400");
+    assert_eq!(lines[99], "key1499\t1499");
+}
+
+// A server should refuse connections and batches beyond its limits as busy
+#[test]
+fn server_limits() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4138";
+    let args = ["--max-connections", "1", "--max-in-flight", "2"];
+    let _server = start_server(&temp_dir, addr, &args);
+
+    // until the server is done with the connection of `start_server`
+    let mut first = KvsClient::connect(addr).unwrap();
+    first.set_retry_policy(RetryPolicy::new(5));
+    first.ping().unwrap();
+    let mut second = KvsClient::connect(addr).unwrap();
+    let e = second.get("key1".to_owned()).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ServerBusy);
+
+    let set = |key: &str| Request::Set {
+        key: key.to_owned(),
+        value: "value".to_owned(),
+    };
+    let e = first
+        .batch(vec![set("key1"), set("key2"), set("key3")])
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ServerBusy);
+    assert_eq!(first.get("key1".to_owned()).unwrap(), None);
+    let results = first.batch(vec![set("key1"), set("key2")]).unwrap();
+    assert!(results.iter().all(Result::is_ok));
+
+    // a connection is taken again once another one is closed
+    drop(first);
+    second.set_retry_policy(RetryPolicy::new(5));
+    assert_eq!(
+        second.get("key1".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+// Should close connections idle beyond the timeout, but not those pinged meanwhile.
+#[test]
+fn server_idle_timeout() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4139";
+    let _server = start_server(&temp_dir, addr, &["--idle-timeout-ms", "300"]);
+
+    let mut pinged = KvsClient::connect(addr).unwrap();
+    let mut idle = KvsClient::connect(addr).unwrap();
+    idle.ping().unwrap();
+    let pool = KvsClientPool::new(addr)
+        .unwrap()
+        .check_after(Duration::from_secs(0));
+    pool.client().unwrap().ping().unwrap();
+    assert_eq!(pool.idle_count(), 1);
+    for _ in 0..6 {
+        thread::sleep(Duration::from_millis(100));
+        pinged.ping().unwrap();
+        pool.keep_alive();
+    }
+    assert!(idle.get("key1".to_owned()).is_err());
+    pinged.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(pool.idle_count(), 1);
+    assert_eq!(
+        pool.client().unwrap().get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // a connection the server closed is dropped from the pool
+    thread::sleep(Duration::from_millis(600));
+    pool.keep_alive();
+    assert_eq!(pool.idle_count(), 0);
+}
+
+// Should read from the replicas and write to the primary, reading from the primary right
+// after a write with read-your-writes.
+#[test]
+fn replicated_client() {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4140";
+    let replica_addr = "127.0.0.1:4141";
+    let primary_server = start_server(&primary_dir, primary_addr, &[]);
+    let _replica = start_server(&replica_dir, replica_addr, &["--replica-of", primary_addr]);
+
+    let primary = primary_addr.parse().unwrap();
+    let replicas = [replica_addr.parse().unwrap()];
+    let mut client = ReplicatedKvsClient::connect(primary, &replicas)
+        .unwrap()
+        .balance(ReadBalance::LeastLatency)
+        .read_your_writes(Duration::from_secs(60));
+    assert_eq!(client.primary(), primary);
+    assert_eq!(client.replicas(), replicas);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // without pinning, a read right after a write may miss it, until the replica catches up
+    let mut client = ReplicatedKvsClient::connect(primary, &replicas).unwrap();
+    let replicated = (0..100).any(|_| {
+        let found = client.get("key1".to_owned()).unwrap() == Some("value1".to_owned());
+        if !found {
+            thread::sleep(Duration::from_millis(50));
+        }
+        found
+    });
+    assert!(replicated);
+    client.remove("key1".to_owned()).unwrap();
+    let mut replica = KvsClient::connect(replica_addr).unwrap();
+    let removed = (0..100).any(|_| {
+        let found = replica.get("key1".to_owned()).unwrap().is_none();
+        if !found {
+            thread::sleep(Duration::from_millis(50));
+        }
+        found
+    });
+    assert!(removed);
+
+    // reads still work without the primary
+    drop(primary_server);
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+}
+
+// Should push the changes of the subscribed prefixes to the subscribed connection.
+#[test]
+fn server_subscribe() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4142";
+    let _server = start_server(&temp_dir, addr, &[]);
+
+    let subscriber = KvsClient::connect(addr).unwrap();
+    let mut subscription = subscriber
+        .subscribe(vec!["user:".to_owned(), "user:1".to_owned()])
+        .unwrap();
+    subscription
+        .set_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    client.set("order:1".to_owned(), "book".to_owned()).unwrap();
+    client.remove("user:1".to_owned()).unwrap();
+
+    let set = subscription.next_event().unwrap();
+    assert_eq!(set.key, b"user:1");
+    assert_eq!(set.op, ChangeOp::Set);
+    assert_eq!(set.new_value, Some(b"alice".to_vec()));
+    let remove = subscription.next().unwrap().unwrap();
+    assert_eq!(remove.key, b"user:1");
+    assert_eq!(remove.op, ChangeOp::Remove);
+    assert_eq!(remove.old_value, Some(b"alice".to_vec()));
+
+    // the subscribed connection takes no more requests, but others do
+    assert_eq!(
+        client.get("order:1".to_owned()).unwrap(),
+        Some("book".to_owned())
+    );
+    let response = client.batch(vec![Request::Subscribe {
+        prefixes: Vec::new(),
+    }]);
+    assert_eq!(
+        response.unwrap()[0].as_ref().unwrap_err().kind(),
+        ErrorKind::Unsupported
+    );
+}
+
+// Should remove expired keys in the background, at most as many per second as asked.
+#[test]
+fn server_expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4143";
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["key1", "key2", "key3"].iter() {
+        store.set_with_ttl(*key, "value".to_owned(), Duration::from_millis(1))?;
+    }
+    store.set("key4".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(10));
+    let mut store = SharedKvStore::new(store)?;
+    let watch = store.subscribe(vec![String::new()])?;
+
+    let server = KvsServer::new(store.clone()).expire_keys(2);
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    let mut removed = Vec::new();
+    for _ in 0..2 {
+        let event = watch.next_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.op, ChangeOp::Remove);
+        assert_eq!(event.old_value, None);
+        removed.push(event.key);
+    }
+    // the last one a second later
+    assert!(watch.try_next().is_none());
+    removed.push(watch.next_timeout(Duration::from_secs(5)).unwrap().key);
+    removed.sort();
+    assert_eq!(
+        removed,
+        vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()]
+    );
+    assert_eq!(store.get("key4".to_owned())?, Some("value".to_owned()));
+
+    shutdown.shutdown();
+    running.join().unwrap()?;
+    assert_eq!(store.expire_keys(10)?, 0);
+    Ok(())
+}
+
+// Should compact, report statistics, flush and back up the store of the server on command
+// of an admin user, or of any client if allowed, and back up in the backup directory only.
+#[test]
+fn server_admin_commands() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acl = backup_dir.path().join("acl.json");
+    let users = json!({
+        "users": [
+            { "name": "app", "token": "u", "read": [""], "write": [""] },
+            { "name": "admin", "token": "a", "read": [""], "write": [""], "admin": true }
+        ]
+    });
+    std::fs::write(&acl, users.to_string()).unwrap();
+    let addr = "127.0.0.1:4144";
+    let args = [
+        "--acl",
+        acl.to_str().unwrap(),
+        "--backup-dir",
+        backup_dir.path().to_str().unwrap(),
+    ];
+    let _server = start_server(&temp_dir, addr, &args);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.authenticate("u".to_owned()).unwrap();
+    for i in 0..10 {
+        client
+            .set("key1".to_owned(), format!("value{}", i))
+            .unwrap();
+    }
+    assert_eq!(
+        client.stats().unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        client.compact().unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        client.flush().unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    drop(client);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.authenticate("a".to_owned()).unwrap();
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.redundant_records, 9);
+    client.compact().unwrap();
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.redundant_records, 0);
+    assert!(stats.compactions >= 1);
+    client.flush().unwrap();
+
+    // backups stay in the backup directory
+    let outside = temp_dir.path().join("backup.tar");
+    for path in [outside.to_str().unwrap(), "../backup.tar", ""].iter() {
+        assert_eq!(
+            client.start_backup(path.to_string()).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+    let backup = backup_dir.path().join("backup.tar");
+    client.start_backup("backup.tar".to_owned()).unwrap();
+    // the backup is written in the background, so it may take a few tries to restore
+    let mut attempt = 0;
+    let restored = loop {
+        let restored = backup_dir.path().join(format!("restored{}", attempt));
+        if KvStore::restore(&backup, &restored).is_ok() {
+            break restored;
+        }
+        attempt += 1;
+        assert!(attempt < 100, "the backup never completed");
+        thread::sleep(Duration::from_millis(50));
+    };
+    let mut store = KvStore::open(&restored).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value9".to_owned())
+    );
+
+    // without an ACL, only if the server allows admin commands to every client
+    for (addr, allowed) in [("127.0.0.1:4151", false), ("127.0.0.1:4152", true)].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let args: &[&str] = if *allowed { &["--allow-admin"] } else { &[] };
+        let _server = start_server(&temp_dir, addr, args);
+        let mut client = KvsClient::connect(addr).unwrap();
+        assert_eq!(client.flush().is_ok(), *allowed);
+        // without a backup directory, no backups
+        assert_eq!(
+            client
+                .start_backup("backup.tar".to_owned())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+}
+
+// Should record who changed which key to the audit log, leaving out refused changes.
+#[test]
+fn server_audit_log() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acl = log_dir.path().join("acl.json");
+    let users = json!({
+        "users": [
+            { "name": "billing", "token": "b", "read": ["billing:"], "write": ["billing:"] }
+        ]
+    });
+    std::fs::write(&acl, users.to_string()).unwrap();
+    let audit_log = log_dir.path().join("audit.log");
+    let addr = "127.0.0.1:4145";
+    let _server = start_server(
+        &temp_dir,
+        addr,
+        &[
+            "--acl",
+            acl.to_str().unwrap(),
+            "--audit-log",
+            audit_log.to_str().unwrap(),
+        ],
+    );
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.authenticate("b".to_owned()).unwrap();
+    client.set("billing:1".to_owned(), "10".to_owned()).unwrap();
+    client
+        .set("hr:1".to_owned(), "secret".to_owned())
+        .unwrap_err();
+    client.get("billing:1".to_owned()).unwrap();
+    client.remove("billing:1".to_owned()).unwrap();
+    client.remove("billing:2".to_owned()).unwrap_err();
+
+    let records: Vec<AuditRecord> = std::fs::read_to_string(&audit_log)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    for (record, op) in records.iter().zip([ChangeOp::Set, ChangeOp::Remove].iter()) {
+        assert_eq!(record.op, *op);
+        assert_eq!(record.key, "billing:1");
+        assert_eq!(record.user.as_deref(), Some("billing"));
+        assert_eq!(
+            record.peer.unwrap().ip(),
+            "127.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+    assert!(records[0].time_ms <= records[1].time_ms);
+}
+
+// Should answer reads of cached keys without the server, dropping the values changed on
+// the server as their changes arrive, and forget the reads of missing keys.
+#[test]
+fn client_near_cache() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4146";
+    let metrics_addr = "127.0.0.1:4147";
+    let _server = start_server(&temp_dir, addr, &["--metrics-addr", metrics_addr]);
+    let gets = || {
+        let mut stream = TcpStream::connect(metrics_addr).expect("unable to connect");
+        write!(stream, "GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let prefix = "kvs_command_duration_seconds_count{command=\"get\"} ";
+        let line = response.lines().find(|line| line.starts_with(prefix));
+        line.map_or(0, |line| line[prefix.len()..].parse::<u64>().unwrap())
+    };
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let near_cache = NearCache::new(100)
+        .prefixes(vec!["cached:".to_owned()])
+        .max_staleness(Duration::from_secs(60));
+    client.set_near_cache(near_cache).unwrap();
+    client.set("cached:1".to_owned(), "1".to_owned()).unwrap();
+    client.set("other:1".to_owned(), "1".to_owned()).unwrap();
+    for _ in 0..3 {
+        assert_eq!(
+            client.get("cached:1".to_owned()).unwrap(),
+            Some("1".to_owned())
+        );
+        client.get("other:1".to_owned()).unwrap();
+    }
+    assert_eq!(gets(), 4);
+
+    // changed by another client
+    let mut other = KvsClient::connect(addr).unwrap();
+    other.set("cached:1".to_owned(), "2".to_owned()).unwrap();
+    let mut attempt = 0;
+    while client.get("cached:1".to_owned()).unwrap() != Some("2".to_owned()) {
+        attempt += 1;
+        assert!(attempt < 100, "the cached value was never invalidated");
+        thread::sleep(Duration::from_millis(50));
+    }
+    other.remove("cached:1".to_owned()).unwrap();
+    let mut attempt = 0;
+    while client.get("cached:1".to_owned()).unwrap().is_some() {
+        attempt += 1;
+        assert!(attempt < 100, "the cached value was never invalidated");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // changed by the client itself
+    client.set("cached:1".to_owned(), "3".to_owned()).unwrap();
+    assert_eq!(
+        client.get("cached:1".to_owned()).unwrap(),
+        Some("3".to_owned())
+    );
+    client.set("cached:1".to_owned(), "4".to_owned()).unwrap();
+    assert_eq!(
+        client.get("cached:1".to_owned()).unwrap(),
+        Some("4".to_owned())
+    );
+
+    // missing keys
+    for i in 0..100 {
+        assert_eq!(client.get(format!("cached:missing{}", i)).unwrap(), None);
+    }
+    assert_eq!(client.near_cache_reads(), 0);
+}